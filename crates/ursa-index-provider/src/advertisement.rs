@@ -85,6 +85,30 @@ impl Advertisement {
         .map_err(AdSigError::SigningError)
     }
 
+    /// Whether the advertisement is from `expected_peer` and signed by it.
+    pub fn verify(&self, expected_peer: &PeerId) -> bool {
+        self.Provider == expected_peer.to_base58()
+            && matches!(self.signer(), Ok(signer) if signer == *expected_peer)
+    }
+
+    /// The peer that signed the advertisement, once the signature is checked against its
+    /// fields.
+    pub fn signer(&self) -> Result<PeerId, AdSigError> {
+        let envelope = match &self.Signature {
+            Ipld::Bytes(bytes) if !bytes.is_empty() => bytes,
+            _ => return Err(AdSigError::MissingSig),
+        };
+        let envelope =
+            SignedEnvelope::from_protobuf_encoding(envelope).map_err(AdSigError::DecodingError)?;
+        let (payload, signing_key) = envelope
+            .payload_and_signing_key(AD_SIGNATURE_DOMAIN.into(), AD_SIGNATURE_CODEC.as_bytes())
+            .map_err(AdSigError::ReadPayloadError)?;
+        if payload != self.sig_payload()? {
+            return Err(AdSigError::PayloadDidNotMatch);
+        }
+        Ok(signing_key.to_peer_id())
+    }
+
     /// computes a signature over all of these fields
     /// https://github.com/MarcoPolo/http-index-provider-example/blob/6ebda4211c93324405c827b5ffc46c513741efa8/src/advertisement.rs#L49
     fn sig_payload(&self) -> Result<Vec<u8>, AdSigError> {
@@ -168,4 +192,16 @@ impl EntryChunk {
             Next: next,
         }
     }
+
+    pub fn entries(&self) -> &[Ipld] {
+        &self.Entries
+    }
+
+    pub fn next(&self) -> Option<&Ipld> {
+        self.Next.as_ref()
+    }
 }
+
+#[cfg(test)]
+#[path = "tests/advertisement_tests.rs"]
+mod advertisement_tests;
@@ -1,3 +1,4 @@
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -10,6 +11,9 @@ pub struct ProviderConfig {
     /// indexer url to point to, eg. https://dev.cid.contact
     #[serde(default = "ProviderConfig::default_indexer_url")]
     pub indexer_url: String,
+    /// gossipsub topic the indexers ingest the announcements from, eg. indexer/ingest/mainnet
+    #[serde(default = "ProviderConfig::default_indexer_topic")]
+    pub indexer_topic: String,
     /// database_path for index provider db
     #[serde(default = "ProviderConfig::default_database_path")]
     pub database_path: PathBuf,
@@ -22,6 +26,16 @@ impl ProviderConfig {
     fn default_indexer_url() -> String {
         "https://dev.cid.contact".to_string()
     }
+    fn default_indexer_topic() -> String {
+        "indexer/ingest/mainnet".to_string()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.indexer_topic.trim().is_empty() {
+            return Err(anyhow!("The indexer topic must not be empty"));
+        }
+        Ok(())
+    }
 }
 
 impl Default for ProviderConfig {
@@ -29,6 +43,7 @@ impl Default for ProviderConfig {
         Self {
             domain: None,
             indexer_url: Self::default_indexer_url(),
+            indexer_topic: Self::default_indexer_topic(),
             database_path: Self::default_database_path(),
         }
     }
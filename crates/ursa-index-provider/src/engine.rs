@@ -10,7 +10,7 @@ use libipld_core::ipld::Ipld;
 use tokio::{
     select,
     sync::{
-        mpsc::{unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender},
+        mpsc::{unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
 };
@@ -73,6 +73,35 @@ pub enum ProviderCommand {
         context_id: Vec<u8>,
         sender: CommandOneShotReceiver<()>,
     },
+    /// gossip the announcement of the current head on the indexer topic
+    AnnounceToIndexer { sender: CommandOneShotSender<()> },
+    /// subscribe to the events emitted by the provider engine
+    Subscribe {
+        sender: UnboundedSender<ProviderEvent>,
+    },
+}
+
+/// Lifecycle status of an advertisement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdvertisementStatus {
+    /// The advertisement has been created locally.
+    Created,
+    /// The advertisement has been published as the new head.
+    Published,
+    /// The indexer accepted the announcement over http. Gossip has no acknowledgement.
+    Acknowledged,
+    /// Publishing the advertisement failed, or it could be announced neither over gossipsub
+    /// nor over http.
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProviderEvent {
+    /// An advertisement moved through its lifecycle.
+    AdvertisementEvent {
+        context_id: Vec<u8>,
+        status: AdvertisementStatus,
+    },
 }
 
 #[derive(Debug)]
@@ -93,11 +122,13 @@ pub struct ProviderEngine<S> {
     /// Handles inbound messages to the provider engine
     command_receiver: UnboundedReceiver<ProviderCommand>,
     /// network command sender for communication with libp2p node
-    network_command_sender: UnboundedSender<NetworkCommand>,
+    network_command_sender: Sender<NetworkCommand>,
     /// List of addresses to submit to indexer.
     addresses: Vec<Multiaddr>,
     /// Handles events from the network.
     network_event_receiver: Receiver<NetworkEvent>,
+    /// Subscribers to the provider engine events.
+    event_subscribers: Vec<UnboundedSender<ProviderEvent>>,
 }
 
 impl<S> ProviderEngine<S>
@@ -109,7 +140,7 @@ where
         store: Arc<UrsaStore<S>>,
         provider_store: Arc<UrsaStore<S>>,
         config: ProviderConfig,
-        network_command_sender: UnboundedSender<NetworkCommand>,
+        network_command_sender: Sender<NetworkCommand>,
         addresses: Vec<Multiaddr>,
         network_event_receiver: Receiver<NetworkEvent>,
     ) -> Self {
@@ -123,6 +154,7 @@ where
             store,
             addresses,
             network_event_receiver,
+            event_subscribers: Vec::new(),
         }
     }
     pub fn command_sender(&self) -> UnboundedSender<ProviderCommand> {
@@ -150,6 +182,7 @@ where
 
     pub async fn start(mut self) -> Result<()> {
         info!("Index provider engine starting up!");
+        self.subscribe_indexer_topic().await?;
         loop {
             select! {
                 Some(command) = self.command_receiver.recv() => {
@@ -159,37 +192,80 @@ where
                             sender,
                             size,
                         } => {
-                            let cid = Cid::try_from(context_id).unwrap();
+                            let cid = match Cid::try_from(context_id.as_slice()) {
+                                Ok(cid) => cid,
+                                Err(e) => {
+                                    warn!("Provider Engine: refusing to advertise an invalid context id: {e}");
+                                    if sender.send(Err(anyhow!("The context id is not a valid cid: {e}"))).is_err() {
+                                        error!("Provider Engine: failed to send the invalid context id error");
+                                    }
+                                    continue;
+                                }
+                            };
                             if let Err(e) = sender.send(Ok(())) {
                                 error!("Provider Engine: {:?}", e);
                             }
                             let peer_id = PeerId::from(self.provider.keypair().public());
 
                             if let Err(e) = self.publish_local(cid, size).await {
-                                error!("Error while publishing the advertisement locally: {:?}", e)
+                                error!("Error while publishing the advertisement locally: {:?}", e);
+                                self.emit_advertisement_event(context_id, AdvertisementStatus::Failed);
                             } else {
-                                match self
+                                let status = match self
                                     .provider
                                     .create_announce_message(peer_id, self.addresses.clone())
                                 {
                                     Ok(announce_message) => {
-                                        if let Err(e) = self
+                                        let gossiped = match self
                                             .gossip_announce(announce_message.clone(), peer_id)
                                             .await
                                         {
-                                            warn!("there was an error while gossiping the announcement, will try to announce via http {:?}", e);
-                                            self.http_announce(announce_message).await;
+                                            Ok(()) => true,
+                                            Err(e) => {
+                                                warn!("there was an error while gossiping the announcement: {:?}", e);
+                                                false
+                                            }
+                                        };
+                                        // only the indexer answering over http acknowledges the announcement
+                                        match self.http_announce(announce_message).await {
+                                            Ok(()) => Some(AdvertisementStatus::Acknowledged),
+                                            Err(e) if gossiped => {
+                                                warn!("the indexer did not acknowledge the gossiped announcement: {:?}", e);
+                                                None
+                                            }
+                                            Err(e) => {
+                                                error!("failed to announce to the indexer via http: {:?}", e);
+                                                Some(AdvertisementStatus::Failed)
+                                            }
                                         }
                                     }
-                                    Err(e) => warn!(
-                                        "There was a problem parsing announcement message: {:?}",
-                                        e
-                                    ),
+                                    Err(e) => {
+                                        warn!(
+                                            "There was a problem parsing announcement message: {:?}",
+                                            e
+                                        );
+                                        Some(AdvertisementStatus::Failed)
+                                    }
+                                };
+                                if let Some(status) = status {
+                                    self.emit_advertisement_event(context_id, status);
                                 }
                             }
                         }
                         // TODO: implement when cache eviction is implemented
                         ProviderCommand::Remove { .. } => todo!(),
+                        ProviderCommand::AnnounceToIndexer { sender } => {
+                            let result = self.announce().await;
+                            if let Err(e) = &result {
+                                warn!("Provider Engine: failed to announce the head to the indexer: {e:?}");
+                            }
+                            if sender.send(result).is_err() {
+                                error!("Provider Engine: failed to send the announcement result");
+                            }
+                        }
+                        ProviderCommand::Subscribe { sender } => {
+                            self.event_subscribers.push(sender);
+                        }
                     }
                 }
                 Some(network_event) = self.network_event_receiver.recv() => {
@@ -222,8 +298,10 @@ where
             .map(|address| address.to_string())
             .collect();
 
-        let advertisement = Advertisement::new(context_id, peer_id, addresses, false, file_size);
+        let advertisement =
+            Advertisement::new(context_id.clone(), peer_id, addresses, false, file_size);
         let provider_id = self.provider.create(advertisement)?;
+        self.emit_advertisement_event(context_id.clone(), AdvertisementStatus::Created);
 
         let dag = self.store.dag_traversal(&(root_cid))?;
         let entries = dag
@@ -243,13 +321,57 @@ where
         self.provider
             .publish(provider_id)
             .expect("publishing the ad should not fail");
+        // peers list the published roots through the exchange protocol, from the content store
+        self.store.record_published_root(&root_cid)?;
+        self.emit_advertisement_event(context_id, AdvertisementStatus::Published);
 
         Ok(())
     }
 
+    fn emit_advertisement_event(&mut self, context_id: Vec<u8>, status: AdvertisementStatus) {
+        let event = ProviderEvent::AdvertisementEvent { context_id, status };
+        // drop the subscribers that went away
+        self.event_subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Subscribe to the indexer topic, to be part of its mesh when announcing.
+    async fn subscribe_indexer_topic(&self) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        let topic = TopicHash::from_raw(&self.config.indexer_topic);
+        let peer_id = PeerId::from(self.provider.keypair().public());
+        let message = NetworkCommand::GossipsubMessage {
+            peer_id,
+            message: GossipsubMessage::Subscribe {
+                peer_id,
+                topic: topic.clone(),
+                sender,
+            },
+        };
+        self.network_command_sender.send(message).await?;
+        // the network service may not be running yet, don't hold the engine up
+        tokio::task::spawn(async move {
+            match receiver.await {
+                Ok(Ok(_)) => info!("Subscribed to the indexer topic {topic}"),
+                Ok(Err(e)) => warn!("Failed to subscribe to the indexer topic {topic}: {e:?}"),
+                Err(_) => warn!("The network service dropped the subscription to {topic}"),
+            }
+        });
+        Ok(())
+    }
+
+    /// Gossip the announcement of the current head, independently of creating advertisements.
+    pub async fn announce(&mut self) -> Result<()> {
+        let peer_id = PeerId::from(self.provider.keypair().public());
+        let announce_message = self
+            .provider
+            .create_announce_message(peer_id, self.addresses.clone())?;
+        self.gossip_announce(announce_message, peer_id).await
+    }
+
     pub async fn gossip_announce(&mut self, data: Vec<u8>, peer_id: PeerId) -> Result<()> {
         let (sender, receiver) = oneshot::channel();
-        let topic = TopicHash::from_raw("indexer/ingest/mainnet");
+        let topic = TopicHash::from_raw(&self.config.indexer_topic);
         let message = NetworkCommand::GossipsubMessage {
             peer_id,
             message: GossipsubMessage::Publish {
@@ -258,18 +380,23 @@ where
                 sender,
             },
         };
-        self.network_command_sender.send(message)?;
+        self.network_command_sender.send(message).await?;
         receiver.await?.map_err(|e| anyhow!(e))?;
         Ok(())
     }
 
-    pub async fn http_announce(&mut self, data: Vec<u8>) {
-        if let Err(e) = surf::put(format!("{}/ingest/announce", self.config.indexer_url))
+    pub async fn http_announce(&mut self, data: Vec<u8>) -> Result<()> {
+        let response = surf::put(format!("{}/ingest/announce", self.config.indexer_url))
             .body(data)
             .await
-        {
-            error!("failed to announce to the indexer via http: {:?}", e);
-        };
+            .map_err(|e| anyhow!(e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "indexer rejected the announcement with status {}",
+                response.status()
+            ));
+        }
+        Ok(())
     }
 }
 
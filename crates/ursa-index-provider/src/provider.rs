@@ -9,7 +9,11 @@ use axum::{
     response::{IntoResponse, Response},
 };
 use fvm_ipld_blockstore::Blockstore;
-use libipld::{codec::Encode, multihash::Code, Cid};
+use libipld::{
+    codec::Encode,
+    multihash::{Code, Multihash},
+    Cid,
+};
 use libipld_cbor::DagCborCodec;
 use libipld_core::{ipld::Ipld, serde::to_ipld};
 use libp2p::multiaddr::Protocol;
@@ -22,10 +26,12 @@ use std::{
     io::Write,
     sync::{Arc, RwLock},
 };
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
 use ursa_store::{BlockstoreExt, UrsaStore};
 
 pub const HEAD_KEY: &str = "head";
+/// Codec of the cids rebuilt from the advertised multihashes.
+const RAW: u64 = 0x55;
 
 pub struct Provider<S> {
     head: Arc<RwLock<Option<Cid>>>,
@@ -64,6 +70,41 @@ where
         let head_lock = self.head.read().unwrap();
         *head_lock
     }
+
+    /// Read the advertisement `cid` from the store.
+    pub fn advertisement(&self, cid: &Cid) -> Result<Option<Advertisement>> {
+        self.store
+            .blockstore()
+            .get(cid)?
+            .map(|data| fvm_ipld_encoding::from_slice(&data).map_err(|e| anyhow!(e)))
+            .transpose()
+    }
+
+    /// The published advertisements, newest first, following their `PreviousID` links.
+    /// Stops at an advertisement missing from the store.
+    pub fn walk_chain(&self) -> impl Iterator<Item = Advertisement> {
+        let provider = self.clone();
+        let mut next = self.head();
+        std::iter::from_fn(move || {
+            let cid = next.take()?;
+            match provider.advertisement(&cid) {
+                Ok(Some(ad)) => {
+                    if let Some(Ipld::Link(previous)) = &ad.PreviousID {
+                        next = Some(*previous);
+                    }
+                    Some(ad)
+                }
+                Ok(None) => {
+                    warn!("The advertisement {cid} is missing from the store");
+                    None
+                }
+                Err(e) => {
+                    warn!("Failed to read the advertisement {cid}: {e:?}");
+                    None
+                }
+            }
+        })
+    }
 }
 
 impl<S> Clone for Provider<S>
@@ -102,6 +143,11 @@ pub trait ProviderInterface: Sync + Send + 'static {
     fn create(&mut self, ad: Advertisement) -> Result<usize>;
     fn add_chunk(&mut self, bytes: Vec<u8>, id: usize) -> Result<()>;
     fn publish(&mut self, id: usize) -> Result<Advertisement>;
+    /// The cid of the last published advertisement, the one new advertisements link to.
+    fn latest_advertisement(&self) -> Option<Cid>;
+    /// The cids advertised under `context_id` by its latest advertisement, in the order they
+    /// were added. Advertisements only carry multihashes, so the cids have the raw codec.
+    fn entries_for_context(&self, context_id: &[u8]) -> Result<Vec<Cid>>;
     fn create_announce_message(
         &mut self,
         peer_id: PeerId,
@@ -143,7 +189,19 @@ where
     fn publish(&mut self, id: usize) -> Result<Advertisement> {
         let mut head = self.head.write().unwrap();
         let keypair = self.keypair.clone();
-        let current_head = head.take();
+        let current_head = *head;
+        // never chain to an advertisement this provider didn't sign
+        if let Some(previous) = current_head {
+            let peer_id = PeerId::from(keypair.public());
+            match self.advertisement(&previous)? {
+                Some(previous_ad) if previous_ad.verify(&peer_id) => (),
+                _ => {
+                    return Err(anyhow!(
+                        "The head advertisement {previous} is not signed by {peer_id}"
+                    ))
+                }
+            }
+        }
         if let Some(mut ad) = self.temp_ads.remove(&id) {
             ad.PreviousID = current_head.map(Ipld::Link);
             let sig = ad.sign(&keypair)?;
@@ -160,6 +218,50 @@ where
         Err(anyhow!("ad not found"))
     }
 
+    fn latest_advertisement(&self) -> Option<Cid> {
+        self.head()
+    }
+
+    fn entries_for_context(&self, context_id: &[u8]) -> Result<Vec<Cid>> {
+        let ad = self
+            .walk_chain()
+            .find(|ad| ad.ContextID == Ipld::Bytes(context_id.to_vec()))
+            .ok_or_else(|| anyhow!("No advertisement found for the context id"))?;
+        if ad.IsRm {
+            return Ok(vec![]);
+        }
+
+        // each chunk links to the one added before it
+        let mut chunks = vec![];
+        let mut next = ad.Entries;
+        while let Some(link) = next {
+            let cid = match link {
+                Ipld::Link(cid) => cid,
+                other => return Err(anyhow!("Invalid entry chunk link {other:?}")),
+            };
+            let chunk: EntryChunk = self
+                .store
+                .blockstore()
+                .get_obj(&cid)?
+                .ok_or_else(|| anyhow!("The entry chunk {cid} is missing from the store"))?;
+            next = chunk.next().cloned();
+            chunks.push(chunk);
+        }
+
+        let mut cids = vec![];
+        for chunk in chunks.iter().rev() {
+            for entry in chunk.entries() {
+                match entry {
+                    Ipld::Bytes(bytes) => {
+                        cids.push(Cid::new_v1(RAW, Multihash::from_bytes(bytes)?));
+                    }
+                    other => return Err(anyhow!("Invalid entry {other:?}")),
+                }
+            }
+        }
+        Ok(cids)
+    }
+
     fn create_announce_message(
         &mut self,
         peer_id: PeerId,
@@ -0,0 +1,47 @@
+#[cfg(test)]
+mod tests {
+    use libipld_core::ipld::Ipld;
+    use libp2p::{identity::Keypair, PeerId};
+
+    use crate::advertisement::{AdSigError, Advertisement};
+
+    fn signed_ad(keypair: &Keypair) -> Advertisement {
+        let peer_id = PeerId::from(keypair.public());
+        let mut ad = Advertisement::new(
+            b"ursa".to_vec(),
+            peer_id,
+            vec!["/ip4/127.0.0.1/tcp/4069".into()],
+            false,
+            1024,
+        );
+        let envelope = ad.sign(keypair).unwrap();
+        ad.Signature = Ipld::Bytes(envelope.into_protobuf_encoding());
+        ad
+    }
+
+    #[test]
+    fn test_verify_advertisement() {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let ad = signed_ad(&keypair);
+        assert!(ad.verify(&peer_id));
+        assert!(!ad.verify(&PeerId::random()));
+
+        let mut tampered = ad.clone();
+        tampered.Addresses = vec!["/ip4/10.0.0.1/tcp/4069".into()];
+        assert!(!tampered.verify(&peer_id));
+        assert!(matches!(
+            tampered.signer(),
+            Err(AdSigError::PayloadDidNotMatch)
+        ));
+
+        let mut unsigned = ad;
+        unsigned.Signature = Ipld::Bytes(vec![]);
+        assert!(matches!(unsigned.signer(), Err(AdSigError::MissingSig)));
+
+        // signed by another key on behalf of the peer
+        let mut forged = signed_ad(&Keypair::generate_ed25519());
+        forged.Provider = peer_id.to_base58();
+        assert!(!forged.verify(&peer_id));
+    }
+}
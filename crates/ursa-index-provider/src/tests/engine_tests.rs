@@ -7,10 +7,25 @@ mod tests {
     use futures::io::BufReader;
     use fvm_ipld_car::load_car;
     use surf::Error as SurfError;
-    use tokio::{sync::oneshot, task};
+    use tokio::{
+        sync::{mpsc::unbounded_channel, oneshot},
+        task,
+        time::timeout,
+    };
     use tracing::{error, info};
 
-    use crate::{engine::ProviderCommand, signed_head::SignedHead, tests::provider_engine_init};
+    use crate::{
+        config::ProviderConfig,
+        engine::{AdvertisementStatus, ProviderCommand, ProviderEngine, ProviderEvent},
+        signed_head::SignedHead,
+        tests::{get_store, mock_indexer, provider_engine_init, provider_engine_init_with_config},
+    };
+    use libp2p::{
+        gossipsub::{MessageId, TopicHash},
+        identity::Keypair,
+    };
+    use tokio::sync::mpsc::channel;
+    use ursa_network::{GossipsubMessage, NetworkCommand};
 
     #[tokio::test]
     async fn test_events() -> Result<(), Box<dyn std::error::Error>> {
@@ -59,4 +74,184 @@ mod tests {
         .await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_advertisement_events() -> Result<(), Box<dyn std::error::Error>> {
+        let (indexer_url, mut announcements) = mock_indexer()?;
+        let config = ProviderConfig {
+            indexer_url,
+            ..Default::default()
+        };
+        let (provider_engine, ursa_service, ..) = provider_engine_init_with_config(8073, config)?;
+        let provider_sender = provider_engine.command_sender();
+
+        let file = File::open("../../test_files/test.car".to_string()).await?;
+        let size = file.metadata().await?.len();
+        let reader = BufReader::new(file);
+        let cids = load_car(provider_engine.store().blockstore(), reader).await?;
+        let context_id = cids[0].to_bytes();
+        let store = provider_engine.store();
+
+        task::spawn(async move {
+            if let Err(err) = ursa_service.start().await {
+                error!("[ursa_service] - {:?}", err);
+            }
+        });
+
+        task::spawn(async move {
+            if let Err(err) = provider_engine.start().await {
+                error!("[provider_engine] - {:?}", err);
+            }
+        });
+
+        let (event_sender, mut event_receiver) = unbounded_channel();
+        provider_sender.send(ProviderCommand::Subscribe {
+            sender: event_sender,
+        })?;
+
+        let (sender, receiver) = oneshot::channel();
+        provider_sender.send(ProviderCommand::Put {
+            context_id: context_id.clone(),
+            size,
+            sender,
+        })?;
+        receiver.await??;
+
+        let mut statuses = Vec::new();
+        while statuses.len() < 3 {
+            let event = timeout(Duration::from_secs(30), event_receiver.recv())
+                .await?
+                .expect("provider engine to be running");
+            let ProviderEvent::AdvertisementEvent {
+                context_id: event_context_id,
+                status,
+            } = event;
+            assert_eq!(event_context_id, context_id);
+            statuses.push(status);
+        }
+
+        assert_eq!(
+            statuses,
+            [
+                AdvertisementStatus::Created,
+                AdvertisementStatus::Published,
+                AdvertisementStatus::Acknowledged
+            ]
+        );
+        assert_eq!(store.published_roots()?, vec![cids[0]]);
+        let announcement = timeout(Duration::from_secs(5), announcements.recv())
+            .await?
+            .expect("the indexer to receive the announcement");
+        assert!(!announcement.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_invalid_context_id() -> Result<(), Box<dyn std::error::Error>> {
+        let (provider_engine, ..) = provider_engine_init(8074)?;
+        let provider_sender = provider_engine.command_sender();
+        task::spawn(async move {
+            if let Err(err) = provider_engine.start().await {
+                error!("[provider_engine] - {:?}", err);
+            }
+        });
+
+        let (sender, receiver) = oneshot::channel();
+        provider_sender.send(ProviderCommand::Put {
+            context_id: b"not a cid".to_vec(),
+            size: 0,
+            sender,
+        })?;
+        let error = timeout(Duration::from_secs(5), receiver)
+            .await??
+            .expect_err("the context id to be refused");
+        assert!(error.to_string().contains("not a valid cid"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexer_topic_validation() {
+        assert!(ProviderConfig::default().validate().is_ok());
+        let config = ProviderConfig {
+            indexer_topic: " ".to_string(),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_announce_to_indexer_topic() -> Result<(), Box<dyn std::error::Error>> {
+        let config = ProviderConfig {
+            indexer_topic: "indexer/ingest/testnet".to_string(),
+            ..Default::default()
+        };
+        let (network_sender, mut network_receiver) = channel(16);
+        let (_event_sender, event_receiver) = channel(16);
+        let mut provider_engine = ProviderEngine::new(
+            Keypair::generate_ed25519(),
+            get_store(),
+            get_store(),
+            config,
+            network_sender,
+            vec!["/ip4/127.0.0.1/tcp/4069".parse().unwrap()],
+            event_receiver,
+        );
+        let provider_sender = provider_engine.command_sender();
+
+        // without a head there is nothing to announce
+        let file = File::open("../../test_files/test.car".to_string()).await?;
+        let size = file.metadata().await?.len();
+        let cids = load_car(provider_engine.store().blockstore(), BufReader::new(file)).await?;
+        provider_engine.publish_local(cids[0], size).await?;
+
+        task::spawn(async move {
+            if let Err(err) = provider_engine.start().await {
+                error!("[provider_engine] - {:?}", err);
+            }
+        });
+
+        let command = timeout(Duration::from_secs(5), network_receiver.recv())
+            .await?
+            .expect("provider engine to be running");
+        match command {
+            NetworkCommand::GossipsubMessage {
+                message: GossipsubMessage::Subscribe { topic, sender, .. },
+                ..
+            } => {
+                assert_eq!(topic, TopicHash::from_raw("indexer/ingest/testnet"));
+                let _ = sender.send(Ok(true));
+            }
+            other => panic!("expected a subscription to the indexer topic, got {other:?}"),
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        provider_sender.send(ProviderCommand::AnnounceToIndexer { sender })?;
+
+        let command = timeout(Duration::from_secs(5), network_receiver.recv())
+            .await?
+            .expect("provider engine to be running");
+        match command {
+            NetworkCommand::GossipsubMessage {
+                message:
+                    GossipsubMessage::Publish {
+                        topic,
+                        data,
+                        sender,
+                    },
+                ..
+            } => {
+                assert_eq!(topic, TopicHash::from_raw("indexer/ingest/testnet"));
+                assert!(!data.is_empty());
+                sender
+                    .send(Ok(MessageId::new(b"announcement")))
+                    .expect("provider engine to wait for the publish result");
+            }
+            other => panic!("expected the announcement to be published, got {other:?}"),
+        }
+        timeout(Duration::from_secs(5), receiver).await???;
+
+        Ok(())
+    }
 }
@@ -1,5 +1,6 @@
 use anyhow::Result;
-use std::net::SocketAddr;
+use axum::{body::Bytes, http::StatusCode, routing::put, Router};
+use std::net::{SocketAddr, TcpListener};
 use std::str::FromStr;
 use std::sync::Arc;
 
@@ -7,7 +8,7 @@ use crate::{config::ProviderConfig, engine::ProviderEngine};
 use db::MemoryDB;
 use libp2p::{identity::Keypair, PeerId};
 use simple_logger::SimpleLogger;
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::{channel, unbounded_channel, UnboundedReceiver};
 use tokio::task;
 use tracing::{info, log::LevelFilter};
 use ursa_network::{NetworkConfig, UrsaService};
@@ -31,6 +32,13 @@ pub fn get_store() -> Arc<UrsaStore<MemoryDB>> {
 
 pub fn provider_engine_init(
     port: u16,
+) -> Result<(ProviderEngine<MemoryDB>, UrsaService<MemoryDB>, PeerId)> {
+    provider_engine_init_with_config(port, ProviderConfig::default())
+}
+
+pub fn provider_engine_init_with_config(
+    port: u16,
+    config: ProviderConfig,
 ) -> Result<(ProviderEngine<MemoryDB>, UrsaService<MemoryDB>, PeerId)> {
     setup_logger(LevelFilter::Info);
 
@@ -50,7 +58,7 @@ pub fn provider_engine_init(
         keypair,
         store,
         index_store,
-        ProviderConfig::default(),
+        config,
         service.command_sender(),
         vec!["/ip4/127.0.0.1/tcp/4069".parse().unwrap()],
         receiver,
@@ -67,3 +75,26 @@ pub fn provider_engine_init(
 
     Ok((provider_engine, service, peer_id))
 }
+
+/// A local indexer accepting every announcement, forwarded to the returned receiver.
+/// Returns its url along with the receiver.
+pub fn mock_indexer() -> Result<(String, UnboundedReceiver<Bytes>)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}", listener.local_addr()?);
+    let (sender, receiver) = unbounded_channel();
+    let router = Router::new().route(
+        "/ingest/announce",
+        put(move |body: Bytes| async move {
+            let _ = sender.send(body);
+            StatusCode::OK
+        }),
+    );
+    let server = axum::Server::from_tcp(listener)?;
+    task::spawn(async move {
+        server
+            .serve(router.into_make_service())
+            .await
+            .expect("Failed to start the mock indexer");
+    });
+    Ok((url, receiver))
+}
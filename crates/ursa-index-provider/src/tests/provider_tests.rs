@@ -1,16 +1,24 @@
 #[cfg(test)]
 mod tests {
     use crate::{
-        advertisement::Advertisement, provider::ProviderInterface, signed_head::SignedHead,
-        tests::provider_engine_init,
+        advertisement::Advertisement,
+        provider::{Provider, ProviderInterface, HEAD_KEY},
+        signed_head::SignedHead,
+        tests::{get_store, provider_engine_init},
     };
 
     use anyhow::Error;
-    use libipld::multihash::{Code, MultihashDigest};
-    use libipld_core::ipld::Ipld;
+    use db::Store;
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
+    use libipld_core::{ipld::Ipld, serde::to_ipld};
+    use libp2p::{identity::Keypair, PeerId};
     use surf::Error as SurfError;
     use tokio::task;
     use tracing::{debug, error, info};
+    use ursa_store::BlockstoreExt;
 
     #[tokio::test]
     async fn test_create_and_get_add() -> Result<(), Box<dyn std::error::Error>> {
@@ -82,4 +90,100 @@ mod tests {
             Err(e) => panic!("{e}"),
         }
     }
+
+    #[test]
+    fn test_walk_chain() -> Result<(), Error> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let mut provider = Provider::new(keypair, get_store());
+        assert_eq!(provider.latest_advertisement(), None);
+        assert_eq!(provider.walk_chain().count(), 0);
+
+        let mut heads = vec![];
+        for context_id in ["first", "second", "third"] {
+            let ad = Advertisement::new(context_id.into(), peer_id, vec![], false, 0);
+            let id = provider.create(ad)?;
+            provider.publish(id)?;
+            heads.push(provider.latest_advertisement().unwrap());
+        }
+
+        let chain: Vec<Advertisement> = provider.walk_chain().collect();
+        let context_ids: Vec<Ipld> = chain.iter().map(|ad| ad.ContextID.clone()).collect();
+        assert_eq!(
+            context_ids,
+            vec![
+                Ipld::Bytes("third".into()),
+                Ipld::Bytes("second".into()),
+                Ipld::Bytes("first".into())
+            ]
+        );
+        assert_eq!(chain[0].PreviousID, Some(Ipld::Link(heads[1])));
+        assert_eq!(chain[1].PreviousID, Some(Ipld::Link(heads[0])));
+        assert_eq!(chain[2].PreviousID, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_for_context() -> Result<(), Error> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let mut provider = Provider::new(keypair, get_store());
+
+        // three chunks, as a context over MAX_ENTRIES cids is split in
+        let cids: Vec<Cid> = (0..15)
+            .map(|i: i32| Cid::new_v1(0x55, Code::Blake3_256.digest(&i.to_ne_bytes())))
+            .collect();
+        let id = provider.create(Advertisement::new(
+            "chunked".into(),
+            peer_id,
+            vec![],
+            false,
+            0,
+        ))?;
+        for chunk in cids.chunks(5) {
+            let entries: Vec<Ipld> = chunk
+                .iter()
+                .map(|cid| Ipld::Bytes(cid.hash().to_bytes()))
+                .collect();
+            provider.add_chunk(fvm_ipld_encoding::to_vec(&entries)?, id)?;
+        }
+        provider.publish(id)?;
+
+        // a later advertisement of another context doesn't hide it
+        let id = provider.create(Advertisement::new(
+            "other".into(),
+            peer_id,
+            vec![],
+            false,
+            0,
+        ))?;
+        provider.publish(id)?;
+
+        assert_eq!(provider.entries_for_context(b"chunked")?, cids);
+        assert!(provider.entries_for_context(b"other")?.is_empty());
+        assert!(provider.entries_for_context(b"unknown").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_publish_refuses_forged_head() -> Result<(), Error> {
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let store = get_store();
+
+        // an advertisement on behalf of the provider, signed by someone else
+        let mut forged = Advertisement::new("forged".into(), peer_id, vec![], false, 0);
+        let envelope = forged.sign(&Keypair::generate_ed25519())?;
+        forged.Signature = Ipld::Bytes(envelope.into_protobuf_encoding());
+        let forged_cid = store
+            .blockstore()
+            .put_obj(&to_ipld(&forged)?, Code::Blake3_256)?;
+        store.db.write(HEAD_KEY, forged_cid.to_bytes())?;
+
+        let mut provider = Provider::new(keypair, store);
+        let id = provider.create(Advertisement::new("ursa".into(), peer_id, vec![], false, 0))?;
+        assert!(provider.publish(id).is_err());
+        assert_eq!(provider.latest_advertisement(), Some(forged_cid));
+        Ok(())
+    }
 }
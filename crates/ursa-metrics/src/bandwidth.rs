@@ -0,0 +1,14 @@
+use metrics::gauge;
+
+/// Bytes sent and received by the node since it started, recorded periodically.
+pub struct Bandwidth {
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+impl super::Recorder for Bandwidth {
+    fn record(&self) {
+        gauge!("bandwidth_inbound_bytes", self.inbound as f64);
+        gauge!("bandwidth_outbound_bytes", self.outbound as f64);
+    }
+}
@@ -0,0 +1,15 @@
+use crate::track_event;
+use libp2p_bitswap::BitswapEvent;
+use metrics::Label;
+
+impl super::Recorder for BitswapEvent {
+    fn record(&self) {
+        // the progress of the queries is recorded by libp2p-bitswap in its own registry
+        if let BitswapEvent::Complete(_, result) = self {
+            track_event(
+                "ursa_bitswap_total",
+                vec![Label::new("block_found", result.is_ok().to_string())],
+            );
+        }
+    }
+}
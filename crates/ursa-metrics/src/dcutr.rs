@@ -0,0 +1,14 @@
+use libp2p::dcutr::behaviour::Event;
+use metrics::increment_counter;
+
+impl super::Recorder for Event {
+    fn record(&self) {
+        increment_counter!(match self {
+            Event::InitiatedDirectConnectionUpgrade { .. } => "dcutr_upgrade_initiated",
+            Event::RemoteInitiatedDirectConnectionUpgrade { .. } =>
+                "dcutr_upgrade_remote_initiated",
+            Event::DirectConnectionUpgradeSucceeded { .. } => "dcutr_upgrade_succeeded",
+            Event::DirectConnectionUpgradeFailed { .. } => "dcutr_upgrade_failed",
+        });
+    }
+}
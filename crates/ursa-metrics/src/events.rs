@@ -0,0 +1,17 @@
+use metrics::increment_counter;
+
+/// Events of the node recorded as metrics that are not events of a libp2p protocol.
+pub enum MetricEvent {
+    /// A peer that doesn't support gossipsub was disconnected.
+    GossipsubUnsupportedPeer,
+}
+
+impl super::Recorder for MetricEvent {
+    fn record(&self) {
+        match self {
+            MetricEvent::GossipsubUnsupportedPeer => {
+                increment_counter!("gossipsub_unsupported_peer_disconnected")
+            }
+        }
+    }
+}
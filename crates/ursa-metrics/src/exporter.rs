@@ -0,0 +1,53 @@
+use crate::labels::is_allowed;
+use anyhow::{anyhow, Result};
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Label, Recorder, SharedString, Unit};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+/// Recorder exporting the metrics to Prometheus with their allowed labels only.
+/// See [`crate::labels::ALLOWED_LABELS`].
+struct LabelGuard<R> {
+    inner: R,
+}
+
+impl<R> LabelGuard<R> {
+    fn guard(key: &Key) -> Key {
+        let labels: Vec<Label> = key.labels().filter(|l| is_allowed(l)).cloned().collect();
+        Key::from_parts(key.name().to_string(), labels)
+    }
+}
+
+impl<R: Recorder> Recorder for LabelGuard<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        self.inner.register_counter(&Self::guard(key))
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        self.inner.register_gauge(&Self::guard(key))
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        self.inner.register_histogram(&Self::guard(key))
+    }
+}
+
+/// Install the Prometheus recorder as the global recorder of the `metrics` crate.
+/// Returns the handle rendering the metrics for the `/metrics` endpoint.
+pub fn install() -> Result<PrometheusHandle> {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+    metrics::set_boxed_recorder(Box::new(LabelGuard { inner: recorder }))
+        .map_err(|e| anyhow!("Failed to install the metrics recorder: {e}"))?;
+    Ok(handle)
+}
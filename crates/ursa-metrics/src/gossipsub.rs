@@ -1,6 +1,7 @@
+use crate::track_event;
 use libp2p::gossipsub::GossipsubEvent;
-use metrics::increment_counter;
 use metrics::Label;
+use metrics::{gauge, increment_counter};
 
 impl super::Recorder for GossipsubEvent {
     fn record(&self) {
@@ -12,13 +13,53 @@ impl super::Recorder for GossipsubEvent {
                 );
             }
             GossipsubEvent::GossipsubNotSupported { peer_id } => {
-                increment_counter!(
+                track_event(
                     "gossipsub_peer_not_supported",
-                    vec![Label::new("peer", peer_id.to_string()),]
+                    vec![Label::new("peer", peer_id.to_string())],
                 );
             }
+            // recorded with GossipSubscriptionChurn for the topics the node is subscribed to
             GossipsubEvent::Subscribed { .. } => {}
             GossipsubEvent::Unsubscribed { .. } => {}
         }
     }
 }
+
+/// A remote peer subscribed to or unsubscribed from a topic the node is subscribed to.
+pub struct GossipSubscriptionChurn {
+    pub topic: String,
+    pub subscribed: bool,
+}
+
+impl super::Recorder for GossipSubscriptionChurn {
+    fn record(&self) {
+        let action = if self.subscribed {
+            "subscribed"
+        } else {
+            "unsubscribed"
+        };
+        increment_counter!(
+            "gossipsub_subscription_churn",
+            vec![
+                Label::new("topic", self.topic.clone()),
+                Label::new("action", action),
+            ]
+        );
+    }
+}
+
+/// Number of peers in the mesh of a gossipsub topic, recorded periodically.
+pub struct GossipMeshSize {
+    pub topic: String,
+    pub peers: usize,
+}
+
+impl super::Recorder for GossipMeshSize {
+    fn record(&self) {
+        gauge!(
+            "gossipsub_mesh_size",
+            self.peers as f64,
+            vec![Label::new("topic", self.topic.clone())]
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use metrics::{increment_counter, Label};
+use tracing::trace;
+
+/// Label keys with a bounded set of values, the only ones metrics are exported with.
+/// Unbounded values like cids, peer or query ids belong in the logs.
+pub const ALLOWED_LABELS: &[&str] = &[
+    "action",
+    "block_found",
+    "bucket",
+    "failure",
+    "method",
+    "path",
+    "peer_status",
+    "protocol",
+    "request",
+    "role",
+    "status",
+    "topic",
+];
+
+pub(crate) fn is_allowed(label: &Label) -> bool {
+    ALLOWED_LABELS.contains(&label.key())
+}
+
+/// Split `labels` into the allowed ones and the ones left for the logs.
+fn partition(labels: Vec<Label>) -> (Vec<Label>, Vec<Label>) {
+    labels.into_iter().partition(is_allowed)
+}
+
+/// Increment the counter `name` with the allowed `labels`, logging the others.
+pub fn track_event(name: &'static str, labels: Vec<Label>) {
+    let (labels, dropped) = partition(labels);
+    for label in dropped {
+        trace!("[{name}] - {}: {}", label.key(), label.value());
+    }
+    increment_counter!(name, labels);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition() {
+        let (allowed, dropped) = partition(vec![
+            Label::new("block_found", "true"),
+            Label::new("cid", "bafy"),
+            Label::new("topic", "/ursa/global"),
+        ]);
+        assert_eq!(
+            allowed,
+            vec![
+                Label::new("block_found", "true"),
+                Label::new("topic", "/ursa/global")
+            ]
+        );
+        assert_eq!(dropped, vec![Label::new("cid", "bafy")]);
+    }
+}
@@ -2,16 +2,29 @@ use lazy_static::lazy_static;
 use prometheus::Registry;
 use std::sync::Arc;
 
+mod bandwidth;
+mod bitswap;
+mod dcutr;
+mod events;
+pub mod exporter;
 mod gossipsub;
 mod identify;
 mod kad;
+pub mod labels;
 pub mod middleware;
 mod ping;
 mod relay;
 mod request_response;
 pub mod routes;
+mod store;
 mod swarm;
 
+pub use bandwidth::Bandwidth;
+pub use events::MetricEvent;
+pub use gossipsub::{GossipMeshSize, GossipSubscriptionChurn};
+pub use labels::track_event;
+pub use store::StoreSize;
+
 lazy_static! {
     pub static ref BITSWAP_REGISTRY: Arc<Registry> = Arc::new(Registry::new());
 }
@@ -1,4 +1,7 @@
-use libp2p::request_response::{RequestResponseEvent, RequestResponseMessage};
+use crate::track_event;
+use libp2p::request_response::{
+    InboundFailure, OutboundFailure, RequestResponseEvent, RequestResponseMessage,
+};
 use metrics::{increment_counter, Label};
 use std::fmt::Debug;
 
@@ -7,39 +10,93 @@ impl<TRequest: Debug, TResponse: Debug> super::Recorder
 {
     fn record(&self) {
         match self {
-            RequestResponseEvent::Message { message, peer, .. } => {
-                match message {
-                    RequestResponseMessage::Request {
-                        request_id,
-                        request,
-                        ..
-                    } => {
-                        increment_counter!("req-res_total_request_received");
-                        increment_counter!(
-                            "req-res_request_received",
-                            vec![
-                                Label::new("peer", peer.to_string()),
-                                Label::new("request_id", request_id.to_string()),
-                                Label::new("request", format!("{request:?}")),
-                            ]
-                        );
-                    }
-                    RequestResponseMessage::Response { request_id, .. } => {
-                        increment_counter!("req-res_total_response_sent");
-                        increment_counter!(
-                            "req-res_response_sent",
-                            vec![
-                                Label::new("peer", peer.to_string()),
-                                Label::new("request_id", request_id.to_string()),
-                                // channel?
-                            ]
-                        );
-                    }
+            RequestResponseEvent::Message { message, peer, .. } => match message {
+                RequestResponseMessage::Request {
+                    request_id,
+                    request,
+                    ..
+                } => {
+                    increment_counter!("req-res_total_request_received");
+                    track_event(
+                        "req-res_request_received",
+                        vec![
+                            Label::new("peer", peer.to_string()),
+                            Label::new("request_id", request_id.to_string()),
+                            Label::new("request_type", format!("{request:?}")),
+                        ],
+                    );
                 }
+                RequestResponseMessage::Response { request_id, .. } => {
+                    increment_counter!("req-res_total_response_sent");
+                    track_event(
+                        "req-res_response_sent",
+                        vec![
+                            Label::new("peer", peer.to_string()),
+                            Label::new("request_id", request_id.to_string()),
+                        ],
+                    );
+                }
+            },
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                track_event(
+                    "req-res_outbound_failure",
+                    vec![
+                        Label::new("peer", peer.to_string()),
+                        outbound_failure_label(error),
+                    ],
+                );
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                track_event(
+                    "req-res_inbound_failure",
+                    vec![
+                        Label::new("peer", peer.to_string()),
+                        inbound_failure_label(error),
+                    ],
+                );
             }
-            RequestResponseEvent::OutboundFailure { .. } => {}
-            RequestResponseEvent::InboundFailure { .. } => {}
             RequestResponseEvent::ResponseSent { .. } => {}
         }
     }
 }
+
+fn outbound_failure_label(error: &OutboundFailure) -> Label {
+    let failure = match error {
+        OutboundFailure::DialFailure => "dial_failure",
+        OutboundFailure::Timeout => "timeout",
+        OutboundFailure::ConnectionClosed => "connection_closed",
+        OutboundFailure::UnsupportedProtocols => "unsupported_protocols",
+    };
+    Label::new("failure", failure)
+}
+
+fn inbound_failure_label(error: &InboundFailure) -> Label {
+    let failure = match error {
+        InboundFailure::Timeout => "timeout",
+        InboundFailure::ConnectionClosed => "connection_closed",
+        InboundFailure::UnsupportedProtocols => "unsupported_protocols",
+        InboundFailure::ResponseOmission => "response_omission",
+    };
+    Label::new("failure", failure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::labels::is_allowed;
+
+    #[test]
+    fn test_failure_labels() {
+        let label = outbound_failure_label(&OutboundFailure::DialFailure);
+        assert_eq!(label, Label::new("failure", "dial_failure"));
+        assert!(is_allowed(&label));
+        assert_eq!(
+            outbound_failure_label(&OutboundFailure::Timeout),
+            Label::new("failure", "timeout")
+        );
+        assert_eq!(
+            inbound_failure_label(&InboundFailure::ResponseOmission),
+            Label::new("failure", "response_omission")
+        );
+    }
+}
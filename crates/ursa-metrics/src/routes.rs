@@ -1,6 +1,6 @@
-use crate::BITSWAP_REGISTRY;
+use crate::{exporter, BITSWAP_REGISTRY};
 use axum::{http::StatusCode, routing::get, Extension, Router};
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_exporter_prometheus::PrometheusHandle;
 use prometheus::{Encoder, TextEncoder};
 use std::sync::Arc;
 
@@ -23,7 +23,5 @@ async fn metrics_handler(handle: Extension<Arc<PrometheusHandle>>) -> (StatusCod
 pub fn init() -> Router {
     Router::new()
         .route("/metrics", get(metrics_handler))
-        .layer(Extension(Arc::new(
-            PrometheusBuilder::new().install_recorder().unwrap(),
-        )))
+        .layer(Extension(Arc::new(exporter::install().unwrap())))
 }
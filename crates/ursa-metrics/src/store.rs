@@ -0,0 +1,14 @@
+use metrics::gauge;
+
+/// Number of blocks and bytes held by the store, recorded periodically.
+pub struct StoreSize {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+impl super::Recorder for StoreSize {
+    fn record(&self) {
+        gauge!("store_blocks", self.blocks as f64);
+        gauge!("store_bytes", self.bytes as f64);
+    }
+}
@@ -16,6 +16,7 @@ impl<TBvEv, THandleErr> Recorder for SwarmEvent<TBvEv, THandleErr> {
                 if u32::from(*num_established) == 1 {
                     increment_gauge!("swarm_connected_peers", 1.0);
                 }
+                increment_gauge!("swarm_open_connections", 1.0);
 
                 increment_counter!(
                     "swarm_connections_established",
@@ -32,6 +33,7 @@ impl<TBvEv, THandleErr> Recorder for SwarmEvent<TBvEv, THandleErr> {
                     "swarm_connections_closed",
                     vec![Role::from(endpoint.clone()).into()]
                 );
+                decrement_gauge!("swarm_open_connections", 1.0);
 
                 // If the last connection to a peer is closed, decrement the protocols identified by them
                 if *num_established == 0 {
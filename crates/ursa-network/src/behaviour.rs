@@ -21,18 +21,21 @@ use graphsync::GraphSync;
 use libipld::{Cid, DefaultParams};
 use libp2p::swarm::behaviour::toggle::Toggle;
 use libp2p::{
-    autonat::{Behaviour as Autonat, Config as AutonatConfig},
+    autonat::{Behaviour as Autonat, Config as AutonatConfig, NatStatus},
     dcutr::behaviour::Behaviour as Dcutr,
     gossipsub::{
         error::{PublishError, SubscriptionError},
-        Gossipsub, IdentTopic as Topic, MessageId, PeerScoreParams, PeerScoreThresholds,
+        Gossipsub, IdentTopic as Topic, MessageAcceptance, MessageId, TopicHash,
     },
     identify::{Behaviour as Identify, Config as IdentifyConfig},
     identity::Keypair,
-    kad::{store::MemoryStore, Kademlia, KademliaConfig},
-    mdns::tokio::Behaviour as Mdns,
+    kad::{
+        store::{MemoryStore, MemoryStoreConfig},
+        Kademlia, KademliaConfig,
+    },
+    mdns::{tokio::Behaviour as Mdns, Config as MdnsConfig},
     multiaddr::Protocol,
-    ping::Behaviour as Ping,
+    ping::{Behaviour as Ping, Config as PingConfig},
     relay::v2::{
         client::Client as RelayClient,
         relay::{Config as RelayConfig, Relay as RelayServer},
@@ -43,6 +46,7 @@ use libp2p::{
 };
 use libp2p_bitswap::{Bitswap, BitswapConfig};
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::iter;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
@@ -54,13 +58,16 @@ use ursa_store::{BitswapStorage, UrsaStore};
 
 use crate::connection::Manager;
 use crate::gossipsub::build_gossipsub;
+use crate::utils::fetch_timing::{StoreTimings, TimedBitswapStorage};
 use crate::{
     codec::protocol::{UrsaExchangeCodec, UrsaProtocol},
     config::NetworkConfig,
+    utils::protocol_version::URSA_PROTOCOL_VERSION,
 };
 
-pub const IPFS_PROTOCOL: &str = "ipfs/0.1.0";
 pub const KAD_PROTOCOL: &[u8] = b"/ursa/kad/0.0.1";
+/// Default maximum size of the kademlia messages.
+const KAD_MAX_PACKET_SIZE: usize = 16 * 1024;
 pub const COMMIT_HASH: &str = run_command_str!("git", "rev-parse", "--short", "HEAD");
 
 pub fn ursa_agent() -> String {
@@ -120,21 +127,25 @@ where
         store: UrsaStore<S>,
         relay_client: Option<libp2p::relay::v2::client::Client>,
         peers: &mut Manager,
+        store_timings: Arc<StoreTimings>,
     ) -> Self {
         let local_public_key = keypair.public();
         let local_peer_id = PeerId::from(local_public_key.clone());
 
         // Setup the ping behaviour
-        let ping = Ping::default();
+        let ping = Ping::new(
+            PingConfig::new()
+                .with_interval(Duration::from_secs(config.ping_interval))
+                .with_timeout(Duration::from_secs(config.ping_timeout)),
+        );
+        let idle_timeout = config.idle_connection_timeout.map(Duration::from_secs);
 
         // Setup the gossip behaviour
-        let mut gossipsub = build_gossipsub(keypair, config);
-        gossipsub
-            .with_peer_score(PeerScoreParams::default(), PeerScoreThresholds::default())
-            .expect("PeerScoreParams and PeerScoreThresholds");
+        let gossipsub = build_gossipsub(keypair, config);
 
         // Setup the bitswap behaviour
-        let bitswap_store = BitswapStorage(Arc::new(store.clone()));
+        let bitswap_store =
+            TimedBitswapStorage::new(BitswapStorage(Arc::new(store.clone())), store_timings);
         let bitswap = Bitswap::new(BitswapConfig::default(), bitswap_store);
 
         if let Err(e) = bitswap.register_metrics(&BITSWAP_REGISTRY) {
@@ -144,27 +155,41 @@ where
 
         // Setup the identify behaviour
         let identify = Identify::new(
-            IdentifyConfig::new(IPFS_PROTOCOL.into(), keypair.public())
-                .with_agent_version(ursa_agent()),
+            IdentifyConfig::new(URSA_PROTOCOL_VERSION.into(), keypair.public())
+                .with_agent_version(config.agent_version.clone()),
         );
 
         let request_response = {
             let mut cfg = RequestResponseConfig::default();
 
-            // todo(botch): calculate an upper limit to allow for large files
-            cfg.set_request_timeout(Duration::from_secs(60));
+            cfg.set_request_timeout(Duration::from_secs(config.exchange_request_timeout));
+            let keep_alive = config
+                .exchange_connection_keepalive
+                .map(Duration::from_secs)
+                .or(idle_timeout);
+            if let Some(keep_alive) = keep_alive {
+                cfg.set_connection_keep_alive(keep_alive);
+            }
 
             let protocols = iter::once((UrsaProtocol, ProtocolSupport::Full));
 
-            RequestResponse::new(UrsaExchangeCodec, protocols, cfg)
+            RequestResponse::new(
+                UrsaExchangeCodec::new(config.max_response_size)
+                    .with_encoding(config.exchange_encoding),
+                protocols,
+                cfg,
+            )
         };
 
         let autonat = config
             .autonat
             .then(|| {
+                let default = AutonatConfig::default();
                 let config = AutonatConfig {
                     throttle_server_period: Duration::from_secs(30),
-                    ..AutonatConfig::default()
+                    // let the confidence reach the threshold the relay client waits for
+                    confidence_max: default.confidence_max.max(config.autonat_confidence),
+                    ..default
                 };
 
                 Autonat::new(local_peer_id, config)
@@ -173,7 +198,15 @@ where
 
         let relay_server = config
             .relay_server
-            .then(|| RelayServer::new(local_public_key.into(), RelayConfig::default()))
+            .then(|| {
+                let config = RelayConfig {
+                    reservation_duration: Duration::from_secs(config.relay_reservation_duration),
+                    max_reservations: config.relay_max_reservations,
+                    max_circuits: config.relay_max_circuits,
+                    ..RelayConfig::default()
+                };
+                RelayServer::new(local_public_key.into(), config)
+            })
             .into();
 
         let dcutr = config
@@ -187,7 +220,7 @@ where
             .into();
 
         let mdns = if config.mdns {
-            Some(Mdns::new(Default::default()).expect("mDNS start"))
+            Some(Mdns::new(config.mdns_config()).expect("mDNS start"))
         } else {
             None
         }
@@ -195,12 +228,21 @@ where
 
         // setup the kademlia behaviour
         let mut kad = {
-            let store = MemoryStore::new(local_peer_id);
+            let store_config = MemoryStoreConfig {
+                max_value_bytes: config.kad_max_record_size,
+                ..Default::default()
+            };
+            let store = MemoryStore::with_config(local_peer_id, store_config);
             let replication_factor = NonZeroUsize::new(config.kad_replication_factor).unwrap();
             let mut kad_config = KademliaConfig::default();
             kad_config
                 .set_protocol_names(vec![Cow::from(KAD_PROTOCOL)])
-                .set_replication_factor(replication_factor);
+                .set_replication_factor(replication_factor)
+                // leave room for the key and the framing of the records
+                .set_max_packet_size(KAD_MAX_PACKET_SIZE.max(config.kad_max_record_size + 4096));
+            if let Some(idle_timeout) = idle_timeout {
+                kad_config.set_connection_idle_timeout(idle_timeout);
+            }
 
             Kademlia::with_config(local_peer_id, store, kad_config.clone())
         };
@@ -215,6 +257,7 @@ where
                 info!("Adding bootstrap node: {peer_id} - {addr}");
                 kad.add_address(&peer_id, addr.clone());
                 peers.insert(peer_id);
+                peers.protect(peer_id);
             } else {
                 warn!("Could not parse bootstrap addr {addr}");
             }
@@ -269,6 +312,52 @@ where
         self.gossipsub.unsubscribe(topic)
     }
 
+    /// Number of peers in the mesh of each subscribed topic.
+    pub fn mesh_peer_counts(&self) -> HashMap<TopicHash, usize> {
+        self.gossipsub
+            .topics()
+            .map(|topic| (topic.clone(), self.gossipsub.mesh_peers(topic).count()))
+            .collect()
+    }
+
+    /// Known peers subscribed to `topic`, whether they are in the mesh or not.
+    /// Gossipsub updates them on the subscription events of the peers.
+    pub fn topic_peers(&self, topic: &TopicHash) -> HashSet<PeerId> {
+        self.gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&topic))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    /// Whether the node itself is subscribed to `topic`.
+    pub fn is_subscribed(&self, topic: &TopicHash) -> bool {
+        self.gossipsub
+            .topics()
+            .any(|subscribed| subscribed == topic)
+    }
+
+    /// Number of known peers subscribed to each topic, whether they are in the mesh or not.
+    pub fn topic_peer_counts(&self) -> HashMap<TopicHash, usize> {
+        let mut counts = HashMap::new();
+        for (_, topics) in self.gossipsub.all_peers() {
+            for topic in topics {
+                *counts.entry(topic.clone()).or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    pub fn report_message_validation_result(
+        &mut self,
+        message_id: &MessageId,
+        propagation_source: &PeerId,
+        acceptance: MessageAcceptance,
+    ) -> Result<bool, PublishError> {
+        self.gossipsub
+            .report_message_validation_result(message_id, propagation_source, acceptance)
+    }
+
     pub fn get_block(
         &mut self,
         cid: Cid,
@@ -284,4 +373,26 @@ where
     ) -> Result<libp2p_bitswap::QueryId> {
         Ok(self.bitswap.sync(cid, providers, iter::once(cid)))
     }
+
+    pub fn cancel(&mut self, query_id: libp2p_bitswap::QueryId) {
+        self.bitswap.cancel(query_id);
+    }
+
+    /// The NAT status reported by autonat, unknown if autonat is disabled.
+    pub fn nat_status(&self) -> NatStatus {
+        self.autonat
+            .as_ref()
+            .map_or(NatStatus::Unknown, Autonat::nat_status)
+    }
+
+    /// Restart mDNS, which immediately queries the local network on every interface.
+    ///
+    /// Returns false if mDNS is disabled.
+    pub fn restart_mdns(&mut self, config: MdnsConfig) -> Result<bool> {
+        if !self.mdns.is_enabled() {
+            return Ok(false);
+        }
+        self.mdns = Some(Mdns::new(config)?).into();
+        Ok(true)
+    }
 }
@@ -9,15 +9,77 @@ use libp2p::{
     },
     request_response::RequestResponseCodec,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::io;
 
 /// Max request size in bytes
 const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024; // 1 << 22
-/// Max response size in bytes
-const MAX_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+/// Default max response size in bytes
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+/// Responses are written in length prefixed frames of at most this many bytes, followed
+/// by an empty frame.
+const RESPONSE_FRAME_SIZE: usize = 1024 * 1024;
+/// Most roots listed in a single [`ResponseType::RootsResponse`].
+pub const MAX_ROOTS_PER_PAGE: usize = 1024;
 
-pub const PROTOCOL_NAME: &[u8] = b"/ursa/txrx/0.0.1";
+pub const PROTOCOL_NAME: &[u8] = b"/ursa/txrx/0.0.3";
+
+/// Serialization of the exchange messages. Each message starts with a byte telling its
+/// encoding, so a node reads the messages of peers configured with another one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExchangeEncoding {
+    #[default]
+    Cbor,
+    /// Readable, for testing and debugging.
+    Json,
+}
+
+impl ExchangeEncoding {
+    fn header(self) -> u8 {
+        match self {
+            ExchangeEncoding::Cbor => 0,
+            ExchangeEncoding::Json => 1,
+        }
+    }
+
+    fn from_header(header: u8) -> Option<Self> {
+        match header {
+            0 => Some(ExchangeEncoding::Cbor),
+            1 => Some(ExchangeEncoding::Json),
+            _ => None,
+        }
+    }
+
+    fn encode<M: Serialize>(self, message: &M) -> io::Result<Vec<u8>> {
+        let mut data = vec![self.header()];
+        match self {
+            ExchangeEncoding::Cbor => data.extend(
+                fvm_ipld_encoding::to_vec(message)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            ),
+            ExchangeEncoding::Json => serde_json::to_writer(&mut data, message)?,
+        }
+        Ok(data)
+    }
+
+    /// Decode a message in the encoding given by its first byte.
+    fn decode<M: DeserializeOwned>(data: &[u8]) -> io::Result<M> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+        let (header, message) = data
+            .split_first()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+        match Self::from_header(*header) {
+            Some(ExchangeEncoding::Cbor) => {
+                fvm_ipld_encoding::from_slice(message).map_err(|e| invalid(e.to_string()))
+            }
+            Some(ExchangeEncoding::Json) => {
+                serde_json::from_slice(message).map_err(|e| invalid(e.to_string()))
+            }
+            None => Err(invalid(format!("Unknown message encoding {header}"))),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct UrsaProtocol;
@@ -29,15 +91,53 @@ impl ProtocolName for UrsaProtocol {
 }
 
 #[derive(Debug, Clone)]
-pub struct UrsaExchangeCodec;
+pub struct UrsaExchangeCodec {
+    /// Max size in bytes of a response read, across all of its frames.
+    max_response_size: usize,
+    /// Encoding of the messages written.
+    encoding: ExchangeEncoding,
+}
+
+impl UrsaExchangeCodec {
+    pub fn new(max_response_size: usize) -> Self {
+        Self {
+            max_response_size,
+            encoding: ExchangeEncoding::default(),
+        }
+    }
+
+    pub fn with_encoding(mut self, encoding: ExchangeEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+}
+
+impl Default for UrsaExchangeCodec {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_RESPONSE_SIZE)
+    }
+}
 
 // todo(botch): think of a proper structure for a request
+/// Variants are encoded tagged with their name, new ones are only appended so peers running
+/// an older version still decode the ones they know.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RequestType {
     // change this to the final cid version
     CarRequest(String),
     CacheRequest(Cid),
     StoreSummary(Box<CacheSummary>),
+    /// A single block, answered with a [`ResponseType::BlockResponse`].
+    BlockRequest(Cid),
+    /// Check that the peer serves the exchange protocol, answered with a
+    /// [`ResponseType::PingResponse`].
+    PingRequest,
+    /// A page of the roots the peer published advertisements for, answered with a
+    /// [`ResponseType::RootsResponse`]. `limit` is capped at [`MAX_ROOTS_PER_PAGE`].
+    ListRoots {
+        offset: usize,
+        limit: usize,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -55,6 +155,16 @@ pub enum ResponseType {
     CarResponse(CarResponse),
     CacheResponse,
     StoreSummaryRequest,
+    /// The request could not be served.
+    Error(String),
+    /// The data of the requested block.
+    BlockResponse(Vec<u8>),
+    PingResponse,
+    /// The roots from the requested offset, and the offset of the next page if there is one.
+    RootsResponse {
+        roots: Vec<Cid>,
+        next: Option<usize>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -78,10 +188,7 @@ impl RequestResponseCodec for UrsaExchangeCodec {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        let request: UrsaExchangeRequest =
-            serde_json::from_str(&String::from_utf8(vec).unwrap()).unwrap();
-
-        Ok(request)
+        ExchangeEncoding::decode(&vec)
     }
 
     async fn read_response<T>(
@@ -92,16 +199,30 @@ impl RequestResponseCodec for UrsaExchangeCodec {
     where
         T: AsyncRead + Unpin + Send,
     {
-        let vec = read_length_prefixed(io, MAX_RESPONSE_SIZE).await?;
+        let mut vec = read_length_prefixed(io, RESPONSE_FRAME_SIZE).await?;
 
         if vec.is_empty() {
             return Err(io::ErrorKind::UnexpectedEof.into());
         }
 
-        let response: UrsaExchangeResponse =
-            serde_json::from_str(&String::from_utf8(vec).unwrap()).unwrap();
+        loop {
+            let frame = read_length_prefixed(io, RESPONSE_FRAME_SIZE).await?;
+            if frame.is_empty() {
+                break;
+            }
+            if vec.len() + frame.len() > self.max_response_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "The response is larger than {} bytes",
+                        self.max_response_size
+                    ),
+                ));
+            }
+            vec.extend(frame);
+        }
 
-        Ok(response)
+        ExchangeEncoding::decode(&vec)
     }
 
     async fn write_request<T>(
@@ -113,7 +234,7 @@ impl RequestResponseCodec for UrsaExchangeCodec {
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let data = serde_json::to_vec(&req).unwrap();
+        let data = self.encoding.encode(&req)?;
         write_length_prefixed(io, &data).await?;
         io.close().await?;
 
@@ -129,8 +250,11 @@ impl RequestResponseCodec for UrsaExchangeCodec {
     where
         T: AsyncWrite + Unpin + Send,
     {
-        let data = serde_json::to_vec(&res).unwrap();
-        write_length_prefixed(io, &data).await?;
+        let data = self.encoding.encode(&res)?;
+        for frame in data.chunks(RESPONSE_FRAME_SIZE) {
+            write_length_prefixed(io, frame).await?;
+        }
+        write_length_prefixed(io, b"").await?;
         io.close().await?;
 
         Ok(())
@@ -139,27 +263,126 @@ impl RequestResponseCodec for UrsaExchangeCodec {
 
 #[cfg(test)]
 mod tests {
-    #[ignore = "todo"]
+    use super::*;
+    use futures::io::Cursor;
+
+    fn car_response(len: usize) -> UrsaExchangeResponse {
+        UrsaExchangeResponse(ResponseType::CarResponse(CarResponse {
+            cid: "cid".to_string(),
+            data: vec![7; len],
+        }))
+    }
+
+    async fn write_then_read(
+        codec: &mut UrsaExchangeCodec,
+        res: UrsaExchangeResponse,
+    ) -> io::Result<UrsaExchangeResponse> {
+        let mut io = Cursor::new(Vec::new());
+        codec.write_response(&UrsaProtocol, &mut io, res).await?;
+        io.set_position(0);
+        codec.read_response(&UrsaProtocol, &mut io).await
+    }
+
     #[tokio::test]
-    async fn test_read_request() {
-        todo!()
+    async fn test_read_request() -> io::Result<()> {
+        let req = UrsaExchangeRequest(RequestType::CarRequest("cid".to_string()));
+        let mut codec = UrsaExchangeCodec::default();
+        let mut io = Cursor::new(Vec::new());
+        codec
+            .write_request(&UrsaProtocol, &mut io, req.clone())
+            .await?;
+        io.set_position(0);
+        assert_eq!(codec.read_request(&UrsaProtocol, &mut io).await?, req);
+        Ok(())
     }
 
-    #[ignore = "todo"]
     #[tokio::test]
-    async fn test_read_response() {
-        todo!()
+    async fn test_write_request() -> io::Result<()> {
+        let req = UrsaExchangeRequest(RequestType::CacheRequest(Cid::default()));
+        let mut io = Cursor::new(Vec::new());
+        UrsaExchangeCodec::default()
+            .write_request(&UrsaProtocol, &mut io, req.clone())
+            .await?;
+        io.set_position(0);
+        let vec = read_length_prefixed(&mut io, MAX_REQUEST_SIZE).await?;
+        assert_eq!(vec[0], ExchangeEncoding::Cbor.header());
+        assert_eq!(ExchangeEncoding::decode::<UrsaExchangeRequest>(&vec)?, req);
+        Ok(())
     }
 
-    #[ignore = "todo"]
     #[tokio::test]
-    async fn test_write_request() {
-        todo!()
+    async fn test_request_encodings() -> io::Result<()> {
+        let req = UrsaExchangeRequest(RequestType::CarRequest(Cid::default().to_string()));
+        let mut written = vec![];
+        for encoding in [ExchangeEncoding::Cbor, ExchangeEncoding::Json] {
+            let mut io = Cursor::new(Vec::new());
+            UrsaExchangeCodec::default()
+                .with_encoding(encoding)
+                .write_request(&UrsaProtocol, &mut io, req.clone())
+                .await?;
+            io.set_position(0);
+            // a codec writing cbor reads json too, and the other way around
+            let mut codec = UrsaExchangeCodec::default().with_encoding(ExchangeEncoding::Json);
+            assert_eq!(codec.read_request(&UrsaProtocol, &mut io).await?, req);
+            written.push(io.into_inner());
+        }
+        assert_ne!(written[0], written[1]);
+
+        let err = ExchangeEncoding::decode::<UrsaExchangeRequest>(&[7, 0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
     }
 
-    #[ignore = "todo"]
     #[tokio::test]
-    async fn test_write_response() {
-        todo!()
+    async fn test_request_encoding() -> io::Result<()> {
+        // the variants known by older peers keep their encoding
+        let req = UrsaExchangeRequest(RequestType::CarRequest("cid".to_string()));
+        assert_eq!(serde_json::to_string(&req)?, r#"{"CarRequest":"cid"}"#);
+
+        let mut codec = UrsaExchangeCodec::default();
+        for req in [
+            RequestType::BlockRequest(Cid::default()),
+            RequestType::PingRequest,
+        ] {
+            let req = UrsaExchangeRequest(req);
+            let mut io = Cursor::new(Vec::new());
+            codec
+                .write_request(&UrsaProtocol, &mut io, req.clone())
+                .await?;
+            io.set_position(0);
+            assert_eq!(codec.read_request(&UrsaProtocol, &mut io).await?, req);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_response() -> io::Result<()> {
+        // fits in a single frame
+        let res = car_response(64 * 1024);
+        let mut codec = UrsaExchangeCodec::default();
+        assert_eq!(write_then_read(&mut codec, res.clone()).await?, res);
+
+        let mut io = Cursor::new(Vec::new());
+        let err = codec
+            .read_response(&UrsaProtocol, &mut io)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_response() -> io::Result<()> {
+        // json encoding makes the response well over three frames
+        let res = car_response(3 * RESPONSE_FRAME_SIZE);
+        let mut codec =
+            UrsaExchangeCodec::new(64 * 1024 * 1024).with_encoding(ExchangeEncoding::Json);
+        assert_eq!(write_then_read(&mut codec, res.clone()).await?, res);
+
+        let mut codec =
+            UrsaExchangeCodec::new(2 * RESPONSE_FRAME_SIZE).with_encoding(ExchangeEncoding::Json);
+        let err = write_then_read(&mut codec, res).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
     }
 }
@@ -1,35 +1,118 @@
-use libp2p::Multiaddr;
+use anyhow::{anyhow, Result};
+use libp2p::{
+    mdns::Config as MdnsConfig, multiaddr::Protocol, swarm::ConnectionLimits, Multiaddr, PeerId,
+};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use ursa_store::DagLimits;
+
+use crate::codec::protocol::{ExchangeEncoding, DEFAULT_MAX_RESPONSE_SIZE};
+use crate::ursa_agent;
+
+/// A problem found in a [`NetworkConfig`] by [`NetworkConfig::validate`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    #[error("The relay client requires autonat to know if the node is behind a NAT")]
+    RelayWithoutAutonat,
+    #[error("The bootstrap node {addr} is invalid: {reason}")]
+    InvalidBootstrapNode { addr: Multiaddr, reason: String },
+    #[error("The listen address {addr} is not available: {reason}")]
+    AddressUnavailable { addr: Multiaddr, reason: String },
+    /// Any other invariant of the config that does not hold.
+    #[error("{0}")]
+    Invalid(String),
+}
 
 /// Ursa Configuration
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct NetworkConfig {
     /// Optional mdns local discovery.
     #[serde(default = "NetworkConfig::default_mdns")]
     pub mdns: bool,
+    /// Seconds between the mDNS queries for peers on the local network. Defaults to 5 minutes.
+    #[serde(default = "NetworkConfig::default_mdns_query_interval")]
+    pub mdns_query_interval: u64,
     /// Optional Provide a relay server for other peers to listen on.
     #[serde(default = "NetworkConfig::default_relay_server")]
     pub relay_server: bool,
     /// Optional autonat. This is used to determine if we are behind a NAT and need to use a relay.
     #[serde(default = "NetworkConfig::default_autonat")]
     pub autonat: bool,
+    /// Number of autonat probes that must confirm a private NAT status, after the one that
+    /// reported it, before the relay client listens on a relay. Defaults to 0, acting on
+    /// the first report.
+    #[serde(default = "NetworkConfig::default_autonat_confidence")]
+    pub autonat_confidence: usize,
     /// Optional Enable listening on a relay server if not publicly available. Requires autonat.
     /// Connections will attempt to upgrade using dcutr.
     #[serde(default = "NetworkConfig::default_relay_client")]
     pub relay_client: bool,
+    /// Lifetime in seconds of the reservations handed out by the relay server, also assumed
+    /// for the reservations of the relay client. Defaults to 1 hour.
+    #[serde(default = "NetworkConfig::default_relay_reservation_duration")]
+    pub relay_reservation_duration: u64,
+    /// Renew a relay reservation once this percentage of its lifetime passed without the
+    /// relay client renewing it. Defaults to 90.
+    #[serde(default = "NetworkConfig::default_relay_renewal_percent")]
+    pub relay_renewal_percent: u8,
+    /// Reservations the relay server hands out at once, the next ones are denied.
+    /// Defaults to 128.
+    #[serde(default = "NetworkConfig::default_relay_max_reservations")]
+    pub relay_max_reservations: usize,
+    /// Circuits the relay server routes at once, the next ones are denied. Defaults to 16.
+    #[serde(default = "NetworkConfig::default_relay_max_circuits")]
+    pub relay_max_circuits: usize,
+    /// Agent version the node reports to its peers through identify.
+    /// Defaults to `ursa/<commit hash>`.
+    #[serde(default = "NetworkConfig::default_agent_version")]
+    pub agent_version: String,
+    /// Name of the network the node joins, eg. mainnet or testnet. Gossipsub topics are
+    /// namespaced with it. Defaults to mainnet.
+    #[serde(default = "NetworkConfig::default_network_name")]
+    pub network_name: String,
     /// set true if it is a bootstrap node. default = false
     #[serde(default = "NetworkConfig::default_bootstrapper")]
     pub bootstrapper: bool,
     /// Swarm listening Address.
     #[serde(default = "NetworkConfig::default_swarm_addrs")]
     pub swarm_addrs: Vec<Multiaddr>,
+    /// Single swarm listening address of older configurations, listened on along with
+    /// `swarm_addrs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swarm_addr: Option<Multiaddr>,
+    /// Listen on and dial `/ws` addresses with WebSocket over TCP. Defaults to false.
+    #[serde(default)]
+    pub transport_ws: bool,
+    /// Listen on and dial `/udp/<port>/webrtc` addresses with WebRTC, for browsers to connect
+    /// directly. The certificate hash is added to the listen addresses, dialers need it.
+    /// Requires the `webrtc` feature. Defaults to false.
+    #[serde(default)]
+    pub transport_webrtc: bool,
+    /// Security protocols of the TCP and WebSocket connections. QUIC always uses TLS.
+    /// Defaults to noise.
+    #[serde(default)]
+    pub security: SecurityProtocol,
+    /// Maximum seconds between the redials of a disconnected bootstrap node, the delay doubling
+    /// from a second on each attempt. Defaults to 5 minutes.
+    #[serde(default = "NetworkConfig::default_bootstrap_redial_max_backoff")]
+    pub bootstrap_redial_max_backoff: u64,
     /// Bootstrap nodes.
     #[serde(default = "NetworkConfig::default_bootstrap_nodes")]
     pub bootstrap_nodes: Vec<Multiaddr>,
     /// Database path.
     #[serde(default = "NetworkConfig::default_database_path")]
     pub database_path: PathBuf,
+    /// Bytes of the blocks recently read from the database kept in memory. Defaults to 64 MiB,
+    /// 0 disables the cache.
+    #[serde(default = "NetworkConfig::default_block_cache_size")]
+    pub block_cache_size: u64,
     /// user identity name
     #[serde(default = "NetworkConfig::default_identity")]
     pub identity: String,
@@ -42,24 +125,165 @@ pub struct NetworkConfig {
     /// Interval to run random kademlia walks to refresh the routing table. Defaults to 5 minutes
     #[serde(default = "NetworkConfig::default_kad_walk_interval")]
     pub kad_walk_interval: u64,
+    /// Interval in seconds to bootstrap kademlia again, reaching the bootstrap nodes that were
+    /// unreachable before. Defaults to 5 minutes, `None` disables it.
+    #[serde(default = "NetworkConfig::default_kad_bootstrap_interval")]
+    pub kad_bootstrap_interval: Option<u64>,
+    /// Maximum size in bytes of the values stored in the DHT. Defaults to 64 KiB.
+    #[serde(default = "NetworkConfig::default_kad_max_record_size")]
+    pub kad_max_record_size: usize,
     /// Maximum number of cache summaries from other peers to store.
     #[serde(default = "NetworkConfig::default_max_cache_summaries")]
     pub max_cache_summaries: usize,
+    /// Disconnect peers that don't support gossipsub. Defaults to true.
+    #[serde(default = "NetworkConfig::default_require_gossipsub")]
+    pub require_gossipsub: bool,
+    /// Maximum size in bytes of a car file served in response to a car request. Defaults to 2 MiB.
+    #[serde(default = "NetworkConfig::default_max_car_response_size")]
+    pub max_car_response_size: u64,
+    /// Limits on the dags traversed to answer car requests. Defaults to at most 10000 blocks
+    /// and a depth of 1024, the bytes being bounded by `max_car_response_size`.
+    #[serde(default = "NetworkConfig::default_car_request_dag_limits")]
+    pub car_request_dag_limits: DagLimits,
+    /// Maximum size in bytes of a request-response response read from a peer. Responses
+    /// are sent in frames of 1 MiB, so this is not bound by the frame size. Defaults to 10 MiB.
+    #[serde(default = "NetworkConfig::default_max_response_size")]
+    pub max_response_size: usize,
+    /// Encoding of the exchange messages the node writes, `cbor` or `json`. Messages in
+    /// either encoding are read. Defaults to cbor.
+    #[serde(default)]
+    pub exchange_encoding: ExchangeEncoding,
+    /// Maximum number of peers a bitswap query is sent to. Defaults to 8.
+    #[serde(default = "NetworkConfig::default_bitswap_fanout")]
+    pub bitswap_fanout: usize,
+    /// Maximum number of distinct cids with pending bitswap queries. Defaults to 1024.
+    #[serde(default = "NetworkConfig::default_max_pending_queries")]
+    pub max_pending_queries: usize,
+    /// Capacity of the channel the network receives commands on. Defaults to 1024.
+    #[serde(default = "NetworkConfig::default_command_buffer")]
+    pub command_buffer: usize,
+    /// Capacity of the channel the network emits events on. Events are dropped while it is full.
+    /// Defaults to 4096.
+    #[serde(default = "NetworkConfig::default_event_buffer")]
+    pub event_buffer: usize,
+    /// Only these peers may connect to the node, when set.
+    #[serde(default)]
+    pub allow_peers: Option<HashSet<PeerId>>,
+    /// Peers that may not connect to the node.
+    #[serde(default)]
+    pub deny_peers: HashSet<PeerId>,
+    /// Limits on the number of connections. `None` leaves a limit unbounded.
+    #[serde(default)]
+    pub connection_limits: ConnectionLimitsConfig,
+    /// Gossipsub parameters. `None` keeps the default of a parameter.
+    #[serde(default)]
+    pub gossip: GossipConfig,
+    /// Interval in seconds between the pings sent on each connection. Defaults to 15 seconds.
+    #[serde(default = "NetworkConfig::default_ping_interval")]
+    pub ping_interval: u64,
+    /// Seconds to wait for a pong before the ping fails. Defaults to 20 seconds.
+    #[serde(default = "NetworkConfig::default_ping_timeout")]
+    pub ping_timeout: u64,
+    /// Seconds a connection is kept open without any gossipsub, kademlia or request-response
+    /// traffic. `None` keeps the default of each protocol.
+    #[serde(default)]
+    pub idle_connection_timeout: Option<u64>,
+    /// Seconds to wait for the response to an exchange request, long enough for the largest
+    /// car responses. Defaults to 60 seconds.
+    #[serde(default = "NetworkConfig::default_exchange_request_timeout")]
+    pub exchange_request_timeout: u64,
+    /// Seconds a connection is kept open without exchange requests. `None` falls back to
+    /// `idle_connection_timeout`.
+    #[serde(default)]
+    pub exchange_connection_keepalive: Option<u64>,
+    /// Requests of each type a peer may send per second on the exchange protocol, the excess
+    /// ones are refused. Defaults to 20.
+    #[serde(default = "NetworkConfig::default_exchange_requests_per_sec")]
+    pub exchange_requests_per_sec: u32,
+    /// Requests of each type a peer may send at once before being rate limited. Defaults to 50.
+    #[serde(default = "NetworkConfig::default_exchange_burst")]
+    pub exchange_burst: u32,
+    /// Seconds a cid no peer could serve is failed right away on later bitswap fetches,
+    /// unless a new peer connects in the meantime. 0 disables it. Defaults to 30 seconds.
+    #[serde(default = "NetworkConfig::default_not_found_cache_ttl")]
+    pub not_found_cache_ttl: u64,
+    /// File the addresses of the known peers are saved to on shutdown and loaded from on
+    /// startup, to reconnect without waiting on the bootstrap nodes. `None` disables it.
+    #[serde(default)]
+    pub peerstore_path: Option<PathBuf>,
+    /// Region of the node. Peers resolved to the same region by `region_resolver` are asked
+    /// for content before the others.
+    #[serde(default)]
+    pub region: Option<String>,
+    /// Resolves the region of the peers from their address, eg. with a GeoIP database.
+    /// Only set in code.
+    #[serde(skip)]
+    pub region_resolver: Option<RegionResolver>,
+}
+
+/// Maps the address of a peer to its region, `None` if it is unknown.
+#[derive(Clone)]
+pub struct RegionResolver(Arc<dyn Fn(&Multiaddr) -> Option<String> + Send + Sync>);
+
+impl RegionResolver {
+    pub fn new(resolve: impl Fn(&Multiaddr) -> Option<String> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(resolve))
+    }
+
+    pub fn region(&self, addr: &Multiaddr) -> Option<String> {
+        (self.0)(addr)
+    }
+}
+
+impl fmt::Debug for RegionResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RegionResolver")
+    }
+}
+
+impl PartialEq for RegionResolver {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
 impl NetworkConfig {
     fn default_mdns() -> bool {
         false
     }
+    fn default_mdns_query_interval() -> u64 {
+        300
+    }
     fn default_autonat() -> bool {
         true
     }
+    fn default_autonat_confidence() -> usize {
+        0
+    }
     fn default_relay_client() -> bool {
         true
     }
     fn default_relay_server() -> bool {
         true
     }
+    fn default_relay_reservation_duration() -> u64 {
+        3600
+    }
+    fn default_relay_renewal_percent() -> u8 {
+        90
+    }
+    fn default_relay_max_reservations() -> usize {
+        128
+    }
+    fn default_relay_max_circuits() -> usize {
+        16
+    }
+    fn default_agent_version() -> String {
+        ursa_agent()
+    }
+    fn default_network_name() -> String {
+        "mainnet".to_string()
+    }
     fn default_bootstrapper() -> bool {
         false
     }
@@ -75,6 +299,9 @@ impl NetworkConfig {
             "/ip4/0.0.0.0/udp/4890/quic-v1".parse().unwrap(),
         ]
     }
+    fn default_block_cache_size() -> u64 {
+        64 * 1024 * 1024
+    }
     fn default_database_path() -> PathBuf {
         "~/.ursa/data/ursa_db".into()
     }
@@ -90,27 +317,534 @@ impl NetworkConfig {
     fn default_kad_walk_interval() -> u64 {
         300
     }
+    fn default_kad_bootstrap_interval() -> Option<u64> {
+        Some(300)
+    }
+    fn default_kad_max_record_size() -> usize {
+        64 * 1024
+    }
     fn default_max_cache_summaries() -> usize {
         10
     }
+    fn default_require_gossipsub() -> bool {
+        true
+    }
+    fn default_command_buffer() -> usize {
+        1024
+    }
+    fn default_event_buffer() -> usize {
+        4096
+    }
+    fn default_bitswap_fanout() -> usize {
+        8
+    }
+    fn default_max_pending_queries() -> usize {
+        1024
+    }
+    fn default_max_response_size() -> usize {
+        DEFAULT_MAX_RESPONSE_SIZE
+    }
+    fn default_max_car_response_size() -> u64 {
+        // responses are json encoded, keep them well under the codec's max response size
+        2 * 1024 * 1024
+    }
+    fn default_car_request_dag_limits() -> DagLimits {
+        DagLimits {
+            max_nodes: Some(10_000),
+            max_depth: Some(1024),
+            max_bytes: None,
+        }
+    }
+    fn default_bootstrap_redial_max_backoff() -> u64 {
+        300
+    }
+    fn default_ping_interval() -> u64 {
+        15
+    }
+    fn default_ping_timeout() -> u64 {
+        20
+    }
+    fn default_exchange_request_timeout() -> u64 {
+        60
+    }
+    fn default_not_found_cache_ttl() -> u64 {
+        30
+    }
+    fn default_exchange_requests_per_sec() -> u32 {
+        20
+    }
+    fn default_exchange_burst() -> u32 {
+        50
+    }
+
+    /// Addresses to listen on, `swarm_addrs` followed by `swarm_addr`.
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let mut addrs = self.swarm_addrs.clone();
+        if let Some(addr) = &self.swarm_addr {
+            if !addrs.contains(addr) {
+                addrs.push(addr.clone());
+            }
+        }
+        addrs
+    }
+
+    /// Check every invariant of the config up front, without starting the service, and
+    /// return all the problems found. The listen addresses with a fixed port are bound and
+    /// released to check they are available.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let invalid = |e: anyhow::Error| ConfigError::Invalid(e.to_string());
+        let mut errors: Vec<ConfigError> = [
+            self.connection_limits.validate(),
+            self.gossip.validate(self.bootstrapper),
+            self.validate_mdns(),
+            self.validate_keep_alive().map(|_| ()),
+            self.validate_relay(),
+            self.validate_exchange_rate(),
+            self.validate_transports(),
+            self.validate_buffers(),
+            self.validate_bitswap(),
+        ]
+        .into_iter()
+        .filter_map(|result| result.err().map(invalid))
+        .collect();
+
+        if self.relay_client && !self.autonat {
+            errors.push(ConfigError::RelayWithoutAutonat);
+        }
+        for addr in &self.bootstrap_nodes {
+            if let Err(reason) = Self::check_bootstrap_node(addr) {
+                errors.push(ConfigError::InvalidBootstrapNode {
+                    addr: addr.clone(),
+                    reason,
+                });
+            }
+        }
+        for addr in self.listen_addrs() {
+            if let Err(reason) = Self::check_available(&addr) {
+                errors.push(ConfigError::AddressUnavailable { addr, reason });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Bootstrap nodes are dialed, so they need a transport, and identified by their peer id.
+    fn check_bootstrap_node(addr: &Multiaddr) -> Result<(), String> {
+        let mut protocols = addr.iter();
+        if !matches!(
+            protocols.next(),
+            Some(
+                Protocol::Ip4(_)
+                    | Protocol::Ip6(_)
+                    | Protocol::Dns(_)
+                    | Protocol::Dns4(_)
+                    | Protocol::Dns6(_)
+            )
+        ) {
+            return Err("it does not start with an ip address or a dns name".to_string());
+        }
+        if !matches!(protocols.next(), Some(Protocol::Tcp(_) | Protocol::Udp(_))) {
+            return Err("it has no tcp or udp port".to_string());
+        }
+        match addr.iter().last() {
+            Some(Protocol::P2p(mh)) => PeerId::from_multihash(mh)
+                .map(|_| ())
+                .map_err(|_| "its peer id is invalid".to_string()),
+            _ => Err("it does not end with a peer id".to_string()),
+        }
+    }
+
+    fn check_available(addr: &Multiaddr) -> Result<(), String> {
+        let mut protocols = addr.iter();
+        let ip: IpAddr = match protocols.next() {
+            Some(Protocol::Ip4(ip)) => ip.into(),
+            Some(Protocol::Ip6(ip)) => ip.into(),
+            _ => return Ok(()),
+        };
+        match protocols.next() {
+            Some(Protocol::Tcp(port)) if port != 0 => TcpListener::bind(SocketAddr::new(ip, port))
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Some(Protocol::Udp(port)) if port != 0 => UdpSocket::bind(SocketAddr::new(ip, port))
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn validate_relay(&self) -> Result<()> {
+        if !(1..=100).contains(&self.relay_renewal_percent) {
+            return Err(anyhow!(
+                "The relay renewal percent must be between 1 and 100, got {}",
+                self.relay_renewal_percent
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_exchange_rate(&self) -> Result<()> {
+        if self.exchange_requests_per_sec == 0 || self.exchange_burst == 0 {
+            return Err(anyhow!(
+                "The exchange request rate and burst must be at least 1"
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_buffers(&self) -> Result<()> {
+        if self.command_buffer == 0 || self.event_buffer == 0 {
+            return Err(anyhow!(
+                "The command and event buffers must hold at least 1 message"
+            ));
+        }
+        if self.max_cache_summaries == 0 {
+            return Err(anyhow!(
+                "The cache of peer content summaries must hold at least 1 summary"
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_bitswap(&self) -> Result<()> {
+        if self.bitswap_fanout == 0 || self.max_pending_queries == 0 {
+            return Err(anyhow!(
+                "The bitswap fanout and the maximum of pending queries must be at least 1"
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn validate_transports(&self) -> Result<()> {
+        if self.transport_webrtc && !cfg!(feature = "webrtc") {
+            return Err(anyhow!(
+                "The WebRTC transport requires ursa-network to be built with the webrtc feature"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Check the ping, idle and exchange timeouts. Returns warnings about the combinations that are
+    /// allowed but likely not intended.
+    pub fn validate_keep_alive(&self) -> Result<Vec<String>> {
+        if self.ping_interval == 0 || self.ping_timeout == 0 {
+            return Err(anyhow!(
+                "The ping interval and timeout must be at least a second"
+            ));
+        }
+        if self.idle_connection_timeout == Some(0) {
+            return Err(anyhow!(
+                "The idle connection timeout must be at least a second"
+            ));
+        }
+        if self.exchange_request_timeout == 0 || self.exchange_connection_keepalive == Some(0) {
+            return Err(anyhow!(
+                "The exchange request timeout and keep alive must be at least a second"
+            ));
+        }
+        let mut warnings = vec![];
+        if let Some(idle_timeout) = self.idle_connection_timeout {
+            if idle_timeout < self.ping_interval {
+                warnings.push(format!(
+                    "idle connections are closed after {idle_timeout}s, before the next ping after {}s",
+                    self.ping_interval
+                ));
+            }
+        }
+        if self.ping_timeout > 2 * self.ping_interval {
+            warnings.push(format!(
+                "the ping timeout of {}s spans several ping intervals of {}s",
+                self.ping_timeout, self.ping_interval
+            ));
+        }
+        Ok(warnings)
+    }
+
+    /// Check the mDNS settings, which are ignored when mDNS is disabled.
+    pub fn validate_mdns(&self) -> Result<()> {
+        if !self.mdns {
+            return Ok(());
+        }
+        if self.mdns_query_interval == 0 {
+            return Err(anyhow!("The mDNS query interval must be at least a second"));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn mdns_config(&self) -> MdnsConfig {
+        MdnsConfig {
+            query_interval: Duration::from_secs(self.mdns_query_interval),
+            ..Default::default()
+        }
+    }
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             mdns: Self::default_mdns(),
+            mdns_query_interval: Self::default_mdns_query_interval(),
             autonat: Self::default_autonat(),
+            autonat_confidence: Self::default_autonat_confidence(),
             relay_client: Self::default_relay_client(),
             relay_server: Self::default_relay_server(),
+            relay_reservation_duration: Self::default_relay_reservation_duration(),
+            relay_renewal_percent: Self::default_relay_renewal_percent(),
+            relay_max_reservations: Self::default_relay_max_reservations(),
+            relay_max_circuits: Self::default_relay_max_circuits(),
+            agent_version: Self::default_agent_version(),
+            network_name: Self::default_network_name(),
             bootstrapper: Self::default_bootstrapper(),
+            bootstrap_redial_max_backoff: Self::default_bootstrap_redial_max_backoff(),
             bootstrap_nodes: Self::default_bootstrap_nodes(),
             swarm_addrs: Self::default_swarm_addrs(),
+            swarm_addr: None,
+            transport_ws: false,
+            transport_webrtc: false,
+            security: SecurityProtocol::default(),
             database_path: Self::default_database_path(),
+            block_cache_size: Self::default_block_cache_size(),
             identity: Self::default_identity(),
             keystore_path: Self::default_keystore_path(),
             kad_replication_factor: Self::default_kad_replication_factor(),
             kad_walk_interval: Self::default_kad_walk_interval(),
+            kad_bootstrap_interval: Self::default_kad_bootstrap_interval(),
+            kad_max_record_size: Self::default_kad_max_record_size(),
             max_cache_summaries: Self::default_max_cache_summaries(),
+            require_gossipsub: Self::default_require_gossipsub(),
+            max_car_response_size: Self::default_max_car_response_size(),
+            car_request_dag_limits: Self::default_car_request_dag_limits(),
+            max_response_size: Self::default_max_response_size(),
+            exchange_encoding: ExchangeEncoding::default(),
+            bitswap_fanout: Self::default_bitswap_fanout(),
+            max_pending_queries: Self::default_max_pending_queries(),
+            command_buffer: Self::default_command_buffer(),
+            event_buffer: Self::default_event_buffer(),
+            allow_peers: None,
+            deny_peers: HashSet::new(),
+            connection_limits: ConnectionLimitsConfig::default(),
+            gossip: GossipConfig::default(),
+            ping_interval: Self::default_ping_interval(),
+            ping_timeout: Self::default_ping_timeout(),
+            idle_connection_timeout: None,
+            exchange_request_timeout: Self::default_exchange_request_timeout(),
+            exchange_connection_keepalive: None,
+            exchange_requests_per_sec: Self::default_exchange_requests_per_sec(),
+            exchange_burst: Self::default_exchange_burst(),
+            not_found_cache_ttl: Self::default_not_found_cache_ttl(),
+            peerstore_path: None,
+            region: None,
+            region_resolver: None,
+        }
+    }
+}
+
+/// Connection limits of the swarm.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+pub struct ConnectionLimitsConfig {
+    /// Maximum number of pending incoming connections. Defaults to 1024.
+    #[serde(default = "ConnectionLimitsConfig::default_max_pending")]
+    pub max_pending_incoming: Option<u32>,
+    /// Maximum number of pending outgoing connections. Defaults to 1024.
+    #[serde(default = "ConnectionLimitsConfig::default_max_pending")]
+    pub max_pending_outgoing: Option<u32>,
+    /// Maximum number of established incoming connections. Defaults to 1024.
+    #[serde(default = "ConnectionLimitsConfig::default_max_established")]
+    pub max_established_incoming: Option<u32>,
+    /// Maximum number of established outgoing connections. Defaults to 1024.
+    #[serde(default = "ConnectionLimitsConfig::default_max_established")]
+    pub max_established_outgoing: Option<u32>,
+    /// Maximum number of established connections in total. Unbounded by default.
+    #[serde(default)]
+    pub max_established_total: Option<u32>,
+    /// Maximum number of established connections to a single peer. Defaults to 8.
+    #[serde(default = "ConnectionLimitsConfig::default_max_established_per_peer")]
+    pub max_established_per_peer: Option<u32>,
+    /// Number of connected peers above which the unprotected peers with the lowest scores are
+    /// disconnected. Bootstrap nodes and relays are protected. Unbounded by default.
+    #[serde(default)]
+    pub max_peers: Option<u32>,
+}
+
+impl ConnectionLimitsConfig {
+    fn default_max_pending() -> Option<u32> {
+        Some(2 << 9)
+    }
+    fn default_max_established() -> Option<u32> {
+        Some(2 << 9)
+    }
+    fn default_max_established_per_peer() -> Option<u32> {
+        Some(8)
+    }
+
+    /// Check that the limits are consistent with each other.
+    pub fn validate(&self) -> Result<()> {
+        let per_peer = match self.max_established_per_peer {
+            Some(per_peer) => per_peer,
+            None => return Ok(()),
+        };
+        if per_peer == 0 {
+            return Err(anyhow!(
+                "max_established_per_peer must be greater than 0, no connection could be established"
+            ));
+        }
+        if let Some(total) = self.max_established_total {
+            if per_peer > total {
+                return Err(anyhow!(
+                    "max_established_per_peer ({per_peer}) is larger than max_established_total ({total})"
+                ));
+            }
+        }
+        if let (Some(incoming), Some(outgoing)) =
+            (self.max_established_incoming, self.max_established_outgoing)
+        {
+            if per_peer > incoming.saturating_add(outgoing) {
+                return Err(anyhow!(
+                    "max_established_per_peer ({per_peer}) is larger than max_established_incoming \
+                     and max_established_outgoing combined ({})",
+                    incoming.saturating_add(outgoing)
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_pending_incoming: Self::default_max_pending(),
+            max_pending_outgoing: Self::default_max_pending(),
+            max_established_incoming: Self::default_max_established(),
+            max_established_outgoing: Self::default_max_established(),
+            max_established_total: None,
+            max_established_per_peer: Self::default_max_established_per_peer(),
+            max_peers: None,
+        }
+    }
+}
+
+/// Gossipsub parameters, to trade propagation latency for bandwidth.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Clone)]
+pub struct GossipConfig {
+    /// Time in milliseconds between two heartbeats. Defaults to 1 second.
+    #[serde(default)]
+    pub heartbeat_interval: Option<u64>,
+    /// Target number of peers in the mesh of a topic. Defaults to 8, 0 on bootstrap nodes.
+    #[serde(default)]
+    pub mesh_n: Option<usize>,
+    /// Peers are added to a mesh below this many peers. Defaults to 4, 0 on bootstrap nodes.
+    #[serde(default)]
+    pub mesh_n_low: Option<usize>,
+    /// Peers are removed from a mesh above this many peers. Defaults to 12, 0 on bootstrap
+    /// nodes.
+    #[serde(default)]
+    pub mesh_n_high: Option<usize>,
+    /// Number of heartbeats messages are kept in the cache for. Defaults to 5.
+    #[serde(default)]
+    pub history_length: Option<usize>,
+    /// No gossip is emitted to or accepted from peers scored below this. Defaults to -10.
+    #[serde(default)]
+    pub gossip_threshold: Option<f64>,
+    /// Messages are not published to peers scored below this. Defaults to -50.
+    #[serde(default)]
+    pub publish_threshold: Option<f64>,
+    /// Messages from peers scored below this are ignored. Defaults to -80.
+    #[serde(default)]
+    pub graylist_threshold: Option<f64>,
+    /// Whether messages are signed with the node's keypair. The nodes of a network must
+    /// agree on it. Defaults to signed.
+    #[serde(default)]
+    pub message_authenticity: GossipAuthenticity,
+}
+
+/// Security protocols offered on the TCP and WebSocket connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityProtocol {
+    #[default]
+    Noise,
+    Tls,
+    /// Negotiate noise or tls, whichever the remote supports, preferring noise.
+    Both,
+}
+
+/// Authenticity of the gossipsub messages.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GossipAuthenticity {
+    /// Messages are signed and carry their source, unsigned or tampered messages are rejected.
+    #[default]
+    Signed,
+    /// Messages carry no source, sequence number or signature.
+    Anonymous,
+}
+
+impl GossipConfig {
+    /// Number of heartbeats the messages are gossiped for, the history length can't be lower.
+    pub(crate) const HISTORY_GOSSIP: usize = 3;
+
+    /// The target, low and high number of mesh peers.
+    pub(crate) fn mesh(&self, bootstrapper: bool) -> (usize, usize, usize) {
+        let (mesh_n, mesh_n_low, mesh_n_high) = if bootstrapper { (0, 0, 0) } else { (8, 4, 12) };
+        (
+            self.mesh_n.unwrap_or(mesh_n),
+            self.mesh_n_low.unwrap_or(mesh_n_low),
+            self.mesh_n_high.unwrap_or(mesh_n_high),
+        )
+    }
+
+    /// The gossip, publish and graylist peer score thresholds.
+    pub(crate) fn thresholds(&self) -> (f64, f64, f64) {
+        (
+            self.gossip_threshold.unwrap_or(-10.0),
+            self.publish_threshold.unwrap_or(-50.0),
+            self.graylist_threshold.unwrap_or(-80.0),
+        )
+    }
+
+    /// Check that the parameters are consistent with each other.
+    pub fn validate(&self, bootstrapper: bool) -> Result<()> {
+        let (mesh_n, mesh_n_low, mesh_n_high) = self.mesh(bootstrapper);
+        if !(mesh_n_low <= mesh_n && mesh_n <= mesh_n_high) {
+            return Err(anyhow!(
+                "The gossipsub mesh sizes must satisfy mesh_n_low ({mesh_n_low}) <= mesh_n ({mesh_n}) <= mesh_n_high ({mesh_n_high})"
+            ));
+        }
+        let (gossip, publish, graylist) = self.thresholds();
+        if !(0.0 >= gossip && gossip >= publish && publish >= graylist) {
+            return Err(anyhow!(
+                "The gossipsub peer score thresholds must satisfy 0 >= gossip_threshold ({gossip}) >= publish_threshold ({publish}) >= graylist_threshold ({graylist})"
+            ));
+        }
+        if self.heartbeat_interval == Some(0) {
+            return Err(anyhow!(
+                "The gossipsub heartbeat_interval must be greater than 0"
+            ));
         }
+        if let Some(history_length) = self.history_length {
+            if history_length < Self::HISTORY_GOSSIP {
+                return Err(anyhow!(
+                    "The gossipsub history_length ({history_length}) must be at least {}",
+                    Self::HISTORY_GOSSIP
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<&ConnectionLimitsConfig> for ConnectionLimits {
+    fn from(config: &ConnectionLimitsConfig) -> Self {
+        ConnectionLimits::default()
+            .with_max_pending_incoming(config.max_pending_incoming)
+            .with_max_pending_outgoing(config.max_pending_outgoing)
+            .with_max_established_incoming(config.max_established_incoming)
+            .with_max_established_outgoing(config.max_established_outgoing)
+            .with_max_established(config.max_established_total)
+            .with_max_established_per_peer(config.max_established_per_peer)
     }
 }
@@ -1,4 +1,5 @@
-use libp2p::PeerId;
+use libipld::Cid;
+use libp2p::{kad::kbucket::Key, PeerId};
 use std::{
     collections::{HashMap, HashSet},
     time::Duration,
@@ -16,6 +17,14 @@ pub struct Manager {
     connected_peers: HashSet<PeerId>,
     /// Set of peers to use in content replication.
     replication_set: HashMap<PeerId, Duration>,
+    /// Last ping rtt of each peer.
+    rtts: HashMap<PeerId, Duration>,
+    /// Peers kept connected when there are too many peers.
+    protected_peers: HashSet<PeerId>,
+    /// Region of the node, if known.
+    local_region: Option<String>,
+    /// Region of the connected peers, when it could be resolved.
+    regions: HashMap<PeerId, String>,
 }
 
 impl Manager {
@@ -41,16 +50,74 @@ impl Manager {
 
     pub fn remove(&mut self, peer: &PeerId) -> bool {
         self.replication_set.remove(peer);
+        self.rtts.remove(peer);
+        self.regions.remove(peer);
         self.connected_peers.remove(peer)
     }
 
+    /// Exempt `peer` from the disconnections when there are too many peers.
+    /// Returns false if it was already protected.
+    pub fn protect(&mut self, peer: PeerId) -> bool {
+        self.protected_peers.insert(peer)
+    }
+
+    pub fn unprotect(&mut self, peer: &PeerId) -> bool {
+        self.protected_peers.remove(peer)
+    }
+
+    pub fn is_protected(&self, peer: &PeerId) -> bool {
+        self.protected_peers.contains(peer)
+    }
+
+    pub fn set_local_region(&mut self, region: Option<String>) {
+        self.local_region = region;
+    }
+
+    pub fn set_region(&mut self, peer: PeerId, region: String) {
+        self.regions.insert(peer, region);
+    }
+
+    /// Whether `peer` is known to be in the region of the node.
+    fn is_local(&self, peer: &PeerId) -> bool {
+        self.local_region.is_some() && self.regions.get(peer) == self.local_region.as_ref()
+    }
+
     pub fn replication_set(&self) -> Vec<PeerId> {
         self.replication_set.clone().into_keys().collect()
     }
 
+    /// Pick at most `fanout` of the `candidates` to ask for `cid`: the ones in the region of
+    /// the node first, then the lowest rtt, then the closest to the cid in the kademlia keyspace.
+    pub fn select_providers(
+        &self,
+        cid: &Cid,
+        candidates: Vec<PeerId>,
+        fanout: usize,
+    ) -> Vec<PeerId> {
+        if candidates.len() <= fanout {
+            return candidates;
+        }
+        let target = Key::new(cid.to_bytes());
+        let mut candidates: Vec<_> = candidates
+            .into_iter()
+            .map(|peer| {
+                let rtt = self.rtts.get(&peer).copied().unwrap_or(Duration::MAX);
+                let remote = !self.is_local(&peer);
+                ((remote, rtt, Key::from(peer).distance(&target)), peer)
+            })
+            .collect();
+        candidates.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        candidates
+            .into_iter()
+            .take(fanout)
+            .map(|(_, peer)| peer)
+            .collect()
+    }
+
     #[cfg(not(test))]
     pub fn handle_rtt_received(&mut self, rtt: Duration, peer: PeerId) {
         debug!("Received {rtt:?} rtt for {peer}");
+        self.rtts.insert(peer, rtt);
         if rtt > MAX_RTT {
             self.replication_set.remove(&peer);
             debug!("{peer} was not added because of high rrt");
@@ -84,6 +151,99 @@ impl Manager {
     #[cfg(test)]
     pub fn handle_rtt_received(&mut self, rtt: Duration, peer: PeerId) {
         debug!("Ignoring rtt and inserting {peer}");
+        self.rtts.insert(peer, rtt);
         self.replication_set.insert(peer, rtt);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Block, DefaultParams};
+
+    fn cid() -> Cid {
+        *Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &ipld!("content"))
+            .unwrap()
+            .cid()
+    }
+
+    #[test]
+    fn test_select_providers_fewer_than_fanout() {
+        let manager = Manager::new();
+        let peers: Vec<_> = (0..3).map(|_| PeerId::random()).collect();
+
+        let selected = manager.select_providers(&cid(), peers.clone(), 8);
+        assert_eq!(selected, peers);
+    }
+
+    #[test]
+    fn test_select_providers_lowest_rtt() {
+        let mut manager = Manager::new();
+        let peers: Vec<_> = (0..12).map(|_| PeerId::random()).collect();
+        for (i, peer) in peers.iter().enumerate() {
+            manager.insert(*peer);
+            // the first two peers were never pinged
+            if i >= 2 {
+                manager.handle_rtt_received(Duration::from_millis(i as u64), *peer);
+            }
+        }
+
+        let mut selected = manager.select_providers(&cid(), peers.clone(), 8);
+        selected.sort();
+        let mut expected = peers[2..10].to_vec();
+        expected.sort();
+        assert_eq!(selected, expected);
+    }
+
+    #[test]
+    fn test_select_providers_same_region() {
+        let mut manager = Manager::new();
+        manager.set_local_region(Some("eu".to_string()));
+        let peers: Vec<_> = (0..12).map(|_| PeerId::random()).collect();
+        for (i, peer) in peers.iter().enumerate() {
+            manager.insert(*peer);
+            manager.handle_rtt_received(Duration::from_millis(i as u64), *peer);
+            // the last four peers are in the region, the others elsewhere or unknown
+            match i {
+                0..=3 => manager.set_region(*peer, "us".to_string()),
+                8..=11 => manager.set_region(*peer, "eu".to_string()),
+                _ => (),
+            }
+        }
+
+        // same region peers first, despite their higher rtt
+        let mut selected = manager.select_providers(&cid(), peers.clone(), 6);
+        selected.sort();
+        let mut expected = [&peers[8..], &peers[..2]].concat();
+        expected.sort();
+        assert_eq!(selected, expected);
+
+        // without a local region the rtt decides
+        manager.set_local_region(None);
+        let mut selected = manager.select_providers(&cid(), peers.clone(), 6);
+        selected.sort();
+        let mut expected = peers[..6].to_vec();
+        expected.sort();
+        assert_eq!(selected, expected);
+    }
+
+    #[test]
+    fn test_select_providers_closest_without_rtt() {
+        let manager = Manager::new();
+        let cid = cid();
+        let peers: Vec<_> = (0..12).map(|_| PeerId::random()).collect();
+
+        let selected = manager.select_providers(&cid, peers.clone(), 4);
+        assert_eq!(selected.len(), 4);
+
+        let target = Key::new(cid.to_bytes());
+        let furthest_selected = selected
+            .iter()
+            .map(|peer| Key::from(*peer).distance(&target))
+            .max()
+            .unwrap();
+        for peer in peers.iter().filter(|peer| !selected.contains(peer)) {
+            assert!(Key::from(*peer).distance(&target) > furthest_selected);
+        }
+    }
+}
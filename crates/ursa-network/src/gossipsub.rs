@@ -1,14 +1,15 @@
-use crate::config::NetworkConfig;
+use crate::config::{GossipAuthenticity, GossipConfig, NetworkConfig};
 use anyhow::anyhow;
 use std::{
     collections::hash_map::DefaultHasher,
     hash::{Hash, Hasher},
+    time::Duration,
 };
 
 use libp2p::{
     gossipsub::{
-        Gossipsub, GossipsubConfigBuilder, GossipsubMessage, MessageAuthenticity, MessageId,
-        ValidationMode,
+        Gossipsub, GossipsubConfig, GossipsubConfigBuilder, GossipsubMessage, MessageAuthenticity,
+        MessageId, PeerScoreParams, PeerScoreThresholds, ValidationMode,
     },
     identity::Keypair,
 };
@@ -16,21 +17,38 @@ use libp2p::{
 const URSA_GOSSIP_PROTOCOL: &str = "ursa/gossipsub/0.0.1";
 
 pub(crate) fn build_gossipsub(keypair: &Keypair, config: &NetworkConfig) -> Gossipsub {
-    let is_bootstrapper = config.bootstrapper;
-    let mesh_n = if is_bootstrapper { 0 } else { 8 };
-    let mesh_n_low = if is_bootstrapper { 0 } else { 4 };
-    let mesh_n_high = if is_bootstrapper { 0 } else { 12 };
+    let authenticity = match config.gossip.message_authenticity {
+        GossipAuthenticity::Signed => MessageAuthenticity::Signed(keypair.clone()),
+        GossipAuthenticity::Anonymous => MessageAuthenticity::Anonymous,
+    };
+    let mut gossipsub = Gossipsub::new(authenticity, gossipsub_config(config))
+        .map_err(|err| anyhow!("{}", err))
+        .unwrap();
+    gossipsub
+        .with_peer_score(PeerScoreParams::default(), peer_score_thresholds(config))
+        .expect("PeerScoreParams and PeerScoreThresholds");
+    gossipsub
+}
+
+fn gossipsub_config(config: &NetworkConfig) -> GossipsubConfig {
+    let (mesh_n, mesh_n_low, mesh_n_high) = config.gossip.mesh(config.bootstrapper);
     let gossip_lazy = mesh_n;
     // D_out
-    let mesh_outbound_min = if is_bootstrapper { 0 } else { (mesh_n / 2) - 1 };
+    let mesh_outbound_min = (mesh_n / 2).saturating_sub(1).min(mesh_n_low);
     let max_transmit_size = 4 * 1024 * 1024;
+    // signatures are verified by gossipsub before the messages reach the service
+    let validation_mode = match config.gossip.message_authenticity {
+        GossipAuthenticity::Signed => ValidationMode::Strict,
+        GossipAuthenticity::Anonymous => ValidationMode::Anonymous,
+    };
     let message_id_fn = move |message: &GossipsubMessage| {
         let mut hasher = DefaultHasher::new();
         message.data.hash(&mut hasher);
         MessageId::from(hasher.finish().to_string())
     };
 
-    let gossip_config = GossipsubConfigBuilder::default()
+    let mut builder = GossipsubConfigBuilder::default();
+    builder
         .protocol_id_prefix(URSA_GOSSIP_PROTOCOL)
         .mesh_n(mesh_n)
         .mesh_n_low(mesh_n_low)
@@ -38,13 +56,88 @@ pub(crate) fn build_gossipsub(keypair: &Keypair, config: &NetworkConfig) -> Goss
         // default to mesh_n
         .gossip_lazy(gossip_lazy)
         .max_transmit_size(max_transmit_size)
-        .validation_mode(ValidationMode::Strict)
+        .validation_mode(validation_mode)
+        // messages are only forwarded once the service validated them
+        .validate_messages()
         .message_id_fn(message_id_fn)
         .mesh_outbound_min(mesh_outbound_min)
-        .build()
-        .expect("gossipsub config");
+        .history_gossip(GossipConfig::HISTORY_GOSSIP);
+    if let Some(heartbeat_interval) = config.gossip.heartbeat_interval {
+        builder.heartbeat_interval(Duration::from_millis(heartbeat_interval));
+    }
+    if let Some(history_length) = config.gossip.history_length {
+        builder.history_length(history_length);
+    }
+    if let Some(idle_timeout) = config.idle_connection_timeout {
+        builder.idle_timeout(Duration::from_secs(idle_timeout));
+    }
+    builder.build().expect("gossipsub config")
+}
 
-    Gossipsub::new(MessageAuthenticity::Signed(keypair.clone()), gossip_config)
-        .map_err(|err| anyhow!("{}", err))
-        .unwrap()
+fn peer_score_thresholds(config: &NetworkConfig) -> PeerScoreThresholds {
+    let (gossip_threshold, publish_threshold, graylist_threshold) = config.gossip.thresholds();
+    PeerScoreThresholds {
+        gossip_threshold,
+        publish_threshold,
+        graylist_threshold,
+        ..PeerScoreThresholds::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gossipsub_config() {
+        let defaults = gossipsub_config(&NetworkConfig::default());
+        assert_eq!(defaults.mesh_n(), 8);
+        assert_eq!(defaults.mesh_n_low(), 4);
+        assert_eq!(defaults.mesh_n_high(), 12);
+        assert_eq!(defaults.mesh_outbound_min(), 3);
+
+        let config = NetworkConfig {
+            gossip: GossipConfig {
+                heartbeat_interval: Some(500),
+                mesh_n: Some(6),
+                mesh_n_low: Some(2),
+                mesh_n_high: Some(9),
+                history_length: Some(10),
+                gossip_threshold: Some(-20.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        config.gossip.validate(config.bootstrapper).unwrap();
+        let custom = gossipsub_config(&config);
+        assert_eq!(custom.heartbeat_interval(), Duration::from_millis(500));
+        assert_eq!(custom.mesh_n(), 6);
+        assert_eq!(custom.mesh_n_low(), 2);
+        assert_eq!(custom.mesh_n_high(), 9);
+        assert_eq!(custom.mesh_outbound_min(), 2);
+        assert_eq!(custom.history_length(), 10);
+        assert_eq!(peer_score_thresholds(&config).gossip_threshold, -20.0);
+        assert_eq!(peer_score_thresholds(&config).publish_threshold, -50.0);
+    }
+
+    #[test]
+    fn test_gossip_config_validate() {
+        let gossip = GossipConfig {
+            mesh_n: Some(3),
+            mesh_n_low: Some(4),
+            ..Default::default()
+        };
+        assert!(gossip.validate(false).is_err());
+        // the bootstrap node defaults to empty meshes
+        let gossip = GossipConfig {
+            mesh_n_high: Some(4),
+            ..Default::default()
+        };
+        assert!(gossip.validate(true).is_ok());
+        let gossip = GossipConfig {
+            publish_threshold: Some(-5.0),
+            ..Default::default()
+        };
+        assert!(gossip.validate(false).is_err());
+    }
 }
@@ -4,10 +4,17 @@ pub mod config;
 mod connection;
 mod gossipsub;
 mod measurements;
+mod retrieval;
 pub mod service;
 mod transport;
 mod utils;
 
 pub use self::behaviour::ursa_agent;
+pub use self::codec::protocol::ExchangeEncoding;
 pub use self::config::*;
+pub use self::retrieval::RetrievalStrategy;
 pub use self::service::*;
+pub use self::utils::event_bus::{EventBus, EventSubscriber};
+pub use self::utils::fetch_diagnostics::{FetchDiagnostics, PeerFetchOutcome};
+pub use self::utils::keystore::load_or_generate_keypair;
+pub use self::utils::protocol_version::URSA_PROTOCOL_VERSION;
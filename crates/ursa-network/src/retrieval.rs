@@ -0,0 +1,181 @@
+use anyhow::{anyhow, Result};
+use db::Store;
+use futures::AsyncReadExt;
+use fvm_ipld_blockstore::Blockstore;
+use fvm_ipld_car::CarReader;
+use libipld::{Block, Cid, DefaultParams};
+use libp2p::PeerId;
+use std::sync::Arc;
+use tokio::sync::{mpsc::Sender, oneshot};
+use tracing::{info, warn};
+use ursa_store::UrsaStore;
+
+use crate::{
+    codec::protocol::{RequestType, ResponseType, UrsaExchangeRequest},
+    NetworkCommand,
+};
+
+/// A way of retrieving content, tried in order by [`NetworkCommand::Fetch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetrievalStrategy {
+    /// Serve the content from the local store.
+    Local,
+    /// Sync the dag from the connected peers with bitswap.
+    Bitswap,
+    /// Request the dag as a car file from a peer over the exchange protocol.
+    CarRequest(PeerId),
+    /// Download the dag as a car file from an http gateway, eg. https://ipfs.io
+    Gateway(String),
+}
+
+/// Try each strategy in order until one retrieves the dag under `cid`.
+///
+/// Returns the strategy that worked along with the dag encoded as a car file.
+pub(crate) async fn fetch<S>(
+    cid: Cid,
+    strategies: Vec<RetrievalStrategy>,
+    store: Arc<UrsaStore<S>>,
+    command_sender: Sender<NetworkCommand>,
+    max_car_size: u64,
+) -> Result<(RetrievalStrategy, Vec<u8>)>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    for strategy in strategies {
+        let result = match &strategy {
+            RetrievalStrategy::Local => store.car_bytes(&cid, max_car_size),
+            RetrievalStrategy::Bitswap => fetch_bitswap(cid, &command_sender)
+                .await
+                .and_then(|_| store.car_bytes(&cid, max_car_size)),
+            RetrievalStrategy::CarRequest(peer_id) => {
+                fetch_car_request(cid, *peer_id, &store, &command_sender).await
+            }
+            RetrievalStrategy::Gateway(url) => fetch_gateway(cid, url, &store, max_car_size).await,
+        };
+
+        match result {
+            Ok(data) => {
+                info!("[Fetch] - retrieved {cid} with {strategy:?}");
+                return Ok((strategy, data));
+            }
+            Err(e) => warn!("[Fetch] - failed to retrieve {cid} with {strategy:?}: {e}"),
+        }
+    }
+
+    Err(anyhow!(
+        "Failed to retrieve {cid} with any of the strategies"
+    ))
+}
+
+/// Sync the dag under `cid` with bitswap, failing unless every block of it was retrieved.
+async fn fetch_bitswap(cid: Cid, command_sender: &Sender<NetworkCommand>) -> Result<()> {
+    let (sender, receiver) = oneshot::channel();
+    let command = NetworkCommand::GetBitswap {
+        cid,
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    };
+    command_sender
+        .send(command)
+        .await
+        .map_err(|_| anyhow!("The network service is not running"))?;
+    let result = receiver
+        .await
+        .map_err(|e| anyhow!("The bitswap channel closed for {cid}: {e}"))??;
+    if !result.is_complete() {
+        return Err(anyhow!(
+            "Bitswap is missing {} blocks of {cid}",
+            result.missing.len()
+        ));
+    }
+    Ok(())
+}
+
+async fn fetch_car_request<S>(
+    cid: Cid,
+    peer_id: PeerId,
+    store: &UrsaStore<S>,
+    command_sender: &Sender<NetworkCommand>,
+) -> Result<Vec<u8>>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    command_sender
+        .send(NetworkCommand::SendRequest {
+            peer_id,
+            request: Box::new(UrsaExchangeRequest(RequestType::CarRequest(
+                cid.to_string(),
+            ))),
+            channel: sender,
+        })
+        .await
+        .map_err(|_| anyhow!("The network service is not running"))?;
+
+    match receiver.await??.0 {
+        ResponseType::CarResponse(car) => {
+            load_verified_car(cid, &car.data, store).await?;
+            Ok(car.data)
+        }
+        ResponseType::Error(e) => Err(anyhow!("{peer_id} failed to serve {cid}: {e}")),
+        response => Err(anyhow!("Unexpected response from {peer_id}: {response:?}")),
+    }
+}
+
+/// Download the car file of `cid` from a gateway, giving up once it is larger than
+/// `max_car_size` bytes.
+async fn fetch_gateway<S>(
+    cid: Cid,
+    url: &str,
+    store: &UrsaStore<S>,
+    max_car_size: u64,
+) -> Result<Vec<u8>>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    let response = surf::get(format!("{url}/ipfs/{cid}"))
+        .header("Accept", "application/vnd.ipld.car")
+        .await
+        .map_err(|e| anyhow!("Error getting content for cid {cid} from {url}: {e}"))?;
+    if !response.status().is_success() {
+        return Err(anyhow!(
+            "Error getting content for cid {cid} from {url}: {}",
+            response.status()
+        ));
+    }
+    let mut data = Vec::new();
+    // read one byte past the limit to tell a car of exactly `max_car_size` from a larger one
+    response
+        .take(max_car_size.saturating_add(1))
+        .read_to_end(&mut data)
+        .await
+        .map_err(|e| anyhow!("Error reading content for cid {cid} from {url}: {e}"))?;
+    if data.len() as u64 > max_car_size {
+        return Err(anyhow!(
+            "The car file of {cid} from {url} is larger than {max_car_size} bytes"
+        ));
+    }
+    load_verified_car(cid, &data, store).await?;
+    Ok(data)
+}
+
+/// Load a car file into the store once it is checked to be rooted at `cid` and each of its
+/// blocks to match its cid, so that nothing is written from an untrusted car file that fails.
+async fn load_verified_car<S>(cid: Cid, data: &[u8], store: &UrsaStore<S>) -> Result<()>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    let mut car = CarReader::new(data).await?;
+    if !car.header.roots.contains(&cid) {
+        return Err(anyhow!("The car file is not rooted at {cid}"));
+    }
+    let mut blocks = Vec::new();
+    while let Some(block) = car.next_block().await? {
+        let block = Block::<DefaultParams>::new(block.cid, block.data)
+            .map_err(|e| anyhow!("The car file of {cid} has an invalid block: {e}"))?;
+        blocks.push(block.into_inner());
+    }
+    store.put_many_keyed(blocks)
+}
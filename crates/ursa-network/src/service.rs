@@ -14,69 +14,201 @@
 use anyhow::{anyhow, Error, Result};
 use bytes::Bytes;
 use db::Store;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use futures_util::stream::StreamExt;
 use fvm_ipld_blockstore::Blockstore;
 use graphsync::{GraphSyncEvent, Request, RequestId as GraphSyncReqId};
 use ipld_traversal::{selector::RecursionLimit, Selector};
 use libipld::Cid;
 use libp2p::{
-    autonat::{Event as AutonatEvent, NatStatus},
+    autonat::{
+        Event as AutonatEvent, NatStatus, OutboundProbeError, OutboundProbeEvent, ResponseError,
+    },
+    bandwidth::BandwidthSinks,
+    core::ConnectedPoint,
+    dcutr::behaviour::Event as DcutrEvent,
     gossipsub::{
         error::{PublishError, SubscriptionError},
-        IdentTopic as Topic, MessageId, TopicHash,
+        IdentTopic as Topic, MessageAcceptance, MessageId, TopicHash,
     },
     identify::Event as IdentifyEvent,
     identity::Keypair,
-    kad::{BootstrapOk, KademliaEvent, QueryResult},
+    kad::{
+        record::Key as RecordKey, BootstrapOk, GetProvidersError, GetProvidersOk, GetRecordOk,
+        KademliaEvent, QueryId as KadQueryId, QueryResult, Quorum, Record,
+    },
     mdns::Event as MdnsEvent,
     multiaddr::Protocol,
     ping::Event as PingEvent,
-    relay::v2::client::Client as RelayClient,
-    request_response::{RequestId, RequestResponseEvent, RequestResponseMessage, ResponseChannel},
+    relay::v2::client::{Client as RelayClient, Event as RelayClientEvent},
+    relay::v2::relay::Event as RelayServerEvent,
+    request_response::{
+        OutboundFailure, RequestId, RequestResponseEvent, RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{dial_opts::DialOpts, AddressScore, ConnectionLimits, SwarmBuilder, SwarmEvent},
     swarm::{ConnectionHandler, IntoConnectionHandler, NetworkBehaviour},
-    swarm::{ConnectionLimits, SwarmBuilder, SwarmEvent},
     Multiaddr, PeerId, Swarm,
 };
-use libp2p_bitswap::{BitswapEvent, QueryId};
+use libp2p_bitswap::{BitswapConfig, BitswapEvent, BitswapStore, QueryId};
 use lru::LruCache;
+use metrics::increment_counter;
 use rand::prelude::SliceRandom;
+use serde::Serialize;
 use std::{
     collections::{HashMap, HashSet},
     fmt::Debug,
+    mem::{self, Discriminant},
     num::{NonZeroU8, NonZeroUsize},
     sync::Arc,
     time::Duration,
 };
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::{
     select,
     sync::{
-        mpsc::{unbounded_channel, Sender},
+        mpsc::{
+            channel, error::TrySendError, unbounded_channel, Receiver, Sender, UnboundedReceiver,
+            UnboundedSender,
+        },
         oneshot,
     },
-    time::{sleep, Instant},
+    time::{interval, interval_at, sleep, Instant},
+};
+use tracing::{debug, error, field, info, info_span, trace, warn, Span};
+use ursa_metrics::{
+    Bandwidth as BandwidthMetrics, GossipMeshSize, GossipSubscriptionChurn, MetricEvent, Recorder,
+    StoreSize,
 };
-use tracing::{debug, error, info, trace, warn};
-use ursa_metrics::Recorder;
-use ursa_store::UrsaStore;
+use ursa_store::{BitswapStorage, DagLimits, GcStats, StoreLatency, UrsaStore};
 
 use crate::behaviour::KAD_PROTOCOL;
-use crate::codec::protocol::{RequestType, ResponseType};
+use crate::codec::protocol::{CarResponse, RequestType, ResponseType, MAX_ROOTS_PER_PAGE};
 use crate::connection::Manager;
 use crate::measurements::MeasurementManager;
+use crate::retrieval::{self, RetrievalStrategy};
 use crate::transport::build_transport;
 use crate::utils::cache_summary::CacheSummary;
+use crate::utils::event_bus::EventBus;
+use crate::utils::fetch_diagnostics::{FetchDiagnostics, FetchPeers};
+use crate::utils::fetch_timing::{FetchTimer, FetchTiming, StoreTimings, TimedBitswapStorage};
+use crate::utils::parallel_sync::ParallelSync;
+use crate::utils::peer_score::{PeerScore, PeerScores, DISCONNECT_THRESHOLD};
+use crate::utils::peerstore::{load_address_book, save_address_book, AddressBook};
+use crate::utils::protocol_version::{is_compatible_version, URSA_PROTOCOL_VERSION};
+use crate::utils::provider_discovery::{provider_key, ProviderDiscovery};
+use crate::utils::rate_limit::RateLimiter;
+use crate::utils::redial::BootstrapRedials;
+use crate::utils::relay_reservation::RelayReservation;
 use crate::{
     behaviour::{Behaviour, BehaviourEvent},
     codec::protocol::{UrsaExchangeRequest, UrsaExchangeResponse},
-    config::NetworkConfig,
+    config::{GossipAuthenticity, NetworkConfig},
 };
 
 pub const URSA_GLOBAL: &str = "/ursa/global";
+/// Topic for content announcements, each message carrying the bytes of a cid.
+pub const URSA_CONTENT: &str = "/ursa/content";
 pub const MESSAGE_PROTOCOL: &[u8] = b"/ursa/message/0.0.1";
+/// How often the disconnected bootstrap nodes are checked for a redial.
+const BOOTSTRAP_REDIAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often relay reservations are checked for renewal.
+const RELAY_RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Block requests kept in flight by syncs with a byte budget and no parallelism set.
+const BUDGETED_SYNC_PARALLEL_BLOCKS: usize = 8;
+/// How often the size of the gossipsub meshes is recorded.
+const GOSSIP_MESH_METRICS_INTERVAL: Duration = Duration::from_secs(30);
+/// How often the number of blocks and bytes in the store is recorded.
+const STORE_SIZE_METRICS_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the bytes sent and received by the node are recorded.
+const BANDWIDTH_METRICS_INTERVAL: Duration = Duration::from_secs(10);
+/// Number of peers whose scores are kept.
+const MAX_PEER_SCORES: usize = 1024;
+/// Number of peers and request types whose exchange request rate is tracked.
+const MAX_RATE_LIMITED_REQUESTERS: usize = 1024;
+
+/// The gossipsub `topic` namespaced to the network named `network_name`.
+pub fn network_topic(network_name: &str, topic: &str) -> Topic {
+    Topic::new(format!("{topic}/{network_name}"))
+}
 
 type BlockOneShotSender<T> = oneshot::Sender<Result<T, Error>>;
+
+/// Outcome of a bitswap fetch of a dag whose root was found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncResult {
+    /// Blocks of the dag added to the local store by the fetch.
+    pub fetched: usize,
+    /// Blocks of the dag no peer served, to fetch again. Their links are unknown, so the
+    /// blocks under them are not listed.
+    pub missing: Vec<Cid>,
+}
+
+impl SyncResult {
+    /// Whether the whole dag is in the local store.
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+}
+
+/// What waits on a count of the blocks of a local dag made off the event loop.
+enum DagCount {
+    /// A fetch of a dag whose root is local, answered right away if the whole dag is.
+    Fetch(NetworkCommand),
+    /// A finished fetch of a dag, whose callers are resolved with the blocks it added.
+    Resolve,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum NetworkError {
+    /// A query was rejected because too many cids are already being fetched.
+    #[error("Too many pending queries, at most {0} cids can be fetched at once")]
+    TooManyPendingQueries(usize),
+    /// A query was cancelled before it completed.
+    #[error("The query was cancelled")]
+    Cancelled,
+    /// A fetch was aborted because it pulled more bytes than its budget.
+    #[error("The fetch was aborted after fetching {fetched} bytes, over the budget of {max_bytes} bytes")]
+    BudgetExceeded { fetched: u64, max_bytes: u64 },
+    /// None of the peers asked had the block, with the reason of each peer.
+    #[error("The requested block with cid {} is not found with any peers", .0.cid)]
+    NotFound(FetchDiagnostics),
+    /// The block was not found by a recent fetch, and is not asked for again until
+    /// `not_found_cache_ttl` expires or a new peer connects.
+    #[error("The requested block with cid {0} was recently not found with any peers")]
+    RecentlyNotFound(Cid),
+    /// A record was not stored in the DHT because its value is too large.
+    #[error("The record value of {size} bytes is over the maximum of {max_size} bytes")]
+    RecordTooLarge { size: usize, max_size: usize },
+    /// A request-response request got no response.
+    #[error("The request to {peer} failed: {failure:?}")]
+    RequestFailed {
+        peer: PeerId,
+        failure: RequestFailure,
+    },
+}
+
+/// Why a request-response request got no response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFailure {
+    /// The peer could not be dialed.
+    DialFailure,
+    /// The peer did not respond in time.
+    Timeout,
+    /// The connection closed before the peer responded.
+    ConnectionClosed,
+    /// The peer does not support the exchange protocol.
+    UnsupportedProtocols,
+}
+
+impl From<&OutboundFailure> for RequestFailure {
+    fn from(failure: &OutboundFailure) -> Self {
+        match failure {
+            OutboundFailure::DialFailure => RequestFailure::DialFailure,
+            OutboundFailure::Timeout => RequestFailure::Timeout,
+            OutboundFailure::ConnectionClosed => RequestFailure::ConnectionClosed,
+            OutboundFailure::UnsupportedProtocols => RequestFailure::UnsupportedProtocols,
+        }
+    }
+}
 type SwarmEventType<S> = SwarmEvent<
 <Behaviour<S> as NetworkBehaviour>::OutEvent,
 <
@@ -87,6 +219,88 @@ type SwarmEventType<S> = SwarmEvent<
     >::Error
 >;
 
+/// NAT status of the node, as reported by autonat.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NatState {
+    /// The node is publicly reachable on the address.
+    Public(Multiaddr),
+    Private,
+    Unknown,
+}
+
+impl From<NatStatus> for NatState {
+    fn from(status: NatStatus) -> Self {
+        match status {
+            NatStatus::Public(addr) => NatState::Public(addr),
+            NatStatus::Private => NatState::Private,
+            NatStatus::Unknown => NatState::Unknown,
+        }
+    }
+}
+
+/// A dial the swarm has not completed yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PendingDial {
+    pub peer_id: PeerId,
+    /// Addresses of the peer known to the behaviour when the dial started.
+    pub addresses: Vec<Multiaddr>,
+    /// How long the dial has been pending.
+    pub pending_for: Duration,
+}
+
+/// Aggregate state of the swarm.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NetworkInfo {
+    /// Number of connected peers.
+    pub num_peers: usize,
+    /// Number of connections, pending or established.
+    pub num_connections: u32,
+    pub num_pending_incoming: u32,
+    pub num_pending_outgoing: u32,
+    pub num_established_incoming: u32,
+    pub num_established_outgoing: u32,
+    pub nat_status: NatState,
+}
+
+/// Readiness of the node, eg. for the probes of an orchestrator.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Health {
+    /// Whether the node listens on at least one address.
+    pub listening: bool,
+    /// Number of connected peers.
+    pub num_peers: usize,
+    /// Whether the node is connected to one of its bootstrap nodes.
+    pub bootstrap_connected: bool,
+    pub nat_status: NatState,
+    /// Time since the last gossipsub event, `None` before the first one. Gossipsub doesn't
+    /// report its heartbeats, so this is the closest sign of the gossip being alive.
+    pub since_last_gossip: Option<Duration>,
+    /// Whether the node listens and is connected to a bootstrap node. Bootstrap nodes only
+    /// need to listen.
+    pub ready: bool,
+}
+
+/// Bytes sent and received by the node over all its connections since it started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Bandwidth {
+    pub inbound: u64,
+    pub outbound: u64,
+}
+
+/// Gossip activity on a subscribed topic.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GossipTopicStats {
+    /// Number of peers in the mesh of the topic.
+    pub mesh_peers: usize,
+    /// Number of known peers subscribed to the topic, whether they are in the mesh or not.
+    pub all_peers: usize,
+    /// Number of messages received on the topic and passed on to the node.
+    pub messages_received: u64,
+    /// Number of messages published on the topic by the node.
+    pub messages_published: u64,
+}
+
 #[derive(Debug)]
 pub enum GossipsubMessage {
     /// A subscribe message.
@@ -109,7 +323,7 @@ pub enum GossipsubMessage {
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum GossipsubEvent {
     /// A message has been received.
     Message {
@@ -137,14 +351,46 @@ pub enum GossipsubEvent {
     },
 }
 
+/// A step of a bitswap fetch traced with [`NetworkCommand::TraceBitswap`].
+///
+/// Bitswap doesn't report which peer answered a want, so blocks are not attributed to peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitswapTrace {
+    /// A want for `block` was sent to `peers`.
+    WantSent { block: Cid, peers: Vec<PeerId> },
+    /// A block of the query landed in the store, `missing` blocks are still wanted.
+    Progress { query_id: QueryId, missing: usize },
+    /// A block requested on its own was received.
+    BlockReceived { block: Cid },
+    /// None of the peers asked had a block requested on its own.
+    BlockNotFound { block: Cid },
+    /// The fetch completed.
+    Complete { found: bool },
+}
+
 /// [network]'s events
 /// Requests and failure events emitted by the `NetworkBehaviour`.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum NetworkEvent {
     /// An event trigger when remote peer connects.
     PeerConnected(PeerId),
     /// An event trigger when remote peer disconnects.
     PeerDisconnected(PeerId),
+    /// A connection to a peer was opened, whether dialed or accepted.
+    ConnectionEstablished {
+        peer: PeerId,
+        endpoint: ConnectedPoint,
+        /// Number of connections to the peer, including this one.
+        num_established: u32,
+    },
+    /// A connection to a peer was closed.
+    ConnectionClosed {
+        peer: PeerId,
+        /// The error that closed the connection, if it was not closed gracefully.
+        cause: Option<String>,
+        /// Number of connections still open to the peer.
+        num_established: u32,
+    },
     /// A Gossip message request was received from a peer.
     Gossipsub(GossipsubEvent),
     /// A message request was received from a peer.
@@ -153,15 +399,83 @@ pub enum NetworkEvent {
     BitswapHave { cid: Cid, query_id: QueryId },
     /// A bitswap WANT event generated by the service.
     BitswapWant { cid: Cid, query_id: QueryId },
+    /// A step of a fetch traced with [`NetworkCommand::TraceBitswap`].
+    BitswapTrace { cid: Cid, trace: BitswapTrace },
     /// New content has been pulled successfully from a peer.
     PullComplete { cid: Cid, size: u64 },
+    /// A relay reservation is close to expiring without being renewed, a renewal was requested.
+    RelayReservationExpiring {
+        relay_peer_id: PeerId,
+        expires_in: Duration,
+    },
+    /// A relay renewed the node's reservation.
+    RelayReservationRenewed { relay_peer_id: PeerId },
+    /// A relay accepted the first request of a reservation.
+    RelayReservationOpened { relay_peer_id: PeerId },
+    /// A reservation on a relay was dropped, or closed or could not be made on the relay the
+    /// node listens on, which makes the node move on to the next bootstrap relay.
+    RelayReservationClosed { relay_peer_id: PeerId },
+    /// The relay server of the node accepted or renewed the reservation of a peer.
+    RelayServerReservationAccepted { peer_id: PeerId, renewed: bool },
+    /// The relay server of the node denied a reservation, usually because of its limits.
+    RelayServerReservationDenied { peer_id: PeerId },
+    /// The relay server of the node started routing a circuit between two peers.
+    RelayServerCircuitOpened {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+    },
+    /// A circuit routed by the relay server of the node closed.
+    RelayServerCircuitClosed {
+        src_peer_id: PeerId,
+        dst_peer_id: PeerId,
+    },
+    /// A hole punch was attempted to replace the relayed connection with a peer by a direct one.
+    DirectConnectionUpgradeAttempted { peer_id: PeerId },
+    /// The hole punch succeeded, the node is directly connected to the peer.
+    DirectConnectionUpgraded { peer_id: PeerId },
+    /// Autonat changed the NAT status of the node.
+    NatStatusChanged { old: NatState, new: NatState },
 }
 
 #[derive(Debug)]
 pub enum NetworkCommand {
+    /// Fetch the dag under `cid` with bitswap. Resolves right away, without a trace, if the
+    /// whole dag is already in the local store.
     GetBitswap {
         cid: Cid,
-        sender: BlockOneShotSender<()>,
+        /// Maximum number of block requests kept in flight while syncing the dag.
+        /// `None` syncs the whole dag with a single bitswap query.
+        max_parallel_blocks: Option<NonZeroUsize>,
+        /// Receives a timing breakdown of the fetch once it completes.
+        trace: Option<oneshot::Sender<FetchTiming>>,
+        /// Abort the fetch with [`NetworkError::BudgetExceeded`] once it pulled more bytes.
+        /// Callers joining a fetch already in flight share its budget.
+        max_bytes: Option<u64>,
+        /// Resolved with the blocks fetched and the ones still missing once the fetch
+        /// completes, failed if not even the root was found.
+        sender: BlockOneShotSender<SyncResult>,
+    },
+
+    /// Log every step of the bitswap fetches of `cid` and emit them as
+    /// [`NetworkEvent::BitswapTrace`], or stop doing so.
+    TraceBitswap {
+        cid: Cid,
+        enabled: bool,
+        sender: oneshot::Sender<()>,
+    },
+
+    /// Like [`NetworkCommand::GetBitswap`], but if the connected peers can't serve the block,
+    /// its providers are looked up in the DHT, dialed, and the block is requested from them.
+    GetBitswapWithDiscovery {
+        cid: Cid,
+        sender: BlockOneShotSender<SyncResult>,
+    },
+
+    /// The data of the single block `cid`, read from the local store or fetched with bitswap.
+    /// Unlike [`NetworkCommand::GetBitswap`], the blocks it links to are not fetched.
+    GetBlock {
+        cid: Cid,
+        sender: BlockOneShotSender<Vec<u8>>,
     },
 
     Put {
@@ -173,10 +487,160 @@ pub enum NetworkCommand {
         sender: oneshot::Sender<HashSet<PeerId>>,
     },
 
+    /// Whether the node has a connection to `peer_id`.
+    IsConnected {
+        peer_id: PeerId,
+        sender: oneshot::Sender<bool>,
+    },
+
     GetListenerAddresses {
         sender: oneshot::Sender<Vec<Multiaddr>>,
     },
 
+    /// The peers in the kademlia routing table with their known addresses.
+    ExportPeers {
+        sender: oneshot::Sender<AddressBook>,
+    },
+
+    /// Fetch the dag under `cid`, trying each retrieval strategy in order until one succeeds.
+    /// Resolves to the strategy that worked and the dag encoded as a car file.
+    Fetch {
+        cid: Cid,
+        strategies: Vec<RetrievalStrategy>,
+        sender: oneshot::Sender<Result<(RetrievalStrategy, Vec<u8>)>>,
+    },
+
+    /// Check whether the full dag under `cid` is in the local store, without fetching
+    /// anything from the network.
+    HasFullDag {
+        cid: Cid,
+        sender: oneshot::Sender<Result<bool>>,
+    },
+
+    /// Re-announce the node on the local network with an mDNS query.
+    MdnsAnnounce { sender: oneshot::Sender<Result<()>> },
+
+    /// Get the time left on each reservation accepted by a relay.
+    GetRelayReservations {
+        sender: oneshot::Sender<HashMap<PeerId, Duration>>,
+    },
+
+    /// Stop listening on a relay, eg. to rotate away from a misbehaving one. Dropping the
+    /// bootstrap relay the node fell back to moves it on to the next one.
+    DropRelayReservation {
+        relay_peer_id: PeerId,
+        sender: oneshot::Sender<Result<()>>,
+    },
+
+    /// Bootstrap kademlia again through the bootstrap nodes.
+    Bootstrap { sender: oneshot::Sender<Result<()>> },
+
+    /// Keep `peer_id` connected when there are more peers than the connection limits allow.
+    ProtectPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+
+    /// Let `peer_id` be disconnected again when there are too many peers.
+    UnprotectPeer {
+        peer_id: PeerId,
+        sender: oneshot::Sender<()>,
+    },
+
+    /// Cancel every bitswap query in flight, failing the callers waiting on them.
+    /// Resolves to the number of queries cancelled.
+    CancelAllQueries { sender: oneshot::Sender<usize> },
+
+    /// Cancel the bitswap fetch of `cid`, failing the callers waiting on it.
+    /// Resolves to whether a fetch of `cid` was in flight.
+    CancelBitswap {
+        cid: Cid,
+        sender: oneshot::Sender<bool>,
+    },
+
+    /// Move the node to the network named `network_name`, leaving the topics of the current
+    /// network for the ones of the new network, and bootstrap again.
+    SwitchNetwork {
+        network_name: String,
+        sender: oneshot::Sender<Result<()>>,
+    },
+
+    /// Announce the node as a provider of `cid` in the DHT, without replicating it.
+    /// Providing resumes on startup like for [`NetworkCommand::Put`].
+    StartProviding {
+        cid: Cid,
+        sender: oneshot::Sender<Result<()>>,
+    },
+
+    /// Look up the providers of `cid` in the DHT.
+    FindProviders {
+        cid: Cid,
+        sender: oneshot::Sender<Result<HashSet<PeerId>>>,
+    },
+
+    /// Store `value` under `key` in the DHT, on the closest peers to the key.
+    PutRecord {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        sender: oneshot::Sender<Result<()>>,
+    },
+
+    /// Get the value stored under `key` in the DHT.
+    GetRecord {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Result<Vec<u8>>>,
+    },
+
+    /// Get the cids the node was asked to provide, which are provided again on startup.
+    GetProvideIntents {
+        sender: oneshot::Sender<Result<Vec<Cid>>>,
+    },
+
+    /// Delete the blocks that can't be reached from a pinned block, a provided or published
+    /// cid, or a dag being synced or read. Answered once the collection finishes.
+    RunGc {
+        sender: oneshot::Sender<Result<GcStats>>,
+    },
+
+    /// Get the latency percentiles of the recent store operations.
+    StoreLatency {
+        sender: oneshot::Sender<StoreLatency>,
+    },
+
+    /// Get the number of connected peers and connections, and the NAT status of the node.
+    GetNetworkInfo {
+        sender: oneshot::Sender<NetworkInfo>,
+    },
+
+    /// Get whether the node is ready to serve, along with what it is based on.
+    GetHealth { sender: oneshot::Sender<Health> },
+
+    /// Get the bytes sent and received by the node since it started.
+    GetBandwidth { sender: oneshot::Sender<Bandwidth> },
+
+    /// Get the mesh size, peer count and message counts of each subscribed gossipsub topic.
+    GetGossipStats {
+        sender: oneshot::Sender<HashMap<String, GossipTopicStats>>,
+    },
+
+    /// Get the dials the swarm is currently trying to complete.
+    DialQueue {
+        sender: oneshot::Sender<Vec<PendingDial>>,
+    },
+
+    /// Get the number of distinct cids with pending bitswap queries, of
+    /// [`NetworkCommand::GetBitswap`] and [`NetworkCommand::GetBlock`].
+    PendingQueryCount { sender: oneshot::Sender<usize> },
+
+    /// Stop the service once the commands already queued are handled.
+    /// Pending queries and requests are failed, and `start` returns.
+    Shutdown { sender: oneshot::Sender<()> },
+
+    /// Get the configuration the network is currently running with.
+    GetConfig {
+        sender: oneshot::Sender<NetworkConfig>,
+    },
+
     SendRequest {
         peer_id: PeerId,
         request: Box<UrsaExchangeRequest>,
@@ -198,15 +662,61 @@ where
     /// The main libp2p swarm emitting events.
     swarm: Swarm<Behaviour<S>>,
     /// Handles outbound messages to peers.
-    command_sender: UnboundedSender<NetworkCommand>,
+    command_sender: Sender<NetworkCommand>,
     /// Handles inbound messages from peers.
-    command_receiver: UnboundedReceiver<NetworkCommand>,
+    command_receiver: Receiver<NetworkCommand>,
     /// Handles events emitted by the ursa network.
     event_sender: Sender<NetworkEvent>,
+    /// Broadcast of the events to the subscribers of [`UrsaService::event_bus`].
+    event_bus: EventBus,
     /// Bitswap pending queries.
     bitswap_queries: FnvHashMap<QueryId, Cid>,
+    /// Bitswap queries of [`NetworkCommand::GetBlock`], with the block they fetch.
+    block_gets: FnvHashMap<QueryId, Cid>,
+    /// Callers of [`NetworkCommand::GetBlock`] waiting on each block, answered together once
+    /// its query completes.
+    block_get_channels: FnvHashMap<Cid, Vec<BlockOneShotSender<Vec<u8>>>>,
+    /// Bitswap sync query in flight for each cid, shared by every caller waiting on the cid.
+    active_queries: FnvHashMap<Cid, QueryId>,
+    /// Peers of the bitswap queries in flight, to explain why they fail.
+    fetch_peers: FnvHashMap<QueryId, FetchPeers>,
+    /// Bitswap syncs with a bounded number of block requests in flight, keyed by root cid.
+    parallel_syncs: FnvHashMap<Cid, ParallelSync>,
+    /// Fetches falling back to the providers found in the DHT, keyed by cid.
+    provider_discoveries: FnvHashMap<Cid, ProviderDiscovery>,
+    /// Provider lookups of [`NetworkCommand::FindProviders`], with the providers found so far.
+    provider_lookups:
+        FnvHashMap<KadQueryId, (HashSet<PeerId>, oneshot::Sender<Result<HashSet<PeerId>>>)>,
+    /// Queries storing a record in the DHT.
+    record_puts: FnvHashMap<KadQueryId, oneshot::Sender<Result<()>>>,
+    /// Queries getting a record from the DHT.
+    record_gets: FnvHashMap<KadQueryId, oneshot::Sender<Result<Vec<u8>>>>,
+    /// Cids whose bitswap fetches are traced.
+    traced_cids: FnvHashSet<Cid>,
+    /// Time bitswap spent in the store.
+    store_timings: Arc<StoreTimings>,
+    /// Bytes sent and received over the transport.
+    bandwidth: Arc<BandwidthSinks>,
+    /// Timers of traced fetches, keyed by root cid.
+    fetch_timers: FnvHashMap<Cid, FetchTimer>,
+    /// Cids no peer could serve, and when they were not found.
+    not_found: FnvHashMap<Cid, Instant>,
     /// hashmap for keeping track of rpc response channels.
-    response_channels: FnvHashMap<Cid, Vec<BlockOneShotSender<()>>>,
+    response_channels: FnvHashMap<Cid, Vec<BlockOneShotSender<SyncResult>>>,
+    /// Blocks of each dag being fetched that were in the local store when the fetch started.
+    sync_baselines: FnvHashMap<Cid, usize>,
+    /// Dags whose callers are resolved once they are counted off the event loop.
+    resolving_dags: FnvHashSet<Cid>,
+    /// Car responses built off the event loop, sent back to be answered through the swarm.
+    car_response_sender: UnboundedSender<(ResponseChannel<UrsaExchangeResponse>, ResponseType)>,
+    /// Receives the car responses built off the event loop.
+    car_response_receiver: UnboundedReceiver<(ResponseChannel<UrsaExchangeResponse>, ResponseType)>,
+    /// Counts of local dags made off the event loop, sent back with what waits on them.
+    dag_count_sender: UnboundedSender<(Cid, DagCount, Result<(usize, Vec<Cid>)>)>,
+    /// Receives the counts of local dags made off the event loop.
+    dag_count_receiver: UnboundedReceiver<(Cid, DagCount, Result<(usize, Vec<Cid>)>)>,
+    /// Blocks in the local store of the dags whose fetches are handled again once counted.
+    counted_dags: FnvHashMap<Cid, usize>,
     /// Pending requests.
     _pending_requests: HashMap<RequestId, ResponseChannel<UrsaExchangeResponse>>,
     /// Pending responses.
@@ -227,6 +737,38 @@ where
     pub public_addr: Option<Multiaddr>,
     /// Graphsync pending requests.
     graphsync_pending: HashMap<GraphSyncReqId, Cid>,
+    /// Messages received and published on each gossipsub topic.
+    gossip_message_counts: HashMap<TopicHash, GossipTopicStats>,
+    /// Disconnect peers that don't support gossipsub.
+    require_gossipsub: bool,
+    /// The configuration the network is currently running with.
+    config: NetworkConfig,
+    /// Whether a listen address on a local network interface expired since the last mDNS announce.
+    lan_addr_expired: bool,
+    /// Callers waiting on the service to shut down.
+    shutdown_senders: Vec<oneshot::Sender<()>>,
+    /// Reservations on relays, keyed by relay peer id.
+    relay_reservations: HashMap<PeerId, RelayReservation>,
+    /// Dials in progress, with the addresses known for the peer and when they started.
+    pending_dials: HashMap<PeerId, (Vec<Multiaddr>, Instant)>,
+    /// Autonat probes that confirmed a private NAT status since it was reported, while
+    /// waiting for `autonat_confidence` of them before listening on a relay.
+    private_nat_probes: Option<usize>,
+    /// Behaviour of the recently seen peers, used to deprioritize and disconnect them.
+    peer_scores: PeerScores,
+    /// Rate of the exchange requests, keyed by peer and request type.
+    request_limiter: RateLimiter<(PeerId, Discriminant<RequestType>)>,
+    /// Peers advertising a protocol version incompatible with the node's, excluded from
+    /// request-response.
+    incompatible_peers: HashSet<PeerId>,
+    /// Bootstrap nodes left to try as a relay, in random order, tried from the back.
+    relay_candidates: Vec<Multiaddr>,
+    /// The bootstrap relay the node listens on, or is requesting a reservation from.
+    active_relay: Option<PeerId>,
+    /// When the last gossipsub event was handled.
+    last_gossip: Option<Instant>,
+    /// Bootstrap nodes that disconnected, redialed until they are connected again.
+    bootstrap_redials: BootstrapRedials,
 }
 
 impl<S> UrsaService<S>
@@ -235,8 +777,8 @@ where
 {
     /// Init a new [`UrsaService`] based on [`NetworkConfig`]
     ///
-    /// For ursa `keypair` we use ed25519 either
-    /// checking for a local store or creating a new keypair.
+    /// The ursa `keypair` is provided by the caller, see [`crate::load_or_generate_keypair`]
+    /// to keep the same identity across restarts.
     ///
     /// For ursa `transport` we build a default QUIC layer and
     /// fail over to tcp.
@@ -244,7 +786,8 @@ where
     /// For ursa behaviour we use [`Behaviour`].
     ///
     /// We construct a [`Swarm`] with [`UrsaTransport`] and [`Behaviour`]
-    /// listening on [`NetworkConfig`] `swarm_addr`.
+    /// listening on [`NetworkConfig`] `swarm_addrs`. Addresses that fail to bind are skipped,
+    /// as long as the node listens on one of them.
     ///
     pub fn new(
         keypair: Keypair,
@@ -252,6 +795,17 @@ where
         store: Arc<UrsaStore<S>>,
         event_sender: Sender<NetworkEvent>,
     ) -> Result<Self> {
+        config.connection_limits.validate()?;
+        config.gossip.validate(config.bootstrapper)?;
+        config.validate_mdns()?;
+        for warning in config.validate_keep_alive()? {
+            warn!("Unusual keep-alive configuration: {warning}");
+        }
+        config.validate_relay()?;
+        config.validate_exchange_rate()?;
+        config.validate_transports()?;
+        config.validate_buffers()?;
+        config.validate_bitswap()?;
         let local_peer_id = PeerId::from(keypair.public());
 
         let (relay_transport, relay_client) = if config.relay_client {
@@ -266,22 +820,20 @@ where
             (None, None)
         };
 
-        let transport = build_transport(&keypair, config, relay_transport);
+        let (transport, bandwidth) = build_transport(&keypair, config, relay_transport)?;
         let mut peers = Manager::new();
+        peers.set_local_region(config.region.clone());
+        let store_timings = Arc::new(StoreTimings::default());
         let behaviour = Behaviour::new(
             &keypair,
             config,
             store.as_ref().clone(),
             relay_client,
             &mut peers,
+            store_timings.clone(),
         );
 
-        let limits = ConnectionLimits::default()
-            .with_max_pending_incoming(Some(2 << 9))
-            .with_max_pending_outgoing(Some(2 << 9))
-            .with_max_established_incoming(Some(2 << 9))
-            .with_max_established_outgoing(Some(2 << 9))
-            .with_max_established_per_peer(Some(8));
+        let limits = ConnectionLimits::from(&config.connection_limits);
 
         let mut swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id)
             .notify_handler_buffer_size(NonZeroUsize::new(2 << 7).unwrap())
@@ -290,23 +842,56 @@ where
             .connection_limits(limits)
             .build();
 
+        // connections with banned peers are closed as soon as they are established
+        for peer_id in &config.deny_peers {
+            swarm.ban_peer_id(*peer_id);
+        }
+
+        if let Some(path) = &config.peerstore_path {
+            match load_address_book(path) {
+                Ok(address_book) => {
+                    info!(
+                        "Imported {} peers from {}",
+                        address_book.len(),
+                        path.display()
+                    );
+                    for (peer_id, addrs) in address_book {
+                        for addr in addrs {
+                            swarm.behaviour_mut().add_address(&peer_id, addr);
+                        }
+                    }
+                }
+                Err(e) => warn!("Failed to import the known peers: {e:?}"),
+            }
+        }
+
         for to_dial in &config.bootstrap_nodes {
             swarm.dial(to_dial.clone())?;
         }
 
-        for addr in &config.swarm_addrs {
-            Swarm::listen_on(&mut swarm, addr.clone())
-                .map_err(|err| anyhow!("{}", err))
-                .unwrap();
+        let listen_addrs = config.listen_addrs();
+        let mut listening = 0;
+        for addr in &listen_addrs {
+            match swarm.listen_on(addr.clone()) {
+                Ok(_) => listening += 1,
+                Err(err) => warn!("Failed to listen on {addr}: {err}"),
+            }
+        }
+        if listening == 0 && !listen_addrs.is_empty() {
+            return Err(anyhow!("Failed to listen on any of {listen_addrs:?}"));
         }
 
-        // subscribe to topic
-        let topic = Topic::new(URSA_GLOBAL);
-        if let Err(error) = swarm.behaviour_mut().subscribe(&topic) {
-            warn!("Failed to subscribe to topic: {}", error);
+        // subscribe to topics
+        for topic in [URSA_GLOBAL, URSA_CONTENT] {
+            let topic = network_topic(&config.network_name, topic);
+            if let Err(error) = swarm.behaviour_mut().subscribe(&topic) {
+                warn!("Failed to subscribe to topic {topic}: {error}");
+            }
         }
 
-        let (command_sender, command_receiver) = unbounded_channel();
+        let (command_sender, command_receiver) = channel(config.command_buffer);
+        let (car_response_sender, car_response_receiver) = unbounded_channel();
+        let (dag_count_sender, dag_count_receiver) = unbounded_channel();
 
         let max_cache_summaries = NonZeroUsize::new(config.max_cache_summaries).unwrap();
         Ok(UrsaService {
@@ -315,8 +900,30 @@ where
             command_sender,
             command_receiver,
             event_sender,
+            event_bus: EventBus::new(config.event_buffer),
             response_channels: Default::default(),
+            sync_baselines: Default::default(),
+            resolving_dags: Default::default(),
             bitswap_queries: Default::default(),
+            block_gets: Default::default(),
+            block_get_channels: Default::default(),
+            not_found: Default::default(),
+            active_queries: Default::default(),
+            fetch_peers: Default::default(),
+            parallel_syncs: Default::default(),
+            provider_discoveries: Default::default(),
+            provider_lookups: Default::default(),
+            record_puts: Default::default(),
+            record_gets: Default::default(),
+            traced_cids: Default::default(),
+            store_timings,
+            bandwidth,
+            fetch_timers: Default::default(),
+            car_response_sender,
+            car_response_receiver,
+            dag_count_sender,
+            dag_count_receiver,
+            counted_dags: Default::default(),
             _pending_requests: HashMap::default(),
             pending_responses: HashMap::default(),
             peers,
@@ -327,24 +934,158 @@ where
             kad_walk_interval: config.kad_walk_interval,
             public_addr: None,
             graphsync_pending: HashMap::default(),
+            gossip_message_counts: HashMap::default(),
+            require_gossipsub: config.require_gossipsub,
+            config: config.clone(),
+            lan_addr_expired: false,
+            shutdown_senders: Vec::new(),
+            relay_reservations: HashMap::default(),
+            pending_dials: HashMap::default(),
+            private_nat_probes: None,
+            peer_scores: PeerScores::new(NonZeroUsize::new(MAX_PEER_SCORES).unwrap()),
+            request_limiter: RateLimiter::new(
+                config.exchange_requests_per_sec,
+                config.exchange_burst,
+                NonZeroUsize::new(MAX_RATE_LIMITED_REQUESTERS).unwrap(),
+            ),
+            incompatible_peers: HashSet::new(),
+            relay_candidates: Vec::new(),
+            active_relay: None,
+            last_gossip: None,
+            bootstrap_redials: BootstrapRedials::new(Duration::from_secs(
+                config.bootstrap_redial_max_backoff,
+            )),
         })
     }
 
+    /// Leave the topics of the current network for the ones of `network_name`, then bootstrap.
+    fn switch_network(&mut self, network_name: String) -> Result<()> {
+        if network_name == self.config.network_name {
+            return Ok(());
+        }
+        info!(
+            "Switching from the {} network to {network_name}",
+            self.config.network_name
+        );
+
+        for topic in [URSA_GLOBAL, URSA_CONTENT] {
+            let old_topic = network_topic(&self.config.network_name, topic);
+            self.swarm.behaviour_mut().unsubscribe(&old_topic)?;
+        }
+        self.config.network_name = network_name;
+        for topic in [URSA_GLOBAL, URSA_CONTENT] {
+            self.swarm
+                .behaviour_mut()
+                .subscribe(&network_topic(&self.config.network_name, topic))?;
+        }
+
+        if !self.config.bootstrapper && !self.config.bootstrap_nodes.is_empty() {
+            if let Err(e) = self.bootstrap() {
+                warn!("{e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Bootstrap kademlia through the bootstrap nodes, added back to the routing table in case
+    /// they were dropped from it after failed dials.
+    fn bootstrap(&mut self) -> Result<()> {
+        let bootstrap_peers: Vec<(PeerId, Multiaddr)> = self
+            .bootstrap_peers()
+            .map(|(peer_id, addr)| (peer_id, addr.clone()))
+            .collect();
+        let kad = &mut self.swarm.behaviour_mut().kad;
+        for (peer_id, addr) in bootstrap_peers {
+            kad.add_address(&peer_id, addr);
+        }
+        kad.bootstrap()
+            .map(|_| ())
+            .map_err(|e| anyhow!("Failed to bootstrap: {e}"))
+    }
+
+    /// Whether `peer_id` passes the allow and deny lists of the config.
+    fn is_peer_allowed(&self, peer_id: &PeerId) -> bool {
+        !self.config.deny_peers.contains(peer_id)
+            && self
+                .config
+                .allow_peers
+                .as_ref()
+                .map_or(true, |allowed| allowed.contains(peer_id))
+    }
+
     pub fn close_command_receiver(&mut self) {
         self.command_receiver.close();
     }
 
-    pub fn command_sender(&self) -> UnboundedSender<NetworkCommand> {
+    pub fn command_sender(&self) -> Sender<NetworkCommand> {
         self.command_sender.clone()
     }
 
+    /// Handle to subscribe to the events, for the consumers other than the one of the
+    /// event channel given to [`UrsaService::new`].
+    pub fn event_bus(&self) -> EventBus {
+        self.event_bus.clone()
+    }
+
     fn emit_event(&mut self, event: NetworkEvent) {
-        let sender = self.event_sender.clone();
-        tokio::task::spawn(async move {
-            if let Err(error) = sender.send(event).await {
-                warn!("[emit_event] - failed to emit network event: {:?}.", error);
-            };
-        });
+        if self.event_bus.has_subscribers() {
+            self.event_bus.publish(event.clone());
+        }
+        match self.event_sender.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(event)) => {
+                increment_counter!("network_events_dropped");
+                warn!("[emit_event] - event channel is full, dropping network event: {event:?}.");
+            }
+            Err(TrySendError::Closed(event)) => {
+                warn!("[emit_event] - failed to emit network event: {event:?}.");
+            }
+        }
+    }
+
+    fn trace_bitswap(&mut self, cid: Cid, trace: BitswapTrace) {
+        if self.traced_cids.contains(&cid) {
+            info!("[BitswapTrace] - {cid}: {trace:?}");
+            self.emit_event(NetworkEvent::BitswapTrace { cid, trace });
+        }
+    }
+
+    /// Update the score of `peer`, disconnecting it once it drops below [`DISCONNECT_THRESHOLD`].
+    fn record_peer_score(&mut self, peer: PeerId, update: impl FnOnce(&mut PeerScore)) {
+        let score = self.peer_scores.record(peer, update);
+        if score < DISCONNECT_THRESHOLD && self.swarm.is_connected(&peer) {
+            warn!("[PeerScore] - disconnecting {peer}, its score dropped to {score}");
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+    }
+
+    /// Whether `peer` stays connected when there are too many peers: the peers protected
+    /// explicitly or as bootstrap nodes, and the relays the node has a reservation on.
+    fn is_protected(&self, peer: &PeerId) -> bool {
+        self.peers.is_protected(peer) || self.relay_reservations.contains_key(peer)
+    }
+
+    /// Disconnect the lowest scored unprotected peers above the maximum number of peers.
+    fn evict_peers(&mut self) {
+        let max_peers = match self.config.connection_limits.max_peers {
+            Some(max_peers) => max_peers as usize,
+            None => return,
+        };
+        let connected = self.swarm.connected_peers().count();
+        if connected <= max_peers {
+            return;
+        }
+        let mut candidates: Vec<(f64, PeerId)> = self
+            .swarm
+            .connected_peers()
+            .filter(|peer| !self.is_protected(peer))
+            .map(|peer| (self.peer_scores.score(peer), *peer))
+            .collect();
+        candidates.sort_unstable_by(|(a, _), (b, _)| a.total_cmp(b));
+        for (score, peer) in candidates.into_iter().take(connected - max_peers) {
+            info!("[ConnectionLimits] - disconnecting {peer} with score {score}, over {max_peers} peers");
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
     }
 
     fn handle_ping(&mut self, ping_event: PingEvent) -> Result<()> {
@@ -370,12 +1111,14 @@ where
                     ping_event.peer.to_base58(),
                     error
                 );
+                self.record_peer_score(ping_event.peer, |score| score.ping_failures += 1);
             }
             Err(libp2p::ping::Failure::Timeout) => {
                 warn!(
                     "[PingFailure::Timeout] - no response was received from {}",
                     ping_event.peer.to_base58()
                 );
+                self.record_peer_score(ping_event.peer, |score| score.ping_failures += 1);
             }
             Err(libp2p::ping::Failure::Unsupported) => {
                 debug!(
@@ -391,11 +1134,22 @@ where
         match identify_event {
             IdentifyEvent::Received { peer_id, info } => {
                 trace!(
-                    "[IdentifyEvent::Received] - with version {} has been received from a peer {}.",
+                    "[IdentifyEvent::Received] - with version {} and agent {} has been received from a peer {}.",
                     info.protocol_version,
+                    info.agent_version,
                     peer_id
                 );
 
+                // the address the peer sees us on is a candidate external address, confirmed
+                // by autonat and used to decide whether to listen on a relay
+                let observed_addr = info.observed_addr.clone();
+                if observed_addr != Multiaddr::empty()
+                    && !observed_addr.iter().any(|p| p == Protocol::P2pCircuit)
+                {
+                    self.swarm
+                        .add_external_address(observed_addr, AddressScore::Finite(1));
+                }
+
                 if self.peers.contains(&peer_id) {
                     trace!(
                         "[IdentifyEvent::Received] - peer {} already known!",
@@ -403,6 +1157,20 @@ where
                     );
                 }
 
+                match is_compatible_version(&info.protocol_version) {
+                    Some(false) => {
+                        warn!(
+                            "[IdentifyEvent::Received] - peer {peer_id} runs protocol version {}, incompatible with {URSA_PROTOCOL_VERSION}. Excluding it from request-response",
+                            info.protocol_version
+                        );
+                        self.incompatible_peers.insert(peer_id);
+                    }
+                    Some(true) => {
+                        self.incompatible_peers.remove(&peer_id);
+                    }
+                    None => (),
+                }
+
                 // check if received identify is from a peer on the same network
                 if info
                     .protocols
@@ -427,111 +1195,922 @@ where
 
     fn handle_autonat(&mut self, autonat_event: AutonatEvent) -> Result<(), Error> {
         match autonat_event {
-            AutonatEvent::StatusChanged { old, new } => match (old, new) {
-                (NatStatus::Unknown, NatStatus::Private) => {
-                    if self.swarm.behaviour().relay_client.is_enabled() {
-                        if let Some(addr) = self.bootstraps.choose(&mut rand::thread_rng()) {
-                            let circuit_addr = addr.clone().with(Protocol::P2pCircuit);
-                            warn!(
-                                "Private NAT detected. Nodes should be publically accessable on 4890(udp) and 6009(tcp), as well as standard http(80) and https(443)! Falling back temporarily to public relay address on bootstrap node {}",
-                                circuit_addr
-                                    .clone()
-                                    .with(
-                                        Protocol::P2p(
-                                            self.swarm.local_peer_id().to_owned().into()
-                                        )
-                                    )
-                            );
-                            self.swarm
-                                .listen_on(circuit_addr)
-                                .expect("failed to listen on relay");
+            AutonatEvent::StatusChanged { old, new } => {
+                self.private_nat_probes = None;
+                self.emit_event(NetworkEvent::NatStatusChanged {
+                    old: old.clone().into(),
+                    new: new.clone().into(),
+                });
+                match (old, new) {
+                    (NatStatus::Unknown, NatStatus::Private) => {
+                        if self.swarm.behaviour().relay_client.is_enabled() {
+                            self.private_nat_probes = Some(0);
+                            self.listen_on_relay_when_confident()?;
                         }
                     }
+                    (_, NatStatus::Public(addr)) => {
+                        info!("Public Nat verified! Public listening address: {}", addr);
+                        self.public_addr = Some(addr);
+                    }
+                    (old, new) => {
+                        warn!("NAT status changed from {:?} to {:?}", old, new);
+                    }
                 }
-                (_, NatStatus::Public(addr)) => {
-                    info!("Public Nat verified! Public listening address: {}", addr);
-                    self.public_addr = Some(addr);
-                }
-                (old, new) => {
-                    warn!("NAT status changed from {:?} to {:?}", old, new);
-                }
-            },
+            }
+            AutonatEvent::OutboundProbe(OutboundProbeEvent::Error {
+                error: OutboundProbeError::Response(ResponseError::DialError),
+                ..
+            }) => {
+                // the server could not dial back, confirming the private status
+                self.private_nat_confirmed()?;
+            }
             AutonatEvent::InboundProbe(_) | AutonatEvent::OutboundProbe(_) => (),
         }
         Ok(())
     }
 
-    fn handle_bitswap(&mut self, bitswap_event: BitswapEvent) -> Result<()> {
-        match bitswap_event {
-            BitswapEvent::Progress(query_id, _) => {
-                trace!(
-                    "[BitswapEvent::Progress] - bitswap request in progress with, id: {}",
-                    query_id
-                );
+    fn private_nat_confirmed(&mut self) -> Result<()> {
+        if let Some(probes) = self.private_nat_probes.as_mut() {
+            *probes += 1;
+            self.listen_on_relay_when_confident()?;
+        }
+        Ok(())
+    }
+
+    /// Listen on the relay of a bootstrap node once enough autonat probes confirmed the
+    /// private NAT status, so a single noisy probe doesn't make the node take a reservation.
+    fn listen_on_relay_when_confident(&mut self) -> Result<()> {
+        match self.private_nat_probes {
+            Some(probes) if probes >= self.config.autonat_confidence => {
+                self.private_nat_probes = None;
             }
-            BitswapEvent::Complete(query_id, result) => {
-                if let Some(cid) = self.bitswap_queries.remove(&query_id) {
-                    if let Some(chans) = self.response_channels.remove(&cid) {
-                        for chan in chans.into_iter() {
-                            match result {
-                                Ok(()) => {
-                                    if chan.send(Ok(())).is_err() {
-                                        error!("[BitswapEvent::Complete] - Bitswap response channel send failed");
-                                    }
-                                }
-                                Err(_) => {
-                                    if chan.send(Err(anyhow!("The requested block with cid {cid:?} is not found with any peers"))).is_err() {
-                                        error!("[BitswapEvent::Complete] - Bitswap response channel send failed");
-                                    }
-                                }
-                            }
-                        }
-                    } else {
-                        debug!("[BitswapEvent::Complete] - Received Bitswap response, but response channel cannot be found");
+            _ => return Ok(()),
+        }
+        warn!("Private NAT detected. Nodes should be publically accessable on 4890(udp) and 6009(tcp), as well as standard http(80) and https(443)! Falling back temporarily to a public relay address on a bootstrap node");
+        self.relay_candidates = self.bootstraps.clone();
+        self.relay_candidates.shuffle(&mut rand::thread_rng());
+        self.listen_on_next_relay();
+        Ok(())
+    }
+
+    /// Listen on the next bootstrap relay that accepts the listener, giving up once none
+    /// are left.
+    fn listen_on_next_relay(&mut self) {
+        self.active_relay = None;
+        while let Some(addr) = self.relay_candidates.pop() {
+            let circuit_addr = addr.with(Protocol::P2pCircuit);
+            match self.listen_on_relay(circuit_addr.clone()) {
+                Ok(relay_peer_id) => {
+                    info!(
+                        "[Relay] - listening on {}",
+                        circuit_addr
+                            .with(Protocol::P2p(self.swarm.local_peer_id().to_owned().into()))
+                    );
+                    self.active_relay = Some(relay_peer_id);
+                    return;
+                }
+                Err(e) => warn!("[Relay] - failed to listen on {circuit_addr}: {e}"),
+            }
+        }
+        warn!("[Relay] - no bootstrap relay left to listen on");
+    }
+
+    /// Move on to the next bootstrap relay if `relay_peer_id` is the active one.
+    fn relay_failed(&mut self, relay_peer_id: PeerId) {
+        if self.active_relay != Some(relay_peer_id) {
+            return;
+        }
+        warn!("[Relay] - the reservation on {relay_peer_id} closed, trying the next relay");
+        if !self.drop_relay_reservation(relay_peer_id) {
+            self.listen_on_next_relay();
+        }
+    }
+
+    /// Stop listening on the relay, moving on to the next bootstrap relay if it was the
+    /// active one. Returns false if the node holds no reservation on the relay.
+    fn drop_relay_reservation(&mut self, relay_peer_id: PeerId) -> bool {
+        let reservation = match self.relay_reservations.remove(&relay_peer_id) {
+            Some(reservation) => reservation,
+            None => return false,
+        };
+        self.swarm.remove_listener(reservation.listener());
+        self.emit_event(NetworkEvent::RelayReservationClosed { relay_peer_id });
+        if self.active_relay == Some(relay_peer_id) {
+            self.listen_on_next_relay();
+        }
+        true
+    }
+
+    fn handle_relay_client(&mut self, relay_event: RelayClientEvent) -> Result<()> {
+        match relay_event {
+            RelayClientEvent::ReservationReqAccepted { relay_peer_id, .. } => {
+                info!(
+                    "[RelayClientEvent::ReservationReqAccepted] - reservation on {relay_peer_id}"
+                );
+                let renewed = self
+                    .relay_reservations
+                    .get_mut(&relay_peer_id)
+                    .map(|reservation| reservation.accepted(Instant::now()));
+                match renewed {
+                    Some(true) => {
+                        self.emit_event(NetworkEvent::RelayReservationRenewed { relay_peer_id })
                     }
-                } else {
-                    error!("[BitswapEvent::Complete] - Query Id {query_id:?} not found in the hash map");
+                    Some(false) => {
+                        self.emit_event(NetworkEvent::RelayReservationOpened { relay_peer_id })
+                    }
+                    None => (),
+                }
+            }
+            RelayClientEvent::ReservationReqFailed {
+                relay_peer_id,
+                renewal,
+                error,
+            } => {
+                warn!("[RelayClientEvent::ReservationReqFailed] - reservation on {relay_peer_id} failed: {error:?}");
+                if !renewal {
+                    self.relay_failed(relay_peer_id);
                 }
             }
+            event => debug!("[RelayClientEvent] {event:?}"),
         }
         Ok(())
     }
 
-    fn handle_gossip(&mut self, gossip_event: libp2p::gossipsub::GossipsubEvent) -> Result<()> {
-        match gossip_event {
-            libp2p::gossipsub::GossipsubEvent::Message {
-                propagation_source,
-                message_id,
-                message,
+    fn handle_relay_server(&mut self, relay_event: RelayServerEvent) -> Result<()> {
+        match relay_event {
+            RelayServerEvent::ReservationReqAccepted {
+                src_peer_id,
+                renewed,
             } => {
-                self.emit_event(NetworkEvent::Gossipsub(GossipsubEvent::Message {
-                    peer_id: propagation_source,
-                    message_id,
-                    message,
-                }));
+                debug!("[RelayServerEvent::ReservationReqAccepted] - reservation of {src_peer_id}");
+                self.emit_event(NetworkEvent::RelayServerReservationAccepted {
+                    peer_id: src_peer_id,
+                    renewed,
+                });
             }
-            libp2p::gossipsub::GossipsubEvent::Subscribed { peer_id, topic } => {
-                self.emit_event(NetworkEvent::Gossipsub(GossipsubEvent::Subscribed {
-                    peer_id,
-                    topic,
-                }));
+            RelayServerEvent::ReservationReqDenied { src_peer_id } => {
+                warn!("[RelayServerEvent::ReservationReqDenied] - reservation of {src_peer_id}");
+                self.emit_event(NetworkEvent::RelayServerReservationDenied {
+                    peer_id: src_peer_id,
+                });
             }
-            libp2p::gossipsub::GossipsubEvent::Unsubscribed { peer_id, topic } => {
-                self.emit_event(NetworkEvent::Gossipsub(GossipsubEvent::Unsubscribed {
-                    peer_id,
-                    topic,
-                }));
+            RelayServerEvent::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                debug!("[RelayServerEvent::CircuitReqAccepted] - circuit from {src_peer_id} to {dst_peer_id}");
+                self.emit_event(NetworkEvent::RelayServerCircuitOpened {
+                    src_peer_id,
+                    dst_peer_id,
+                });
+            }
+            RelayServerEvent::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error,
+            } => {
+                debug!("[RelayServerEvent::CircuitClosed] - circuit from {src_peer_id} to {dst_peer_id} closed: {error:?}");
+                self.emit_event(NetworkEvent::RelayServerCircuitClosed {
+                    src_peer_id,
+                    dst_peer_id,
+                });
             }
-            libp2p::gossipsub::GossipsubEvent::GossipsubNotSupported { .. } => (),
+            event => debug!("[RelayServerEvent] {event:?}"),
         }
         Ok(())
     }
 
-    pub fn handle_kad(&mut self, event: KademliaEvent) -> Result<()> {
-        match event {
-            KademliaEvent::OutboundQueryProgressed { id, result, .. } => match result {
-                QueryResult::Bootstrap(result) => match result {
-                    Ok(BootstrapOk {
+    /// Follow the hole punches attempted by DCUtR once a relayed connection is established.
+    fn handle_dcutr(&mut self, dcutr_event: DcutrEvent) -> Result<()> {
+        match dcutr_event {
+            DcutrEvent::InitiatedDirectConnectionUpgrade { remote_peer_id, .. }
+            | DcutrEvent::RemoteInitiatedDirectConnectionUpgrade { remote_peer_id, .. } => {
+                debug!("[DcutrEvent] - upgrading the relayed connection with {remote_peer_id}");
+                self.emit_event(NetworkEvent::DirectConnectionUpgradeAttempted {
+                    peer_id: remote_peer_id,
+                });
+            }
+            DcutrEvent::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                info!("[DcutrEvent::DirectConnectionUpgradeSucceeded] - direct connection with {remote_peer_id}");
+                self.emit_event(NetworkEvent::DirectConnectionUpgraded {
+                    peer_id: remote_peer_id,
+                });
+            }
+            DcutrEvent::DirectConnectionUpgradeFailed {
+                remote_peer_id,
+                error,
+            } => {
+                warn!("[DcutrEvent::DirectConnectionUpgradeFailed] - staying relayed with {remote_peer_id}: {error:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Listen on `circuit_addr` through a relay, keeping track of the reservation.
+    fn listen_on_relay(&mut self, circuit_addr: Multiaddr) -> Result<PeerId> {
+        let relay_peer_id = circuit_addr
+            .iter()
+            .filter_map(|protocol| match protocol {
+                Protocol::P2p(mh) => PeerId::from_multihash(mh).ok(),
+                _ => None,
+            })
+            .last()
+            .ok_or_else(|| anyhow!("The relay address {circuit_addr} has no peer id"))?;
+        let listener = self.swarm.listen_on(circuit_addr.clone())?;
+        self.relay_reservations
+            .insert(relay_peer_id, RelayReservation::new(circuit_addr, listener));
+        Ok(relay_peer_id)
+    }
+
+    /// Renew the relay reservations that are close to expiring.
+    fn renew_relay_reservations(&mut self) {
+        let now = Instant::now();
+        let lifetime = Duration::from_secs(self.config.relay_reservation_duration);
+        let renewal_percent = self.config.relay_renewal_percent;
+
+        let mut expiring = Vec::new();
+        for (relay_peer_id, reservation) in self.relay_reservations.iter_mut() {
+            if !reservation.needs_renewal(now, lifetime, renewal_percent) {
+                continue;
+            }
+            let expires_in = reservation
+                .expires_at(lifetime)
+                .map(|expires_at| expires_at.saturating_duration_since(now))
+                .unwrap_or_default();
+            warn!("[Relay] - reservation on {relay_peer_id} expires in {expires_in:?}, renewing");
+
+            // the relay client requests a new reservation for the new listener
+            match self.swarm.listen_on(reservation.addr().clone()) {
+                Ok(listener) => {
+                    let old = reservation.renew_with(listener);
+                    self.swarm.remove_listener(old);
+                }
+                Err(e) => {
+                    warn!("[Relay] - failed to renew the reservation on {relay_peer_id}: {e}")
+                }
+            }
+            expiring.push(NetworkEvent::RelayReservationExpiring {
+                relay_peer_id: *relay_peer_id,
+                expires_in,
+            });
+        }
+        for event in expiring {
+            self.emit_event(event);
+        }
+    }
+
+    fn handle_bitswap(&mut self, bitswap_event: BitswapEvent) -> Result<()> {
+        match bitswap_event {
+            BitswapEvent::Progress(query_id, missing) => {
+                trace!(
+                    "[BitswapEvent::Progress] - bitswap request in progress with, id: {}",
+                    query_id
+                );
+                if let Some(cid) = self.bitswap_queries.get(&query_id).copied() {
+                    self.trace_bitswap(cid, BitswapTrace::Progress { query_id, missing });
+                }
+            }
+            BitswapEvent::Complete(query_id, result) => {
+                let fetch_peers = self.fetch_peers.remove(&query_id);
+                if let Some(cid) = self.block_gets.remove(&query_id) {
+                    let error = match fetch_peers {
+                        Some(fetch_peers) if result.is_err() && !self.has_local_block(&cid) => {
+                            let request_timeout = BitswapConfig::default().request_timeout;
+                            self.remember_not_found(cid);
+                            Some(NetworkError::NotFound(
+                                fetch_peers.diagnostics(cid, request_timeout),
+                            ))
+                        }
+                        _ => None,
+                    };
+                    for sender in self.block_get_channels.remove(&cid).unwrap_or_default() {
+                        let data = match &error {
+                            Some(error) => Err(error.clone().into()),
+                            None => self.read_local_block(&cid),
+                        };
+                        if sender.send(data).is_err() {
+                            debug!(
+                                "[BitswapEvent::Complete] - a caller of the block {cid} went away"
+                            );
+                        }
+                    }
+                    return Ok(());
+                }
+                if let Some(cid) = self.bitswap_queries.remove(&query_id) {
+                    if self.parallel_syncs.contains_key(&cid) {
+                        return self.handle_parallel_sync_block(cid, query_id, result.is_ok());
+                    }
+                    self.active_queries.remove(&cid);
+                    if result.is_err() && self.provider_discoveries.contains_key(&cid) {
+                        return self.poll_provider_discovery(cid);
+                    }
+                    match fetch_peers {
+                        Some(fetch_peers) if result.is_err() && !self.has_local_block(&cid) => {
+                            let request_timeout = BitswapConfig::default().request_timeout;
+                            let diagnostics = fetch_peers.diagnostics(cid, request_timeout);
+                            self.fail_response_channels(cid, NetworkError::NotFound(diagnostics));
+                        }
+                        _ => self.spawn_sync_resolution(cid),
+                    }
+                } else {
+                    error!("[BitswapEvent::Complete] - Query Id {query_id:?} not found in the hash map");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve the callers waiting on `cid` with the blocks of the dag fetched and the ones
+    /// still missing, or fail them if not even the root was fetched.
+    fn resolve_response_channels(&mut self, cid: Cid, result: Option<SyncResult>) {
+        match result {
+            Some(result) => {
+                if !result.is_complete() {
+                    warn!(
+                        "[BitswapEvent::Complete] - {} blocks of {cid} are not found with any peers",
+                        result.missing.len()
+                    );
+                }
+                self.send_responses(cid, result.is_complete(), || Ok(result.clone()));
+            }
+            None => {
+                self.remember_not_found(cid);
+                self.send_responses(cid, false, || {
+                    Err(anyhow!(
+                        "The requested block with cid {cid:?} is not found with any peers"
+                    ))
+                });
+            }
+        }
+    }
+
+    /// Resolve the callers waiting on `cid` once the dag is counted off the event loop.
+    fn spawn_sync_resolution(&mut self, cid: Cid) {
+        if self.resolving_dags.insert(cid) {
+            self.spawn_dag_count(cid, DagCount::Resolve);
+        }
+    }
+
+    /// The blocks of the dag under `cid` fetched since its callers started waiting and the
+    /// ones still missing, from a count of the local dag. `None` if the root is missing.
+    fn sync_result(&self, cid: &Cid, count: Result<(usize, Vec<Cid>)>) -> Option<SyncResult> {
+        match count {
+            Ok((0, _)) => None,
+            Ok((present, missing)) => {
+                let baseline = self.sync_baselines.get(cid).copied().unwrap_or_default();
+                Some(SyncResult {
+                    fetched: present.saturating_sub(baseline),
+                    missing,
+                })
+            }
+            Err(e) => {
+                warn!("Failed to read the dag under {cid} from the local store: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Remember the blocks of the dag under `cid` already in the local store, to report only
+    /// the ones the fetch adds. Only dags whose root is local were counted.
+    fn record_sync_baseline(&mut self, cid: Cid) {
+        let present = self.counted_dags.get(&cid).copied().unwrap_or_default();
+        self.sync_baselines.insert(cid, present);
+    }
+
+    /// Whether a fetch of `cid` waits on a count of the local dag first: its root is local,
+    /// and no fetch of it is pending or already counted it.
+    fn needs_dag_count(&self, cid: &Cid) -> bool {
+        !self.counted_dags.contains_key(cid)
+            && !self.response_channels.contains_key(cid)
+            && self.has_local_block(cid)
+    }
+
+    /// Count the blocks of the dag under `cid` in the local store on the blocking pool, the
+    /// count is handled with `purpose` once it comes back to the event loop.
+    fn spawn_dag_count(&self, cid: Cid, purpose: DagCount) {
+        let store = Arc::clone(&self.store);
+        let dag_count_sender = self.dag_count_sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let count = store.local_dag_blocks(&cid);
+            // the receiver is only dropped with the service
+            let _ = dag_count_sender.send((cid, purpose, count));
+        });
+    }
+
+    /// Handle a count of the local dag under `cid` made by [`Self::spawn_dag_count`].
+    fn handle_dag_count(
+        &mut self,
+        cid: Cid,
+        purpose: DagCount,
+        count: Result<(usize, Vec<Cid>)>,
+    ) -> Result<()> {
+        match purpose {
+            DagCount::Fetch(command) => match count {
+                Ok((_, missing)) if missing.is_empty() => {
+                    debug!("[NetworkCommand::GetBitswap] - {cid} is already in the local store");
+                    if let NetworkCommand::GetBitswap { sender, .. }
+                    | NetworkCommand::GetBitswapWithDiscovery { sender, .. } = command
+                    {
+                        if sender.send(Ok(SyncResult::default())).is_err() {
+                            debug!("[NetworkCommand::GetBitswap] - the caller of {cid} went away");
+                        }
+                    }
+                    Ok(())
+                }
+                count => {
+                    let present = count.map_or_else(
+                        |e| {
+                            warn!("Failed to read the dag under {cid} from the local store: {e:?}");
+                            0
+                        },
+                        |(present, _)| present,
+                    );
+                    self.counted_dags.insert(cid, present);
+                    let handled = self.handle_command(command);
+                    self.counted_dags.remove(&cid);
+                    handled
+                }
+            },
+            DagCount::Resolve => {
+                // the callers were answered meanwhile, or joined by one that fetches again
+                if !self.resolving_dags.remove(&cid)
+                    || self.active_queries.contains_key(&cid)
+                    || self.parallel_syncs.contains_key(&cid)
+                {
+                    return Ok(());
+                }
+                let result = self.sync_result(&cid, count);
+                self.resolve_response_channels(cid, result);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fail the callers waiting on `cid` with `error`.
+    fn fail_response_channels(&mut self, cid: Cid, error: NetworkError) {
+        if let NetworkError::NotFound(_) = error {
+            self.remember_not_found(cid);
+        }
+        self.send_responses(cid, false, || Err(error.clone().into()));
+    }
+
+    /// The number of distinct cids with bitswap queries pending, bounded by
+    /// `max_pending_queries`.
+    fn pending_query_count(&self) -> usize {
+        self.response_channels.len() + self.block_get_channels.len()
+    }
+
+    /// Fail the fetches of `cid` right away until `not_found_cache_ttl` expires.
+    fn remember_not_found(&mut self, cid: Cid) {
+        if self.config.not_found_cache_ttl == 0 {
+            return;
+        }
+        let ttl = Duration::from_secs(self.config.not_found_cache_ttl);
+        self.not_found.retain(|_, at| at.elapsed() < ttl);
+        self.not_found.insert(cid, Instant::now());
+    }
+
+    /// Whether `cid` was not found by a fetch within the last `not_found_cache_ttl`.
+    fn is_recently_not_found(&mut self, cid: &Cid) -> bool {
+        let ttl = Duration::from_secs(self.config.not_found_cache_ttl);
+        match self.not_found.get(cid) {
+            Some(at) if at.elapsed() < ttl => true,
+            Some(_) => {
+                self.not_found.remove(cid);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn send_responses(&mut self, cid: Cid, found: bool, result: impl Fn() -> Result<SyncResult>) {
+        self.provider_discoveries.remove(&cid);
+        self.sync_baselines.remove(&cid);
+        self.resolving_dags.remove(&cid);
+        self.trace_bitswap(cid, BitswapTrace::Complete { found });
+        if let Some(timer) = self.fetch_timers.remove(&cid) {
+            timer.finish(&self.store_timings);
+        }
+        if let Some(chans) = self.response_channels.remove(&cid) {
+            for chan in chans.into_iter() {
+                if chan.send(result()).is_err() {
+                    error!("[BitswapEvent::Complete] - Bitswap response channel send failed");
+                }
+            }
+        } else {
+            debug!("[BitswapEvent::Complete] - Received Bitswap response, but response channel cannot be found");
+        }
+    }
+
+    /// Request `cid` from the next provider that connected, or look its providers up if that
+    /// wasn't done yet. The callers are failed once every provider was tried.
+    fn poll_provider_discovery(&mut self, cid: Cid) -> Result<()> {
+        if self.active_queries.contains_key(&cid) {
+            return Ok(());
+        }
+        let discovery = match self.provider_discoveries.get_mut(&cid) {
+            Some(discovery) => discovery,
+            None => return Ok(()),
+        };
+
+        if discovery.lookup().is_none() {
+            info!("[ProviderDiscovery] - looking up the providers of {cid}");
+            let query_id = self
+                .swarm
+                .behaviour_mut()
+                .kad
+                .get_providers(provider_key(&cid));
+            discovery.start_lookup(query_id);
+        } else if let Some(provider) = discovery.next_provider() {
+            debug!("[ProviderDiscovery] - requesting {cid} from provider {provider}");
+            self.trace_bitswap(
+                cid,
+                BitswapTrace::WantSent {
+                    block: cid,
+                    peers: vec![provider],
+                },
+            );
+            let query_id = self.swarm.behaviour_mut().sync_block(cid, vec![provider])?;
+            self.bitswap_queries.insert(query_id, cid);
+            self.active_queries.insert(cid, query_id);
+            self.emit_event(NetworkEvent::BitswapWant { cid, query_id });
+        } else if discovery.is_exhausted() {
+            warn!("[ProviderDiscovery] - no provider could serve {cid}");
+            self.spawn_sync_resolution(cid);
+        }
+        Ok(())
+    }
+
+    fn handle_providers_found(
+        &mut self,
+        query_id: KadQueryId,
+        result: Result<GetProvidersOk, GetProvidersError>,
+        last: bool,
+    ) -> Result<()> {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let (cid, discovery) = match self
+            .provider_discoveries
+            .iter_mut()
+            .find(|(_, discovery)| discovery.lookup() == Some(query_id))
+        {
+            Some((cid, discovery)) => (*cid, discovery),
+            None => {
+                debug!("[KademliaEvent::GetProviders] - {query_id:?} is not a provider discovery");
+                return Ok(());
+            }
+        };
+
+        match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                for provider in providers.into_iter().filter(|p| *p != local_peer_id) {
+                    if discovery.found(provider, self.peers.contains(&provider)) {
+                        debug!(
+                            "[KademliaEvent::GetProviders] - dialing provider {provider} of {cid}"
+                        );
+                        if let Err(e) = self.swarm.dial(provider) {
+                            warn!("[KademliaEvent::GetProviders] - failed to dial provider {provider}: {e:?}");
+                            discovery.dial_failed(&provider);
+                        }
+                    }
+                }
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => discovery.finish_lookup(),
+            Err(e) => {
+                warn!("[KademliaEvent::GetProviders] - lookup of the providers of {cid} failed: {e:?}");
+                discovery.finish_lookup();
+            }
+        }
+        if last {
+            discovery.finish_lookup();
+        }
+        self.poll_provider_discovery(cid)
+    }
+
+    /// Collect the providers found by a [`NetworkCommand::FindProviders`] lookup, and send
+    /// them once the lookup is over.
+    fn handle_provider_lookup(
+        &mut self,
+        query_id: KadQueryId,
+        result: Result<GetProvidersOk, GetProvidersError>,
+        last: bool,
+    ) -> Result<()> {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let finished = match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                if let Some((found, _)) = self.provider_lookups.get_mut(&query_id) {
+                    found.extend(providers.into_iter().filter(|p| *p != local_peer_id));
+                }
+                last
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => true,
+            Err(e) => {
+                warn!("[KademliaEvent::GetProviders] - provider lookup {query_id:?} failed: {e:?}");
+                true
+            }
+        };
+        if finished {
+            if let Some((found, sender)) = self.provider_lookups.remove(&query_id) {
+                debug!(
+                    "[KademliaEvent::GetProviders] - found {} providers with {query_id:?}",
+                    found.len()
+                );
+                // the caller may have given up on a long lookup
+                if sender.send(Ok(found)).is_err() {
+                    debug!("[KademliaEvent::GetProviders] - the caller of {query_id:?} went away");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move on with the provider discoveries that were dialing `peer_id`.
+    fn handle_provider_dialed(&mut self, peer_id: &PeerId, connected: bool) -> Result<()> {
+        let cids: Vec<Cid> = self
+            .provider_discoveries
+            .iter_mut()
+            .filter_map(|(cid, discovery)| {
+                let dialing = if connected {
+                    discovery.connected(peer_id)
+                } else {
+                    discovery.dial_failed(peer_id)
+                };
+                dialing.then_some(*cid)
+            })
+            .collect();
+        for cid in cids {
+            self.poll_provider_discovery(cid)?;
+        }
+        Ok(())
+    }
+
+    /// Start syncing the dag under `root`, keeping at most `max_parallel` block requests in flight,
+    /// and aborting once more than `max_bytes` were fetched.
+    fn start_parallel_sync(
+        &mut self,
+        root: Cid,
+        providers: Vec<PeerId>,
+        max_parallel: NonZeroUsize,
+        max_bytes: Option<u64>,
+    ) -> Result<()> {
+        let missing = match self.missing_blocks(&root) {
+            Ok(missing) => missing,
+            Err(e) => {
+                self.abort_parallel_sync(root, e);
+                return Ok(());
+            }
+        };
+        let sync = ParallelSync::new(max_parallel, providers, missing).with_max_bytes(max_bytes);
+        self.parallel_syncs.insert(root, sync);
+        self.poll_parallel_sync(root)
+    }
+
+    /// Stop the sync under `root` after a store error, failing its callers only.
+    fn abort_parallel_sync(&mut self, root: Cid, error: Error) {
+        warn!("[ParallelSync] - aborting the sync of {root}: {error:?}");
+        self.cancel_parallel_sync(&root);
+        self.send_responses(root, false, || {
+            Err(anyhow!("The sync of {root} failed: {error}"))
+        });
+    }
+
+    /// Stop the sync under `root`, cancelling its block requests in flight.
+    fn cancel_parallel_sync(&mut self, root: &Cid) -> Option<ParallelSync> {
+        let sync = self.parallel_syncs.remove(root)?;
+        for query_id in sync.in_flight() {
+            self.swarm.behaviour_mut().cancel(*query_id);
+            self.bitswap_queries.remove(query_id);
+        }
+        Some(sync)
+    }
+
+    fn missing_blocks(&self, cid: &Cid) -> Result<Vec<Cid>> {
+        TimedBitswapStorage::new(
+            BitswapStorage(self.store.clone()),
+            self.store_timings.clone(),
+        )
+        .missing_blocks(cid)
+    }
+
+    /// Request queued blocks of the sync under `root` until the parallelism limit is reached,
+    /// or resolve the callers if there is nothing left to fetch.
+    fn poll_parallel_sync(&mut self, root: Cid) -> Result<()> {
+        if let Some(sync) = self.parallel_syncs.get_mut(&root) {
+            if sync.is_complete() {
+                let fetched = sync.fetched_blocks();
+                let missing = self
+                    .parallel_syncs
+                    .remove(&root)
+                    .map(ParallelSync::into_missing)
+                    .unwrap_or_default();
+                // not even the root was served
+                let result = (!missing.contains(&root)).then_some(SyncResult { fetched, missing });
+                self.resolve_response_channels(root, result);
+                return Ok(());
+            }
+
+            let traced = self.traced_cids.contains(&root);
+            let behaviour = self.swarm.behaviour_mut();
+            let mut wants = Vec::new();
+            let mut failed = None;
+            while let Some(cid) = sync.next_block() {
+                let query_id = match behaviour.get_block(cid, sync.providers().iter().copied()) {
+                    Ok(query_id) => query_id,
+                    Err(e) => {
+                        failed = Some(e);
+                        break;
+                    }
+                };
+                sync.register_query(query_id, cid);
+                self.bitswap_queries.insert(query_id, root);
+                trace!("[ParallelSync] - requesting block {cid} of {root} with query {query_id}");
+                if traced {
+                    wants.push(BitswapTrace::WantSent {
+                        block: cid,
+                        peers: sync.providers().to_vec(),
+                    });
+                }
+            }
+            for want in wants {
+                self.trace_bitswap(root, want);
+            }
+            if let Some(e) = failed {
+                self.abort_parallel_sync(root, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_parallel_sync_block(
+        &mut self,
+        root: Cid,
+        query_id: QueryId,
+        found: bool,
+    ) -> Result<()> {
+        let block = self
+            .parallel_syncs
+            .get_mut(&root)
+            .and_then(|sync| sync.complete_query(&query_id));
+
+        if let Some(block) = block {
+            let trace = if found {
+                BitswapTrace::BlockReceived { block }
+            } else {
+                BitswapTrace::BlockNotFound { block }
+            };
+            self.trace_bitswap(root, trace);
+        }
+
+        match block {
+            Some(cid) if found => {
+                let size = match self.store.get_size(&cid) {
+                    Ok(size) => size.unwrap_or_default() as u64,
+                    Err(e) => {
+                        self.abort_parallel_sync(root, e);
+                        return Ok(());
+                    }
+                };
+                let within_budget = self
+                    .parallel_syncs
+                    .get_mut(&root)
+                    .map_or(true, |sync| sync.record_fetched(size));
+                if !within_budget {
+                    if let Some(sync) = self.cancel_parallel_sync(&root) {
+                        let fetched = sync.fetched_bytes();
+                        let max_bytes = sync.max_bytes().unwrap_or_default();
+                        warn!("[ParallelSync] - aborting the sync of {root} after fetching {fetched} bytes, over the budget of {max_bytes} bytes");
+                        self.fail_response_channels(
+                            root,
+                            NetworkError::BudgetExceeded { fetched, max_bytes },
+                        );
+                    }
+                    return Ok(());
+                }
+
+                // a block whose children can't be read is reported missing at the end
+                let missing = self.missing_blocks(&cid);
+                if let Some(sync) = self.parallel_syncs.get_mut(&root) {
+                    match missing {
+                        Ok(missing) => sync.enqueue(missing),
+                        Err(e) => {
+                            warn!("[ParallelSync] - skipping the children of {cid}: {e:?}");
+                            sync.record_missing(cid);
+                        }
+                    }
+                }
+                self.poll_parallel_sync(root)
+            }
+            // the block is reported missing once the rest of the dag is synced
+            Some(cid) => {
+                debug!("[ParallelSync] - block {cid} of {root} is not found with any peers");
+                if let Some(sync) = self.parallel_syncs.get_mut(&root) {
+                    sync.record_missing(cid);
+                }
+                self.poll_parallel_sync(root)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn handle_gossip(&mut self, gossip_event: libp2p::gossipsub::GossipsubEvent) -> Result<()> {
+        self.last_gossip = Some(Instant::now());
+        match gossip_event {
+            libp2p::gossipsub::GossipsubEvent::Message {
+                propagation_source,
+                message_id,
+                message,
+            } => {
+                // messages still in flight on the topics of a network the node switched away from
+                let subscribed = self
+                    .swarm
+                    .behaviour()
+                    .gossipsub
+                    .topics()
+                    .any(|topic| *topic == message.topic);
+                let content_topic = network_topic(&self.config.network_name, URSA_CONTENT);
+                let signed = self.config.gossip.message_authenticity == GossipAuthenticity::Signed;
+                let rejection = if signed && message.source.is_none() {
+                    Some("unsigned message")
+                } else if message.topic == content_topic.hash()
+                    && Cid::try_from(message.data.as_slice()).is_err()
+                {
+                    Some("content announcement that is not a valid cid")
+                } else {
+                    None
+                };
+                // a message of the node looped back by a peer
+                let from_self = message.source.as_ref() == Some(self.swarm.local_peer_id());
+                let acceptance = match (subscribed && !from_self, rejection) {
+                    (false, _) => MessageAcceptance::Ignore,
+                    (true, None) => MessageAcceptance::Accept,
+                    (true, Some(_)) => MessageAcceptance::Reject,
+                };
+                // gossipsub may have dropped the message from its cache already
+                if let Err(e) = self.swarm.behaviour_mut().report_message_validation_result(
+                    &message_id,
+                    &propagation_source,
+                    acceptance,
+                ) {
+                    warn!("[GossipsubEvent::Message] - failed to report the validation of {message_id}: {e:?}");
+                }
+
+                if !subscribed {
+                    debug!("[GossipsubEvent::Message] - ignored message from {propagation_source} on {}, the node is not subscribed", message.topic);
+                    return Ok(());
+                }
+                if from_self {
+                    debug!("[GossipsubEvent::Message] - ignored a message of the node forwarded back by {propagation_source}");
+                    return Ok(());
+                }
+                if let Some(rejection) = rejection {
+                    warn!("[GossipsubEvent::Message] - rejected {rejection} from {propagation_source}");
+                    self.record_peer_score(propagation_source, |score| score.invalid_gossip += 1);
+                    return Ok(());
+                }
+
+                self.gossip_message_counts
+                    .entry(message.topic.clone())
+                    .or_default()
+                    .messages_received += 1;
+                self.emit_event(NetworkEvent::Gossipsub(GossipsubEvent::Message {
+                    peer_id: propagation_source,
+                    message_id,
+                    message,
+                }));
+            }
+            libp2p::gossipsub::GossipsubEvent::Subscribed { peer_id, topic } => {
+                self.record_subscription_churn(&topic, true);
+                self.emit_event(NetworkEvent::Gossipsub(GossipsubEvent::Subscribed {
+                    peer_id,
+                    topic,
+                }));
+            }
+            libp2p::gossipsub::GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                self.record_subscription_churn(&topic, false);
+                self.emit_event(NetworkEvent::Gossipsub(GossipsubEvent::Unsubscribed {
+                    peer_id,
+                    topic,
+                }));
+            }
+            libp2p::gossipsub::GossipsubEvent::GossipsubNotSupported { peer_id } => {
+                if self.require_gossipsub {
+                    info!("[GossipsubEvent::GossipsubNotSupported] - disconnecting peer {peer_id}");
+                    MetricEvent::GossipsubUnsupportedPeer.record();
+                    if self.swarm.disconnect_peer_id(peer_id).is_err() {
+                        debug!("[GossipsubEvent::GossipsubNotSupported] - peer {peer_id} is already disconnected");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle_kad(&mut self, event: KademliaEvent) -> Result<()> {
+        match event {
+            KademliaEvent::OutboundQueryProgressed {
+                id, result, step, ..
+            } => match result {
+                QueryResult::Bootstrap(result) => match result {
+                    Ok(BootstrapOk {
                         peer,
                         num_remaining,
                     }) => {
@@ -547,6 +2126,42 @@ where
                         warn!("[KademliaEvent::Bootstrap] - Bootstrap failed: {e:?}");
                     }
                 },
+                QueryResult::GetProviders(result) => {
+                    if self.provider_lookups.contains_key(&id) {
+                        return self.handle_provider_lookup(id, result, step.last);
+                    }
+                    return self.handle_providers_found(id, result, step.last);
+                }
+                QueryResult::PutRecord(result) => {
+                    if let Some(sender) = self.record_puts.remove(&id) {
+                        let result = result
+                            .map(|_| ())
+                            .map_err(|e| anyhow!("Failed to store the record: {e:?}"));
+                        if sender.send(result).is_err() {
+                            debug!("[QueryResult::PutRecord] - the caller of {id:?} went away");
+                        }
+                    }
+                }
+                QueryResult::GetRecord(result) => {
+                    let result = match result {
+                        Ok(GetRecordOk::FoundRecord(found)) => {
+                            // the first record found is enough
+                            if let Some(mut query) = self.swarm.behaviour_mut().kad.query_mut(&id) {
+                                query.finish();
+                            }
+                            Ok(found.record.value)
+                        }
+                        Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => {
+                            Err(anyhow!("No record found"))
+                        }
+                        Err(e) => Err(anyhow!("Failed to get the record: {e:?}")),
+                    };
+                    if let Some(sender) = self.record_gets.remove(&id) {
+                        if sender.send(result).is_err() {
+                            debug!("[QueryResult::GetRecord] - the caller of {id:?} went away");
+                        }
+                    }
+                }
                 other => debug!("[KademliaEvent::OutboundQueryProgressed] - {id:?}: {other:?}"),
             },
             _ => debug!("[KademliaEvent] - {event:?}"),
@@ -575,6 +2190,20 @@ where
         Ok(())
     }
 
+    fn mdns_announce(&mut self) -> Result<()> {
+        self.lan_addr_expired = false;
+        if self
+            .swarm
+            .behaviour_mut()
+            .restart_mdns(self.config.mdns_config())?
+        {
+            info!("[mDNS] - re-announcing on the local network");
+            Ok(())
+        } else {
+            Err(anyhow!("mDNS is disabled"))
+        }
+    }
+
     fn handle_req_res(
         &mut self,
         req_res_event: RequestResponseEvent<UrsaExchangeRequest, UrsaExchangeResponse>,
@@ -586,8 +2215,47 @@ where
                     request,
                     channel,
                 } => {
+                    if self.incompatible_peers.contains(&peer) {
+                        debug!("[BehaviourEvent::RequestMessage] refusing the request from {peer}, its protocol version is incompatible");
+                        let response = ResponseType::Error(format!(
+                            "Incompatible protocol version, expected one compatible with {URSA_PROTOCOL_VERSION}"
+                        ));
+                        if self
+                            .swarm
+                            .behaviour_mut()
+                            .request_response
+                            .send_response(channel, UrsaExchangeResponse(response))
+                            .is_err()
+                        {
+                            error!("[BehaviourEvent::RequestMessage] failed to send the refusal");
+                        }
+                        return Ok(());
+                    }
+                    if !self
+                        .request_limiter
+                        .check((peer, mem::discriminant(&request.0)))
+                    {
+                        warn!("[BehaviourEvent::RequestMessage] refusing the request from {peer}, it sent too many");
+                        let response = ResponseType::Error(format!(
+                            "Too many requests, at most {} per second are served",
+                            self.config.exchange_requests_per_sec
+                        ));
+                        if self
+                            .swarm
+                            .behaviour_mut()
+                            .request_response
+                            .send_response(channel, UrsaExchangeResponse(response))
+                            .is_err()
+                        {
+                            error!("[BehaviourEvent::RequestMessage] failed to send the refusal");
+                        }
+                        return Ok(());
+                    }
                     match request.0 {
-                        RequestType::CarRequest(_) => (),
+                        RequestType::CarRequest(cid) => {
+                            info!("[BehaviourEvent::RequestMessage] car request from {peer} for {cid}");
+                            self.spawn_car_response(cid, channel);
+                        }
                         RequestType::CacheRequest(cid) => {
                             info!("[BehaviourEvent::RequestMessage] cache request from {peer} for {cid}");
 
@@ -621,6 +2289,77 @@ where
                                 error!("[BehaviourEvent::RequestMessage] failed to send response")
                             }
                         }
+                        RequestType::BlockRequest(cid) => {
+                            debug!("[BehaviourEvent::RequestMessage] block request from {peer} for {cid}");
+                            let response = match <UrsaStore<S> as Blockstore>::get(
+                                &self.store,
+                                &cid,
+                            ) {
+                                Ok(Some(data)) => ResponseType::BlockResponse(data),
+                                Ok(None) => ResponseType::Error(format!(
+                                    "The block with cid {cid} is not found"
+                                )),
+                                Err(e) => {
+                                    warn!("[BehaviourEvent::RequestMessage] failed to serve block request for {cid}: {e}");
+                                    ResponseType::Error(e.to_string())
+                                }
+                            };
+                            if self
+                                .swarm
+                                .behaviour_mut()
+                                .request_response
+                                .send_response(channel, UrsaExchangeResponse(response))
+                                .is_err()
+                            {
+                                error!(
+                                    "[BehaviourEvent::RequestMessage] failed to send BlockResponse"
+                                )
+                            }
+                        }
+                        RequestType::ListRoots { offset, limit } => {
+                            debug!("[BehaviourEvent::RequestMessage] roots listed by {peer} from {offset}");
+                            let response = match self.store.published_roots() {
+                                Ok(roots) => {
+                                    let end = offset
+                                        .saturating_add(limit.min(MAX_ROOTS_PER_PAGE))
+                                        .min(roots.len());
+                                    let page = roots.get(offset..end).unwrap_or_default().to_vec();
+                                    let next = (end < roots.len()).then_some(end);
+                                    ResponseType::RootsResponse { roots: page, next }
+                                }
+                                Err(e) => {
+                                    warn!("[BehaviourEvent::RequestMessage] failed to list the roots: {e}");
+                                    ResponseType::Error(e.to_string())
+                                }
+                            };
+                            if self
+                                .swarm
+                                .behaviour_mut()
+                                .request_response
+                                .send_response(channel, UrsaExchangeResponse(response))
+                                .is_err()
+                            {
+                                error!(
+                                    "[BehaviourEvent::RequestMessage] failed to send RootsResponse"
+                                )
+                            }
+                        }
+                        RequestType::PingRequest => {
+                            if self
+                                .swarm
+                                .behaviour_mut()
+                                .request_response
+                                .send_response(
+                                    channel,
+                                    UrsaExchangeResponse(ResponseType::PingResponse),
+                                )
+                                .is_err()
+                            {
+                                error!(
+                                    "[BehaviourEvent::RequestMessage] failed to send PingResponse"
+                                )
+                            }
+                        }
                         RequestType::StoreSummary(cache_summary) => {
                             self.peer_cached_content.put(peer, *cache_summary);
                             if self
@@ -657,23 +2396,104 @@ where
                         self.measurement_manager
                             .register_response(peer, request_id.to_string(), 0);
                     }
+                    if let ResponseType::CarResponse(_) | ResponseType::BlockResponse(_) =
+                        response.0
+                    {
+                        self.record_peer_score(peer, |score| score.served += 1);
+                    }
 
                     if let Some(request) = self.pending_responses.remove(&request_id) {
                         if request.send(Ok(response)).is_err() {
                             warn!("[RequestResponseMessage::Response] - failed to send request: {request_id:?}");
                         }
+                    } else {
+                        debug!("[RequestResponseMessage::Response] - failed to remove channel for: {request_id:?}");
                     }
-
-                    debug!("[RequestResponseMessage::Response] - failed to remove channel for: {request_id:?}");
                 }
             },
-            RequestResponseEvent::OutboundFailure { .. }
-            | RequestResponseEvent::InboundFailure { .. }
-            | RequestResponseEvent::ResponseSent { .. } => (),
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!("[RequestResponseEvent::OutboundFailure] - request {request_id} to {peer} failed: {error}");
+                let failure = RequestFailure::from(&error);
+                if failure == RequestFailure::Timeout {
+                    self.record_peer_score(peer, |score| score.timeouts += 1);
+                }
+                if let Some(request) = self.pending_responses.remove(&request_id) {
+                    if request
+                        .send(Err(NetworkError::RequestFailed { peer, failure }.into()))
+                        .is_err()
+                    {
+                        warn!("[RequestResponseEvent::OutboundFailure] - failed to send request: {request_id:?}");
+                    }
+                }
+            }
+            RequestResponseEvent::InboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                warn!("[RequestResponseEvent::InboundFailure] - request {request_id} from {peer} failed: {error}");
+            }
+            RequestResponseEvent::ResponseSent { peer, request_id } => {
+                trace!("[RequestResponseEvent::ResponseSent] - response to {request_id} sent to {peer}");
+            }
         }
         Ok(())
     }
 
+    /// Encode the dag under `cid` as a car file on the blocking pool to answer a car request,
+    /// the response is sent once it comes back to the event loop.
+    fn spawn_car_response(&self, cid: String, channel: ResponseChannel<UrsaExchangeResponse>) {
+        let store = Arc::clone(&self.store);
+        let max_size = self.config.max_car_response_size;
+        let limits = self.config.car_request_dag_limits;
+        // no need to load more blocks than fit in the response
+        let limits = DagLimits {
+            max_bytes: Some(limits.max_bytes.map_or(max_size, |max| max.min(max_size))),
+            ..limits
+        };
+        let car_response_sender = self.car_response_sender.clone();
+        tokio::task::spawn_blocking(move || {
+            let data = Cid::try_from(cid.as_str())
+                .map_err(anyhow::Error::from)
+                .and_then(|root| {
+                    if !store.blockstore().has(&root)? {
+                        return Err(anyhow!("The block with cid {root} is not found"));
+                    }
+                    store.car_bytes_with_limits(&root, max_size, &limits)
+                });
+            let response = match data {
+                Ok(data) => ResponseType::CarResponse(CarResponse { cid, data }),
+                Err(e) => {
+                    warn!("[BehaviourEvent::RequestMessage] failed to serve car request for {cid}: {e}");
+                    ResponseType::Error(e.to_string())
+                }
+            };
+            // the receiver is only dropped with the service
+            let _ = car_response_sender.send((channel, response));
+        });
+    }
+
+    /// Answer a car request with the response built by [`Self::spawn_car_response`].
+    fn send_car_response(
+        &mut self,
+        channel: ResponseChannel<UrsaExchangeResponse>,
+        response: ResponseType,
+    ) {
+        if self
+            .swarm
+            .behaviour_mut()
+            .request_response
+            .send_response(channel, UrsaExchangeResponse(response))
+            .is_err()
+        {
+            error!("[BehaviourEvent::RequestMessage] failed to send CarResponse")
+        }
+    }
+
     fn handle_graphsync(&mut self, event: GraphSyncEvent) -> Result<()> {
         match event {
             GraphSyncEvent::Completed {
@@ -716,6 +2536,8 @@ where
 
     /// Handle swarm events
     pub fn handle_swarm_event(&mut self, event: SwarmEventType<S>) -> Result<()> {
+        let span = self.swarm_event_span(&event);
+        let _entered = span.enter();
         // record basic swarm metrics
 
         event.record();
@@ -731,7 +2553,7 @@ where
                     self.handle_ping(ping_event)
                 }
                 BehaviourEvent::Bitswap(bitswap_event) => {
-                    // bitswap metrics are internal
+                    bitswap_event.record();
                     self.handle_bitswap(bitswap_event)
                 }
                 BehaviourEvent::Gossipsub(gossip_event) => {
@@ -749,24 +2571,77 @@ where
                 }
                 BehaviourEvent::RelayServer(relay_event) => {
                     relay_event.record();
-                    Ok(())
+                    self.handle_relay_server(relay_event)
+                }
+                BehaviourEvent::RelayClient(relay_event) => self.handle_relay_client(relay_event),
+                BehaviourEvent::Dcutr(dcutr_event) => {
+                    dcutr_event.record();
+                    self.handle_dcutr(dcutr_event)
                 }
-                BehaviourEvent::RelayClient(_) => Ok(()),
-                BehaviourEvent::Dcutr(_) => Ok(()),
                 BehaviourEvent::Graphsync(event) => self.handle_graphsync(event),
             },
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                self.pending_dials.remove(&peer_id);
+                if !self.is_peer_allowed(&peer_id) {
+                    warn!("[SwarmEvent::ConnectionEstablished] - {peer_id} is not allowed to connect, banning it");
+                    increment_counter!("swarm_connection_denied");
+                    self.swarm.ban_peer_id(peer_id);
+                    return Ok(());
+                }
+                if let Some(resolver) = &self.config.region_resolver {
+                    if let Some(region) = resolver.region(endpoint.get_remote_address()) {
+                        self.peers.set_region(peer_id, region);
+                    }
+                }
+                self.emit_event(NetworkEvent::ConnectionEstablished {
+                    peer: peer_id,
+                    endpoint,
+                    num_established: num_established.get(),
+                });
                 if self.peers.insert(peer_id) {
                     debug!("Peer connected: {peer_id}");
+                    // the new peer may have the blocks the others didn't
+                    self.not_found.clear();
                     self.emit_event(NetworkEvent::PeerConnected(peer_id));
                 };
-                Ok(())
+                if self.bootstrap_redials.cancel(&peer_id) {
+                    info!("Reconnected to the bootstrap node {peer_id}");
+                }
+                self.evict_peers();
+                self.handle_provider_dialed(&peer_id, true)
             }
             SwarmEvent::ConnectionClosed {
                 peer_id,
                 num_established,
+                cause,
                 ..
             } => {
+                if cause.is_some() {
+                    self.record_peer_score(peer_id, |score| score.disconnects += 1);
+                }
+                self.emit_event(NetworkEvent::ConnectionClosed {
+                    peer: peer_id,
+                    cause: cause.map(|e| e.to_string()),
+                    num_established,
+                });
+                if num_established == 0 {
+                    for fetch_peers in self.fetch_peers.values_mut() {
+                        fetch_peers.disconnected(&peer_id);
+                    }
+                    self.incompatible_peers.remove(&peer_id);
+                    if self
+                        .bootstrap_peers()
+                        .any(|(bootstrap, _)| bootstrap == peer_id)
+                    {
+                        debug!("Lost the connection to the bootstrap node {peer_id}, redialing it");
+                        self.bootstrap_redials.schedule(peer_id, Instant::now());
+                    }
+                }
                 if num_established == 0 && self.peers.remove(&peer_id) {
                     self.peer_cached_content.pop(&peer_id);
                     debug!("Peer disconnected: {peer_id}");
@@ -774,16 +2649,225 @@ where
                 }
                 Ok(())
             }
+            SwarmEvent::Dialing(peer_id) => {
+                let addresses = self.swarm.behaviour_mut().addresses_of_peer(&peer_id);
+                trace!("[SwarmEvent::Dialing] - dialing {peer_id} at {addresses:?}");
+                self.pending_dials
+                    .entry(peer_id)
+                    .or_insert_with(|| (addresses, Instant::now()));
+                Ok(())
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+            } => {
+                debug!("[SwarmEvent::OutgoingConnectionError] - failed to dial {peer_id}: {error}");
+                self.pending_dials.remove(&peer_id);
+                // the relay could not be reached to request the reservation
+                let reserving = self
+                    .relay_reservations
+                    .get(&peer_id)
+                    .map_or(false, |reservation| {
+                        reservation.expires_at(Duration::ZERO).is_none()
+                    });
+                if reserving {
+                    self.relay_failed(peer_id);
+                }
+                self.handle_provider_dialed(&peer_id, false)
+            }
+            SwarmEvent::IncomingConnection { send_back_addr, .. } => {
+                // the peer id is only known once the connection is established, incoming
+                // connections are filtered by the allow and deny lists from there
+                trace!("[SwarmEvent::IncomingConnection] - from {send_back_addr}");
+                Ok(())
+            }
+            SwarmEvent::BannedPeer { peer_id, .. } => {
+                debug!("[SwarmEvent::BannedPeer] - closed the connection with {peer_id}");
+                Ok(())
+            }
+            SwarmEvent::ListenerClosed { listener_id, .. } => {
+                let relay_peer_id = self
+                    .relay_reservations
+                    .iter()
+                    .find(|(_, reservation)| reservation.listener() == listener_id)
+                    .map(|(relay_peer_id, _)| *relay_peer_id);
+                if let Some(relay_peer_id) = relay_peer_id {
+                    self.relay_failed(relay_peer_id);
+                }
+                Ok(())
+            }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                if is_lan_address(&address) {
+                    self.lan_addr_expired = true;
+                }
+                Ok(())
+            }
+            SwarmEvent::NewListenAddr { address, .. } => {
+                // the local interface changed, let the local network know about the new address
+                if self.lan_addr_expired && is_lan_address(&address) {
+                    self.lan_addr_expired = false;
+                    if self
+                        .swarm
+                        .behaviour_mut()
+                        .restart_mdns(self.config.mdns_config())?
+                    {
+                        info!("[SwarmEvent::NewListenAddr] - re-announcing {address} with mDNS");
+                    }
+                }
+                Ok(())
+            }
             _ => Ok(()),
         }
     }
 
+    /// Span the logs of a swarm event are attached to, with its peer, cid and query id.
+    fn swarm_event_span(&self, event: &SwarmEventType<S>) -> Span {
+        let span = info_span!(
+            "swarm_event",
+            peer = field::Empty,
+            cid = field::Empty,
+            query_id = field::Empty
+        );
+        let peer = match event {
+            SwarmEvent::Behaviour(event) => match event {
+                BehaviourEvent::Bitswap(
+                    BitswapEvent::Progress(query_id, _) | BitswapEvent::Complete(query_id, _),
+                ) => {
+                    span.record("query_id", field::display(query_id));
+                    if let Some(cid) = self.bitswap_queries.get(query_id) {
+                        span.record("cid", field::display(cid));
+                    }
+                    None
+                }
+                BehaviourEvent::Identify(
+                    IdentifyEvent::Received { peer_id, .. }
+                    | IdentifyEvent::Sent { peer_id }
+                    | IdentifyEvent::Pushed { peer_id }
+                    | IdentifyEvent::Error { peer_id, .. },
+                ) => Some(peer_id),
+                BehaviourEvent::Ping(PingEvent { peer, .. }) => Some(peer),
+                BehaviourEvent::Gossipsub(
+                    libp2p::gossipsub::GossipsubEvent::Message {
+                        propagation_source: peer_id,
+                        ..
+                    }
+                    | libp2p::gossipsub::GossipsubEvent::Subscribed { peer_id, .. }
+                    | libp2p::gossipsub::GossipsubEvent::Unsubscribed { peer_id, .. }
+                    | libp2p::gossipsub::GossipsubEvent::GossipsubNotSupported { peer_id },
+                ) => Some(peer_id),
+                BehaviourEvent::Kad(KademliaEvent::OutboundQueryProgressed { id, .. }) => {
+                    span.record("query_id", field::debug(id));
+                    None
+                }
+                BehaviourEvent::Kad(KademliaEvent::RoutingUpdated { peer, .. }) => Some(peer),
+                BehaviourEvent::RequestResponse(event) => {
+                    let (peer, request_id) = match event {
+                        RequestResponseEvent::Message { peer, message } => match message {
+                            RequestResponseMessage::Request { request_id, .. }
+                            | RequestResponseMessage::Response { request_id, .. } => {
+                                (peer, request_id)
+                            }
+                        },
+                        RequestResponseEvent::OutboundFailure {
+                            peer, request_id, ..
+                        }
+                        | RequestResponseEvent::InboundFailure {
+                            peer, request_id, ..
+                        }
+                        | RequestResponseEvent::ResponseSent { peer, request_id } => {
+                            (peer, request_id)
+                        }
+                    };
+                    span.record("query_id", field::display(request_id));
+                    Some(peer)
+                }
+                _ => None,
+            },
+            SwarmEvent::ConnectionEstablished { peer_id, .. }
+            | SwarmEvent::ConnectionClosed { peer_id, .. }
+            | SwarmEvent::Dialing(peer_id)
+            | SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                ..
+            }
+            | SwarmEvent::BannedPeer { peer_id, .. } => Some(peer_id),
+            _ => None,
+        };
+        if let Some(peer) = peer {
+            span.record("peer", field::display(peer));
+        }
+        span
+    }
+
+    /// Span the logs of a command are attached to, carrying the peer or cid it is about.
+    fn command_span(command: &NetworkCommand) -> Span {
+        let span = info_span!(
+            "network_command",
+            peer = field::Empty,
+            cid = field::Empty,
+            query_id = field::Empty
+        );
+        match command {
+            NetworkCommand::GetBitswap { cid, .. }
+            | NetworkCommand::TraceBitswap { cid, .. }
+            | NetworkCommand::GetBitswapWithDiscovery { cid, .. }
+            | NetworkCommand::GetBlock { cid, .. }
+            | NetworkCommand::Put { cid, .. }
+            | NetworkCommand::Fetch { cid, .. }
+            | NetworkCommand::HasFullDag { cid, .. }
+            | NetworkCommand::CancelBitswap { cid, .. }
+            | NetworkCommand::StartProviding { cid, .. }
+            | NetworkCommand::FindProviders { cid, .. } => {
+                span.record("cid", field::display(cid));
+            }
+            NetworkCommand::DropRelayReservation {
+                relay_peer_id: peer_id,
+                ..
+            }
+            | NetworkCommand::IsConnected { peer_id, .. }
+            | NetworkCommand::ProtectPeer { peer_id, .. }
+            | NetworkCommand::UnprotectPeer { peer_id, .. }
+            | NetworkCommand::SendRequest { peer_id, .. }
+            | NetworkCommand::GossipsubMessage { peer_id, .. } => {
+                span.record("peer", field::display(peer_id));
+            }
+            _ => {}
+        }
+        span
+    }
+
     /// Handle commands
     pub fn handle_command(&mut self, command: NetworkCommand) -> Result<()> {
+        let span = Self::command_span(&command);
+        let _entered = span.enter();
         match command {
-            NetworkCommand::GetBitswap { cid, sender } => {
+            NetworkCommand::GetBitswap {
+                cid,
+                max_parallel_blocks,
+                trace,
+                max_bytes,
+                sender,
+            } => {
+                if self.needs_dag_count(&cid) {
+                    let command = NetworkCommand::GetBitswap {
+                        cid,
+                        max_parallel_blocks,
+                        trace,
+                        max_bytes,
+                        sender,
+                    };
+                    self.spawn_dag_count(cid, DagCount::Fetch(command));
+                    return Ok(());
+                }
+                if self.is_recently_not_found(&cid) {
+                    debug!("[NetworkCommand::GetBitswap] - {cid} was recently not found");
+                    return sender
+                        .send(Err(NetworkError::RecentlyNotFound(cid).into()))
+                        .map_err(|_| anyhow!("Failed to get a bitswap block!"));
+                }
                 info!("Getting cid {cid} via bitswap");
 
+                let peer_selection_start = Instant::now();
                 let peers = self.peers.peers();
 
                 if peers.is_empty() {
@@ -798,8 +2882,17 @@ where
                 } else {
                     if let Some(chans) = self.response_channels.get_mut(&cid) {
                         chans.push(sender);
+                    } else if self.pending_query_count() >= self.config.max_pending_queries {
+                        warn!("[NetworkCommand::GetBitswap] - rejecting {cid}, too many pending queries");
+                        return sender
+                            .send(Err(NetworkError::TooManyPendingQueries(
+                                self.config.max_pending_queries,
+                            )
+                            .into()))
+                            .map_err(|_| anyhow!("Failed to get a bitswap block!"));
                     } else {
                         self.response_channels.insert(cid, vec![sender]);
+                        self.record_sync_baseline(cid);
                     }
 
                     let peers = peers
@@ -812,11 +2905,65 @@ where
                         })
                         .copied()
                         .collect();
+                    // the low scored peers are only asked if there are not enough others
+                    let fanout = self.config.bitswap_fanout;
+                    let (peers, low_scored) = self.peer_scores.partition(peers);
+                    let mut peers = self.peers.select_providers(&cid, peers, fanout);
+                    if peers.len() < fanout {
+                        let left = fanout - peers.len();
+                        peers.extend(self.peers.select_providers(&cid, low_scored, left));
+                    }
+
+                    if let Some(trace) = trace {
+                        if let Some(timer) = self.fetch_timers.get_mut(&cid) {
+                            timer.add_sender(trace);
+                        } else {
+                            let timer = FetchTimer::new(
+                                peer_selection_start.elapsed(),
+                                &self.store_timings,
+                                trace,
+                            );
+                            self.fetch_timers.insert(cid, timer);
+                        }
+                    }
+
+                    // the callers are resolved together once the query in flight completes
+                    if self.active_queries.contains_key(&cid)
+                        || self.parallel_syncs.contains_key(&cid)
+                    {
+                        debug!(
+                            "[NetworkCommand::GetBitswap] - joining the query in flight for {cid}"
+                        );
+                        return Ok(());
+                    }
+
+                    // blocks are fetched one by one to count the bytes pulled against the budget
+                    if max_parallel_blocks.is_some() || max_bytes.is_some() {
+                        let max_parallel = max_parallel_blocks.unwrap_or_else(|| {
+                            NonZeroUsize::new(BUDGETED_SYNC_PARALLEL_BLOCKS).unwrap()
+                        });
+                        return self.start_parallel_sync(cid, peers, max_parallel, max_bytes);
+                    }
 
+                    if self.traced_cids.contains(&cid) {
+                        let want = BitswapTrace::WantSent {
+                            block: cid,
+                            peers: peers.clone(),
+                        };
+                        self.trace_bitswap(cid, want);
+                    }
+                    let fetch_peers =
+                        FetchPeers::new(peers.iter().copied().collect(), self.peers.peers());
                     let query = self.swarm.behaviour_mut().sync_block(cid, peers);
 
                     if let Ok(query_id) = query {
+                        span.record("query_id", field::display(query_id));
+                        debug!(
+                            "[NetworkCommand::GetBitswap] - started the bitswap query for {cid}"
+                        );
+                        self.fetch_peers.insert(query_id, fetch_peers);
                         self.bitswap_queries.insert(query_id, cid);
+                        self.active_queries.insert(cid, query_id);
                         self.emit_event(NetworkEvent::BitswapWant { cid, query_id });
                     } else {
                         error!(
@@ -826,10 +2973,130 @@ where
                     }
                 }
             }
+            NetworkCommand::TraceBitswap {
+                cid,
+                enabled,
+                sender,
+            } => {
+                if enabled {
+                    info!("[NetworkCommand::TraceBitswap] - tracing the bitswap fetches of {cid}");
+                    self.traced_cids.insert(cid);
+                } else {
+                    self.traced_cids.remove(&cid);
+                }
+                sender
+                    .send(())
+                    .map_err(|_| anyhow!("Failed to send the bitswap trace toggle result"))?;
+            }
+            NetworkCommand::GetBitswapWithDiscovery { cid, sender } => {
+                if self.needs_dag_count(&cid) {
+                    let command = NetworkCommand::GetBitswapWithDiscovery { cid, sender };
+                    self.spawn_dag_count(cid, DagCount::Fetch(command));
+                    return Ok(());
+                }
+                if self.is_recently_not_found(&cid) {
+                    return sender
+                        .send(Err(NetworkError::RecentlyNotFound(cid).into()))
+                        .map_err(|_| anyhow!("Failed to get a bitswap block!"));
+                }
+                if !self.response_channels.contains_key(&cid)
+                    && self.pending_query_count() >= self.config.max_pending_queries
+                {
+                    warn!("[NetworkCommand::GetBitswapWithDiscovery] - rejecting {cid}, too many pending queries");
+                    return sender
+                        .send(Err(NetworkError::TooManyPendingQueries(
+                            self.config.max_pending_queries,
+                        )
+                        .into()))
+                        .map_err(|_| anyhow!("Failed to get a bitswap block!"));
+                }
+
+                self.provider_discoveries.entry(cid).or_default();
+                if !self.peers.peers().is_empty() {
+                    return self.handle_command(NetworkCommand::GetBitswap {
+                        cid,
+                        max_parallel_blocks: None,
+                        trace: None,
+                        max_bytes: None,
+                        sender,
+                    });
+                }
+
+                // no peer to ask, look the providers up right away
+                info!("Getting cid {cid} from the providers found in the DHT");
+                if !self.response_channels.contains_key(&cid) {
+                    self.record_sync_baseline(cid);
+                }
+                self.response_channels.entry(cid).or_default().push(sender);
+                self.poll_provider_discovery(cid)?;
+            }
+            NetworkCommand::GetBlock { cid, sender } => {
+                if self.has_local_block(&cid) {
+                    return sender
+                        .send(self.read_local_block(&cid))
+                        .map_err(|_| anyhow!("Failed to send the block {cid}"));
+                }
+                if self.is_recently_not_found(&cid) {
+                    debug!("[NetworkCommand::GetBlock] - {cid} was recently not found");
+                    return sender
+                        .send(Err(NetworkError::RecentlyNotFound(cid).into()))
+                        .map_err(|_| anyhow!("Failed to send the block {cid}"));
+                }
+                let peers = self.peers.peers();
+                if peers.is_empty() {
+                    return sender
+                        .send(Err(anyhow!(
+                            "There were no peers provided and the block does not exist in local store"
+                        )))
+                        .map_err(|_| anyhow!("Failed to send the block {cid}"));
+                }
+                // the callers are answered together once the query in flight completes
+                if let Some(chans) = self.block_get_channels.get_mut(&cid) {
+                    debug!("[NetworkCommand::GetBlock] - joining the query in flight for {cid}");
+                    chans.push(sender);
+                    return Ok(());
+                }
+                if self.pending_query_count() >= self.config.max_pending_queries {
+                    warn!("[NetworkCommand::GetBlock] - rejecting {cid}, too many pending queries");
+                    return sender
+                        .send(Err(NetworkError::TooManyPendingQueries(
+                            self.config.max_pending_queries,
+                        )
+                        .into()))
+                        .map_err(|_| anyhow!("Failed to send the block {cid}"));
+                }
+                let fanout = self.config.bitswap_fanout;
+                let (peers, low_scored) = self.peer_scores.partition(peers);
+                let mut peers = self.peers.select_providers(&cid, peers, fanout);
+                if peers.len() < fanout {
+                    let left = fanout - peers.len();
+                    peers.extend(self.peers.select_providers(&cid, low_scored, left));
+                }
+                let fetch_peers =
+                    FetchPeers::new(peers.iter().copied().collect(), self.peers.peers());
+                let query_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .get_block(cid, peers.into_iter())?;
+                span.record("query_id", field::display(query_id));
+                debug!("[NetworkCommand::GetBlock] - started the bitswap query for {cid}");
+                self.fetch_peers.insert(query_id, fetch_peers);
+                self.block_gets.insert(query_id, cid);
+                self.block_get_channels.insert(cid, vec![sender]);
+            }
             NetworkCommand::Put { cid, sender } => {
+                // record the intent first, so providing resumes if the node restarts
+                if let Err(e) = self.store.record_provide_intent(&cid) {
+                    return sender
+                        .send(Err(e))
+                        .map_err(|e| anyhow!("PUT failed: {e:?}."));
+                }
                 // replicate content
                 let swarm = self.swarm.behaviour_mut();
                 for peer in self.peers.replication_set() {
+                    if self.incompatible_peers.contains(&peer) {
+                        continue;
+                    }
                     info!("[NetworkCommand::Put] - sending cache request to peer {peer} for {cid}");
                     swarm
                         .request_response
@@ -837,6 +3104,15 @@ where
                 }
                 // update cache summary and share it with the connected peers
                 self.update_and_share_cache_summary(&cid)?;
+                // let the nodes that are not connected find the content
+                if let Err(e) = self
+                    .swarm
+                    .behaviour_mut()
+                    .kad
+                    .start_providing(provider_key(&cid))
+                {
+                    warn!("[NetworkCommand::Put] - failed to provide {cid}: {e:?}");
+                }
 
                 sender
                     .send(Ok(()))
@@ -847,6 +3123,16 @@ where
                     .send(self.peers.peers())
                     .map_err(|_| anyhow!("Failed to get Libp2p peers!"))?;
             }
+            NetworkCommand::IsConnected { peer_id, sender } => {
+                sender
+                    .send(self.swarm.is_connected(&peer_id))
+                    .map_err(|_| anyhow!("Failed to send the connection status of {peer_id}"))?;
+            }
+            NetworkCommand::ExportPeers { sender } => {
+                sender
+                    .send(self.address_book())
+                    .map_err(|_| anyhow!("Failed to send the known peers"))?;
+            }
             NetworkCommand::GetListenerAddresses { sender } => {
                 let mut addresses: Vec<&Multiaddr> = self.swarm.listeners().collect();
                 if let Some(value) = &self.public_addr {
@@ -856,11 +3142,263 @@ where
                     .send(addresses.into_iter().cloned().collect())
                     .map_err(|_| anyhow!("Failed to get listener addresses from network"))?;
             }
+            NetworkCommand::Fetch {
+                cid,
+                strategies,
+                sender,
+            } => {
+                let store = self.store.clone();
+                let command_sender = self.command_sender.clone();
+                let max_car_size = self.config.max_car_response_size;
+                tokio::task::spawn(async move {
+                    let result =
+                        retrieval::fetch(cid, strategies, store, command_sender, max_car_size)
+                            .await;
+                    if sender.send(result).is_err() {
+                        warn!("[NetworkCommand::Fetch] - failed to send the result for {cid}");
+                    }
+                });
+            }
+            NetworkCommand::MdnsAnnounce { sender } => {
+                sender
+                    .send(self.mdns_announce())
+                    .map_err(|_| anyhow!("Failed to send the mDNS announce result"))?;
+            }
+            NetworkCommand::HasFullDag { cid, sender } => {
+                let store = Arc::clone(&self.store);
+                tokio::task::spawn_blocking(move || {
+                    if sender.send(store.has_full_dag(&cid)).is_err() {
+                        debug!("[NetworkCommand::HasFullDag] - the caller of {cid} went away");
+                    }
+                });
+            }
+            NetworkCommand::GetRelayReservations { sender } => {
+                let now = Instant::now();
+                let lifetime = Duration::from_secs(self.config.relay_reservation_duration);
+                let reservations = self
+                    .relay_reservations
+                    .iter()
+                    .filter_map(|(relay_peer_id, reservation)| {
+                        let expires_at = reservation.expires_at(lifetime)?;
+                        Some((*relay_peer_id, expires_at.saturating_duration_since(now)))
+                    })
+                    .collect();
+                sender
+                    .send(reservations)
+                    .map_err(|_| anyhow!("Failed to get the relay reservations"))?;
+            }
+            NetworkCommand::DropRelayReservation {
+                relay_peer_id,
+                sender,
+            } => {
+                let result = if self.drop_relay_reservation(relay_peer_id) {
+                    info!("[NetworkCommand::DropRelayReservation] - dropped the reservation on {relay_peer_id}");
+                    Ok(())
+                } else {
+                    Err(anyhow!("No reservation on the relay {relay_peer_id}"))
+                };
+                sender
+                    .send(result)
+                    .map_err(|_| anyhow!("Failed to send the relay reservation drop result"))?;
+            }
+            NetworkCommand::Bootstrap { sender } => {
+                let result = self.bootstrap();
+                if result.is_ok() {
+                    info!("[NetworkCommand::Bootstrap] - bootstrapping into the network");
+                }
+                sender
+                    .send(result)
+                    .map_err(|_| anyhow!("Failed to send the bootstrap result"))?;
+            }
+            NetworkCommand::ProtectPeer { peer_id, sender } => {
+                if self.peers.protect(peer_id) {
+                    info!("[NetworkCommand::ProtectPeer] - protecting {peer_id}");
+                }
+                sender
+                    .send(())
+                    .map_err(|_| anyhow!("Failed to send the peer protection result"))?;
+            }
+            NetworkCommand::UnprotectPeer { peer_id, sender } => {
+                if self.peers.unprotect(&peer_id) {
+                    info!("[NetworkCommand::UnprotectPeer] - unprotecting {peer_id}");
+                    self.evict_peers();
+                }
+                sender
+                    .send(())
+                    .map_err(|_| anyhow!("Failed to send the peer protection result"))?;
+            }
+            NetworkCommand::CancelAllQueries { sender } => {
+                let cancelled = self.cancel_all_queries();
+                info!("[NetworkCommand::CancelAllQueries] - cancelled {cancelled} queries");
+                sender
+                    .send(cancelled)
+                    .map_err(|_| anyhow!("Failed to send the number of cancelled queries"))?;
+            }
+            NetworkCommand::CancelBitswap { cid, sender } => {
+                let cancelled = self.cancel_query(&cid);
+                info!("[NetworkCommand::CancelBitswap] - cancelled fetch of {cid}: {cancelled}");
+                sender
+                    .send(cancelled)
+                    .map_err(|_| anyhow!("Failed to send the bitswap cancellation result"))?;
+            }
+            NetworkCommand::SwitchNetwork {
+                network_name,
+                sender,
+            } => {
+                sender
+                    .send(self.switch_network(network_name))
+                    .map_err(|_| anyhow!("Failed to send the network switch result"))?;
+            }
+            NetworkCommand::StartProviding { cid, sender } => {
+                // record the intent first, so providing resumes if the node restarts
+                let result = self.store.record_provide_intent(&cid).and_then(|_| {
+                    self.swarm
+                        .behaviour_mut()
+                        .kad
+                        .start_providing(provider_key(&cid))
+                        .map(|_| ())
+                        .map_err(|e| anyhow!("Failed to provide {cid}: {e:?}"))
+                });
+                sender
+                    .send(result)
+                    .map_err(|_| anyhow!("Failed to send the start providing result"))?;
+            }
+            NetworkCommand::FindProviders { cid, sender } => {
+                info!("[NetworkCommand::FindProviders] - looking up the providers of {cid}");
+                let query_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .kad
+                    .get_providers(provider_key(&cid));
+                self.provider_lookups
+                    .insert(query_id, (HashSet::new(), sender));
+            }
+            NetworkCommand::PutRecord { key, value, sender } => {
+                let max_size = self.config.kad_max_record_size;
+                if value.len() > max_size {
+                    return sender
+                        .send(Err(NetworkError::RecordTooLarge {
+                            size: value.len(),
+                            max_size,
+                        }
+                        .into()))
+                        .map_err(|_| anyhow!("Failed to send the put record result"));
+                }
+                let record = Record::new(RecordKey::new(&key), value);
+                match self
+                    .swarm
+                    .behaviour_mut()
+                    .kad
+                    .put_record(record, Quorum::One)
+                {
+                    Ok(query_id) => {
+                        self.record_puts.insert(query_id, sender);
+                    }
+                    Err(e) => sender
+                        .send(Err(anyhow!("Failed to store the record: {e:?}")))
+                        .map_err(|_| anyhow!("Failed to send the put record result"))?,
+                }
+            }
+            NetworkCommand::GetRecord { key, sender } => {
+                let query_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .kad
+                    .get_record(RecordKey::new(&key));
+                self.record_gets.insert(query_id, sender);
+            }
+            NetworkCommand::GetProvideIntents { sender } => {
+                sender
+                    .send(self.store.provide_intents())
+                    .map_err(|_| anyhow!("Failed to get the provide intents"))?;
+            }
+            NetworkCommand::RunGc { sender } => {
+                // the blocks of the dags still syncing are not all linked yet
+                let syncing: Vec<Cid> = self
+                    .response_channels
+                    .keys()
+                    .chain(self.block_get_channels.keys())
+                    .chain(self.parallel_syncs.keys())
+                    .copied()
+                    .collect();
+                let store = Arc::clone(&self.store);
+                tokio::task::spawn_blocking(move || {
+                    let stats = store.gc_keeping(&syncing);
+                    if let Ok(stats) = &stats {
+                        info!(
+                            "[NetworkCommand::RunGc] - removed {} blocks, {} bytes",
+                            stats.blocks, stats.bytes
+                        );
+                    }
+                    if sender.send(stats).is_err() {
+                        warn!("[NetworkCommand::RunGc] - failed to send the garbage collection result");
+                    }
+                });
+            }
+            NetworkCommand::StoreLatency { sender } => {
+                sender
+                    .send(self.store.latency())
+                    .map_err(|_| anyhow!("Failed to get the store latency"))?;
+            }
+            NetworkCommand::GetNetworkInfo { sender } => {
+                sender
+                    .send(self.network_info())
+                    .map_err(|_| anyhow!("Failed to get the network info"))?;
+            }
+            NetworkCommand::GetHealth { sender } => {
+                sender
+                    .send(self.health())
+                    .map_err(|_| anyhow!("Failed to get the health"))?;
+            }
+            NetworkCommand::GetBandwidth { sender } => {
+                sender
+                    .send(self.bandwidth())
+                    .map_err(|_| anyhow!("Failed to get the bandwidth"))?;
+            }
+            NetworkCommand::GetGossipStats { sender } => {
+                sender
+                    .send(self.gossip_stats())
+                    .map_err(|_| anyhow!("Failed to get the gossip stats"))?;
+            }
+            NetworkCommand::DialQueue { sender } => {
+                let dials = self
+                    .pending_dials
+                    .iter()
+                    .map(|(peer_id, (addresses, started))| PendingDial {
+                        peer_id: *peer_id,
+                        addresses: addresses.clone(),
+                        pending_for: started.elapsed(),
+                    })
+                    .collect();
+                sender
+                    .send(dials)
+                    .map_err(|_| anyhow!("Failed to get the dial queue"))?;
+            }
+            NetworkCommand::PendingQueryCount { sender } => {
+                sender
+                    .send(self.pending_query_count())
+                    .map_err(|_| anyhow!("Failed to get the pending query count"))?;
+            }
+            NetworkCommand::Shutdown { sender } => {
+                self.shutdown_senders.push(sender);
+            }
+            NetworkCommand::GetConfig { sender } => {
+                sender
+                    .send(self.config.clone())
+                    .map_err(|_| anyhow!("Failed to get the network config"))?;
+            }
             NetworkCommand::SendRequest {
                 peer_id,
                 request,
                 channel,
             } => {
+                if self.incompatible_peers.contains(&peer_id) {
+                    return channel
+                        .send(Err(anyhow!(
+                            "The protocol version of {peer_id} is incompatible with {URSA_PROTOCOL_VERSION}"
+                        )))
+                        .map_err(|_| anyhow!("Failed to send the request result"));
+                }
                 let request_id = self
                     .swarm
                     .behaviour_mut()
@@ -912,10 +3450,16 @@ where
                     let publish = self
                         .swarm
                         .behaviour_mut()
-                        .publish(Topic::new(topic.into_string()), data.to_vec());
-
-                    if let Err(e) = &publish {
-                        warn!("Publish error: {e:?}");
+                        .publish(Topic::new(topic.clone().into_string()), data.to_vec());
+
+                    match &publish {
+                        Ok(_) => {
+                            self.gossip_message_counts
+                                .entry(topic)
+                                .or_default()
+                                .messages_published += 1;
+                        }
+                        Err(e) => warn!("Publish error: {e:?}"),
                     }
 
                     sender
@@ -931,6 +3475,9 @@ where
         self.cached_content.insert(cid.to_bytes());
         let swarm = self.swarm.behaviour_mut();
         for peer in self.peers.ref_peers() {
+            if self.incompatible_peers.contains(peer) {
+                continue;
+            }
             let request = UrsaExchangeRequest(RequestType::StoreSummary(Box::new(
                 self.cached_content.clone(),
             )));
@@ -970,40 +3517,458 @@ where
         }
     }
 
+    fn gossip_stats(&self) -> HashMap<String, GossipTopicStats> {
+        let behaviour = self.swarm.behaviour();
+        let all_peers = behaviour.topic_peer_counts();
+        behaviour
+            .mesh_peer_counts()
+            .into_iter()
+            .map(|(topic, mesh_peers)| {
+                let counts = self
+                    .gossip_message_counts
+                    .get(&topic)
+                    .cloned()
+                    .unwrap_or_default();
+                let stats = GossipTopicStats {
+                    mesh_peers,
+                    all_peers: all_peers.get(&topic).copied().unwrap_or_default(),
+                    ..counts
+                };
+                (topic.into_string(), stats)
+            })
+            .collect()
+    }
+
+    /// Remote peers may subscribe to any topic, so only the churn on the topics of the node is
+    /// recorded to keep the number of topic labels bounded.
+    fn record_subscription_churn(&self, topic: &TopicHash, subscribed: bool) {
+        if self.swarm.behaviour().is_subscribed(topic) {
+            GossipSubscriptionChurn {
+                topic: topic.to_string(),
+                subscribed,
+            }
+            .record();
+        }
+    }
+
+    fn record_gossip_mesh_size(&self) {
+        for (topic, peers) in self.swarm.behaviour().mesh_peer_counts() {
+            GossipMeshSize {
+                topic: topic.into_string(),
+                peers,
+            }
+            .record();
+        }
+    }
+
+    fn record_store_size(&self) {
+        let store = Arc::clone(&self.store);
+        // the first count reads the whole block index
+        tokio::task::spawn_blocking(move || match store.size() {
+            Ok(size) => StoreSize {
+                blocks: size.blocks,
+                bytes: size.bytes,
+            }
+            .record(),
+            Err(e) => warn!("[record_store_size] - failed to get the store size: {e:?}"),
+        });
+    }
+
+    /// The bootstrap nodes whose address has a peer id, with their address.
+    fn bootstrap_peers(&self) -> impl Iterator<Item = (PeerId, &Multiaddr)> {
+        self.bootstraps.iter().filter_map(|addr| {
+            addr.iter()
+                .filter_map(|protocol| match protocol {
+                    Protocol::P2p(mh) => PeerId::from_multihash(mh).ok(),
+                    _ => None,
+                })
+                .last()
+                .map(|peer_id| (peer_id, addr))
+        })
+    }
+
+    /// The data of the block `cid` in the local store.
+    fn read_local_block(&self, cid: &Cid) -> Result<Vec<u8>> {
+        <UrsaStore<S> as Blockstore>::get(&self.store, cid)?
+            .ok_or_else(|| anyhow!("The block with cid {cid} is not found"))
+    }
+
+    /// Whether the block `cid` itself is in the local store.
+    fn has_local_block(&self, cid: &Cid) -> bool {
+        self.store.blockstore().has(cid).unwrap_or_else(|e| {
+            warn!("Failed to check whether {cid} is in the local store: {e:?}");
+            false
+        })
+    }
+
+    /// Dial the disconnected bootstrap nodes whose backoff elapsed.
+    fn redial_bootstraps(&mut self) {
+        for peer_id in self.bootstrap_redials.due(Instant::now()) {
+            let addresses: Vec<Multiaddr> = self
+                .bootstrap_peers()
+                .filter(|(bootstrap, _)| *bootstrap == peer_id)
+                .map(|(_, addr)| addr.clone())
+                .collect();
+            info!("Redialing the bootstrap node {peer_id}");
+            let opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+            if let Err(e) = self.swarm.dial(opts) {
+                warn!("Failed to redial the bootstrap node {peer_id}: {e}");
+            }
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        let listening = self.swarm.listeners().next().is_some();
+        let bootstrap_connected = self
+            .bootstrap_peers()
+            .any(|(peer_id, _)| self.swarm.is_connected(&peer_id));
+        Health {
+            listening,
+            num_peers: self.swarm.network_info().num_peers(),
+            bootstrap_connected,
+            nat_status: self.swarm.behaviour().nat_status().into(),
+            since_last_gossip: self.last_gossip.map(|last| last.elapsed()),
+            ready: listening && (bootstrap_connected || self.config.bootstrapper),
+        }
+    }
+
+    fn bandwidth(&self) -> Bandwidth {
+        Bandwidth {
+            inbound: self.bandwidth.total_inbound(),
+            outbound: self.bandwidth.total_outbound(),
+        }
+    }
+
+    fn record_bandwidth(&self) {
+        let Bandwidth { inbound, outbound } = self.bandwidth();
+        BandwidthMetrics { inbound, outbound }.record();
+    }
+
+    fn network_info(&self) -> NetworkInfo {
+        let info = self.swarm.network_info();
+        let counters = info.connection_counters();
+        NetworkInfo {
+            num_peers: info.num_peers(),
+            num_connections: counters.num_connections(),
+            num_pending_incoming: counters.num_pending_incoming(),
+            num_pending_outgoing: counters.num_pending_outgoing(),
+            num_established_incoming: counters.num_established_incoming(),
+            num_established_outgoing: counters.num_established_outgoing(),
+            nat_status: self.swarm.behaviour().nat_status().into(),
+        }
+    }
+
+    /// Cancel every bitswap query in flight, failing their callers with [`NetworkError::Cancelled`].
+    /// Returns the number of queries cancelled.
+    fn cancel_all_queries(&mut self) -> usize {
+        let cancelled = self.bitswap_queries.len() + self.block_gets.len();
+        for (query_id, _) in self.bitswap_queries.drain() {
+            self.swarm.behaviour_mut().cancel(query_id);
+        }
+        for (query_id, _) in self.block_gets.drain() {
+            self.swarm.behaviour_mut().cancel(query_id);
+        }
+        for (_, chans) in self.block_get_channels.drain() {
+            for chan in chans {
+                // the caller may have stopped waiting for the block
+                let _ = chan.send(Err(NetworkError::Cancelled.into()));
+            }
+        }
+        self.active_queries.clear();
+        self.fetch_peers.clear();
+        self.parallel_syncs.clear();
+        for (_, discovery) in self.provider_discoveries.drain() {
+            if let Some(mut query) = discovery
+                .lookup()
+                .and_then(|id| self.swarm.behaviour_mut().kad.query_mut(&id))
+            {
+                query.finish();
+            }
+        }
+        self.fetch_timers.clear();
+        self.sync_baselines.clear();
+        self.resolving_dags.clear();
+        for (_, chans) in self.response_channels.drain() {
+            for chan in chans {
+                // the caller may have stopped waiting for the block
+                let _ = chan.send(Err(NetworkError::Cancelled.into()));
+            }
+        }
+        cancelled
+    }
+
+    /// Fail every caller still waiting on the network with `reason`, when the service loop
+    /// exits without answering them.
+    fn fail_pending(&mut self, reason: &str) {
+        for (_, chans) in self.response_channels.drain() {
+            for chan in chans {
+                // the caller may have stopped waiting for the block
+                let _ = chan.send(Err(anyhow!("{reason}")));
+            }
+        }
+        for (_, chans) in self.block_get_channels.drain() {
+            for chan in chans {
+                let _ = chan.send(Err(anyhow!("{reason}")));
+            }
+        }
+        self.block_gets.clear();
+        for (_, chan) in self.pending_responses.drain() {
+            let _ = chan.send(Err(anyhow!("{reason}")));
+        }
+        for (_, (_, sender)) in self.provider_lookups.drain() {
+            let _ = sender.send(Err(anyhow!("{reason}")));
+        }
+        for (_, sender) in self.record_puts.drain() {
+            let _ = sender.send(Err(anyhow!("{reason}")));
+        }
+        for (_, sender) in self.record_gets.drain() {
+            let _ = sender.send(Err(anyhow!("{reason}")));
+        }
+        // the callers of the fetches are in `response_channels`
+        self.provider_discoveries.clear();
+        self.sync_baselines.clear();
+        self.resolving_dags.clear();
+        self.fetch_timers.clear();
+    }
+
+    /// Cancel the bitswap queries and provider lookup fetching `cid`, failing its callers.
+    /// Returns whether anything was in flight for `cid`.
+    fn cancel_query(&mut self, cid: &Cid) -> bool {
+        let query_ids: Vec<QueryId> = self
+            .bitswap_queries
+            .iter()
+            .filter(|(_, query_cid)| *query_cid == cid)
+            .map(|(query_id, _)| *query_id)
+            .collect();
+        for query_id in &query_ids {
+            self.swarm.behaviour_mut().cancel(*query_id);
+            self.bitswap_queries.remove(query_id);
+            self.fetch_peers.remove(query_id);
+        }
+        self.active_queries.remove(cid);
+        let sync = self.cancel_parallel_sync(cid);
+        let discovery = self.provider_discoveries.remove(cid);
+        if let Some(mut query) = discovery
+            .as_ref()
+            .and_then(|discovery| discovery.lookup())
+            .and_then(|id| self.swarm.behaviour_mut().kad.query_mut(&id))
+        {
+            query.finish();
+        }
+        let block_get = self
+            .block_gets
+            .iter()
+            .find(|(_, block_cid)| *block_cid == cid)
+            .map(|(query_id, _)| *query_id);
+        if let Some(query_id) = block_get {
+            self.swarm.behaviour_mut().cancel(query_id);
+            self.block_gets.remove(&query_id);
+            self.fetch_peers.remove(&query_id);
+        }
+        let block_get_chans = self.block_get_channels.remove(cid).unwrap_or_default();
+        self.fetch_timers.remove(cid);
+        self.sync_baselines.remove(cid);
+        self.resolving_dags.remove(cid);
+        let chans = self.response_channels.remove(cid).unwrap_or_default();
+        let cancelled = !query_ids.is_empty()
+            || sync.is_some()
+            || discovery.is_some()
+            || !chans.is_empty()
+            || block_get.is_some();
+        for chan in chans {
+            // the caller may have stopped waiting for the block
+            let _ = chan.send(Err(NetworkError::Cancelled.into()));
+        }
+        for chan in block_get_chans {
+            let _ = chan.send(Err(NetworkError::Cancelled.into()));
+        }
+        cancelled
+    }
+
+    /// The peers in the kademlia routing table with their addresses.
+    fn address_book(&mut self) -> AddressBook {
+        let mut address_book = vec![];
+        for bucket in self.swarm.behaviour_mut().kad.kbuckets() {
+            for entry in bucket.iter() {
+                let addrs = entry.node.value.iter().cloned().collect();
+                address_book.push((*entry.node.key.preimage(), addrs));
+            }
+        }
+        address_book
+    }
+
+    /// Handle the commands already queued, then leave the gossipsub topics and fail
+    /// everything that is still waiting on the network.
+    /// The known peers are saved to the peerstore if one is configured.
+    fn shutdown(&mut self) {
+        info!("Shutting down the network service");
+        self.command_receiver.close();
+        while let Ok(command) = self.command_receiver.try_recv() {
+            if let Err(e) = self.handle_command(command) {
+                warn!("[Shutdown] - failed to handle a pending command: {e:?}");
+            }
+        }
+
+        let topics: Vec<TopicHash> = self.swarm.behaviour().gossipsub.topics().cloned().collect();
+        for topic in topics {
+            if let Err(e) = self
+                .swarm
+                .behaviour_mut()
+                .unsubscribe(&Topic::new(topic.into_string()))
+            {
+                warn!("[Shutdown] - failed to unsubscribe: {e:?}");
+            }
+        }
+
+        if let Some(path) = self.config.peerstore_path.clone() {
+            let address_book = self.address_book();
+            match save_address_book(&path, &address_book) {
+                Ok(()) => info!("Saved {} peers to {}", address_book.len(), path.display()),
+                Err(e) => warn!("[Shutdown] - failed to save the known peers: {e:?}"),
+            }
+        }
+
+        self.cancel_all_queries();
+        self.fail_pending("The network service shut down");
+
+        for sender in self.shutdown_senders.drain(..) {
+            // the caller may have stopped waiting for the shutdown
+            let _ = sender.send(());
+        }
+    }
+
+    /// Provide again the content the node was asked to provide before it restarted,
+    /// forgetting the content that is no longer in the store.
+    fn replay_provide_intents(&mut self) -> Result<()> {
+        for cid in self.store.provide_intents()? {
+            if !self.store.blockstore().has(&cid)? {
+                info!("Dropping the intent to provide {cid}, it is no longer in the store");
+                self.store.remove_provide_intent(&cid)?;
+                continue;
+            }
+            info!("Resuming providing {cid}");
+            self.cached_content.insert(cid.to_bytes());
+            if let Err(e) = self
+                .swarm
+                .behaviour_mut()
+                .kad
+                .start_providing(provider_key(&cid))
+            {
+                warn!("Failed to provide {cid}: {e:?}");
+            }
+        }
+        Ok(())
+    }
+
     /// Start the ursa network service loop.
     ///
     /// Poll `swarm` and `command_receiver` from [`UrsaService`].
     /// - `swarm` handles the network events [Event].
     /// - `command_receiver` handles inbound commands [Command].
+    ///
+    /// Returns once a [`NetworkCommand::Shutdown`] is handled.
     pub async fn start(mut self) -> Result<()> {
         info!(
             "Node starting up with peerId {:?}",
             self.swarm.local_peer_id()
         );
+        self.replay_provide_intents()?;
 
         let kad_walk_delay = sleep(Duration::from_secs(self.kad_walk_interval));
         tokio::pin!(kad_walk_delay);
+        let mut relay_renewal = interval(RELAY_RENEWAL_CHECK_INTERVAL);
+        let mut bootstrap_redial = interval(BOOTSTRAP_REDIAL_CHECK_INTERVAL);
+        let mut gossip_mesh_metrics = interval(GOSSIP_MESH_METRICS_INTERVAL);
+        let mut store_size_metrics = interval(STORE_SIZE_METRICS_INTERVAL);
+        let mut bandwidth_metrics = interval(BANDWIDTH_METRICS_INTERVAL);
+        // the node bootstrapped once already when it was created
+        let kad_bootstrap_period = Duration::from_secs(
+            self.config
+                .kad_bootstrap_interval
+                .unwrap_or_default()
+                .max(1),
+        );
+        let mut kad_bootstrap =
+            interval_at(Instant::now() + kad_bootstrap_period, kad_bootstrap_period);
+        let kad_bootstrap_enabled =
+            self.config.kad_bootstrap_interval.is_some() && !self.config.bootstrapper;
 
         loop {
             select! {
                 event = self.swarm.next() => {
-                    let event = event.ok_or_else(|| anyhow!("Swarm Event invalid!"))?;
-                    self.handle_swarm_event(event).expect("Handle swarm event.");
+                    let handled = event
+                        .ok_or_else(|| anyhow!("Swarm Event invalid!"))
+                        .and_then(|event| self.handle_swarm_event(event));
+                    if let Err(e) = handled {
+                        self.fail_pending(&format!("The network service stopped: {e}"));
+                        return Err(e);
+                    }
                 },
                 command = self.command_receiver.recv() => {
-                    let command = command.ok_or_else(|| anyhow!("Command invalid!"))?;
-                    self.handle_command(command).expect("Handle rpc command.");
+                    let handled = command
+                        .ok_or_else(|| anyhow!("Command invalid!"))
+                        .and_then(|command| self.handle_command(command));
+                    if let Err(e) = handled {
+                        self.fail_pending(&format!("The network service stopped: {e}"));
+                        return Err(e);
+                    }
+                    if !self.shutdown_senders.is_empty() {
+                        self.shutdown();
+                        return Ok(());
+                    }
                 },
+                Some((channel, response)) = self.car_response_receiver.recv() => {
+                    self.send_car_response(channel, response);
+                }
+                Some((cid, purpose, count)) = self.dag_count_receiver.recv() => {
+                    if let Err(e) = self.handle_dag_count(cid, purpose, count) {
+                        self.fail_pending(&format!("The network service stopped: {e}"));
+                        return Err(e);
+                    }
+                }
                 _ = &mut kad_walk_delay => {
                     info!("Starting random kademlia walk");
                     self.swarm.behaviour_mut().kad.get_closest_peers(PeerId::random());
                     kad_walk_delay.as_mut().reset(Instant::now() + Duration::from_secs(self.kad_walk_interval));
                 }
+                _ = relay_renewal.tick() => {
+                    self.renew_relay_reservations();
+                }
+                _ = bootstrap_redial.tick() => {
+                    self.redial_bootstraps();
+                }
+                _ = gossip_mesh_metrics.tick() => {
+                    self.record_gossip_mesh_size();
+                }
+                _ = store_size_metrics.tick() => {
+                    self.record_store_size();
+                }
+                _ = bandwidth_metrics.tick() => {
+                    self.record_bandwidth();
+                }
+                _ = kad_bootstrap.tick(), if kad_bootstrap_enabled => {
+                    if let Err(e) = self.bootstrap() {
+                        debug!("[Bootstrap] - {e}");
+                    }
+                }
             }
         }
     }
 }
 
+/// Whether `address` is on a local network, where peers can be discovered with mDNS.
+fn is_lan_address(address: &Multiaddr) -> bool {
+    address.iter().any(|protocol| match protocol {
+        Protocol::Ip4(ip) => ip.is_private() || ip.is_link_local(),
+        Protocol::Ip6(ip) => (ip.segments()[0] & 0xffc0) == 0xfe80,
+        _ => false,
+    })
+}
+
 #[cfg(test)]
 #[path = "tests/service_tests.rs"]
 mod service_tests;
+
+#[cfg(test)]
+#[path = "tests/test_support.rs"]
+pub(crate) mod test_support;
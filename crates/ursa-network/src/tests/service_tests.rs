@@ -1,10 +1,17 @@
+use super::test_support::{spawn_connected_nodes, test_dir};
 use crate::behaviour::BehaviourEvent;
+use crate::transport::build_transport;
 use crate::utils::cache_summary::CacheSummary;
+use crate::utils::peer_score::DEPRIORITIZE_THRESHOLD;
+use crate::utils::provider_discovery::provider_key;
 use crate::{
-    codec::protocol::{RequestType, UrsaExchangeRequest},
-    NetworkCommand, NetworkConfig, UrsaService, URSA_GLOBAL,
+    codec::protocol::{RequestType, ResponseType, UrsaExchangeRequest, UrsaExchangeResponse},
+    network_topic, ursa_agent, BitswapTrace, ConfigError, ConnectionLimitsConfig,
+    GossipAuthenticity, GossipConfig, GossipsubEvent, NatState, NetworkCommand, NetworkConfig,
+    NetworkError, NetworkEvent, PeerFetchOutcome, RequestFailure, RetrievalStrategy,
+    SecurityProtocol, SyncResult, UrsaService, URSA_CONTENT, URSA_GLOBAL, URSA_PROTOCOL_VERSION,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use async_fs::File;
 use bytes::Bytes;
 use db::MemoryDB;
@@ -13,21 +20,28 @@ use futures::StreamExt;
 use fvm_ipld_car::{load_car, CarReader};
 use ipld_traversal::blockstore::Blockstore;
 use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Block, Cid, DefaultParams, Ipld};
-use libp2p::kad::{BootstrapOk, KademliaEvent, QueryResult};
-use libp2p::request_response::RequestResponseEvent;
+use libp2p::autonat::{Event as AutonatEvent, NatStatus};
+use libp2p::kad::{store::RecordStore, BootstrapOk, KademliaEvent, QueryResult};
+use libp2p::mdns::Event as MdnsEvent;
+use libp2p::request_response::{OutboundFailure, RequestResponseEvent};
 use libp2p::{
-    gossipsub::IdentTopic as Topic, identity::Keypair, multiaddr::Protocol, swarm::SwarmEvent,
-    Multiaddr, PeerId,
+    gossipsub::{GossipsubMessage, IdentTopic as Topic, MessageId},
+    identity::Keypair,
+    multiaddr::Protocol,
+    ping::Behaviour as Ping,
+    swarm::{dial_opts::DialOpts, DialError, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
 };
 use libp2p_bitswap::BitswapStore;
 use simple_logger::SimpleLogger;
+use std::collections::HashSet;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::{sync::Arc, time::Duration, vec};
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
 use tokio::{select, sync::oneshot, time::timeout};
-use tracing::warn;
 use tracing::{error, info, log::LevelFilter};
-use ursa_store::{BitswapStorage, UrsaStore};
+use ursa_store::{BitswapStorage, ChunkingStrategy, UrsaStore};
 
 fn create_block(ipld: Ipld) -> Block<DefaultParams> {
     Block::encode(DagCborCodec, Code::Blake3_256, &ipld).unwrap()
@@ -82,6 +96,21 @@ async fn network_init(
     Multiaddr,
     PeerId,
     Arc<UrsaStore<MemoryDB>>,
+)> {
+    let (sender, _) = channel(4096);
+    network_init_with_events(config, bootstrap_addr, bootstrap_keypair, sender).await
+}
+
+async fn network_init_with_events(
+    config: &mut NetworkConfig,
+    bootstrap_addr: Option<Multiaddr>,
+    bootstrap_keypair: Option<Keypair>,
+    event_sender: Sender<NetworkEvent>,
+) -> Result<(
+    UrsaService<MemoryDB>,
+    Multiaddr,
+    PeerId,
+    Arc<UrsaStore<MemoryDB>>,
 )> {
     let keypair = match bootstrap_keypair {
         Some(k) => k,
@@ -97,8 +126,7 @@ async fn network_init(
         config.bootstrap_nodes = vec![addr];
     }
 
-    let (sender, _) = channel(4096);
-    let mut service = UrsaService::new(keypair, config, Arc::clone(&store), sender)?;
+    let mut service = UrsaService::new(keypair, config, Arc::clone(&store), event_sender)?;
 
     let node_addrs = async {
         loop {
@@ -138,38 +166,412 @@ async fn test_network_start() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_network_gossip() -> Result<()> {
+async fn test_get_config() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+
+    let mut config = NetworkConfig {
+        swarm_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+        kad_walk_interval: 42,
+        require_gossipsub: false,
+        ..Default::default()
+    };
+    let keypair = Keypair::generate_ed25519();
+    let (service, ..) = network_init(&mut config, None, Some(keypair.clone())).await?;
+    let sender = service.command_sender();
+    tokio::task::spawn(async move { service.start().await.unwrap() });
+
+    let (config_sender, config_receiver) = oneshot::channel();
+    assert!(sender
+        .send(NetworkCommand::GetConfig {
+            sender: config_sender,
+        })
+        .await
+        .is_ok());
+    let running = config_receiver.await?;
+    assert_eq!(running, config);
+
+    // the keypair of the node is not part of the returned config, however it is encoded
+    let secret = serde_json::to_string(&keypair.to_protobuf_encoding()?)?;
+    let secret = secret.trim_start_matches('[').trim_end_matches(']');
+    assert!(!serde_json::to_string(&running)?.contains(secret));
+    assert!(!format!("{running:?}").contains(secret));
+
+    // a config changed at runtime is reflected in the returned one
+    let (switch_sender, switch_receiver) = oneshot::channel();
+    sender
+        .send(NetworkCommand::SwitchNetwork {
+            network_name: "testnet-b".to_string(),
+            sender: switch_sender,
+        })
+        .await?;
+    switch_receiver.await??;
+
+    let (config_sender, config_receiver) = oneshot::channel();
+    sender
+        .send(NetworkCommand::GetConfig {
+            sender: config_sender,
+        })
+        .await?;
+    assert_eq!(
+        config_receiver.await?,
+        NetworkConfig {
+            network_name: "testnet-b".to_string(),
+            ..config
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn test_config_validate() -> Result<()> {
+    let valid = NetworkConfig {
+        swarm_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+        ..Default::default()
+    };
+    assert_eq!(valid.validate(), Ok(()));
+
+    let relay = NetworkConfig {
+        relay_client: true,
+        autonat: false,
+        ..valid.clone()
+    };
+    assert_eq!(
+        relay.validate(),
+        Err(vec![ConfigError::RelayWithoutAutonat])
+    );
+
+    // addresses that don't parse are refused when loading the config
+    assert!(
+        serde_json::from_str::<NetworkConfig>(r#"{"bootstrap_nodes": ["not an address"]}"#)
+            .is_err()
+    );
+    let without_peer_id: Multiaddr = "/ip4/127.0.0.1/tcp/6009".parse().unwrap();
+    let bootstrap = NetworkConfig {
+        bootstrap_nodes: vec![without_peer_id.clone()],
+        ..valid.clone()
+    };
+    assert!(matches!(
+        &bootstrap.validate().unwrap_err()[..],
+        [ConfigError::InvalidBootstrapNode { addr, .. }] if *addr == without_peer_id
+    ));
+
+    let limits = NetworkConfig {
+        connection_limits: ConnectionLimitsConfig {
+            max_established_per_peer: Some(4),
+            max_established_total: Some(2),
+            ..Default::default()
+        },
+        ..valid.clone()
+    };
+    assert!(matches!(
+        &limits.validate().unwrap_err()[..],
+        [ConfigError::Invalid(e)] if e.contains("max_established_total")
+    ));
+
+    // empty channels and caches panic, and no bitswap query could ever be sent
+    for zero in [
+        NetworkConfig {
+            command_buffer: 0,
+            ..valid.clone()
+        },
+        NetworkConfig {
+            event_buffer: 0,
+            ..valid.clone()
+        },
+        NetworkConfig {
+            bitswap_fanout: 0,
+            ..valid.clone()
+        },
+        NetworkConfig {
+            max_pending_queries: 0,
+            ..valid.clone()
+        },
+        NetworkConfig {
+            max_cache_summaries: 0,
+            ..valid.clone()
+        },
+    ] {
+        assert!(matches!(
+            &zero.validate().unwrap_err()[..],
+            [ConfigError::Invalid(e)] if e.contains("at least 1")
+        ));
+    }
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let taken: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", listener.local_addr()?.port())
+        .parse()
+        .unwrap();
+    let port = NetworkConfig {
+        swarm_addrs: vec![taken.clone()],
+        ..valid.clone()
+    };
+    assert!(matches!(
+        &port.validate().unwrap_err()[..],
+        [ConfigError::AddressUnavailable { addr, .. }] if *addr == taken
+    ));
+
+    // every problem is reported at once
+    let all = NetworkConfig {
+        relay_client: true,
+        autonat: false,
+        bootstrap_nodes: vec![without_peer_id],
+        connection_limits: limits.connection_limits,
+        swarm_addrs: vec![taken],
+        ..valid
+    };
+    assert_eq!(all.validate().unwrap_err().len(), 4);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown() -> Result<()> {
     setup_logger(LevelFilter::Info);
     let mut config = NetworkConfig::default();
+    let (service, ..) = network_init(&mut config, None, None).await?;
+    let sender = service.command_sender();
+    let handle = tokio::task::spawn(async move { service.start().await });
 
-    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
-    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+    // a bitswap query for content nobody has is still pending at shutdown
+    let (get_sender, get_receiver) = oneshot::channel();
+    assert!(sender
+        .send(NetworkCommand::GetBitswap {
+            cid: *get_block(b"never found").cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender: get_sender,
+        })
+        .await
+        .is_ok());
+
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    assert!(sender
+        .send(NetworkCommand::Shutdown {
+            sender: shutdown_sender,
+        })
+        .await
+        .is_ok());
+
+    timeout(Duration::from_secs(5), shutdown_receiver).await??;
+    assert!(timeout(Duration::from_secs(5), handle).await??.is_ok());
+    assert!(get_receiver.await?.is_err());
+
+    // the service no longer accepts commands
+    let (config_sender, _) = oneshot::channel();
+    assert!(sender
+        .send(NetworkCommand::GetConfig {
+            sender: config_sender,
+        })
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shutdown_fails_dht_queries() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+    let (mut service, ..) = network_init(&mut config, None, None).await?;
+
+    // the swarm is not polled, so the lookup is still in flight at shutdown
+    let (providers_sender, providers_receiver) = oneshot::channel();
+    service.handle_command(NetworkCommand::FindProviders {
+        cid: *get_block(b"never provided").cid(),
+        sender: providers_sender,
+    })?;
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    service.handle_command(NetworkCommand::Shutdown {
+        sender: shutdown_sender,
+    })?;
+    service.shutdown();
+
+    shutdown_receiver.await?;
+    let error = providers_receiver.await?.unwrap_err();
+    assert_eq!(error.to_string(), "The network service shut down");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_loop_exit_fails_pending_queries() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+    let (mut service, ..) = network_init(&mut config, None, None).await?;
+
+    // a bitswap query for content nobody has is still pending when the loop exits
+    let (get_sender, get_receiver) = oneshot::channel();
+    service.handle_command(NetworkCommand::GetBitswap {
+        cid: *get_block(b"never found").cid(),
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender: get_sender,
+    })?;
+
+    // the loop errors out once its command channel is closed
+    service.close_command_receiver();
+    assert!(timeout(Duration::from_secs(5), service.start())
+        .await?
+        .is_err());
+
+    let error = get_receiver.await?.unwrap_err();
+    assert!(error.to_string().starts_with("The network service stopped"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fail_pending_fails_dht_queries() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+    let (mut service, ..) = network_init(&mut config, None, None).await?;
+
+    // the swarm is not polled, so the queries are still in flight
+    let (providers_sender, providers_receiver) = oneshot::channel();
+    service.handle_command(NetworkCommand::FindProviders {
+        cid: *get_block(b"never provided").cid(),
+        sender: providers_sender,
+    })?;
+    let (put_sender, put_receiver) = oneshot::channel();
+    service.handle_command(NetworkCommand::PutRecord {
+        key: b"key".to_vec(),
+        value: b"value".to_vec(),
+        sender: put_sender,
+    })?;
+    let (get_sender, get_receiver) = oneshot::channel();
+    service.handle_command(NetworkCommand::GetRecord {
+        key: b"key".to_vec(),
+        sender: get_sender,
+    })?;
+
+    service.fail_pending("The network service stopped");
+    let errors = [
+        providers_receiver.await?.unwrap_err(),
+        put_receiver.await?.unwrap_err(),
+        get_receiver.await?.unwrap_err(),
+    ];
+    for error in errors {
+        assert_eq!(error.to_string(), "The network service stopped");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_has_full_dag() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+    let (service, _, _, store) = network_init(&mut config, None, None).await?;
+    let sender = service.command_sender();
+    tokio::task::spawn(async move { service.start().await.unwrap() });
+
+    let file = File::open(Path::new("../../test_files/test.car")).await?;
+    let cids = load_car(store.blockstore(), BufReader::new(file)).await?;
+    let missing = *get_block(b"missing").cid();
+
+    for (cid, expected) in [(cids[0], true), (missing, false)] {
+        let (has_sender, has_receiver) = oneshot::channel();
+        assert!(sender
+            .send(NetworkCommand::HasFullDag {
+                cid,
+                sender: has_sender,
+            })
+            .await
+            .is_ok());
+        assert_eq!(has_receiver.await??, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_event_channel_is_bounded() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        command_buffer: 4,
+        event_buffer: 8,
+        ..Default::default()
+    };
+
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node, ..) = network_init_with_events(&mut config, None, None, event_sender).await?;
+
+    // nobody is consuming the events, the ones that don't fit are dropped
+    for _ in 0..10_000 {
+        node.emit_event(NetworkEvent::PeerConnected(PeerId::random()));
+    }
+    assert_eq!(node.event_sender.capacity(), 0);
+
+    let mut received = 0;
+    while event_receiver.try_recv().is_ok() {
+        received += 1;
+    }
+    assert_eq!(received, config.event_buffer);
+
+    // the events emitted once the consumer caught up are delivered again
+    let peer_id = PeerId::random();
+    node.emit_event(NetworkEvent::PeerConnected(peer_id));
+    assert!(matches!(
+        event_receiver.try_recv(),
+        Ok(NetworkEvent::PeerConnected(id)) if id == peer_id
+    ));
 
+    // the service is not running, so the commands past the buffer are refused
+    let command_sender = node.command_sender();
+    for _ in 0..config.command_buffer {
+        let (sender, _) = oneshot::channel();
+        assert!(command_sender
+            .try_send(NetworkCommand::GetPeers { sender })
+            .is_ok());
+    }
+    let (sender, _) = oneshot::channel();
+    assert!(matches!(
+        command_sender.try_send(NetworkCommand::GetPeers { sender }),
+        Err(TrySendError::Full(_))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_events() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node_1, node_1_addrs, peer_id_1, _) =
+        network_init_with_events(&mut config, None, None, event_sender).await?;
+    let (mut node_2, _, peer_id_2, _) = network_init(&mut config, None, None).await?;
+
+    // dial directly, without going through discovery
+    node_2.swarm.dial(node_1_addrs)?;
+
+    let mut connected = false;
     loop {
         select! {
             event_1 = node_1.swarm.select_next_some() => {
-                if let SwarmEvent::ConnectionEstablished { .. } = event_1 {
-                    let topic = Topic::new(URSA_GLOBAL);
-                    if let Err(error) = node_1.swarm.behaviour_mut().publish(topic, Bytes::from_static(b"hello world!")) {
-                        warn!("Failed to send with error: {error:?}");
-                    };
-                }
+                node_1.handle_swarm_event(event_1)?;
             }
-            event_2 = node_2.swarm.select_next_some() => {
-                if let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
-                    libp2p::gossipsub::GossipsubEvent::Message {
-                        propagation_source,
-                        message_id,
-                        message,
-                    },
-                )) = event_2
-                {
-                    info!(
-                        "peer: {propagation_source:?}, id: {message_id:?}, message: {message:?}"
-                    );
-                    assert_eq!(Bytes::from_static(b"hello world!"), message.data);
+            _ = node_2.swarm.select_next_some() => {}
+            Some(event) = event_receiver.recv() => match event {
+                NetworkEvent::ConnectionEstablished {
+                    peer,
+                    endpoint,
+                    num_established,
+                } if peer == peer_id_2 => {
+                    assert!(endpoint.is_listener());
+                    assert_eq!(num_established, 1);
+                    connected = true;
+                    assert!(node_2.swarm.disconnect_peer_id(peer_id_1).is_ok());
+                }
+                NetworkEvent::ConnectionClosed {
+                    peer,
+                    num_established,
+                    ..
+                } if peer == peer_id_2 => {
+                    assert!(connected);
+                    assert_eq!(num_established, 0);
                     break;
                 }
+                _ => {}
             }
         }
     }
@@ -178,74 +580,193 @@ async fn test_network_gossip() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_network_mdns() -> Result<()> {
+async fn test_get_network_info() -> Result<()> {
     setup_logger(LevelFilter::Info);
     let mut config = NetworkConfig {
-        mdns: true,
         bootstrap_nodes: vec![],
         ..Default::default()
     };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node_1, node_1_addrs, _, _) =
+        network_init_with_events(&mut config, None, None, event_sender).await?;
+    let (mut node_2, _, peer_id_2, _) = network_init(&mut config, None, None).await?;
 
-    let (node_1, _, peer_id_1, ..) = network_init(&mut config, None, None).await?;
-    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+    let (sender, receiver) = oneshot::channel();
+    node_1.handle_command(NetworkCommand::GetNetworkInfo { sender })?;
+    let info = receiver.await?;
+    assert_eq!(info.num_peers, 0);
+    assert_eq!(info.nat_status, NatState::Unknown);
 
-    let (mut node_2, ..) = network_init(&mut config, None, None).await?;
+    node_2.swarm.dial(node_1_addrs)?;
 
     loop {
-        let event = node_2.swarm.select_next_some().await;
-        if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
-            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}");
-            if peer_id == peer_id_1 {
-                break;
+        select! {
+            event_1 = node_1.swarm.select_next_some() => {
+                node_1.handle_swarm_event(event_1)?;
             }
-        };
+            _ = node_2.swarm.select_next_some() => {}
+            Some(NetworkEvent::ConnectionEstablished { peer, .. }) = event_receiver.recv() => {
+                if peer == peer_id_2 {
+                    break;
+                }
+            }
+        }
     }
+
+    let (sender, receiver) = oneshot::channel();
+    node_1.handle_command(NetworkCommand::GetNetworkInfo { sender })?;
+    let info = receiver.await?;
+    assert!(info.num_peers >= 1);
+    assert!(info.num_established_incoming >= 1);
+
     Ok(())
 }
 
 #[tokio::test]
-async fn test_network_kad() -> Result<()> {
+async fn test_dial_queue() -> Result<()> {
     setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node, ..) = network_init(&mut config, None, None).await?;
 
-    let (bootstrap, bootstrap_addr, bootstrap_id) =
-        run_bootstrap(&mut NetworkConfig::default()).await?;
+    // accepts the tcp connection but never answers the upgrade, so the dial hangs
+    let unresponsive = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let port = unresponsive.local_addr()?.port();
+    let peer_id = PeerId::random();
+    node.swarm.dial(
+        DialOpts::peer_id(peer_id)
+            .addresses(vec![format!("/ip4/127.0.0.1/tcp/{port}").parse()?])
+            .build(),
+    )?;
 
-    tokio::task::spawn(async move { bootstrap.start().await.unwrap() });
+    loop {
+        let event = node.swarm.select_next_some().await;
+        let dialing = matches!(event, SwarmEvent::Dialing(dialed) if dialed == peer_id);
+        node.handle_swarm_event(event)?;
+        if dialing {
+            break;
+        }
+    }
 
-    let (mut node_1, _, peer_id_1, ..) = network_init(
-        &mut NetworkConfig::default(),
-        Some(bootstrap_addr.clone()),
-        None,
-    )
-    .await?;
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::DialQueue { sender })?;
+    let dials = receiver.await?;
+    assert!(dials.iter().any(|dial| dial.peer_id == peer_id));
 
-    // wait for node 1 to identify with bootstrap
-    loop {
-        if let SwarmEvent::Behaviour(BehaviourEvent::Identify(libp2p::identify::Event::Sent {
-            peer_id,
-            ..
-        })) = node_1.swarm.select_next_some().await
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_reservation_renewal() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut relay_config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_server: true,
+        relay_reservation_duration: 6,
+        ..Default::default()
+    };
+    let (relay, relay_addrs, relay_peer_id, _) =
+        network_init(&mut relay_config, None, None).await?;
+
+    // renew well before the relay client would on its own, at three quarters of the lifetime
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_client: true,
+        relay_reservation_duration: 6,
+        relay_renewal_percent: 25,
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node, ..) = network_init_with_events(&mut config, None, None, event_sender).await?;
+    node.listen_on_relay(relay_addrs.with(Protocol::P2pCircuit))?;
+
+    let node_sender = node.command_sender();
+    tokio::task::spawn(async move { relay.start().await.unwrap() });
+    tokio::task::spawn(async move { node.start().await.unwrap() });
+
+    let expires_in = loop {
+        let event = timeout(Duration::from_secs(10), event_receiver.recv())
+            .await?
+            .expect("event");
+        if let NetworkEvent::RelayReservationExpiring {
+            relay_peer_id: peer,
+            expires_in,
+        } = event
         {
-            info!("[SwarmEvent::Identify::Sent]: {peer_id:?}, {bootstrap_id:?}");
-            if peer_id == bootstrap_id {
+            assert_eq!(peer, relay_peer_id);
+            break expires_in;
+        }
+    };
+    assert!(expires_in > Duration::ZERO);
+
+    // the reservation is renewed before it expires
+    timeout(expires_in, async {
+        loop {
+            if let Some(NetworkEvent::RelayReservationRenewed {
+                relay_peer_id: peer,
+            }) = event_receiver.recv().await
+            {
+                assert_eq!(peer, relay_peer_id);
                 break;
             }
         }
-    }
+    })
+    .await?;
 
-    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+    let (sender, receiver) = oneshot::channel();
+    assert!(node_sender
+        .send(NetworkCommand::GetRelayReservations { sender })
+        .await
+        .is_ok());
+    let reservations = receiver.await?;
+    assert!(reservations[&relay_peer_id] > Duration::from_secs(4));
+    Ok(())
+}
 
-    let (mut node_2, ..) =
-        network_init(&mut NetworkConfig::default(), Some(bootstrap_addr), None).await?;
+#[tokio::test]
+async fn test_relay_server_reservation_limit() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut relay_config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_server: true,
+        relay_max_reservations: 1,
+        ..Default::default()
+    };
+    let (relay_sender, mut relay_events) = channel(relay_config.event_buffer);
+    let (relay, relay_addrs, ..) =
+        network_init_with_events(&mut relay_config, None, None, relay_sender).await?;
+    tokio::task::spawn(async move { relay.start().await.unwrap() });
 
-    // wait for node 2 to connect with node 1 through kad peer discovery
-    loop {
-        if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
-            node_2.swarm.select_next_some().await
-        {
-            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}");
-            if peer_id == peer_id_1 {
-                break;
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_client: true,
+        ..Default::default()
+    };
+    // the first client takes the only slot, the second one is denied
+    for accepted in [true, false] {
+        let (mut node, _, node_peer_id, _) = network_init(&mut config, None, None).await?;
+        node.listen_on_relay(relay_addrs.clone().with(Protocol::P2pCircuit))?;
+        tokio::task::spawn(async move { node.start().await.unwrap() });
+
+        loop {
+            let event = timeout(Duration::from_secs(10), relay_events.recv())
+                .await?
+                .expect("event");
+            match event {
+                NetworkEvent::RelayServerReservationAccepted { peer_id, renewed } => {
+                    assert!(accepted);
+                    assert_eq!(peer_id, node_peer_id);
+                    assert!(!renewed);
+                    break;
+                }
+                NetworkEvent::RelayServerReservationDenied { peer_id } => {
+                    assert!(!accepted);
+                    assert_eq!(peer_id, node_peer_id);
+                    break;
+                }
+                _ => (),
             }
         }
     }
@@ -253,147 +774,2740 @@ async fn test_network_kad() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_network_req_res() -> Result<()> {
+async fn test_dcutr_attempted_after_relayed_connection() -> Result<()> {
     setup_logger(LevelFilter::Info);
-    let mut config = NetworkConfig::default();
+    let mut relay_config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_server: true,
+        ..Default::default()
+    };
+    let (relay, relay_addrs, ..) = network_init(&mut relay_config, None, None).await?;
+    tokio::task::spawn(async move { relay.start().await.unwrap() });
 
-    let (mut node_1, node_1_addrs, peer_id_1, ..) = network_init(&mut config, None, None).await?;
-    let (mut node_2, _, peer_id_2, ..) =
-        network_init(&mut config, Some(node_1_addrs), None).await?;
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_client: true,
+        ..Default::default()
+    };
+    let (listener_sender, mut listener_events) = channel(config.event_buffer);
+    let (mut listener, _, listener_id, _) =
+        network_init_with_events(&mut config, None, None, listener_sender).await?;
+    let circuit_addr = relay_addrs.with(Protocol::P2pCircuit);
+    listener.listen_on_relay(circuit_addr.clone())?;
+    tokio::task::spawn(async move { listener.start().await.unwrap() });
 
-    // Wait for at least one connection
     loop {
-        if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
-            node_1.swarm.select_next_some().await
-        {
-            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}: ");
+        let event = timeout(Duration::from_secs(10), listener_events.recv())
+            .await?
+            .expect("event");
+        if let NetworkEvent::RelayReservationOpened { .. } = event {
             break;
         }
     }
 
-    let node_1_sender = node_1.command_sender();
-    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+    // the dialer reaches the listener through the relay, then both try to hole punch
+    let (dialer_sender, mut dialer_events) = channel(config.event_buffer);
+    let (mut dialer, _, dialer_id, _) =
+        network_init_with_events(&mut config, None, None, dialer_sender).await?;
+    dialer
+        .swarm
+        .dial(circuit_addr.with(Protocol::P2p(listener_id.into())))?;
+    tokio::task::spawn(async move { dialer.start().await.unwrap() });
 
-    let (sender, _) = oneshot::channel();
-    let request = UrsaExchangeRequest(RequestType::CarRequest("Qm".to_string()));
-    let msg = NetworkCommand::SendRequest {
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                Some(event) = listener_events.recv() => {
+                    if let NetworkEvent::DirectConnectionUpgradeAttempted { peer_id } = event {
+                        assert_eq!(peer_id, dialer_id);
+                        break;
+                    }
+                }
+                Some(event) = dialer_events.recv() => {
+                    if let NetworkEvent::DirectConnectionUpgradeAttempted { peer_id } = event {
+                        assert_eq!(peer_id, listener_id);
+                        break;
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_nat_status_changed_waits_for_confidence() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let relay_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/1/p2p/{}", PeerId::random())
+        .parse()
+        .unwrap();
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![relay_addr],
+        relay_client: true,
+        autonat_confidence: 2,
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node, ..) = network_init_with_events(&mut config, None, None, event_sender).await?;
+
+    node.handle_autonat(AutonatEvent::StatusChanged {
+        old: NatStatus::Unknown,
+        new: NatStatus::Private,
+    })?;
+    loop {
+        let event = timeout(Duration::from_secs(5), event_receiver.recv())
+            .await?
+            .expect("event");
+        if let NetworkEvent::NatStatusChanged { old, new } = event {
+            assert_eq!(old, NatState::Unknown);
+            assert_eq!(new, NatState::Private);
+            break;
+        }
+    }
+
+    // a single probe is not enough to take a reservation
+    node.private_nat_confirmed()?;
+    assert!(node.relay_reservations.is_empty());
+    node.private_nat_confirmed()?;
+    assert_eq!(node.relay_reservations.len(), 1);
+
+    // later probes don't take another one
+    node.private_nat_confirmed()?;
+    assert_eq!(node.relay_reservations.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_relay_failover() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut relay_config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_server: true,
+        ..Default::default()
+    };
+    let (relay, relay_addrs, relay_peer_id, _) =
+        network_init(&mut relay_config, None, None).await?;
+    tokio::task::spawn(async move { relay.start().await.unwrap() });
+
+    let unavailable_peer_id = PeerId::random();
+    let unavailable_addrs: Multiaddr = format!("/ip4/127.0.0.1/tcp/1/p2p/{unavailable_peer_id}")
+        .parse()
+        .unwrap();
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_client: true,
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node, ..) = network_init_with_events(&mut config, None, None, event_sender).await?;
+
+    // candidates are tried from the back, the unavailable relay first
+    node.relay_candidates = vec![relay_addrs, unavailable_addrs];
+    node.listen_on_next_relay();
+    assert_eq!(node.active_relay, Some(unavailable_peer_id));
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let event = node.swarm.select_next_some().await;
+            node.handle_swarm_event(event).unwrap();
+            let accepted = node
+                .relay_reservations
+                .get(&relay_peer_id)
+                .map_or(false, |reservation| {
+                    reservation.expires_at(Duration::ZERO).is_some()
+                });
+            if accepted {
+                break;
+            }
+        }
+    })
+    .await?;
+    assert_eq!(node.active_relay, Some(relay_peer_id));
+    assert!(!node.relay_reservations.contains_key(&unavailable_peer_id));
+
+    let mut closed = None;
+    while let Ok(event) = event_receiver.try_recv() {
+        if let NetworkEvent::RelayReservationClosed { relay_peer_id } = event {
+            closed = Some(relay_peer_id);
+        }
+    }
+    assert_eq!(closed, Some(unavailable_peer_id));
+
+    // with no relay left the node gives up without failing
+    node.relay_failed(relay_peer_id);
+    assert_eq!(node.active_relay, None);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drop_relay_reservation() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut relay_config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_server: true,
+        ..Default::default()
+    };
+    let (relay, relay_addrs, relay_peer_id, _) =
+        network_init(&mut relay_config, None, None).await?;
+    tokio::task::spawn(async move { relay.start().await.unwrap() });
+
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        relay_client: true,
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node, ..) = network_init_with_events(&mut config, None, None, event_sender).await?;
+    node.listen_on_relay(relay_addrs.with(Protocol::P2pCircuit))?;
+
+    let is_circuit = |addr: &Multiaddr| addr.iter().any(|p| p == Protocol::P2pCircuit);
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let event = node.swarm.select_next_some().await;
+            node.handle_swarm_event(event).unwrap();
+            let accepted = node.relay_reservations[&relay_peer_id]
+                .expires_at(Duration::ZERO)
+                .is_some();
+            if accepted && node.swarm.listeners().any(is_circuit) {
+                break;
+            }
+        }
+    })
+    .await?;
+    let mut opened = None;
+    while let Ok(event) = event_receiver.try_recv() {
+        if let NetworkEvent::RelayReservationOpened { relay_peer_id } = event {
+            opened = Some(relay_peer_id);
+        }
+    }
+    assert_eq!(opened, Some(relay_peer_id));
+
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::DropRelayReservation {
+        relay_peer_id,
+        sender,
+    })?;
+    receiver.await??;
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let event = node.swarm.select_next_some().await;
+            let closed = matches!(event, SwarmEvent::ListenerClosed { .. });
+            node.handle_swarm_event(event).unwrap();
+            if closed {
+                break;
+            }
+        }
+    })
+    .await?;
+    assert!(!node.swarm.listeners().any(is_circuit));
+    assert!(node.relay_reservations.is_empty());
+
+    // there is nothing left to drop
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::DropRelayReservation {
+        relay_peer_id,
+        sender,
+    })?;
+    assert!(receiver.await?.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_incompatible_protocol_version() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node, ..) = network_init(&mut config, None, None).await?;
+
+    let keypair = Keypair::generate_ed25519();
+    let peer_id = PeerId::from(keypair.public());
+    let identify = |protocol_version: &str| libp2p::identify::Event::Received {
+        peer_id,
+        info: libp2p::identify::Info {
+            public_key: keypair.public(),
+            protocol_version: protocol_version.to_string(),
+            agent_version: ursa_agent(),
+            listen_addrs: vec![],
+            protocols: vec![],
+            observed_addr: Multiaddr::empty(),
+        },
+    };
+
+    node.handle_identify(identify("ursa/0.2.0"))?;
+    assert!(node.incompatible_peers.contains(&peer_id));
+
+    let (channel, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::SendRequest {
+        peer_id,
+        request: Box::new(UrsaExchangeRequest(RequestType::CarRequest(
+            Cid::default().to_string(),
+        ))),
+        channel,
+    })?;
+    assert!(receiver.await?.is_err());
+
+    // the peer upgraded
+    node.handle_identify(identify(URSA_PROTOCOL_VERSION))?;
+    assert!(!node.incompatible_peers.contains(&peer_id));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_identify_agent_version() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        agent_version: "ursa/test-agent".to_string(),
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, peer_id_1, _) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let info = timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Identify(
+                        libp2p::identify::Event::Received { peer_id, info },
+                    )) = &event_2
+                    {
+                        if *peer_id == peer_id_1 {
+                            let info = info.clone();
+                            node_2.handle_swarm_event(event_2).unwrap();
+                            return info;
+                        }
+                    }
+                    node_2.handle_swarm_event(event_2).unwrap();
+                }
+            }
+        }
+    })
+    .await?;
+    assert_eq!(info.agent_version, "ursa/test-agent");
+
+    // the address node 1 sees node 2 on is a candidate external address of node 2
+    assert!(node_2
+        .swarm
+        .external_addresses()
+        .any(|record| record.addr == info.observed_addr));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exchange_rate_limit() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let config = NetworkConfig {
+        exchange_requests_per_sec: 1,
+        exchange_burst: 3,
+        ..Default::default()
+    };
+    let nodes = spawn_connected_nodes_with(2, &config).await?;
+    let (peer_id_1, node_2_sender) = (nodes[0].peer_id, &nodes[1].command_sender);
+
+    let block = get_block(&b"rate limited"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let mut receivers = vec![];
+    for _ in 0..6 {
+        let (channel, receiver) = oneshot::channel();
+        node_2_sender
+            .send(NetworkCommand::SendRequest {
+                peer_id: peer_id_1,
+                request: Box::new(UrsaExchangeRequest(RequestType::CarRequest(
+                    block.cid().to_string(),
+                ))),
+                channel,
+            })
+            .await?;
+        receivers.push(receiver);
+    }
+
+    let (mut served, mut refused) = (0, 0);
+    for receiver in receivers {
+        match timeout(Duration::from_secs(10), receiver).await???.0 {
+            ResponseType::CarResponse(_) => served += 1,
+            ResponseType::Error(e) => {
+                assert!(e.starts_with("Too many requests"), "{e}");
+                refused += 1;
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+    // the burst is served, a token may have been refilled meanwhile
+    assert!(served >= 3, "served {served}");
+    assert!(refused >= 2, "refused {refused}");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exchange_block_and_ping_requests() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (peer_id_1, node_2_sender) = (nodes[0].peer_id, &nodes[1].command_sender);
+
+    let block = get_block(&b"block request"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let missing = get_block(&b"missing block"[..]);
+    for request in [
+        RequestType::BlockRequest(*block.cid()),
+        RequestType::BlockRequest(*missing.cid()),
+        RequestType::PingRequest,
+    ] {
+        let (channel, receiver) = oneshot::channel();
+        node_2_sender
+            .send(NetworkCommand::SendRequest {
+                peer_id: peer_id_1,
+                request: Box::new(UrsaExchangeRequest(request.clone())),
+                channel,
+            })
+            .await?;
+        let response = timeout(Duration::from_secs(10), receiver).await???.0;
+        match (request, response) {
+            (RequestType::BlockRequest(cid), ResponseType::BlockResponse(data)) => {
+                assert_eq!(cid, *block.cid());
+                assert_eq!(data, block.data());
+            }
+            (RequestType::BlockRequest(cid), ResponseType::Error(e)) => {
+                assert_eq!(cid, *missing.cid());
+                assert!(e.contains("not found"), "{e}");
+            }
+            (RequestType::PingRequest, ResponseType::PingResponse) => (),
+            (request, response) => panic!("unexpected response {response:?} to {request:?}"),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_roots() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (peer_id_1, peer_id_2) = (nodes[0].peer_id, nodes[1].peer_id);
+
+    // as the index provider does once it published the advertisements
+    let roots = [
+        get_block(&b"first root"[..]),
+        get_block(&b"second root"[..]),
+    ];
+    for root in &roots {
+        nodes[1].store.record_published_root(root.cid())?;
+    }
+
+    let list_roots = |sender: Sender<NetworkCommand>, peer_id, offset| async move {
+        let (channel, receiver) = oneshot::channel();
+        sender
+            .send(NetworkCommand::SendRequest {
+                peer_id,
+                request: Box::new(UrsaExchangeRequest(RequestType::ListRoots {
+                    offset,
+                    limit: 1,
+                })),
+                channel,
+            })
+            .await?;
+        match timeout(Duration::from_secs(10), receiver).await???.0 {
+            ResponseType::RootsResponse { roots, next } => Ok::<_, anyhow::Error>((roots, next)),
+            response => panic!("unexpected response {response:?}"),
+        }
+    };
+
+    // node 1 pages through the roots of node 2
+    let (page, next) = list_roots(nodes[0].command_sender.clone(), peer_id_2, 0).await?;
+    assert_eq!(page, vec![*roots[0].cid()]);
+    assert_eq!(next, Some(1));
+    let (page, next) = list_roots(nodes[0].command_sender.clone(), peer_id_2, 1).await?;
+    assert_eq!(page, vec![*roots[1].cid()]);
+    assert_eq!(next, None);
+
+    // node 1 published nothing
+    let (page, next) = list_roots(nodes[1].command_sender.clone(), peer_id_1, 0).await?;
+    assert!(page.is_empty());
+    assert_eq!(next, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_request_failures_classified() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node, ..) = network_init(&mut config, None, None).await?;
+    let unreachable = PeerId::random();
+    let ping = || Box::new(UrsaExchangeRequest(RequestType::PingRequest));
+
+    // the peer has no known address
+    let (channel, mut receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::SendRequest {
+        peer_id: unreachable,
+        request: ping(),
+        channel,
+    })?;
+    let result = timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                event = node.swarm.select_next_some() => node.handle_swarm_event(event).unwrap(),
+                result = &mut receiver => break result,
+            }
+        }
+    })
+    .await??;
+    let error = result.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::RequestFailed { peer, failure: RequestFailure::DialFailure })
+            if *peer == unreachable
+    ));
+
+    // timeouts degrade the score of the peer
+    let (channel, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::SendRequest {
+        peer_id: unreachable,
+        request: ping(),
+        channel,
+    })?;
+    let request_id = *node.pending_responses.keys().next().unwrap();
+    node.handle_req_res(RequestResponseEvent::OutboundFailure {
+        peer: unreachable,
+        request_id,
+        error: OutboundFailure::Timeout,
+    })?;
+    let error = receiver.await?.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::RequestFailed {
+            failure: RequestFailure::Timeout,
+            ..
+        })
+    ));
+    assert_eq!(node.peer_scores.get(&unreachable).timeouts, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exchange_request_timeout() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        exchange_request_timeout: 1,
+        ..Default::default()
+    };
+    assert!(NetworkConfig {
+        exchange_request_timeout: 0,
+        ..Default::default()
+    }
+    .validate_keep_alive()
+    .is_err());
+    let (mut node_1, node_1_addrs, peer_id_1, _) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let (channel, mut receiver) = oneshot::channel();
+    node_2.handle_command(NetworkCommand::SendRequest {
+        peer_id: peer_id_1,
+        request: Box::new(UrsaExchangeRequest(RequestType::PingRequest)),
+        channel,
+    })?;
+
+    // node 1 holds on to the requests without ever responding
+    let mut unanswered = vec![];
+    let result = timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => unanswered.push(event_1),
+                event_2 = node_2.swarm.select_next_some() => node_2.handle_swarm_event(event_2).unwrap(),
+                result = &mut receiver => break result,
+            }
+        }
+    })
+    .await??;
+    assert!(unanswered.iter().any(|event| matches!(
+        event,
+        SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+            RequestResponseEvent::Message { .. }
+        ))
+    )));
+    assert!(matches!(
+        result.unwrap_err().downcast_ref::<NetworkError>(),
+        Some(NetworkError::RequestFailed {
+            failure: RequestFailure::Timeout,
+            ..
+        })
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invalid_gossip_degrades_peer_score() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node, ..) = network_init(&mut config, None, None).await?;
+
+    let peer = PeerId::random();
+    let topic = network_topic(&config.network_name, URSA_CONTENT).hash();
+    for n in 0..2 {
+        assert!(node.peer_scores.score(&peer) >= DEPRIORITIZE_THRESHOLD);
+        node.handle_gossip(libp2p::gossipsub::GossipsubEvent::Message {
+            propagation_source: peer,
+            message_id: MessageId::from(format!("invalid-{n}")),
+            message: GossipsubMessage {
+                source: None,
+                data: b"not a cid".to_vec(),
+                sequence_number: None,
+                topic: topic.clone(),
+            },
+        })?;
+    }
+    assert_eq!(node.peer_scores.get(&peer).invalid_gossip, 2);
+    assert!(node.peer_scores.score(&peer) < DEPRIORITIZE_THRESHOLD);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unsigned_gossip_rejected() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    for authenticity in [GossipAuthenticity::Signed, GossipAuthenticity::Anonymous] {
+        let mut config = NetworkConfig {
+            bootstrap_nodes: vec![],
+            gossip: GossipConfig {
+                message_authenticity: authenticity,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (event_sender, mut event_receiver) = channel(config.event_buffer);
+        let (mut node, ..) =
+            network_init_with_events(&mut config, None, None, event_sender).await?;
+
+        // a signed message stripped of its source and signature
+        let peer = PeerId::random();
+        let cid = *create_block(ipld!(&b"tampered"[..])).cid();
+        node.handle_gossip(libp2p::gossipsub::GossipsubEvent::Message {
+            propagation_source: peer,
+            message_id: MessageId::from("tampered"),
+            message: GossipsubMessage {
+                source: None,
+                data: cid.to_bytes(),
+                sequence_number: None,
+                topic: network_topic(&config.network_name, URSA_CONTENT).hash(),
+            },
+        })?;
+
+        let mut delivered = false;
+        while let Ok(event) = event_receiver.try_recv() {
+            delivered |= matches!(
+                event,
+                NetworkEvent::Gossipsub(GossipsubEvent::Message { .. })
+            );
+        }
+        let signed = authenticity == GossipAuthenticity::Signed;
+        assert_eq!(delivered, !signed);
+        assert_eq!(node.peer_scores.get(&peer).invalid_gossip, signed as u32);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_ping_interval() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ping_interval: 1,
+        ping_timeout: 1,
+        ..Default::default()
+    };
+    assert!(config.validate_keep_alive()?.is_empty());
+    let (mut node_1, node_1_addrs, peer_id_1, _) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    // with the default interval of 15s, only the first ping would be sent
+    let mut pings = 0;
+    let _ = timeout(Duration::from_secs(5), async {
+        loop {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Ping(libp2p::ping::Event {
+                        peer,
+                        result: Ok(libp2p::ping::Success::Ping { .. }),
+                    })) = event_2
+                    {
+                        if peer == peer_id_1 {
+                            pings += 1;
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .await;
+    assert!(pings >= 3, "only {pings} pings in 5s");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dual_stack_listening() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        swarm_addrs: vec![
+            "/ip4/127.0.0.1/tcp/0".parse()?,
+            // not a local address, fails to bind without stopping the others
+            "/ip4/192.0.2.1/tcp/0".parse()?,
+        ],
+        swarm_addr: Some("/ip6/::1/tcp/0".parse()?),
+        ..Default::default()
+    };
+    let (event_sender, _) = channel(config.event_buffer);
+    let mut node = UrsaService::new(
+        Keypair::generate_ed25519(),
+        &config,
+        get_store(),
+        event_sender,
+    )?;
+
+    let (mut ip4, mut ip6) = (false, false);
+    timeout(Duration::from_secs(5), async {
+        while !(ip4 && ip6) {
+            if let SwarmEvent::NewListenAddr { address, .. } = node.swarm.select_next_some().await {
+                match address.iter().next() {
+                    Some(Protocol::Ip4(_)) => ip4 = true,
+                    Some(Protocol::Ip6(_)) => ip6 = true,
+                    _ => (),
+                }
+            }
+        }
+    })
+    .await?;
+    assert_eq!(node.swarm.listeners().count(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_transport() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        swarm_addrs: vec!["/ip4/127.0.0.1/tcp/0/ws".parse()?],
+        transport_ws: true,
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, peer_id_1, _) =
+        network_init(&mut config, None, Some(Keypair::generate_ed25519())).await?;
+    assert!(node_1_addrs.iter().any(|p| matches!(p, Protocol::Ws(_))));
+
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        transport_ws: true,
+        ..Default::default()
+    };
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Ping(libp2p::ping::Event {
+                        peer,
+                        result: Ok(libp2p::ping::Success::Ping { .. }),
+                    })) = event_2
+                    {
+                        if peer == peer_id_1 {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(feature = "webrtc")]
+#[tokio::test]
+async fn test_webrtc_transport() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        swarm_addrs: vec!["/ip4/127.0.0.1/udp/0/webrtc".parse()?],
+        transport_webrtc: true,
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, peer_id_1, _) =
+        network_init(&mut config, None, Some(Keypair::generate_ed25519())).await?;
+    // browsers dial with the hash of the node's certificate
+    assert!(node_1_addrs
+        .iter()
+        .any(|p| matches!(p, Protocol::Certhash(_))));
+
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        transport_webrtc: true,
+        ..Default::default()
+    };
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Identify(
+                        libp2p::identify::Event::Received { peer_id, .. },
+                    )) = event_2
+                    {
+                        if peer_id == peer_id_1 {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[test]
+fn test_webrtc_transport_requires_feature() {
+    let config = NetworkConfig {
+        transport_webrtc: true,
+        ..Default::default()
+    };
+    assert_eq!(
+        config.validate_transports().is_ok(),
+        cfg!(feature = "webrtc")
+    );
+}
+
+#[tokio::test]
+async fn test_tls_security() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    for (security_1, security_2) in [
+        (SecurityProtocol::Tls, SecurityProtocol::Tls),
+        (SecurityProtocol::Both, SecurityProtocol::Tls),
+        (SecurityProtocol::Noise, SecurityProtocol::Both),
+    ] {
+        let mut config = NetworkConfig {
+            bootstrap_nodes: vec![],
+            security: security_1,
+            ..Default::default()
+        };
+        let (mut node_1, node_1_addrs, peer_id_1, _) =
+            network_init(&mut config, None, None).await?;
+        let mut config = NetworkConfig {
+            bootstrap_nodes: vec![],
+            security: security_2,
+            ..Default::default()
+        };
+        let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+        timeout(Duration::from_secs(10), async {
+            loop {
+                select! {
+                    _ = node_1.swarm.select_next_some() => (),
+                    event_2 = node_2.swarm.select_next_some() => {
+                        if let SwarmEvent::Behaviour(BehaviourEvent::Identify(
+                            libp2p::identify::Event::Received { peer_id, .. },
+                        )) = event_2
+                        {
+                            if peer_id == peer_id_1 {
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_event_bus_subscribers() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, _, peer_id_2, _) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let bus = node_1.event_bus();
+    let mut subscribers = [bus.subscribe(), bus.subscribe()];
+    tokio::task::spawn(async move { node_2.start().await.unwrap() });
+
+    timeout(Duration::from_secs(10), async {
+        while !node_1.peers.contains(&peer_id_2) {
+            let event = node_1.swarm.select_next_some().await;
+            node_1.handle_swarm_event(event).unwrap();
+        }
+    })
+    .await?;
+
+    // each subscriber receives every event on its own
+    for subscriber in &mut subscribers {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(NetworkEvent::PeerConnected(peer)) = subscriber.recv().await {
+                    if peer == peer_id_2 {
+                        break;
+                    }
+                }
+            }
+        })
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_self_originated_gossip_ignored() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node, _, peer_id, _) =
+        network_init_with_events(&mut config, None, None, event_sender).await?;
+
+    // a message the node published on a topic it is subscribed to, forwarded back by a peer
+    let peer = PeerId::random();
+    let cid = *create_block(ipld!(&b"announced"[..])).cid();
+    node.handle_gossip(libp2p::gossipsub::GossipsubEvent::Message {
+        propagation_source: peer,
+        message_id: MessageId::from("looped"),
+        message: GossipsubMessage {
+            source: Some(peer_id),
+            data: cid.to_bytes(),
+            sequence_number: Some(1),
+            topic: network_topic(&config.network_name, URSA_CONTENT).hash(),
+        },
+    })?;
+
+    while let Ok(event) = event_receiver.try_recv() {
+        assert!(
+            !matches!(
+                event,
+                NetworkEvent::Gossipsub(GossipsubEvent::Message { .. })
+            ),
+            "the node received its own message"
+        );
+    }
+    // forwarding it is not the peer's fault
+    assert_eq!(node.peer_scores.get(&peer).invalid_gossip, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_health() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let health = node_2.health();
+    assert!(health.listening);
+    assert_eq!(health.num_peers, 0);
+    assert!(!health.bootstrap_connected);
+    assert!(!health.ready);
+
+    timeout(Duration::from_secs(10), async {
+        while !node_2.health().ready {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => node_2.handle_swarm_event(event_2).unwrap(),
+            }
+        }
+    })
+    .await?;
+    let health = node_2.health();
+    assert!(health.bootstrap_connected);
+    assert_eq!(health.num_peers, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bootstrap_redial() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, peer_id_1, _) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    timeout(Duration::from_secs(10), async {
+        while !node_2.peers.contains(&peer_id_1) {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => node_2.handle_swarm_event(event_2).unwrap(),
+            }
+        }
+    })
+    .await?;
+
+    // the bootstrap link drops
+    assert!(node_2.swarm.disconnect_peer_id(peer_id_1).is_ok());
+    timeout(Duration::from_secs(10), async {
+        while !node_2.bootstrap_redials.contains(&peer_id_1) {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => node_2.handle_swarm_event(event_2).unwrap(),
+            }
+        }
+    })
+    .await?;
+
+    let mut redial = tokio::time::interval(Duration::from_millis(100));
+    let mut redialed = false;
+    timeout(Duration::from_secs(10), async {
+        while node_2.bootstrap_redials.contains(&peer_id_1) {
+            select! {
+                _ = redial.tick() => node_2.redial_bootstraps(),
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => {
+                    redialed |= matches!(event_2, SwarmEvent::Dialing(peer) if peer == peer_id_1);
+                    node_2.handle_swarm_event(event_2).unwrap();
+                }
+            }
+        }
+    })
+    .await?;
+    assert!(redialed);
+    assert!(node_2.swarm.is_connected(&peer_id_1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bootstrap_command() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    // the bootstrap node is down when the node starts
+    let bootstrap_keypair = Keypair::generate_ed25519();
+    let bootstrap_id = PeerId::from(bootstrap_keypair.public());
+    let port = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port();
+    let bootstrap_listen: Multiaddr = format!("/ip4/127.0.0.1/tcp/{port}").parse()?;
+    let bootstrap_addr = bootstrap_listen
+        .clone()
+        .with(Protocol::P2p(bootstrap_id.into()));
+
+    let mut config = NetworkConfig {
+        kad_bootstrap_interval: None,
+        ..Default::default()
+    };
+    let (mut node, ..) = network_init(&mut config, Some(bootstrap_addr), None).await?;
+    timeout(Duration::from_secs(10), async {
+        loop {
+            let event = node.swarm.select_next_some().await;
+            let failed = matches!(
+                event,
+                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer), .. } if peer == bootstrap_id
+            );
+            node.handle_swarm_event(event).unwrap();
+            if failed {
+                break;
+            }
+        }
+    })
+    .await?;
+
+    let mut bootstrap_config = NetworkConfig {
+        swarm_addrs: vec![bootstrap_listen],
+        bootstrapper: true,
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut bootstrap, ..) =
+        network_init(&mut bootstrap_config, None, Some(bootstrap_keypair)).await?;
+
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::Bootstrap { sender })?;
+    receiver.await??;
+
+    let in_routing_table = |node: &mut UrsaService<MemoryDB>| {
+        node.swarm.behaviour_mut().kad.kbuckets().any(|bucket| {
+            bucket
+                .iter()
+                .any(|entry| *entry.node.key.preimage() == bootstrap_id)
+        })
+    };
+    timeout(Duration::from_secs(10), async {
+        while !(node.swarm.is_connected(&bootstrap_id) && in_routing_table(&mut node)) {
+            select! {
+                _ = bootstrap.swarm.select_next_some() => (),
+                event = node.swarm.select_next_some() => node.handle_swarm_event(event).unwrap(),
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_is_connected() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, peer_id_1, _) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    timeout(Duration::from_secs(10), async {
+        while !node_2.swarm.is_connected(&peer_id_1) {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => node_2.handle_swarm_event(event_2).unwrap(),
+            }
+        }
+    })
+    .await?;
+
+    for (peer_id, connected) in [(peer_id_1, true), (PeerId::random(), false)] {
+        let (sender, receiver) = oneshot::channel();
+        node_2.handle_command(NetworkCommand::IsConnected { peer_id, sender })?;
+        assert_eq!(receiver.await?, connected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_export_import_peers() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let dir = test_dir()?;
+    let peerstore_path = dir.path().join("peers.json");
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        peerstore_path: Some(peerstore_path.clone()),
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, peer_id_1, _) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let (sender, receiver) = oneshot::channel();
+    node_2.handle_command(NetworkCommand::ExportPeers { sender })?;
+    let address_book = receiver.await?;
+    assert!(address_book
+        .iter()
+        .any(|(peer_id, addrs)| *peer_id == peer_id_1 && !addrs.is_empty()));
+
+    // the address book is saved on shutdown and imported by the next node started with it
+    node_2.shutdown();
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        peerstore_path: Some(peerstore_path.clone()),
+        ..Default::default()
+    };
+    let (mut node_3, ..) = network_init(&mut config, None, None).await?;
+    node_3.swarm.dial(DialOpts::peer_id(peer_id_1).build())?;
+
+    timeout(Duration::from_secs(10), async {
+        while !node_3.swarm.is_connected(&peer_id_1) {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_3 = node_3.swarm.select_next_some() => node_3.handle_swarm_event(event_3).unwrap(),
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_protected_peers_kept_over_max_peers() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let (mut bootstrap, bootstrap_addr, bootstrap_id) =
+        run_bootstrap(&mut NetworkConfig::default()).await?;
+
+    let mut config = NetworkConfig {
+        connection_limits: ConnectionLimitsConfig {
+            max_peers: Some(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (mut node, node_addr, ..) = network_init(&mut config, Some(bootstrap_addr), None).await?;
+    assert!(node.is_protected(&bootstrap_id));
+
+    // an unprotected peer connecting over the limit is disconnected
+    let (mut other, _, other_id, _) =
+        network_init(&mut NetworkConfig::default(), Some(node_addr), None).await?;
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                _ = bootstrap.swarm.select_next_some() => (),
+                _ = other.swarm.select_next_some() => (),
+                event = node.swarm.select_next_some() => {
+                    let evicted = matches!(
+                        event,
+                        SwarmEvent::ConnectionClosed { peer_id, .. } if peer_id == other_id
+                    );
+                    node.handle_swarm_event(event).unwrap();
+                    if evicted && node.swarm.is_connected(&bootstrap_id) {
+                        break;
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+    assert!(!node.swarm.is_connected(&other_id));
+    assert!(node.swarm.is_connected(&bootstrap_id));
+
+    // protected peers are kept too
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::ProtectPeer {
+        peer_id: other_id,
+        sender,
+    })?;
+    receiver.await?;
+    assert!(node.is_protected(&other_id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_limits_per_peer() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+
+    let mut limited_config = NetworkConfig {
+        connection_limits: ConnectionLimitsConfig {
+            max_established_per_peer: Some(2),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (mut node_2, ..) =
+        network_init(&mut limited_config, Some(node_1_addrs.clone()), None).await?;
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                _ = node_1.swarm.select_next_some() => (),
+                event_2 = node_2.swarm.select_next_some() => match event_2 {
+                    SwarmEvent::ConnectionEstablished { num_established, .. } => {
+                        // keep dialing until the limit is hit
+                        if num_established.get() <= 2 {
+                            node_2.swarm.dial(node_1_addrs.clone()).unwrap();
+                        }
+                    }
+                    SwarmEvent::OutgoingConnectionError {
+                        error: DialError::ConnectionLimit(limit),
+                        ..
+                    } => {
+                        assert_eq!(limit.limit, 2);
+                        break;
+                    }
+                    _ => (),
+                },
+            }
+        }
+    })
+    .await
+    .expect("connection limit to be hit");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_denied_peer_cannot_connect() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let keypair_2 = Keypair::generate_ed25519();
+    let peer_id_2 = PeerId::from(keypair_2.public());
+
+    let mut config_1 = NetworkConfig {
+        bootstrap_nodes: vec![],
+        deny_peers: HashSet::from([peer_id_2]),
+        ..Default::default()
+    };
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config_1, None, None).await?;
+
+    let mut config_2 = NetworkConfig {
+        bootstrap_nodes: vec![],
+        swarm_addrs: vec!["/ip4/0.0.0.0/tcp/0".parse()?],
+        ..Default::default()
+    };
+    let (mut node_2, ..) = network_init(&mut config_2, None, Some(keypair_2)).await?;
+    node_2.swarm.dial(node_1_addrs)?;
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => match event_1 {
+                    SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                        assert_ne!(peer_id, peer_id_2);
+                    }
+                    SwarmEvent::BannedPeer { peer_id, .. } if peer_id == peer_id_2 => break,
+                    _ => (),
+                },
+                _ = node_2.swarm.select_next_some() => (),
+            }
+        }
+    })
+    .await
+    .expect("denied peer to be rejected");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_allow_list_rejects_strangers() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config_1 = NetworkConfig {
+        bootstrap_nodes: vec![],
+        allow_peers: Some(HashSet::from([PeerId::random()])),
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(config_1.event_buffer);
+    let (mut node_1, node_1_addrs, ..) =
+        network_init_with_events(&mut config_1, None, None, event_sender).await?;
+
+    let mut config_2 = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_2, _, peer_id_2, _) = network_init(&mut config_2, None, None).await?;
+    node_2.swarm.dial(node_1_addrs)?;
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => {
+                    let closed = matches!(
+                        event_1,
+                        SwarmEvent::ConnectionClosed { peer_id, .. } if peer_id == peer_id_2
+                    );
+                    node_1.handle_swarm_event(event_1).unwrap();
+                    if closed {
+                        break;
+                    }
+                }
+                _ = node_2.swarm.select_next_some() => (),
+            }
+        }
+    })
+    .await
+    .expect("stranger to be disconnected");
+
+    while let Ok(event) = event_receiver.try_recv() {
+        assert!(!matches!(
+            event,
+            NetworkEvent::PeerConnected(_) | NetworkEvent::ConnectionEstablished { .. }
+        ));
+    }
+    assert!(!node_1.peers.contains(&peer_id_2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_limits_validation() -> Result<()> {
+    let config = NetworkConfig {
+        connection_limits: ConnectionLimitsConfig {
+            max_established_total: Some(4),
+            max_established_per_peer: Some(8),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (sender, _) = channel(1);
+    let service = UrsaService::new(Keypair::generate_ed25519(), &config, get_store(), sender);
+    assert!(service.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_gossip() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut nodes = spawn_connected_nodes(2).await?;
+    let topic = Topic::new("ursa/test/gossip").hash();
+
+    let (sender, receiver) = oneshot::channel();
+    nodes[1]
+        .command_sender
+        .send(NetworkCommand::GossipsubMessage {
+            peer_id: nodes[1].peer_id,
+            message: crate::GossipsubMessage::Subscribe {
+                peer_id: nodes[1].peer_id,
+                topic: topic.clone(),
+                sender,
+            },
+        })
+        .await?;
+    receiver.await?.map_err(|e| anyhow!("{e:?}"))?;
+
+    // node 1 publishes once it knows node 2 listens on the topic
+    let peer_id_2 = nodes[1].peer_id;
+    timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(NetworkEvent::Gossipsub(GossipsubEvent::Subscribed {
+                peer_id,
+                topic: subscribed,
+            })) = nodes[0].events.recv().await
+            {
+                if peer_id == peer_id_2 && subscribed == topic {
+                    break;
+                }
+            }
+        }
+    })
+    .await?;
+
+    let (sender, receiver) = oneshot::channel();
+    nodes[0]
+        .command_sender
+        .send(NetworkCommand::GossipsubMessage {
+            peer_id: nodes[0].peer_id,
+            message: crate::GossipsubMessage::Publish {
+                topic: topic.clone(),
+                data: Bytes::from_static(b"hello world!"),
+                sender,
+            },
+        })
+        .await?;
+    receiver.await?.map_err(|e| anyhow!("{e:?}"))?;
+
+    let message = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(NetworkEvent::Gossipsub(GossipsubEvent::Message { message, .. })) =
+                nodes[1].events.recv().await
+            {
+                if message.topic == topic {
+                    break message;
+                }
+            }
+        }
+    })
+    .await?;
+    assert_eq!(Bytes::from_static(b"hello world!"), message.data);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gossipsub_content_announcement_validation() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+
+    let (event_sender, mut event_receiver) = channel(4096);
+    let (mut node_1, node_1_addrs, peer_id_1, ..) =
+        network_init_with_events(&mut config, None, None, event_sender).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let topic = network_topic(&config.network_name, URSA_CONTENT);
+    let cid = *get_block(&b"announced content"[..]).cid();
+
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => {
+                    node_1.handle_swarm_event(event_1).unwrap();
+                }
+                event_2 = node_2.swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
+                        libp2p::gossipsub::GossipsubEvent::Subscribed { peer_id, topic: subscribed },
+                    )) = event_2
+                    {
+                        if peer_id == peer_id_1 && subscribed == topic.hash() {
+                            let behaviour = node_2.swarm.behaviour_mut();
+                            behaviour.publish(topic.clone(), b"not a cid".to_vec()).unwrap();
+                            behaviour.publish(topic.clone(), cid.to_bytes()).unwrap();
+                        }
+                    }
+                }
+                Some(event) = event_receiver.recv() => {
+                    if let NetworkEvent::Gossipsub(GossipsubEvent::Message { message, .. }) = event {
+                        if message.topic == topic.hash() {
+                            assert_eq!(message.data, cid.to_bytes());
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_switch_network() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config_1 = NetworkConfig {
+        network_name: "testnet-a".to_string(),
+        ..Default::default()
+    };
+    let (event_sender, mut event_receiver) = channel(4096);
+    let (mut node_1, node_1_addrs, peer_id_1, ..) =
+        network_init_with_events(&mut config_1, None, None, event_sender).await?;
+
+    let mut config_2 = NetworkConfig {
+        network_name: "testnet-b".to_string(),
+        ..Default::default()
+    };
+    let (mut node_2, ..) = network_init(&mut config_2, Some(node_1_addrs), None).await?;
+    // node 2 stays on the old network topics as well
+    let old_topic = network_topic("testnet-a", URSA_GLOBAL);
+    node_2.swarm.behaviour_mut().subscribe(&old_topic)?;
+
+    let (sender, switched) = oneshot::channel();
+    node_1.handle_command(NetworkCommand::SwitchNetwork {
+        network_name: "testnet-b".to_string(),
+        sender,
+    })?;
+    switched.await??;
+    assert_eq!(node_1.config.network_name, "testnet-b");
+
+    let new_topic = network_topic("testnet-b", URSA_GLOBAL);
+    loop {
+        select! {
+            event_1 = node_1.swarm.select_next_some() => {
+                node_1.handle_swarm_event(event_1).unwrap();
+            }
+            event_2 = node_2.swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
+                    libp2p::gossipsub::GossipsubEvent::Subscribed { peer_id, topic },
+                )) = event_2
+                {
+                    if peer_id == peer_id_1 && topic == new_topic.hash() {
+                        let behaviour = node_2.swarm.behaviour_mut();
+                        // nobody on the old network is left to receive it
+                        assert!(behaviour.publish(old_topic.clone(), b"old network".to_vec()).is_err());
+                        behaviour.publish(new_topic.clone(), b"new network".to_vec()).unwrap();
+                    }
+                }
+            }
+            Some(event) = event_receiver.recv() => {
+                if let NetworkEvent::Gossipsub(GossipsubEvent::Message { message, .. }) = event {
+                    assert_eq!(message.topic, new_topic.hash());
+                    assert_eq!(message.data, b"new network");
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gossipsub_topic_membership() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, _, peer_id_2, ..) =
+        network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let topic = Topic::new("/ursa/test/membership");
+    let topic_hash = topic.hash();
+
+    timeout(Duration::from_secs(10), async {
+        while !node_1
+            .swarm
+            .behaviour()
+            .topic_peers(&topic_hash)
+            .contains(&peer_id_2)
+        {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => node_1.handle_swarm_event(event_1).unwrap(),
+                event_2 = node_2.swarm.select_next_some() => {
+                    if let SwarmEvent::ConnectionEstablished { .. } = event_2 {
+                        node_2.swarm.behaviour_mut().subscribe(&topic).unwrap();
+                    }
+                }
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gossip_mesh_stats() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, node_2_addrs, ..) =
+        network_init(&mut config, Some(node_1_addrs.clone()), None).await?;
+    let (mut node_3, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+    node_3.swarm.dial(node_2_addrs)?;
+
+    // the events are not handled by the service, so the peers are not made explicit
+    // gossipsub peers on identify and join the meshes on the next heartbeats
+    let topic = network_topic(&config.network_name, URSA_GLOBAL).hash();
+    let mesh_formed = async {
+        loop {
+            select! {
+                _ = node_1.swarm.select_next_some() => {}
+                _ = node_2.swarm.select_next_some() => {}
+                _ = node_3.swarm.select_next_some() => {}
+            }
+            let meshes = [&node_1, &node_2, &node_3].map(|node| {
+                node.swarm
+                    .behaviour()
+                    .mesh_peer_counts()
+                    .get(&topic)
+                    .copied()
+            });
+            if meshes.iter().all(|peers| *peers == Some(2)) {
+                break;
+            }
+        }
+    };
+    timeout(Duration::from_secs(30), mesh_formed).await?;
+
+    let (sender, receiver) = oneshot::channel();
+    node_1.handle_command(NetworkCommand::GetGossipStats { sender })?;
+    let stats = receiver.await?;
+    let global = stats
+        .get(topic.as_str())
+        .expect("stats of the global topic");
+    assert_eq!(global.mesh_peers, 2);
+    assert_eq!(global.all_peers, 2);
+    assert_eq!(global.messages_published, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_gossipsub_unsupported_peer_is_disconnected() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+
+    // a bare node that only speaks ping
+    let keypair = Keypair::generate_ed25519();
+    let bare_peer_id = PeerId::from(keypair.public());
+    let mut bare_node = Swarm::with_tokio_executor(
+        build_transport(&keypair, &config, None)?.0,
+        Ping::default(),
+        bare_peer_id,
+    );
+    bare_node.dial(node_1_addrs)?;
+
+    let mut not_supported = false;
+    timeout(Duration::from_secs(30), async {
+        loop {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => {
+                    match &event_1 {
+                        SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(
+                            libp2p::gossipsub::GossipsubEvent::GossipsubNotSupported { peer_id },
+                        )) if *peer_id == bare_peer_id => not_supported = true,
+                        SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. }
+                            if *peer_id == bare_peer_id && not_supported => break,
+                        _ => (),
+                    }
+                    node_1.handle_swarm_event(event_1).unwrap();
+                }
+                _ = bare_node.select_next_some() => (),
+            }
+        }
+    })
+    .await
+    .expect("peer without gossipsub to be disconnected");
+
+    assert!(!node_1.swarm.is_connected(&bare_peer_id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_mdns() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        mdns: true,
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+
+    let (node_1, _, peer_id_1, ..) = network_init(&mut config, None, None).await?;
+    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+
+    let (mut node_2, ..) = network_init(&mut config, None, None).await?;
+
+    loop {
+        let event = node_2.swarm.select_next_some().await;
+        if let SwarmEvent::ConnectionEstablished { peer_id, .. } = event {
+            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}");
+            if peer_id == peer_id_1 {
+                break;
+            }
+        };
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_mdns_announce() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        mdns: true,
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+
+    let (mut node_1, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, _, peer_id_2, ..) = network_init(&mut config, None, None).await?;
+
+    let mut announced = false;
+    loop {
+        select! {
+            event_1 = node_1.swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(BehaviourEvent::Mdns(MdnsEvent::Discovered(peers))) = &event_1 {
+                    if peers.iter().any(|(peer_id, _)| *peer_id == peer_id_2) {
+                        if announced {
+                            // rediscovered after the forced announce
+                            break;
+                        }
+                        let (sender, receiver) = oneshot::channel();
+                        node_1.handle_command(NetworkCommand::MdnsAnnounce { sender })?;
+                        receiver.await??;
+                        announced = true;
+                        continue;
+                    }
+                }
+                node_1.handle_swarm_event(event_1)?;
+            }
+            _ = node_2.swarm.select_next_some() => (),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_mdns_config() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let (sender, _) = channel(1);
+
+    // an mDNS query interval of zero is refused
+    let config = NetworkConfig {
+        mdns: true,
+        mdns_query_interval: 0,
+        ..Default::default()
+    };
+    let node = UrsaService::new(
+        Keypair::generate_ed25519(),
+        &config,
+        get_store(),
+        sender.clone(),
+    );
+    assert!(node.is_err());
+
+    // the query interval is not used without mDNS, and nothing is announced
+    let config = NetworkConfig {
+        mdns: false,
+        ..config
+    };
+    let mut node = UrsaService::new(Keypair::generate_ed25519(), &config, get_store(), sender)?;
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::MdnsAnnounce { sender })?;
+    assert!(receiver.await?.is_err());
+
+    // nodes querying more often than the default still discover each other
+    let mut config = NetworkConfig {
+        mdns: true,
+        mdns_query_interval: 1,
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node_1, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, _, peer_id_2, ..) = network_init(&mut config, None, None).await?;
+    timeout(Duration::from_secs(10), async {
+        loop {
+            select! {
+                event_1 = node_1.swarm.select_next_some() => {
+                    if let SwarmEvent::Behaviour(BehaviourEvent::Mdns(MdnsEvent::Discovered(peers))) = event_1 {
+                        if peers.iter().any(|(peer_id, _)| *peer_id == peer_id_2) {
+                            break;
+                        }
+                    }
+                }
+                _ = node_2.swarm.select_next_some() => (),
+            }
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_kad() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+
+    let (bootstrap, bootstrap_addr, bootstrap_id) =
+        run_bootstrap(&mut NetworkConfig::default()).await?;
+
+    tokio::task::spawn(async move { bootstrap.start().await.unwrap() });
+
+    let (mut node_1, _, peer_id_1, ..) = network_init(
+        &mut NetworkConfig::default(),
+        Some(bootstrap_addr.clone()),
+        None,
+    )
+    .await?;
+
+    // wait for node 1 to identify with bootstrap
+    loop {
+        if let SwarmEvent::Behaviour(BehaviourEvent::Identify(libp2p::identify::Event::Sent {
+            peer_id,
+            ..
+        })) = node_1.swarm.select_next_some().await
+        {
+            info!("[SwarmEvent::Identify::Sent]: {peer_id:?}, {bootstrap_id:?}");
+            if peer_id == bootstrap_id {
+                break;
+            }
+        }
+    }
+
+    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+
+    let (mut node_2, ..) =
+        network_init(&mut NetworkConfig::default(), Some(bootstrap_addr), None).await?;
+
+    // wait for node 2 to connect with node 1 through kad peer discovery
+    loop {
+        if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
+            node_2.swarm.select_next_some().await
+        {
+            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}");
+            if peer_id == peer_id_1 {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_req_res() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+
+    let (mut node_1, node_1_addrs, peer_id_1, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, _, peer_id_2, ..) =
+        network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    // Wait for at least one connection
+    loop {
+        if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
+            node_1.swarm.select_next_some().await
+        {
+            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}: ");
+            break;
+        }
+    }
+
+    let node_1_sender = node_1.command_sender();
+    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+
+    let (sender, _) = oneshot::channel();
+    let request = UrsaExchangeRequest(RequestType::CarRequest("Qm".to_string()));
+    let msg = NetworkCommand::SendRequest {
+        peer_id: peer_id_2,
+        request: Box::new(request),
+        channel: sender,
+    };
+
+    assert!(node_1_sender.send(msg).await.is_ok());
+
+    loop {
+        if let SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+            RequestResponseEvent::Message { peer, message },
+        )) = timeout(Duration::from_secs(5), node_2.swarm.select_next_some())
+            .await
+            .expect("event to be received")
+        {
+            info!("[RequestResponseEvent::Message]: {peer:?}, {message:?}");
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_req_res_round_trip() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (node_1_sender, peer_id_2) = (&nodes[0].command_sender, nodes[1].peer_id);
+
+    let (sender, receiver) = oneshot::channel();
+    let request = UrsaExchangeRequest(RequestType::StoreSummary(Box::new(CacheSummary::default())));
+    let msg = NetworkCommand::SendRequest {
+        peer_id: peer_id_2,
+        request: Box::new(request),
+        channel: sender,
+    };
+    assert!(node_1_sender.send(msg).await.is_ok());
+
+    let response = timeout(Duration::from_secs(10), receiver)
+        .await
+        .expect("response to be received")??;
+    assert_eq!(
+        response,
+        UrsaExchangeResponse(ResponseType::StoreSummaryRequest)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_car_request() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (node_1_sender, store_1) = (&nodes[0].command_sender, &nodes[0].store);
+    let (peer_id_2, store_2) = (nodes[1].peer_id, &nodes[1].store);
+
+    // put the car file in store 2
+    let file = File::open(Path::new("../../test_files/test.car")).await?;
+    let cids = load_car(store_2.blockstore(), BufReader::new(file)).await?;
+    let root = cids[0];
+
+    let (sender, receiver) = oneshot::channel();
+    let request = UrsaExchangeRequest(RequestType::CarRequest(root.to_string()));
+    let msg = NetworkCommand::SendRequest {
         peer_id: peer_id_2,
         request: Box::new(request),
         channel: sender,
     };
+    assert!(node_1_sender.send(msg).await.is_ok());
+
+    let response = timeout(Duration::from_secs(10), receiver)
+        .await
+        .expect("response to be received")??;
+    let car = match response.0 {
+        ResponseType::CarResponse(car) => car,
+        response => panic!("unexpected response {response:?}"),
+    };
+    assert_eq!(car.cid, root.to_string());
+
+    let roots = load_car(store_1.blockstore(), car.data.as_slice()).await?;
+    assert_eq!(roots, vec![root]);
+    assert_eq!(
+        store_1.dag_traversal(&root)?.len(),
+        store_2.dag_traversal(&root)?.len()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_req_res_outbound_failure() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+
+    let (node_1, ..) = network_init(&mut config, None, None).await?;
+    let node_1_sender = node_1.command_sender();
+    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+
+    // the peer is unknown, dialing it fails
+    let (sender, receiver) = oneshot::channel();
+    let request = UrsaExchangeRequest(RequestType::StoreSummary(Box::new(CacheSummary::default())));
+    let msg = NetworkCommand::SendRequest {
+        peer_id: PeerId::random(),
+        request: Box::new(request),
+        channel: sender,
+    };
+    assert!(node_1_sender.send(msg).await.is_ok());
 
-    assert!(node_1_sender.send(msg).is_ok());
+    let response = timeout(Duration::from_secs(10), receiver)
+        .await
+        .expect("failure to be received")?;
+    assert!(response.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_network_req_res_inbound_failure() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
 
+    let (mut node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, _, peer_id_2, ..) =
+        network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    // Wait for at least one connection
     loop {
-        if let SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
-            RequestResponseEvent::Message { peer, message },
-        )) = timeout(Duration::from_secs(5), node_2.swarm.select_next_some())
+        if let SwarmEvent::ConnectionEstablished { .. } = node_1.swarm.select_next_some().await {
+            break;
+        }
+    }
+
+    let node_1_sender = node_1.command_sender();
+    tokio::task::spawn(async move { node_1.start().await.unwrap() });
+
+    let (sender, _receiver) = oneshot::channel();
+    let request = UrsaExchangeRequest(RequestType::StoreSummary(Box::new(CacheSummary::default())));
+    let msg = NetworkCommand::SendRequest {
+        peer_id: peer_id_2,
+        request: Box::new(request),
+        channel: sender,
+    };
+    assert!(node_1_sender.send(msg).await.is_ok());
+
+    loop {
+        let event = timeout(Duration::from_secs(10), node_2.swarm.select_next_some())
             .await
-            .expect("event to be received")
+            .expect("event to be received");
+        match event {
+            // drop the request without answering it
+            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                RequestResponseEvent::Message { .. },
+            )) => (),
+            SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
+                RequestResponseEvent::InboundFailure { .. },
+            )) => {
+                node_2.handle_swarm_event(event)?;
+                break;
+            }
+            event => node_2.handle_swarm_event(event)?,
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_get() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let bitswap_store_1 = BitswapStorage(nodes[0].store.clone());
+    let mut bitswap_store_2 = BitswapStorage(nodes[1].store.clone());
+
+    let block = get_block(&b"hello world"[..]);
+    info!("inserting block into bitswap store for node 1");
+    insert_block(bitswap_store_1, &block);
+
+    let (sender, receiver) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: *block.cid(),
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    };
+
+    assert!(node_2_sender.send(msg).await.is_ok());
+
+    let res = receiver
+        .await
+        .expect("Unable to receive from bitswap channel");
+
+    match res {
+        Ok(_) => {
+            let store_1_block = bitswap_store_2.get(block.cid()).unwrap();
+
+            info!(
+                "inserting block into bitswap store for node 1, {:?}",
+                store_1_block
+            );
+            assert_eq!(store_1_block, Some(block.data().to_vec()));
+        }
+        Err(e) => panic!("{e:?}"),
+    }
+
+    Ok(())
+}
+
+/// Log lines written by a tracing subscriber, shared with the test reading them.
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8_lossy(&self.0.lock().unwrap())
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+#[tokio::test]
+async fn test_bitswap_logs_carry_query_id() -> Result<()> {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    // the nodes run on the test thread, so their logs go to this subscriber
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let block = get_block(&b"correlated"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::GetBitswap {
+            cid: *block.cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })
+        .await?;
+    timeout(Duration::from_secs(10), receiver).await???;
+
+    let cid = format!("cid={}", block.cid());
+    assert!(logs.lines().iter().any(|line| {
+        line.contains("started the bitswap query")
+            && line.contains("network_command{")
+            && line.contains(&cid)
+            && line.contains("query_id=")
+    }));
+
+    Ok(())
+}
+
+/// Value of the bitswap completions counter in the metrics served at `addr`.
+async fn scrape_bitswap_total(addr: std::net::SocketAddr) -> Result<f64> {
+    let metrics = surf::get(format!("http://{addr}/metrics"))
+        .recv_string()
+        .await
+        .map_err(|e| e.into_inner())?;
+    // the completions are only labeled with bounded values
+    for line in metrics
+        .lines()
+        .filter(|l| l.starts_with("ursa_bitswap_total"))
+    {
+        assert!(line.starts_with("ursa_bitswap_total{block_found=\""));
+        assert!(!line.contains("cid="));
+    }
+    Ok(metrics
+        .lines()
+        .filter(|line| line.starts_with("ursa_bitswap_total{block_found=\"true\"}"))
+        .filter_map(|line| line.split_whitespace().last()?.parse().ok())
+        .sum())
+}
+
+#[tokio::test]
+async fn test_bitswap_metrics_exported() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let server = axum::Server::bind(&"127.0.0.1:0".parse()?)
+        .serve(ursa_metrics::routes::init().into_make_service());
+    let metrics_addr = server.local_addr();
+    tokio::task::spawn(server);
+
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let block = get_block(&b"exported"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let before = scrape_bitswap_total(metrics_addr).await?;
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::GetBitswap {
+            cid: *block.cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })
+        .await?;
+    timeout(Duration::from_secs(10), receiver).await???;
+
+    assert!(scrape_bitswap_total(metrics_addr).await? > before);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_get_with_provider_discovery() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+
+    let (bootstrap, bootstrap_addr, bootstrap_id) =
+        run_bootstrap(&mut NetworkConfig::default()).await?;
+    tokio::task::spawn(async move { bootstrap.start().await.unwrap() });
+
+    // the provider records the block in the dht through the bootstrap node
+    let (mut provider, _, provider_id, provider_store) = network_init(
+        &mut NetworkConfig::default(),
+        Some(bootstrap_addr.clone()),
+        None,
+    )
+    .await?;
+    let block = get_block(&b"hello providers"[..]);
+    insert_block(BitswapStorage(provider_store), &block);
+    let (sender, receiver) = oneshot::channel();
+    provider.handle_command(NetworkCommand::Put {
+        cid: *block.cid(),
+        sender,
+    })?;
+    receiver.await??;
+    loop {
+        if let SwarmEvent::Behaviour(BehaviourEvent::Kad(
+            KademliaEvent::OutboundQueryProgressed {
+                result: QueryResult::StartProviding(result),
+                ..
+            },
+        )) = provider.swarm.select_next_some().await
         {
-            info!("[RequestResponseEvent::Message]: {peer:?}, {message:?}");
+            assert!(result.is_ok());
+            break;
+        }
+    }
+    tokio::task::spawn(async move { provider.start().await.unwrap() });
+
+    // the fetching node only knows the bootstrap node, which doesn't have the block
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node, _, _, store) = network_init(&mut config, None, None).await?;
+    node.swarm.dial(bootstrap_addr)?;
+    loop {
+        let event = node.swarm.select_next_some().await;
+        let identified = matches!(
+            &event,
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(libp2p::identify::Event::Received {
+                peer_id,
+                ..
+            })) if *peer_id == bootstrap_id
+        );
+        node.handle_swarm_event(event)?;
+        if identified {
             break;
         }
     }
+    assert!(!node.peers.contains(&provider_id));
+
+    let node_sender = node.command_sender();
+    tokio::task::spawn(async move { node.start().await.unwrap() });
+
+    let (sender, receiver) = oneshot::channel();
+    node_sender
+        .send(NetworkCommand::GetBitswapWithDiscovery {
+            cid: *block.cid(),
+            sender,
+        })
+        .await?;
+    timeout(Duration::from_secs(30), receiver).await???;
+
+    let stored = BitswapStorage(store).get(block.cid())?;
+    assert_eq!(stored, Some(block.data().to_vec()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_get_local_block() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig {
+        bootstrap_nodes: vec![],
+        ..Default::default()
+    };
+    let (mut node, _, _, store) = network_init(&mut config, None, None).await?;
+
+    let block = get_block(&b"already here"[..]);
+    insert_block(BitswapStorage(store), &block);
+
+    // no peer to ask, the block is served from the local store
+    assert!(node.peers.peers().is_empty());
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::GetBitswap {
+        cid: *block.cid(),
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    })?;
+    // the local dag is counted off the event loop before the fetch is answered
+    let (cid, purpose, count) = node.dag_count_receiver.recv().await.unwrap();
+    node.handle_dag_count(cid, purpose, count)?;
+    receiver.await??;
+    assert!(node.bitswap_queries.is_empty());
+    assert!(node.response_channels.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_get_trace() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let block = get_block(&b"hello timing"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let (sender, receiver) = oneshot::channel();
+    let (trace_sender, trace_receiver) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: *block.cid(),
+        max_parallel_blocks: None,
+        trace: Some(trace_sender),
+        max_bytes: None,
+        sender,
+    };
+
+    assert!(node_2_sender.send(msg).await.is_ok());
+
+    receiver
+        .await
+        .expect("Unable to receive from bitswap channel")?;
+    let timing = trace_receiver
+        .await
+        .expect("Unable to receive the fetch timing");
+
+    info!("fetch timing: {timing:?}");
+    assert!(timing.network_wait > Duration::ZERO);
+    assert!(timing.block_insertion > Duration::ZERO);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bandwidth() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let content = vec![7; 256 * 1024];
+    let block = get_block(&content);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let get_bandwidth = || async {
+        let (sender, receiver) = oneshot::channel();
+        node_2_sender
+            .send(NetworkCommand::GetBandwidth { sender })
+            .await?;
+        Ok::<_, anyhow::Error>(receiver.await?)
+    };
+    let before = get_bandwidth().await?;
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::GetBitswap {
+            cid: *block.cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })
+        .await?;
+    timeout(Duration::from_secs(30), receiver).await???;
+
+    let after = get_bandwidth().await?;
+    let received = after.inbound - before.inbound;
+    assert!(received >= block.data().len() as u64);
+    // the block plus the framing and the other protocols' traffic
+    assert!(
+        received < 2 * block.data().len() as u64,
+        "received {received} bytes"
+    );
+    assert!(after.outbound > before.outbound);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_trace_bitswap() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut nodes = spawn_connected_nodes(2).await?;
+    let (peer_id_1, node_2_sender) = (nodes[0].peer_id, nodes[1].command_sender.clone());
+
+    let block = get_block(&b"hello tracing"[..]);
+    let untraced = get_block(&b"hello silence"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &untraced);
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::TraceBitswap {
+            cid: *block.cid(),
+            enabled: true,
+            sender,
+        })
+        .await?;
+    receiver.await?;
+
+    for fetched in [&untraced, &block] {
+        let (sender, receiver) = oneshot::channel();
+        node_2_sender
+            .send(NetworkCommand::GetBitswap {
+                cid: *fetched.cid(),
+                max_parallel_blocks: None,
+                trace: None,
+                max_bytes: None,
+                sender,
+            })
+            .await?;
+        receiver.await??;
+    }
+
+    let mut traces = Vec::new();
+    loop {
+        if let NetworkEvent::BitswapTrace { cid, trace } = nodes[1].events.recv().await.unwrap() {
+            assert_eq!(cid, *block.cid());
+            let complete = matches!(trace, BitswapTrace::Complete { .. });
+            traces.push(trace);
+            if complete {
+                break;
+            }
+        }
+    }
+
+    assert!(matches!(
+        &traces[0],
+        BitswapTrace::WantSent { block: want, peers }
+            if want == block.cid() && peers.contains(&peer_id_1)
+    ));
+    assert_eq!(traces.last(), Some(&BitswapTrace::Complete { found: true }));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_falls_back_to_bitswap() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (store_1, node_2_sender) = (&nodes[0].store, &nodes[1].command_sender);
+
+    let block = get_block(&b"hello fetch"[..]);
+    insert_block(BitswapStorage(store_1.clone()), &block);
+
+    let (sender, receiver) = oneshot::channel();
+    let msg = NetworkCommand::Fetch {
+        cid: *block.cid(),
+        strategies: vec![RetrievalStrategy::Local, RetrievalStrategy::Bitswap],
+        sender,
+    };
+    assert!(node_2_sender.send(msg).await.is_ok());
+
+    let (strategy, car) = timeout(Duration::from_secs(30), receiver)
+        .await
+        .expect("fetch to complete")??;
+    assert_eq!(strategy, RetrievalStrategy::Bitswap);
+    assert_eq!(car, store_1.car_bytes(block.cid(), u64::MAX)?);
+
+    Ok(())
+}
+
+async fn pending_query_count(sender: &Sender<NetworkCommand>) -> usize {
+    let (count_sender, receiver) = oneshot::channel();
+    assert!(sender
+        .send(NetworkCommand::PendingQueryCount {
+            sender: count_sender
+        })
+        .await
+        .is_ok());
+    receiver.await.expect("pending query count")
+}
+
+#[tokio::test]
+async fn test_bitswap_max_pending_queries() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let config = NetworkConfig {
+        max_pending_queries: 1,
+        ..Default::default()
+    };
+    let nodes = spawn_connected_nodes_with(2, &config).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let block = get_block(&b"pending query"[..]);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+
+    let (sender, pending) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: *block.cid(),
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    };
+    assert!(node_2_sender.send(msg).await.is_ok());
+    assert_eq!(pending_query_count(node_2_sender).await, 1);
+
+    // the map is saturated, a query for another cid is rejected
+    let (sender, rejected) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: *get_block(&b"rejected query"[..]).cid(),
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    };
+    assert!(node_2_sender.send(msg).await.is_ok());
+    let error = rejected.await?.expect_err("query to be rejected");
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::TooManyPendingQueries(1))
+    ));
+
+    // while the pending query completes
+    pending.await??;
+    assert_eq!(pending_query_count(node_2_sender).await, 0);
 
     Ok(())
 }
 
 #[tokio::test]
-async fn test_bitswap_get() -> Result<()> {
+async fn test_bitswap_coalesces_concurrent_gets() -> Result<()> {
     setup_logger(LevelFilter::Info);
     let mut config = NetworkConfig {
         mdns: true,
         ..Default::default()
     };
 
-    let (mut node_1, node_1_addrs, peer_id_1, store_1) =
-        network_init(&mut config, None, None).await?;
-    let (node_2, _, _, store_2) = network_init(&mut config, Some(node_1_addrs), None).await?;
-
-    let bitswap_store_1 = BitswapStorage(store_1.clone());
-    let mut bitswap_store_2 = BitswapStorage(store_2.clone());
+    let (mut node_1, node_1_addrs, _, store_1) = network_init(&mut config, None, None).await?;
+    let (event_sender, mut event_receiver) = channel(config.event_buffer);
+    let (mut node_2, ..) =
+        network_init_with_events(&mut config, Some(node_1_addrs), None, event_sender).await?;
 
-    let block = get_block(&b"hello world"[..]);
-    info!("inserting block into bitswap store for node 1");
-    insert_block(bitswap_store_1, &block);
+    let block = get_block(&b"popular block"[..]);
+    insert_block(BitswapStorage(store_1.clone()), &block);
 
     // Wait for at least one connection
     loop {
-        if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
-            node_1.swarm.select_next_some().await
-        {
-            info!(
-                "[SwarmEvent::ConnectionEstablished]: {:?}, {:?}: ",
-                peer_id, peer_id_1
-            );
+        if let SwarmEvent::ConnectionEstablished { .. } = node_1.swarm.select_next_some().await {
             break;
         }
     }
 
-    let node_2_sender = node_2.command_sender();
+    let mut receivers = Vec::new();
+    for _ in 0..10 {
+        let (sender, receiver) = oneshot::channel();
+        node_2.handle_command(NetworkCommand::GetBitswap {
+            cid: *block.cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })?;
+        receivers.push(receiver);
+    }
 
     // Start nodes
     tokio::task::spawn(async move { node_1.start().await.unwrap() });
     tokio::task::spawn(async move { node_2.start().await.unwrap() });
 
-    let (sender, receiver) = oneshot::channel();
-    let msg = NetworkCommand::GetBitswap {
-        cid: *block.cid(),
-        sender,
-    };
+    for receiver in receivers {
+        timeout(Duration::from_secs(10), receiver).await???;
+    }
 
-    assert!(node_2_sender.send(msg).is_ok());
+    let mut queries = 0;
+    while let Ok(event) = event_receiver.try_recv() {
+        if let NetworkEvent::BitswapWant { cid, .. } = event {
+            assert_eq!(cid, *block.cid());
+            queries += 1;
+        }
+    }
+    assert_eq!(queries, 1);
 
-    let res = receiver
-        .await
-        .expect("Unable to receive from bitswap channel");
+    Ok(())
+}
 
-    match res {
-        Ok(_) => {
-            let store_1_block = bitswap_store_2.get(block.cid()).unwrap();
+#[tokio::test]
+async fn test_bitswap_cancel_all_queries() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
 
-            info!(
-                "inserting block into bitswap store for node 1, {:?}",
-                store_1_block
-            );
-            assert_eq!(store_1_block, Some(block.data().to_vec()));
-        }
-        Err(e) => panic!("{e:?}"),
+    let (_node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let mut receivers = Vec::new();
+    for content in [&b"first"[..], b"second", b"third"] {
+        let (sender, receiver) = oneshot::channel();
+        node_2.handle_command(NetworkCommand::GetBitswap {
+            cid: *get_block(content).cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })?;
+        receivers.push(receiver);
+    }
+
+    let (sender, cancelled) = oneshot::channel();
+    node_2.handle_command(NetworkCommand::CancelAllQueries { sender })?;
+    assert_eq!(cancelled.await?, 3);
+    assert!(node_2.response_channels.is_empty());
+
+    for receiver in receivers {
+        let error = receiver.await?.expect_err("query to be cancelled");
+        assert!(matches!(
+            error.downcast_ref::<NetworkError>(),
+            Some(NetworkError::Cancelled)
+        ));
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_cancel_query() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+
+    let (_node_1, node_1_addrs, ..) = network_init(&mut config, None, None).await?;
+    let (mut node_2, ..) = network_init(&mut config, Some(node_1_addrs), None).await?;
+
+    let (cancelled_cid, kept_cid) = (*get_block(b"cancelled").cid(), *get_block(b"kept").cid());
+    let mut receivers = Vec::new();
+    for cid in [cancelled_cid, kept_cid] {
+        let (sender, receiver) = oneshot::channel();
+        node_2.handle_command(NetworkCommand::GetBitswap {
+            cid,
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })?;
+        receivers.push(receiver);
     }
 
+    let (sender, cancelled) = oneshot::channel();
+    node_2.handle_command(NetworkCommand::CancelBitswap {
+        cid: cancelled_cid,
+        sender,
+    })?;
+    assert!(cancelled.await?);
+    assert!(!node_2.response_channels.contains_key(&cancelled_cid));
+    assert!(!node_2.active_queries.contains_key(&cancelled_cid));
+    assert!(node_2.response_channels.contains_key(&kept_cid));
+
+    let error = receivers
+        .remove(0)
+        .await?
+        .expect_err("query to be cancelled");
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::Cancelled)
+    ));
+
+    // nothing is left to cancel
+    let (sender, cancelled) = oneshot::channel();
+    node_2.handle_command(NetworkCommand::CancelBitswap {
+        cid: cancelled_cid,
+        sender,
+    })?;
+    assert!(!cancelled.await?);
+
     Ok(())
 }
 
 #[tokio::test]
 async fn test_bitswap_sync() -> Result<()> {
     setup_logger(LevelFilter::Info);
-    let mut config = NetworkConfig {
-        mdns: true,
-        ..Default::default()
+    let nodes = spawn_connected_nodes(2).await?;
+    let (store_1, node_2_sender) = (&nodes[0].store, &nodes[1].command_sender);
+    let mut bitswap_store_2 = BitswapStorage(nodes[1].store.clone());
+
+    // put the car file in store 1
+    let path = Path::new("../../test_files/test.car");
+    let file = File::open(path).await?;
+    let reader = BufReader::new(file);
+    let cids = load_car(store_1.blockstore(), reader).await?;
+
+    let file_h = File::open(path).await?;
+    let reader_h = BufReader::new(file_h);
+    let mut car_reader = CarReader::new(reader_h).await?;
+
+    let mut cids_vec = Vec::<Cid>::new();
+    while let Some(block) = car_reader.next_block().await? {
+        cids_vec.push(block.cid);
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: cids[0],
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
     };
 
-    let (mut node_1, node_1_addrs, peer_id_1, store_1) =
-        network_init(&mut config, None, None).await?;
-    let (node_2, _, _, store_2) = network_init(&mut config, Some(node_1_addrs), None).await?;
+    assert!(node_2_sender.send(msg).await.is_ok());
 
-    let mut bitswap_store_2 = BitswapStorage(store_2.clone());
+    let res = receiver
+        .await
+        .expect("Unable to receive from bitswap channel");
 
-    // Wait for at least one connection
-    loop {
-        if let SwarmEvent::ConnectionEstablished { peer_id, .. } =
-            node_1.swarm.select_next_some().await
-        {
-            info!("[SwarmEvent::ConnectionEstablished]: {peer_id:?}, {peer_id_1:?}: ");
-            break;
+    match res {
+        Ok(_) => {
+            for cid in cids_vec {
+                assert!(bitswap_store_2.contains(&cid).unwrap());
+            }
         }
+        Err(e) => panic!("{e:?}"),
     }
 
-    let node_2_sender = node_2.command_sender();
+    Ok(())
+}
 
-    // Start nodes
-    tokio::task::spawn(async move { node_1.start().await.unwrap() });
-    tokio::task::spawn(async move { node_2.start().await.unwrap() });
+async fn sync_with_parallelism(max_parallel_blocks: usize) -> Result<()> {
+    let nodes = spawn_connected_nodes(2).await?;
+    let (store_1, node_2_sender) = (&nodes[0].store, &nodes[1].command_sender);
+    let mut bitswap_store_2 = BitswapStorage(nodes[1].store.clone());
 
     // put the car file in store 1
     let path = Path::new("../../test_files/test.car");
@@ -413,13 +3527,17 @@ async fn test_bitswap_sync() -> Result<()> {
     let (sender, receiver) = oneshot::channel();
     let msg = NetworkCommand::GetBitswap {
         cid: cids[0],
+        max_parallel_blocks: NonZeroUsize::new(max_parallel_blocks),
+        trace: None,
+        max_bytes: None,
         sender,
     };
 
-    assert!(node_2_sender.send(msg).is_ok());
+    assert!(node_2_sender.send(msg).await.is_ok());
 
-    let res = receiver
+    let res = timeout(Duration::from_secs(30), receiver)
         .await
+        .expect("sync to complete")
         .expect("Unable to receive from bitswap channel");
 
     match res {
@@ -434,6 +3552,299 @@ async fn test_bitswap_sync() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_bitswap_sync_max_parallel_blocks() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+
+    for max_parallel_blocks in [1, 8] {
+        info!("syncing dag with {max_parallel_blocks} blocks in flight");
+        sync_with_parallelism(max_parallel_blocks).await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_sync_skips_block_not_found() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+
+    // the provider lost one leaf of the dag
+    let data: Vec<u8> = (0..8192u32).map(|i| (i * 31 / 7) as u8).collect();
+    let root = nodes[0]
+        .store
+        .import(&data, &ChunkingStrategy::FixedSize(1024))?;
+    let leaves: Vec<Cid> = nodes[0]
+        .store
+        .dag_traversal(&root)?
+        .into_iter()
+        .map(|(cid, _)| cid)
+        .filter(|cid| *cid != root)
+        .collect();
+    nodes[0].store.delete_block(&leaves[0])?;
+
+    let (sender, receiver) = oneshot::channel();
+    nodes[1]
+        .command_sender
+        .send(NetworkCommand::GetBitswap {
+            cid: root,
+            max_parallel_blocks: NonZeroUsize::new(2),
+            trace: None,
+            max_bytes: None,
+            sender,
+        })
+        .await?;
+
+    let result = timeout(Duration::from_secs(30), receiver).await???;
+    assert_eq!(result.missing, vec![leaves[0]]);
+    // the root and every leaf but the lost one
+    assert_eq!(result.fetched, leaves.len());
+    let mut bitswap_store = BitswapStorage(nodes[1].store.clone());
+    for leaf in &leaves[1..] {
+        assert!(bitswap_store.contains(leaf)?);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_sync_max_bytes() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (store_1, store_2) = (&nodes[0].store, &nodes[1].store);
+    let node_2_sender = &nodes[1].command_sender;
+
+    let path = Path::new("../../test_files/test.car");
+    let reader = BufReader::new(File::open(path).await?);
+    let cids = load_car(store_1.blockstore(), reader).await?;
+
+    let max_bytes = 1024;
+    let (sender, receiver) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: cids[0],
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: Some(max_bytes),
+        sender,
+    };
+    assert!(node_2_sender.send(msg).await.is_ok());
+
+    let error = timeout(Duration::from_secs(30), receiver)
+        .await
+        .expect("sync to be aborted")?
+        .expect_err("sync to go over the budget");
+    match error.downcast_ref::<NetworkError>() {
+        Some(NetworkError::BudgetExceeded {
+            fetched,
+            max_bytes: budget,
+        }) => {
+            assert_eq!(*budget, max_bytes);
+            assert!(*fetched > max_bytes);
+        }
+        other => panic!("expected the budget to be exceeded, got {other:?}"),
+    }
+    assert!(!store_2.has_full_dag(&cids[0])?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_fetch_diagnostics() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (peer_id_1, node_2_sender) = (nodes[0].peer_id, &nodes[1].command_sender);
+
+    // neither node has the block
+    let block = get_block(&b"nowhere to be found"[..]);
+    let (sender, receiver) = oneshot::channel();
+    let msg = NetworkCommand::GetBitswap {
+        cid: *block.cid(),
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    };
+    assert!(node_2_sender.send(msg).await.is_ok());
+
+    let error = timeout(Duration::from_secs(30), receiver)
+        .await
+        .expect("fetch to fail")?
+        .expect_err("the block to not be found");
+    match error.downcast_ref::<NetworkError>() {
+        Some(NetworkError::NotFound(diagnostics)) => {
+            assert_eq!(diagnostics.cid, *block.cid());
+            assert_eq!(
+                diagnostics.peers_with(PeerFetchOutcome::DontHave),
+                HashSet::from([peer_id_1])
+            );
+        }
+        other => panic!("expected the block to not be found, got {other:?}"),
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_not_found_cache() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    let cid = *get_block(&b"still nowhere to be found"[..]).cid();
+    let get = |sender| NetworkCommand::GetBitswap {
+        cid,
+        max_parallel_blocks: None,
+        trace: None,
+        max_bytes: None,
+        sender,
+    };
+
+    // the first get asks the peers
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender.send(get(sender)).await?;
+    let error = timeout(Duration::from_secs(30), receiver)
+        .await
+        .expect("fetch to fail")?
+        .expect_err("the block to not be found");
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::NotFound(_))
+    ));
+
+    // the second one fails right away
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender.send(get(sender)).await?;
+    let error = timeout(Duration::from_millis(500), receiver)
+        .await
+        .expect("fetch to fail from the negative cache")?
+        .expect_err("the block to not be found");
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::RecentlyNotFound(c)) if *c == cid
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_bitswap_sync_reports_missing_blocks() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let node_2_sender = &nodes[1].command_sender;
+
+    // node 1 has the root and one leaf, but not the other one
+    let leaf = get_block(&b"available leaf"[..]);
+    let unavailable = get_block(&b"unavailable leaf"[..]);
+    let root = create_block(Ipld::List(vec![
+        Ipld::Link(*leaf.cid()),
+        Ipld::Link(*unavailable.cid()),
+    ]));
+    insert_block(BitswapStorage(nodes[0].store.clone()), &root);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &leaf);
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::GetBitswap {
+            cid: *root.cid(),
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender,
+        })
+        .await?;
+    let result = timeout(Duration::from_secs(30), receiver).await???;
+    assert_eq!(
+        result,
+        SyncResult {
+            fetched: 2,
+            missing: vec![*unavailable.cid()],
+        }
+    );
+    assert!(!result.is_complete());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_block() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (store_2, node_2_sender) = (&nodes[1].store, &nodes[1].command_sender);
+
+    // only the block is fetched, not the ones it links to
+    let leaf = get_block(&b"linked leaf"[..]);
+    let block = create_block(Ipld::List(vec![Ipld::Link(*leaf.cid())]));
+    insert_block(BitswapStorage(nodes[0].store.clone()), &block);
+    insert_block(BitswapStorage(nodes[0].store.clone()), &leaf);
+
+    // the second time, it's read from the local store
+    for _ in 0..2 {
+        let (sender, receiver) = oneshot::channel();
+        node_2_sender
+            .send(NetworkCommand::GetBlock {
+                cid: *block.cid(),
+                sender,
+            })
+            .await?;
+        let data = timeout(Duration::from_secs(10), receiver).await???;
+        assert_eq!(data, block.data());
+        assert!(!BitswapStorage(store_2.clone()).contains(leaf.cid())?);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_block_admission() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let sender = &nodes[1].command_sender;
+
+    // concurrent gets of a block nobody has share one query, and fail together
+    let cid = *get_block(&b"no block here"[..]).cid();
+    let mut receivers = vec![];
+    for _ in 0..2 {
+        let (block_sender, receiver) = oneshot::channel();
+        sender
+            .send(NetworkCommand::GetBlock {
+                cid,
+                sender: block_sender,
+            })
+            .await?;
+        receivers.push(receiver);
+    }
+    assert_eq!(pending_query_count(sender).await, 1);
+    for receiver in receivers {
+        let error = timeout(Duration::from_secs(30), receiver)
+            .await
+            .expect("get to fail")?
+            .expect_err("the block to not be found");
+        assert!(matches!(
+            error.downcast_ref::<NetworkError>(),
+            Some(NetworkError::NotFound(_))
+        ));
+    }
+    assert_eq!(pending_query_count(sender).await, 0);
+
+    // the next get fails right away from the negative cache
+    let (block_sender, receiver) = oneshot::channel();
+    sender
+        .send(NetworkCommand::GetBlock {
+            cid,
+            sender: block_sender,
+        })
+        .await?;
+    let error = timeout(Duration::from_millis(500), receiver)
+        .await
+        .expect("get to fail from the negative cache")?
+        .expect_err("the block to not be found");
+    assert!(matches!(
+        error.downcast_ref::<NetworkError>(),
+        Some(NetworkError::RecentlyNotFound(c)) if *c == cid
+    ));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_put_command() -> Result<()> {
     setup_logger(LevelFilter::Info);
@@ -529,24 +3940,143 @@ async fn test_put_command() -> Result<()> {
         cid: *block.cid(),
         sender,
     };
-    assert!(node_1_sender.send(request).is_ok());
+    assert!(node_1_sender.send(request).await.is_ok());
     assert!(receiver.await.is_ok());
 
     // Wait for node 1 to send cache request to node 2.
     // Wait for node 2 to pull content from node 1.
-    for s in (3..5).rev() {
-        tokio::time::sleep(Duration::from_secs(s)).await;
+    let replicated = timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(data) = store_2.get(block.cid()).unwrap() {
+                break data;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("content to be replicated");
+    assert_eq!(replicated, block.data().to_vec());
+
+    Ok(())
+}
 
-        let store_1_block = store_2.get(block.cid()).unwrap();
-        info!("Block received {store_1_block:?}");
+#[tokio::test]
+async fn test_provide_intents_replayed_on_restart() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let mut config = NetworkConfig::default();
+    let (mut node, .., store) = network_init(&mut config, None, None).await?;
 
-        if store_1_block.is_some() {
-            assert_eq!(store_1_block, Some(block.data().to_vec()));
-            return Ok(());
-        }
-    }
+    let block = get_block(&b"provide me"[..]);
+    store.put_keyed(block.cid(), block.data())?;
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::Put {
+        cid: *block.cid(),
+        sender,
+    })?;
+    receiver.await??;
+    drop(node);
+
+    // restart the node on the same db
+    let store = Arc::new(UrsaStore::new(Arc::clone(&store.db)));
+    let (event_sender, _) = channel(4096);
+    let mut node = UrsaService::new(Keypair::generate_ed25519(), &config, store, event_sender)?;
+
+    let (sender, receiver) = oneshot::channel();
+    node.handle_command(NetworkCommand::GetProvideIntents { sender })?;
+    assert_eq!(receiver.await??, vec![*block.cid()]);
+
+    let key = provider_key(block.cid());
+    let is_provided = |node: &mut UrsaService<MemoryDB>| {
+        node.swarm
+            .behaviour_mut()
+            .kad
+            .store_mut()
+            .provided()
+            .any(|record| record.key == key)
+    };
+    assert!(!is_provided(&mut node));
+    node.replay_provide_intents()?;
+    assert!(is_provided(&mut node));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_find_providers() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let nodes = spawn_connected_nodes(2).await?;
+    let (peer_id_1, node_2_sender) = (nodes[0].peer_id, &nodes[1].command_sender);
+
+    let block = get_block(&b"find my providers"[..]);
+    nodes[0].store.put_keyed(block.cid(), block.data())?;
+    let (sender, receiver) = oneshot::channel();
+    nodes[0]
+        .command_sender
+        .send(NetworkCommand::StartProviding {
+            cid: *block.cid(),
+            sender,
+        })
+        .await?;
+    receiver.await??;
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::FindProviders {
+            cid: *block.cid(),
+            sender,
+        })
+        .await?;
+    let providers = timeout(Duration::from_secs(30), receiver).await???;
+    assert!(providers.contains(&peer_id_1));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_put_and_get_record() -> Result<()> {
+    setup_logger(LevelFilter::Info);
+    let config = NetworkConfig {
+        kad_max_record_size: 1024,
+        ..Default::default()
+    };
+    let nodes = spawn_connected_nodes_with(2, &config).await?;
+    let (node_1_sender, node_2_sender) = (&nodes[0].command_sender, &nodes[1].command_sender);
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::PutRecord {
+            key: b"name".to_vec(),
+            value: vec![0; 2048],
+            sender,
+        })
+        .await?;
+    let err = receiver.await?.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(NetworkError::RecordTooLarge { size: 2048, .. })
+    ));
+
+    let (sender, receiver) = oneshot::channel();
+    node_2_sender
+        .send(NetworkCommand::PutRecord {
+            key: b"name".to_vec(),
+            value: b"pointer".to_vec(),
+            sender,
+        })
+        .await?;
+    timeout(Duration::from_secs(30), receiver).await???;
+
+    let (sender, receiver) = oneshot::channel();
+    node_1_sender
+        .send(NetworkCommand::GetRecord {
+            key: b"name".to_vec(),
+            sender,
+        })
+        .await?;
+    let value = timeout(Duration::from_secs(30), receiver).await???;
+    assert_eq!(value, b"pointer".to_vec());
 
-    panic!("Failed to replicate content")
+    Ok(())
 }
 
 #[tokio::test]
@@ -0,0 +1,97 @@
+//! Nodes spun up for the tests, connected without fixed sleeps.
+
+use crate::{NetworkCommand, NetworkConfig, NetworkEvent, UrsaService};
+use anyhow::{anyhow, Result};
+use db::MemoryDB;
+use futures::StreamExt;
+use libp2p::{identity::Keypair, multiaddr::Protocol, swarm::SwarmEvent, Multiaddr, PeerId};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tempfile::TempDir;
+use tokio::{
+    sync::mpsc::{channel, Receiver, Sender},
+    time::timeout,
+};
+use ursa_store::UrsaStore;
+
+/// A running node of [`spawn_connected_nodes`].
+pub(crate) struct TestNode {
+    pub peer_id: PeerId,
+    /// The listen address of the node, with its peer id.
+    pub addr: Multiaddr,
+    pub store: Arc<UrsaStore<MemoryDB>>,
+    pub command_sender: Sender<NetworkCommand>,
+    /// The events emitted once the node was connected to the others.
+    pub events: Receiver<NetworkEvent>,
+}
+
+/// Start `n` nodes on free local ports with in-memory stores, each dialing the nodes started
+/// before it, and wait until every node reported every other one as connected.
+pub(crate) async fn spawn_connected_nodes(n: usize) -> Result<Vec<TestNode>> {
+    spawn_connected_nodes_with(n, &NetworkConfig::default()).await
+}
+
+/// Like [`spawn_connected_nodes`], the nodes being configured with `config` apart from
+/// their listen and bootstrap addresses.
+pub(crate) async fn spawn_connected_nodes_with(
+    n: usize,
+    config: &NetworkConfig,
+) -> Result<Vec<TestNode>> {
+    let mut nodes: Vec<TestNode> = Vec::with_capacity(n);
+    for _ in 0..n {
+        let config = NetworkConfig {
+            swarm_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse()?],
+            bootstrap_nodes: nodes.iter().map(|node| node.addr.clone()).collect(),
+            ..config.clone()
+        };
+        let keypair = Keypair::generate_ed25519();
+        let peer_id = PeerId::from(keypair.public());
+        let store = Arc::new(UrsaStore::new(Arc::new(MemoryDB::default())));
+        let (event_sender, events) = channel(4096);
+        let mut service = UrsaService::new(keypair, &config, Arc::clone(&store), event_sender)?;
+
+        let mut addr = loop {
+            match timeout(Duration::from_secs(5), service.swarm.select_next_some()).await? {
+                SwarmEvent::NewListenAddr { address, .. } => break address,
+                // the dials of the other nodes may already be in progress
+                event => service.handle_swarm_event(event)?,
+            }
+        };
+        addr.push(Protocol::P2p(peer_id.into()));
+        let command_sender = service.command_sender();
+        tokio::task::spawn(async move { service.start().await.unwrap() });
+
+        nodes.push(TestNode {
+            peer_id,
+            addr,
+            store,
+            command_sender,
+            events,
+        });
+    }
+
+    let peer_ids: HashSet<PeerId> = nodes.iter().map(|node| node.peer_id).collect();
+    for node in nodes.iter_mut() {
+        let mut waiting: HashSet<PeerId> = peer_ids.clone();
+        waiting.remove(&node.peer_id);
+        timeout(Duration::from_secs(10), async {
+            while !waiting.is_empty() {
+                match node.events.recv().await {
+                    Some(NetworkEvent::PeerConnected(peer_id)) => {
+                        waiting.remove(&peer_id);
+                    }
+                    Some(_) => (),
+                    None => return Err(anyhow!("The node {} stopped", node.peer_id)),
+                }
+            }
+            Ok(())
+        })
+        .await
+        .map_err(|_| anyhow!("The node {} didn't connect to the others", node.peer_id))??;
+    }
+    Ok(nodes)
+}
+
+/// A directory of its own for the files written by a test, removed when dropped.
+pub(crate) fn test_dir() -> Result<TempDir> {
+    Ok(tempfile::Builder::new().prefix("ursa-test-").tempdir()?)
+}
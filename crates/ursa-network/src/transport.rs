@@ -1,79 +1,173 @@
 //! Ursa Transport implementation.
+use anyhow::Result;
+use futures::{AsyncRead, AsyncWrite};
+#[cfg(feature = "webrtc")]
+use libp2p::webrtc;
 use libp2p::{
+    bandwidth::BandwidthSinks,
     core::{
         muxing::StreamMuxerBox,
         transport::{upgrade, Boxed, OrTransport},
-        upgrade::SelectUpgrade,
+        upgrade::{MapInboundUpgrade, MapOutboundUpgrade, SelectUpgrade},
     },
     identity::Keypair,
     mplex, noise, quic,
     relay::v2::client::transport::ClientTransport,
     swarm::derive_prelude::EitherOutput,
-    tcp, yamux, PeerId, Transport,
+    tcp, tls, websocket, yamux, PeerId, Transport, TransportExt,
 };
+use std::sync::Arc;
 
-use crate::config::NetworkConfig;
+use crate::config::{NetworkConfig, SecurityProtocol};
 
 /// Creates a new [`UrsaTransport`].
 ///
 /// Defaults to QUIC transport over TCP.
 /// If QUIC fails to establish a connection, we fail over to TCP.
+/// With `transport_ws`, `/ws` addresses are listened on and dialed with WebSocket over TCP.
+/// With `transport_webrtc`, `/webrtc` addresses are listened on and dialed with WebRTC.
+///
+/// The bytes sent and received over every connection are counted in the returned sinks.
 pub(crate) fn build_transport(
     keypair: &Keypair,
-    // todo(botch): make some of the transport options configurable
-    _config: &NetworkConfig,
+    config: &NetworkConfig,
     relay_transport: Option<ClientTransport>,
-) -> Boxed<(PeerId, StreamMuxerBox)> {
-    let id_keys = keypair;
-
+) -> Result<(Boxed<(PeerId, StreamMuxerBox)>, Arc<BandwidthSinks>)> {
     let tcp = {
         let tcp_config = tcp::Config::default().port_reuse(true);
         let tcp_transport = tcp::tokio::Transport::new(tcp_config);
 
-        let noise = {
-            let dh_keys = noise::Keypair::<noise::X25519Spec>::new()
-                .into_authentic(id_keys)
-                .expect("Signing libp2p-noise static DH keypair failed.");
-
-            noise::NoiseConfig::xx(dh_keys).into_authenticated()
-        };
-
-        let mplex = {
-            let mut mplex_config = mplex::MplexConfig::new();
-            mplex_config.set_max_buffer_behaviour(mplex::MaxBufferBehaviour::Block);
-            mplex_config.set_max_buffer_size(usize::MAX);
-
-            let mut yamux_config = yamux::YamuxConfig::default();
-            yamux_config.set_window_update_mode(yamux::WindowUpdateMode::on_read());
-
-            SelectUpgrade::new(yamux_config, mplex_config)
-        };
-
         if let Some(relay) = relay_transport {
-            tcp_transport
-                .or_transport(relay)
-                .upgrade(upgrade::Version::V1)
-                .authenticate(noise)
-                .multiplex(mplex)
-                .boxed()
+            authenticate_and_multiplex(tcp_transport.or_transport(relay), keypair, config.security)
         } else {
-            tcp_transport
-                .upgrade(upgrade::Version::V1)
-                .authenticate(noise)
-                .multiplex(mplex)
-                .boxed()
+            authenticate_and_multiplex(tcp_transport, keypair, config.security)
         }
     };
 
+    // plain tcp doesn't accept `/ws` addresses, they fall through to the websocket transport
+    let tcp = if config.transport_ws {
+        let ws_transport =
+            websocket::WsConfig::new(tcp::tokio::Transport::new(tcp::Config::default()));
+        OrTransport::new(
+            tcp,
+            authenticate_and_multiplex(ws_transport, keypair, config.security),
+        )
+        .map(|either_output, _| match either_output {
+            EitherOutput::First(output) => output,
+            EitherOutput::Second(output) => output,
+        })
+        .boxed()
+    } else {
+        tcp
+    };
+
     let quic = {
         let quic_config = quic::Config::new(keypair);
         quic::tokio::Transport::new(quic_config)
     };
 
-    OrTransport::new(quic, tcp)
+    let transport = OrTransport::new(quic, tcp)
         .map(|either_output, _| match either_output {
             EitherOutput::First((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
-            EitherOutput::Second((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+            EitherOutput::Second((peer_id, muxer)) => (peer_id, muxer),
         })
-        .boxed()
+        .boxed();
+
+    // quic refuses the `/webrtc` addresses, they fall through to the webrtc transport
+    #[cfg(feature = "webrtc")]
+    let transport = if config.transport_webrtc {
+        OrTransport::new(transport, webrtc_transport(keypair)?)
+            .map(|either_output, _| match either_output {
+                EitherOutput::First(output) => output,
+                EitherOutput::Second((peer_id, connection)) => {
+                    (peer_id, StreamMuxerBox::new(connection))
+                }
+            })
+            .boxed()
+    } else {
+        transport
+    };
+
+    Ok(transport.with_bandwidth_logging())
+}
+
+/// WebRTC with a certificate generated for this run, its hash is part of the listen addresses.
+#[cfg(feature = "webrtc")]
+fn webrtc_transport(keypair: &Keypair) -> Result<webrtc::tokio::Transport> {
+    let certificate = webrtc::tokio::Certificate::generate(&mut rand::thread_rng())
+        .map_err(|e| anyhow::anyhow!("Generating the WebRTC certificate failed: {e}"))?;
+    Ok(webrtc::tokio::Transport::new(keypair.clone(), certificate))
+}
+
+/// Secure `transport` with `security` and multiplex its connections with yamux or mplex.
+fn authenticate_and_multiplex<T, C>(
+    transport: T,
+    keypair: &Keypair,
+    security: SecurityProtocol,
+) -> Boxed<(PeerId, StreamMuxerBox)>
+where
+    T: Transport<Output = C> + Send + Unpin + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    C: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let transport = transport.upgrade(upgrade::Version::V1);
+    match security {
+        SecurityProtocol::Noise => transport
+            .authenticate(noise_config(keypair))
+            .multiplex(muxer_config())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed(),
+        SecurityProtocol::Tls => transport
+            .authenticate(tls_config(keypair))
+            .multiplex(muxer_config())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+            .boxed(),
+        SecurityProtocol::Both => {
+            let security = SelectUpgrade::new(noise_config(keypair), tls_config(keypair));
+            // both upgrades authenticate the remote, move the peer id out of the either
+            let security = MapOutboundUpgrade::new(
+                MapInboundUpgrade::new(security, either_peer_id),
+                either_peer_id,
+            );
+            transport
+                .authenticate(security)
+                .multiplex(muxer_config())
+                .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+                .boxed()
+        }
+    }
+}
+
+fn either_peer_id<A, B>(
+    output: EitherOutput<(PeerId, A), (PeerId, B)>,
+) -> (PeerId, EitherOutput<A, B>) {
+    match output {
+        EitherOutput::First((peer_id, stream)) => (peer_id, EitherOutput::First(stream)),
+        EitherOutput::Second((peer_id, stream)) => (peer_id, EitherOutput::Second(stream)),
+    }
+}
+
+fn noise_config(keypair: &Keypair) -> noise::NoiseAuthenticated<noise::XX, noise::X25519Spec, ()> {
+    let dh_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(keypair)
+        .expect("Signing libp2p-noise static DH keypair failed.");
+
+    noise::NoiseConfig::xx(dh_keys).into_authenticated()
+}
+
+fn tls_config(keypair: &Keypair) -> tls::Config {
+    tls::Config::new(keypair).expect("Generating the libp2p-tls certificate failed.")
+}
+
+fn muxer_config() -> SelectUpgrade<yamux::YamuxConfig, mplex::MplexConfig> {
+    let mut mplex_config = mplex::MplexConfig::new();
+    mplex_config.set_max_buffer_behaviour(mplex::MaxBufferBehaviour::Block);
+    mplex_config.set_max_buffer_size(usize::MAX);
+
+    let mut yamux_config = yamux::YamuxConfig::default();
+    yamux_config.set_window_update_mode(yamux::WindowUpdateMode::on_read());
+
+    SelectUpgrade::new(yamux_config, mplex_config)
 }
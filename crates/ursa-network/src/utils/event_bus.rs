@@ -0,0 +1,59 @@
+use metrics::increment_counter;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::NetworkEvent;
+
+/// Broadcasts the events of the service to any number of subscribers, alongside the
+/// event channel given to the service. Handles are cheap to clone.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<NetworkEvent>,
+}
+
+impl EventBus {
+    /// A bus keeping up to `capacity` events for the subscribers that fall behind.
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Receive the events emitted from now on.
+    pub fn subscribe(&self) -> EventSubscriber {
+        EventSubscriber {
+            receiver: self.sender.subscribe(),
+        }
+    }
+
+    pub(crate) fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub(crate) fn publish(&self, event: NetworkEvent) {
+        // fails only when nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+}
+
+/// Receives the events of an [`EventBus`].
+#[derive(Debug)]
+pub struct EventSubscriber {
+    receiver: broadcast::Receiver<NetworkEvent>,
+}
+
+impl EventSubscriber {
+    /// Wait for the next event. The events a lagging subscriber missed are skipped.
+    /// Returns `None` once the service and every handle of the bus are dropped.
+    pub async fn recv(&mut self) -> Option<NetworkEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => {
+                    increment_counter!("network_events_lagged");
+                    warn!("[EventSubscriber] - lagging behind, skipped {missed} network events");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use libipld::Cid;
+use libp2p::PeerId;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// Why a peer did not provide the block of a failed bitswap fetch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerFetchOutcome {
+    /// The peer was asked and answered that it doesn't have the block.
+    DontHave,
+    /// The peer was asked and the query ran past the bitswap request timeout.
+    TimedOut,
+    /// The connection to the peer closed while the query was in flight.
+    Disconnected,
+    /// The peer was connected but not asked, its cache summary lacks the block or it
+    /// was past the fanout.
+    NotAsked,
+}
+
+/// Per peer detail of a failed bitswap fetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchDiagnostics {
+    pub cid: Cid,
+    pub peers: HashMap<PeerId, PeerFetchOutcome>,
+}
+
+impl FetchDiagnostics {
+    /// Peers with the given outcome.
+    pub fn peers_with(&self, outcome: PeerFetchOutcome) -> HashSet<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_, o)| **o == outcome)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+/// Peers of a bitswap query in flight.
+///
+/// Bitswap doesn't report the answer of each peer, so a peer that was asked, stayed
+/// connected and answered before the request timeout is taken to not have the block.
+pub struct FetchPeers {
+    started: Instant,
+    asked: HashSet<PeerId>,
+    not_asked: HashSet<PeerId>,
+    disconnected: HashSet<PeerId>,
+}
+
+impl FetchPeers {
+    pub fn new(asked: HashSet<PeerId>, connected: impl IntoIterator<Item = PeerId>) -> Self {
+        let not_asked = connected
+            .into_iter()
+            .filter(|peer| !asked.contains(peer))
+            .collect();
+        Self {
+            started: Instant::now(),
+            asked,
+            not_asked,
+            disconnected: HashSet::new(),
+        }
+    }
+
+    /// Record the connection to `peer` closing.
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        if self.asked.contains(peer) {
+            self.disconnected.insert(*peer);
+        }
+    }
+
+    pub fn diagnostics(self, cid: Cid, request_timeout: Duration) -> FetchDiagnostics {
+        let timed_out = self.started.elapsed() >= request_timeout;
+        let mut peers: HashMap<_, _> = self
+            .not_asked
+            .into_iter()
+            .map(|peer| (peer, PeerFetchOutcome::NotAsked))
+            .collect();
+        for peer in self.asked {
+            let outcome = if self.disconnected.contains(&peer) {
+                PeerFetchOutcome::Disconnected
+            } else if timed_out {
+                PeerFetchOutcome::TimedOut
+            } else {
+                PeerFetchOutcome::DontHave
+            };
+            peers.insert(peer, outcome);
+        }
+        FetchDiagnostics { cid, peers }
+    }
+}
@@ -0,0 +1,152 @@
+use libipld::{Block, Cid, DefaultParams, Result};
+use libp2p_bitswap::BitswapStore;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+use ursa_store::BitswapStorage;
+
+/// Timing breakdown of a bitswap fetch.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FetchTiming {
+    /// Time spent selecting the peers to query.
+    pub peer_selection: Duration,
+    /// Time spent waiting on the network for bitswap queries to complete.
+    pub network_wait: Duration,
+    /// Time spent inserting fetched blocks into the store.
+    pub block_insertion: Duration,
+    /// Time spent checking the store for blocks that are already present.
+    pub store_checks: Duration,
+}
+
+/// Cumulative time spent in the bitswap store.
+///
+/// The store is shared by every query, so time spent while a fetch is in flight is
+/// attributed to it. With concurrent fetches the numbers overlap.
+#[derive(Debug, Default)]
+pub struct StoreTimings {
+    insert_nanos: AtomicU64,
+    check_nanos: AtomicU64,
+}
+
+impl StoreTimings {
+    pub fn record_insert(&self, elapsed: Duration) {
+        self.insert_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_check(&self, elapsed: Duration) {
+        self.check_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> (u64, u64) {
+        (
+            self.insert_nanos.load(Ordering::Relaxed),
+            self.check_nanos.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Timing of a traced fetch that is still in flight.
+pub struct FetchTimer {
+    started: Instant,
+    peer_selection: Duration,
+    store_snapshot: (u64, u64),
+    senders: Vec<oneshot::Sender<FetchTiming>>,
+}
+
+impl FetchTimer {
+    pub fn new(
+        peer_selection: Duration,
+        store_timings: &StoreTimings,
+        sender: oneshot::Sender<FetchTiming>,
+    ) -> Self {
+        Self {
+            started: Instant::now(),
+            peer_selection,
+            store_snapshot: store_timings.snapshot(),
+            senders: vec![sender],
+        }
+    }
+
+    pub fn add_sender(&mut self, sender: oneshot::Sender<FetchTiming>) {
+        self.senders.push(sender);
+    }
+
+    /// Compute the breakdown and send it to everyone tracing this fetch.
+    pub fn finish(self, store_timings: &StoreTimings) {
+        let (insert, check) = store_timings.snapshot();
+        let block_insertion = Duration::from_nanos(insert.saturating_sub(self.store_snapshot.0));
+        let store_checks = Duration::from_nanos(check.saturating_sub(self.store_snapshot.1));
+        let timing = FetchTiming {
+            peer_selection: self.peer_selection,
+            network_wait: self
+                .started
+                .elapsed()
+                .saturating_sub(block_insertion + store_checks),
+            block_insertion,
+            store_checks,
+        };
+
+        for sender in self.senders {
+            // the caller may have stopped waiting for the timing
+            let _ = sender.send(timing.clone());
+        }
+    }
+}
+
+/// [`BitswapStorage`] that records the time bitswap spends in the store.
+pub struct TimedBitswapStorage<S>
+where
+    S: fvm_ipld_blockstore::Blockstore + db::Store + Send + Sync + 'static,
+{
+    inner: BitswapStorage<S>,
+    timings: Arc<StoreTimings>,
+}
+
+impl<S> TimedBitswapStorage<S>
+where
+    S: fvm_ipld_blockstore::Blockstore + db::Store + Send + Sync + 'static,
+{
+    pub fn new(inner: BitswapStorage<S>, timings: Arc<StoreTimings>) -> Self {
+        Self { inner, timings }
+    }
+}
+
+impl<S> BitswapStore for TimedBitswapStorage<S>
+where
+    S: fvm_ipld_blockstore::Blockstore + db::Store + Send + Sync + 'static,
+{
+    type Params = DefaultParams;
+
+    fn contains(&mut self, cid: &Cid) -> Result<bool> {
+        let started = Instant::now();
+        let res = self.inner.contains(cid);
+        self.timings.record_check(started.elapsed());
+        res
+    }
+
+    fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        self.inner.get(cid)
+    }
+
+    fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
+        let started = Instant::now();
+        let res = self.inner.insert(block);
+        self.timings.record_insert(started.elapsed());
+        res
+    }
+
+    fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
+        let started = Instant::now();
+        let res = self.inner.missing_blocks(cid);
+        self.timings.record_check(started.elapsed());
+        res
+    }
+}
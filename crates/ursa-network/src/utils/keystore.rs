@@ -0,0 +1,57 @@
+use anyhow::{Context, Result};
+use libp2p::identity::Keypair;
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+use tracing::info;
+
+/// Load the protobuf encoded keypair stored at `path`, or generate an ed25519 keypair
+/// and store it there, so the node keeps the same [`libp2p::PeerId`] across restarts.
+pub fn load_or_generate_keypair(path: &Path) -> Result<Keypair> {
+    if path.exists() {
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read keypair from {}", path.display()))?;
+        let keypair = Keypair::from_protobuf_encoding(&bytes)
+            .with_context(|| format!("Failed to decode keypair from {}", path.display()))?;
+        info!(
+            "Loaded keypair {} from {}",
+            keypair.public().to_peer_id(),
+            path.display()
+        );
+        return Ok(keypair);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, keypair.to_protobuf_encoding()?)
+        .with_context(|| format!("Failed to write keypair to {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!(
+        "Generated keypair {} at {}",
+        keypair.public().to_peer_id(),
+        path.display()
+    );
+
+    Ok(keypair)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_support::test_dir;
+
+    #[test]
+    fn test_keypair_persists_peer_id() -> Result<()> {
+        let dir = test_dir()?;
+        let path = dir.path().join("node.key");
+
+        let generated = load_or_generate_keypair(&path)?;
+        assert!(path.exists());
+        let reloaded = load_or_generate_keypair(&path)?;
+        assert_eq!(
+            generated.public().to_peer_id(),
+            reloaded.public().to_peer_id()
+        );
+        Ok(())
+    }
+}
@@ -1 +1,13 @@
 pub mod cache_summary;
+pub mod event_bus;
+pub mod fetch_diagnostics;
+pub mod fetch_timing;
+pub mod keystore;
+pub mod parallel_sync;
+pub mod peer_score;
+pub mod peerstore;
+pub mod protocol_version;
+pub mod provider_discovery;
+pub mod rate_limit;
+pub mod redial;
+pub mod relay_reservation;
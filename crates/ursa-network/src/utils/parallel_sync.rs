@@ -0,0 +1,122 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use libipld::Cid;
+use libp2p::PeerId;
+use libp2p_bitswap::QueryId;
+use std::{collections::VecDeque, num::NonZeroUsize};
+
+/// Bitswap sync of a dag that keeps at most `max_parallel` block requests in flight.
+///
+/// Blocks are requested one by one with bitswap `get` queries. Once a block lands in the
+/// store, its missing children are queued and requested as soon as there is room.
+pub struct ParallelSync {
+    /// Maximum number of block requests in flight.
+    max_parallel: usize,
+    /// Peers the blocks are requested from.
+    providers: Vec<PeerId>,
+    /// Missing blocks that have not been requested yet.
+    queue: VecDeque<Cid>,
+    /// Block requests in flight.
+    in_flight: FnvHashMap<QueryId, Cid>,
+    /// Every block that has been queued so far.
+    seen: FnvHashSet<Cid>,
+    /// Number of bytes the sync may fetch before it is aborted.
+    max_bytes: Option<u64>,
+    /// Number of bytes fetched so far.
+    fetched_bytes: u64,
+    /// Number of blocks fetched so far.
+    fetched_blocks: usize,
+    /// Blocks no provider served.
+    missing: Vec<Cid>,
+}
+
+impl ParallelSync {
+    pub fn new(max_parallel: NonZeroUsize, providers: Vec<PeerId>, missing: Vec<Cid>) -> Self {
+        let mut sync = Self {
+            max_parallel: max_parallel.get(),
+            providers,
+            queue: VecDeque::new(),
+            in_flight: FnvHashMap::default(),
+            seen: FnvHashSet::default(),
+            max_bytes: None,
+            fetched_bytes: 0,
+            fetched_blocks: 0,
+            missing: Vec::new(),
+        };
+        sync.enqueue(missing);
+        sync
+    }
+
+    /// Abort the sync once it fetched more than `max_bytes`.
+    pub fn with_max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    pub fn fetched_bytes(&self) -> u64 {
+        self.fetched_bytes
+    }
+
+    pub fn fetched_blocks(&self) -> usize {
+        self.fetched_blocks
+    }
+
+    /// Record a fetched block of `size` bytes. Returns false if the sync went over its budget.
+    pub fn record_fetched(&mut self, size: u64) -> bool {
+        self.fetched_blocks += 1;
+        self.fetched_bytes += size;
+        self.max_bytes
+            .map_or(true, |max_bytes| self.fetched_bytes <= max_bytes)
+    }
+
+    /// Record a block no provider served, to report once the rest of the dag is synced.
+    pub fn record_missing(&mut self, cid: Cid) {
+        self.missing.push(cid);
+    }
+
+    /// The blocks no provider served.
+    pub fn into_missing(self) -> Vec<Cid> {
+        self.missing
+    }
+
+    pub fn providers(&self) -> &[PeerId] {
+        &self.providers
+    }
+
+    /// Queue missing blocks, skipping the ones that were already queued.
+    pub fn enqueue(&mut self, cids: impl IntoIterator<Item = Cid>) {
+        for cid in cids {
+            if self.seen.insert(cid) {
+                self.queue.push_back(cid);
+            }
+        }
+    }
+
+    /// Next block to request, if there is room for another request in flight.
+    pub fn next_block(&mut self) -> Option<Cid> {
+        if self.in_flight.len() >= self.max_parallel {
+            return None;
+        }
+        self.queue.pop_front()
+    }
+
+    pub fn register_query(&mut self, query_id: QueryId, cid: Cid) {
+        self.in_flight.insert(query_id, cid);
+    }
+
+    /// Remove a finished request, returning the cid of the block it was fetching.
+    pub fn complete_query(&mut self, query_id: &QueryId) -> Option<Cid> {
+        self.in_flight.remove(query_id)
+    }
+
+    pub fn in_flight(&self) -> impl Iterator<Item = &QueryId> {
+        self.in_flight.keys()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.queue.is_empty() && self.in_flight.is_empty()
+    }
+}
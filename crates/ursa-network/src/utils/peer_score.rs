@@ -0,0 +1,94 @@
+use libp2p::PeerId;
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+/// Peers scored below this are asked for content only once the better scored ones are.
+pub const DEPRIORITIZE_THRESHOLD: f64 = -10.0;
+/// Peers scored below this are disconnected.
+pub const DISCONNECT_THRESHOLD: f64 = -50.0;
+
+const SERVED_WEIGHT: f64 = 1.0;
+const PING_FAILURE_WEIGHT: f64 = -1.0;
+const INVALID_GOSSIP_WEIGHT: f64 = -10.0;
+const DISCONNECT_WEIGHT: f64 = -2.0;
+const TIMEOUT_WEIGHT: f64 = -1.0;
+
+/// Behaviour of a peer, kept across its connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerScore {
+    pub ping_failures: u32,
+    /// Content requests the peer answered with the content.
+    pub served: u32,
+    pub invalid_gossip: u32,
+    /// Connections to the peer closed with an error.
+    pub disconnects: u32,
+    /// Requests the peer did not respond to in time.
+    pub timeouts: u32,
+}
+
+impl PeerScore {
+    pub fn score(&self) -> f64 {
+        self.served as f64 * SERVED_WEIGHT
+            + self.ping_failures as f64 * PING_FAILURE_WEIGHT
+            + self.invalid_gossip as f64 * INVALID_GOSSIP_WEIGHT
+            + self.disconnects as f64 * DISCONNECT_WEIGHT
+            + self.timeouts as f64 * TIMEOUT_WEIGHT
+    }
+}
+
+/// Scores of the most recently seen peers.
+pub struct PeerScores {
+    scores: LruCache<PeerId, PeerScore>,
+}
+
+impl PeerScores {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            scores: LruCache::new(capacity),
+        }
+    }
+
+    /// Update the metrics of `peer`. Returns its new score.
+    pub fn record(&mut self, peer: PeerId, update: impl FnOnce(&mut PeerScore)) -> f64 {
+        let mut score = self.scores.pop(&peer).unwrap_or_default();
+        update(&mut score);
+        self.scores.put(peer, score);
+        score.score()
+    }
+
+    pub fn get(&self, peer: &PeerId) -> PeerScore {
+        self.scores.peek(peer).copied().unwrap_or_default()
+    }
+
+    pub fn score(&self, peer: &PeerId) -> f64 {
+        self.get(peer).score()
+    }
+
+    /// Split `peers` into the ones to ask for content first and the deprioritized ones.
+    pub fn partition(&self, peers: Vec<PeerId>) -> (Vec<PeerId>, Vec<PeerId>) {
+        peers
+            .into_iter()
+            .partition(|peer| self.score(peer) >= DEPRIORITIZE_THRESHOLD)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partition() {
+        let mut scores = PeerScores::new(NonZeroUsize::new(8).unwrap());
+        let (good, bad) = (PeerId::random(), PeerId::random());
+        scores.record(good, |score| score.served += 1);
+        for _ in 0..2 {
+            scores.record(bad, |score| score.invalid_gossip += 1);
+        }
+        assert!(scores.score(&bad) < DEPRIORITIZE_THRESHOLD);
+
+        let unknown = PeerId::random();
+        let (first, last) = scores.partition(vec![good, bad, unknown]);
+        assert_eq!(first, vec![good, unknown]);
+        assert_eq!(last, vec![bad]);
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::{Context, Result};
+use libp2p::{Multiaddr, PeerId};
+use std::{fs, path::Path};
+
+/// Addresses known for each peer, saved across restarts to reconnect without bootstrapping.
+pub type AddressBook = Vec<(PeerId, Vec<Multiaddr>)>;
+
+/// Load the json encoded address book stored at `path`, empty if there is none yet.
+pub fn load_address_book(path: &Path) -> Result<AddressBook> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let bytes = fs::read(path)
+        .with_context(|| format!("Failed to read the address book from {}", path.display()))?;
+    serde_json::from_slice(&bytes)
+        .with_context(|| format!("Failed to decode the address book from {}", path.display()))
+}
+
+pub fn save_address_book(path: &Path, address_book: &AddressBook) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_vec(address_book)?)
+        .with_context(|| format!("Failed to write the address book to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::test_support::test_dir;
+
+    #[test]
+    fn test_address_book_round_trip() -> Result<()> {
+        let dir = test_dir()?;
+        let path = dir.path().join("peerstore").join("peers.json");
+        assert!(load_address_book(&path)?.is_empty());
+
+        let address_book = vec![(
+            PeerId::random(),
+            vec!["/ip4/127.0.0.1/tcp/6009".parse().unwrap()],
+        )];
+        save_address_book(&path, &address_book)?;
+        assert_eq!(load_address_book(&path)?, address_book);
+        Ok(())
+    }
+}
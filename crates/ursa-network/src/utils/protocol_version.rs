@@ -0,0 +1,37 @@
+/// Protocol version advertised by the node through identify.
+pub const URSA_PROTOCOL_VERSION: &str = "ursa/0.1.0";
+
+/// Parse an `ursa/<major>.<minor>.<patch>` protocol version.
+fn parse_version(version: &str) -> Option<(u64, u64, u64)> {
+    let mut parts = version.strip_prefix("ursa/")?.splitn(3, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = parts.next()?.parse().ok()?;
+    Some((major, minor, patch))
+}
+
+/// Whether a peer advertising the protocol version `remote` can exchange with the node,
+/// or `None` if `remote` is not an ursa protocol version.
+///
+/// Versions with the same major are compatible. Before 1.0 the minor must match as well.
+pub fn is_compatible_version(remote: &str) -> Option<bool> {
+    let (major, minor, _) = parse_version(URSA_PROTOCOL_VERSION)?;
+    let (remote_major, remote_minor, _) = parse_version(remote)?;
+    Some(major == remote_major && (major > 0 || minor == remote_minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_compatible_version() {
+        assert_eq!(parse_version("ursa/1.2.3"), Some((1, 2, 3)));
+        assert_eq!(is_compatible_version(URSA_PROTOCOL_VERSION), Some(true));
+        assert_eq!(is_compatible_version("ursa/0.1.7"), Some(true));
+        assert_eq!(is_compatible_version("ursa/0.2.0"), Some(false));
+        assert_eq!(is_compatible_version("ursa/1.1.0"), Some(false));
+        assert_eq!(is_compatible_version("ipfs/0.1.0"), None);
+        assert_eq!(is_compatible_version("ursa/0.1"), None);
+    }
+}
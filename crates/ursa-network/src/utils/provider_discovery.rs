@@ -0,0 +1,80 @@
+use libipld::Cid;
+use libp2p::{
+    kad::{record::Key, QueryId},
+    PeerId,
+};
+use std::collections::{HashSet, VecDeque};
+
+/// Key the providers of `cid` are recorded under in the DHT.
+pub fn provider_key(cid: &Cid) -> Key {
+    Key::new(&cid.hash().to_bytes())
+}
+
+/// Fetch of a cid that falls back to the providers recorded in the DHT.
+///
+/// Once the connected peers fail to serve the cid, the providers are looked up and dialed,
+/// and the cid is requested from each of them as they connect, one at a time.
+#[derive(Default)]
+pub struct ProviderDiscovery {
+    /// Kademlia query looking up the providers, once it is started.
+    lookup: Option<QueryId>,
+    /// Whether the lookup finished.
+    lookup_finished: bool,
+    /// Providers being dialed.
+    dialing: HashSet<PeerId>,
+    /// Connected providers the cid was not requested from yet.
+    connected: VecDeque<PeerId>,
+    /// Every provider found so far.
+    seen: HashSet<PeerId>,
+}
+
+impl ProviderDiscovery {
+    pub fn lookup(&self) -> Option<QueryId> {
+        self.lookup
+    }
+
+    pub fn start_lookup(&mut self, query_id: QueryId) {
+        self.lookup = Some(query_id);
+    }
+
+    pub fn finish_lookup(&mut self) {
+        self.lookup_finished = true;
+    }
+
+    /// Record a provider found by the lookup. Returns true if it needs to be dialed.
+    pub fn found(&mut self, peer_id: PeerId, is_connected: bool) -> bool {
+        if !self.seen.insert(peer_id) {
+            return false;
+        }
+        if is_connected {
+            self.connected.push_back(peer_id);
+            false
+        } else {
+            self.dialing.insert(peer_id)
+        }
+    }
+
+    /// Record a peer connecting. Returns true if it is a provider being dialed.
+    pub fn connected(&mut self, peer_id: &PeerId) -> bool {
+        if self.dialing.remove(peer_id) {
+            self.connected.push_back(*peer_id);
+            return true;
+        }
+        false
+    }
+
+    /// Record a peer that could not be dialed. Returns true if it is a provider being dialed.
+    pub fn dial_failed(&mut self, peer_id: &PeerId) -> bool {
+        self.dialing.remove(peer_id)
+    }
+
+    /// Next connected provider to request the cid from.
+    pub fn next_provider(&mut self) -> Option<PeerId> {
+        self.connected.pop_front()
+    }
+
+    /// Whether every provider found was tried and no more can be found.
+    pub fn is_exhausted(&self) -> bool {
+        self.lookup_finished && self.dialing.is_empty() && self.connected.is_empty()
+    }
+}
@@ -0,0 +1,77 @@
+use lru::LruCache;
+use std::{hash::Hash, num::NonZeroUsize, time::Instant};
+
+/// Tokens of a key, refilled over time up to the burst.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of the most recently seen keys, each allowing `rate` events per second
+/// with bursts of up to `burst` events.
+pub struct RateLimiter<K: Hash + Eq> {
+    rate: f64,
+    burst: f64,
+    buckets: LruCache<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(rate: u32, burst: u32, capacity: NonZeroUsize) -> Self {
+        Self {
+            rate: rate as f64,
+            burst: burst as f64,
+            buckets: LruCache::new(capacity),
+        }
+    }
+
+    /// Take a token for `key`. Returns false if there is none left.
+    pub fn check(&mut self, key: K) -> bool {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&mut self, key: K, now: Instant) -> bool {
+        let mut bucket = self.buckets.pop(&key).unwrap_or(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        self.buckets.put(key, bucket);
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = RateLimiter::new(2, 3, NonZeroUsize::new(8).unwrap());
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at("a", now));
+        }
+        assert!(!limiter.check_at("a", now));
+        // the other keys have their own bucket
+        assert!(limiter.check_at("b", now));
+
+        // refilled at 2 tokens per second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at("a", later));
+        assert!(!limiter.check_at("a", later));
+        // up to the burst
+        let much_later = later + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at("a", much_later));
+        }
+        assert!(!limiter.check_at("a", much_later));
+    }
+}
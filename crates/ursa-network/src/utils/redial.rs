@@ -0,0 +1,94 @@
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Delay before the first redial of a peer, doubled on every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+struct Redial {
+    attempts: u32,
+    next: Instant,
+}
+
+/// Redials of the disconnected bootstrap nodes, backing off exponentially up to
+/// `max_backoff` until they are connected again.
+#[derive(Debug)]
+pub struct BootstrapRedials {
+    max_backoff: Duration,
+    pending: HashMap<PeerId, Redial>,
+}
+
+impl BootstrapRedials {
+    pub fn new(max_backoff: Duration) -> Self {
+        Self {
+            max_backoff,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Start redialing `peer`, unless it is already being redialed.
+    pub fn schedule(&mut self, peer: PeerId, now: Instant) {
+        let next = now + INITIAL_BACKOFF.min(self.max_backoff);
+        self.pending
+            .entry(peer)
+            .or_insert(Redial { attempts: 0, next });
+    }
+
+    /// Stop redialing `peer`, once connected. Returns whether it was redialed.
+    pub fn cancel(&mut self, peer: &PeerId) -> bool {
+        self.pending.remove(peer).is_some()
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.pending.contains_key(peer)
+    }
+
+    /// The peers to redial at `now`. Their next redial is pushed back, in case this one fails.
+    pub fn due(&mut self, now: Instant) -> Vec<PeerId> {
+        let max_backoff = self.max_backoff;
+        self.pending
+            .iter_mut()
+            .filter(|(_, redial)| redial.next <= now)
+            .map(|(peer, redial)| {
+                redial.attempts += 1;
+                let backoff = INITIAL_BACKOFF
+                    .checked_mul(2u32.saturating_pow(redial.attempts))
+                    .map_or(max_backoff, |backoff| backoff.min(max_backoff));
+                redial.next = now + backoff;
+                *peer
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut redials = BootstrapRedials::new(Duration::from_secs(5));
+        let peer = PeerId::random();
+        let now = Instant::now();
+        redials.schedule(peer, now);
+        assert!(redials.due(now).is_empty());
+
+        // redialed after 1s, then 2s and 4s later, then every 5s
+        let mut at = now;
+        for backoff in [1, 2, 4, 5, 5] {
+            at += Duration::from_secs(backoff);
+            assert!(redials.due(at - Duration::from_millis(1)).is_empty());
+            assert_eq!(redials.due(at), vec![peer]);
+        }
+
+        // already scheduled, keeps its backoff
+        redials.schedule(peer, at);
+        assert!(redials.due(at + Duration::from_secs(4)).is_empty());
+
+        assert!(redials.cancel(&peer));
+        assert!(!redials.contains(&peer));
+    }
+}
@@ -0,0 +1,61 @@
+use libp2p::{core::transport::ListenerId, Multiaddr};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A reservation the node holds, or is requesting, on a relay.
+///
+/// The relay client renews reservations on its own. If a reservation gets close to
+/// expiring without that happening, the service renews it by listening on the relay again.
+pub struct RelayReservation {
+    /// The circuit address listened on through the relay.
+    addr: Multiaddr,
+    /// The listener of the circuit address.
+    listener: ListenerId,
+    /// When the relay last accepted the reservation.
+    accepted_at: Option<Instant>,
+    /// Whether a renewal was requested since the reservation was last accepted.
+    renewing: bool,
+}
+
+impl RelayReservation {
+    pub fn new(addr: Multiaddr, listener: ListenerId) -> Self {
+        Self {
+            addr,
+            listener,
+            accepted_at: None,
+            renewing: false,
+        }
+    }
+
+    pub fn addr(&self) -> &Multiaddr {
+        &self.addr
+    }
+
+    pub fn listener(&self) -> ListenerId {
+        self.listener
+    }
+
+    /// Record the relay accepting the reservation. Returns true if it was renewed.
+    pub fn accepted(&mut self, now: Instant) -> bool {
+        self.renewing = false;
+        self.accepted_at.replace(now).is_some()
+    }
+
+    pub fn expires_at(&self, lifetime: Duration) -> Option<Instant> {
+        self.accepted_at.map(|accepted_at| accepted_at + lifetime)
+    }
+
+    /// Whether `renewal_percent` of the reservation's lifetime passed without it being renewed.
+    pub fn needs_renewal(&self, now: Instant, lifetime: Duration, renewal_percent: u8) -> bool {
+        !self.renewing
+            && self.accepted_at.map_or(false, |accepted_at| {
+                now >= accepted_at + lifetime * renewal_percent as u32 / 100
+            })
+    }
+
+    /// Record a renewal requested through a new `listener`. Returns the replaced listener.
+    pub fn renew_with(&mut self, listener: ListenerId) -> ListenerId {
+        self.renewing = true;
+        std::mem::replace(&mut self.listener, listener)
+    }
+}
@@ -1,15 +1,18 @@
 use anyhow::{anyhow, Context as _, Result};
-use async_fs::{create_dir_all, File};
+use async_fs::{create_dir_all, read, File};
 use async_trait::async_trait;
 use axum::body::StreamBody;
+use bytes::Bytes;
 use db::Store;
 use ethers::core::types::TransactionRequest;
+use fnv::FnvHashSet;
 use futures::channel::mpsc::unbounded;
 use futures::io::BufReader;
-use futures::{AsyncRead, AsyncWriteExt, SinkExt};
+use futures::stream::{self, BoxStream};
+use futures::{AsyncRead, AsyncReadExt, AsyncWriteExt, SinkExt, StreamExt};
 use fvm_ipld_blockstore::Blockstore;
-use fvm_ipld_car::{load_car, CarHeader, CarReader};
-use libipld::Cid;
+use fvm_ipld_car::{CarHeader, CarReader};
+use libipld::{store::DefaultParams, Block, Cid};
 use libp2p::{Multiaddr, PeerId};
 use narwhal_types::{TransactionProto, TransactionsClient};
 use serde::{Deserialize, Serialize};
@@ -32,14 +35,16 @@ use tokio_util::{compat::TokioAsyncWriteCompatExt, io::ReaderStream};
 use tracing::{debug, error, info};
 use ursa_consensus::AbciQueryQuery;
 use ursa_index_provider::engine::ProviderCommand;
-use ursa_network::NetworkCommand;
-use ursa_store::UrsaStore;
+use ursa_network::{NetworkCommand, PeerFetchOutcome};
+use ursa_store::{ChunkingStrategy, UrsaStore};
 
 use crate::config::OriginConfig;
 
 pub const MAX_BLOCK_SIZE: usize = 1048576;
 pub const MAX_CHUNK_SIZE: usize = 104857600;
 pub const DEFAULT_CHUNK_SIZE: usize = 10 * 1024 * 1024; // chunk to ~10MB CARs
+/// Bytes read at once from the streams put on the network.
+const STREAM_READ_SIZE: usize = 64 * 1024;
 
 /// Network Api
 #[derive(Deserialize, Serialize)]
@@ -77,29 +82,123 @@ pub const ETH_SEND_TRANSACTION: &str = "eth_sendTransaction";
 pub type EthCall = Vec<u8>;
 pub const ETH_CALL: &str = "eth_call";
 
+/// Why the content under a cid could not be served.
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    /// The block is not in the store and could not be fetched from the network or the origin.
+    #[error("The block with cid {0} could not be found")]
+    BlockNotFound(Cid),
+    /// The peers asked for the block did not answer before the bitswap request timeout.
+    #[error("Timed out fetching the block with cid {0}")]
+    BitswapTimeout(Cid),
+    /// Too many cids are already being fetched, the fetch may be retried later.
+    #[error("Too many pending fetches to fetch the block with cid {0}")]
+    Overloaded(Cid),
+    /// The fetch pulled more bytes than its budget.
+    #[error("Fetching the block with cid {0} went over its budget")]
+    BudgetExceeded(Cid),
+    /// The fetch was cancelled before it completed.
+    #[error("Fetching the block with cid {0} was cancelled")]
+    Cancelled(Cid),
+    /// The car file could not be read.
+    #[error("Invalid car file: {0}")]
+    InvalidCar(String),
+    /// The store failed to read or write blocks.
+    #[error("Store error: {0}")]
+    StoreError(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl NetworkError {
+    /// Classify the failure to fetch `cid` from the network. The dags the peers only
+    /// served in part are not found.
+    pub(crate) fn fetch_failed(cid: Cid, error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<ursa_network::NetworkError>() {
+            Some(ursa_network::NetworkError::NotFound(diagnostics))
+                if !diagnostics
+                    .peers_with(PeerFetchOutcome::TimedOut)
+                    .is_empty() =>
+            {
+                NetworkError::BitswapTimeout(cid)
+            }
+            Some(
+                ursa_network::NetworkError::NotFound(_)
+                | ursa_network::NetworkError::RecentlyNotFound(_),
+            )
+            | None => NetworkError::BlockNotFound(cid),
+            Some(ursa_network::NetworkError::TooManyPendingQueries(_)) => {
+                NetworkError::Overloaded(cid)
+            }
+            Some(ursa_network::NetworkError::BudgetExceeded { .. }) => {
+                NetworkError::BudgetExceeded(cid)
+            }
+            Some(ursa_network::NetworkError::Cancelled) => NetworkError::Cancelled(cid),
+            Some(error) => NetworkError::Other(anyhow!("{error}")),
+        }
+    }
+}
+
 /// Abstraction of Ursa's server commands
 #[async_trait]
 pub trait NetworkInterface: Sync + Send + 'static {
     /// Get a bitswap block from the network
-    async fn get(&self, cid: Cid) -> Result<Vec<u8>>;
+    async fn get(&self, cid: Cid) -> Result<Vec<u8>, NetworkError>;
 
     /// Get content under a cid
-    async fn get_data(&self, root_cid: Cid) -> Result<Vec<(Cid, Vec<u8>)>>;
+    async fn get_data(&self, root_cid: Cid) -> Result<Vec<(Cid, Vec<u8>)>, NetworkError>;
 
     /// get the file locally via cli
-    async fn get_file(&self, path: String, cid: Cid) -> Result<()>;
+    async fn get_file(&self, path: String, cid: Cid) -> Result<(), NetworkError>;
+
+    /// Read `length` bytes of the file under a cid starting at `offset`, cut short at
+    /// the end of the file
+    async fn get_file_range(
+        &self,
+        root_cid: Cid,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, NetworkError>;
+
+    /// Stream the data of the blocks under a cid, in the order of a car file, fetching
+    /// the missing blocks from the network as the traversal reaches them
+    fn stream_file(&self, root_cid: Cid) -> BoxStream<'static, Result<Bytes, NetworkError>>;
 
     /// Stream the car file from server
     async fn stream(
         &self,
         root_cid: Cid,
-    ) -> Result<StreamBody<ReaderStream<tokio::io::DuplexStream>>>;
+    ) -> Result<StreamBody<ReaderStream<tokio::io::DuplexStream>>, NetworkError>;
 
     /// Put a car file and start providing to the network
-    async fn put_car<R: AsyncRead + Send + Unpin>(&self, file: Car<R>) -> Result<Vec<Cid>>;
+    async fn put_car<R: AsyncRead + Send + Unpin>(
+        &self,
+        file: Car<R>,
+    ) -> Result<Vec<Cid>, NetworkError>;
 
     /// Put a file using a local path
-    async fn put_file(&self, path: String) -> Result<Vec<Cid>>;
+    async fn put_file(&self, path: String) -> Result<Vec<Cid>, NetworkError>;
+
+    /// Put the raw bytes read from a stream, split into blocks with the default chunking,
+    /// and start providing them
+    async fn put_stream<R: AsyncRead + Send + Unpin>(
+        &self,
+        reader: R,
+    ) -> Result<Vec<Cid>, NetworkError>;
+
+    /// Import a raw file, split into blocks with `chunking`, and start providing it
+    async fn import_file(
+        &self,
+        path: String,
+        chunking: ChunkingStrategy,
+    ) -> Result<Cid, NetworkError>;
+
+    /// Fetch the dag under a cid if needed and pin all of its blocks, so garbage
+    /// collection keeps the content while it is served
+    async fn pin_file(&self, root_cid: Cid) -> Result<(), NetworkError>;
+
+    /// Release the pins of the blocks of the dag under a cid
+    async fn unpin_file(&self, root_cid: Cid) -> Result<(), NetworkError>;
 
     /// Get peers from the network
     async fn get_peers(&self) -> Result<HashSet<PeerId>>;
@@ -122,7 +221,7 @@ where
     S: Blockstore + Store + Send + Sync + 'static,
 {
     pub store: Arc<UrsaStore<S>>,
-    pub network_send: Sender<NetworkCommand>,
+    pub network_send: BoundedSender<NetworkCommand>,
     pub provider_send: Sender<ProviderCommand>,
     mempool_address: String,
     pending_requests: PendingRequests,
@@ -136,62 +235,51 @@ impl<S> NetworkInterface for NodeNetworkInterface<S>
 where
     S: Blockstore + Store + Send + Sync + 'static,
 {
-    async fn get(&self, cid: Cid) -> Result<Vec<u8>> {
+    async fn get(&self, cid: Cid) -> Result<Vec<u8>, NetworkError> {
         self.sync_content(cid).await?;
-        let content =
-            self.store.blockstore().get(&cid)?.ok_or_else(|| {
-                anyhow!("content was fetched but could not be found in blockstore")
-            })?;
-        Ok(content)
+        self.store
+            .blockstore()
+            .get(&cid)
+            .map_err(|e| NetworkError::StoreError(e.to_string()))?
+            .ok_or(NetworkError::BlockNotFound(cid))
     }
 
-    async fn get_data(&self, root_cid: Cid) -> Result<Vec<(Cid, Vec<u8>)>> {
+    async fn get_data(&self, root_cid: Cid) -> Result<Vec<(Cid, Vec<u8>)>, NetworkError> {
         self.sync_content(root_cid).await?;
-        let dag = self.store.dag_traversal(&root_cid)?;
+        let store_error = |e: anyhow::Error| NetworkError::StoreError(e.to_string());
+        if let Some(cid) = self
+            .store
+            .first_missing_block(&root_cid)
+            .map_err(store_error)?
+        {
+            return Err(NetworkError::BlockNotFound(cid));
+        }
+        let dag = self.store.dag_traversal(&root_cid).map_err(store_error)?;
         info!("Dag traversal done, now streaming the file");
         Ok(dag)
     }
 
+    async fn get_file_range(
+        &self,
+        root_cid: Cid,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, NetworkError> {
+        self.sync_content(root_cid).await?;
+        Ok(self.store.read_file_range(&root_cid, offset, length)?)
+    }
+
     /// Used through CLI
-    async fn get_file(&self, path: String, root_cid: Cid) -> Result<()> {
+    async fn get_file(&self, path: String, root_cid: Cid) -> Result<(), NetworkError> {
         info!("getting and storing the file at: {path}");
-
-        let header = CarHeader {
-            roots: vec![root_cid],
-            version: 1,
-        };
-
-        let buffer: Arc<RwLock<Vec<u8>>> = Default::default();
-        let (mut tx, mut rx) = unbounded();
-
-        let buffer_cloned = buffer.clone();
-        let write_task = tokio::task::spawn(async move {
-            header
-                .write_stream_async(&mut *buffer_cloned.write().await, &mut rx)
-                .await
-                .unwrap()
-        });
         let dag = self.get_data(root_cid).await?;
-
-        for (cid, data) in dag {
-            tx.send((cid, data)).await?;
-        }
-        drop(tx);
-        write_task.await?;
-
-        let buffer: Vec<_> = buffer.read().await.clone();
-        let file_path = PathBuf::from(path).join(format!("{root_cid}.car"));
-        create_dir_all(file_path.parent().unwrap()).await?;
-        let mut file = File::create(file_path).await?;
-        file.write_all(&buffer).await?;
-        file.sync_all().await?;
-        Ok(())
+        Ok(write_car_file(path, root_cid, dag).await?)
     }
 
     async fn stream(
         &self,
         root_cid: Cid,
-    ) -> Result<StreamBody<ReaderStream<tokio::io::DuplexStream>>> {
+    ) -> Result<StreamBody<ReaderStream<tokio::io::DuplexStream>>, NetworkError> {
         let header = CarHeader {
             roots: vec![root_cid],
             version: 1,
@@ -213,32 +301,149 @@ where
         let dag = self.get_data(root_cid).await?;
 
         for (cid, data) in dag {
-            tx.send((cid, data)).await?;
+            tx.send((cid, data))
+                .await
+                .context("Failed to stream the car file")?;
         }
         drop(tx);
 
         Ok(body)
     }
 
-    async fn put_car<R: AsyncRead + Send + Unpin>(&self, car: Car<R>) -> Result<Vec<Cid>> {
+    fn stream_file(&self, root_cid: Cid) -> BoxStream<'static, Result<Bytes, NetworkError>> {
+        let store = self.store.clone();
+        let network_send = self.network_send.clone();
+        // the dag is kept from garbage collection until the stream ends
+        let mut held = self.store.hold_roots();
+        held.add(root_cid);
+
+        // same traversal as `UrsaStore::dag_traversal`, one block at a time
+        stream::try_unfold(
+            (vec![root_cid], FnvHashSet::default(), held),
+            move |(mut current, mut refs, held)| {
+                let store = store.clone();
+                let network_send = network_send.clone();
+                async move {
+                    while let Some(cid) = current.pop() {
+                        if !refs.insert(cid) {
+                            continue;
+                        }
+                        let data = match store.blockstore().get(&cid)? {
+                            Some(data) => data,
+                            None => get_block(&network_send, cid)
+                                .await
+                                .map_err(|e| NetworkError::fetch_failed(cid, &e))?,
+                        };
+                        let mut links = FnvHashSet::default();
+                        Block::<DefaultParams>::new(cid, data.clone())?.references(&mut links)?;
+                        current.extend(links);
+                        return Ok(Some((Bytes::from(data), (current, refs, held))));
+                    }
+                    Ok(None)
+                }
+            },
+        )
+        .boxed()
+    }
+
+    async fn put_car<R: AsyncRead + Send + Unpin>(
+        &self,
+        car: Car<R>,
+    ) -> Result<Vec<Cid>, NetworkError> {
         let size = car.size;
-        let cids = load_car(self.store.blockstore(), car).await?;
-        let root_cid = cids[0];
+        let invalid_car = |e: fvm_ipld_car::Error| NetworkError::InvalidCar(e.to_string());
+        let mut reader = CarReader::new(car).await.map_err(invalid_car)?;
+        let cids = reader.header.roots.clone();
+        let root_cid = *cids
+            .first()
+            .ok_or_else(|| NetworkError::InvalidCar("The car file has no root".to_string()))?;
+        // the blocks are kept from garbage collection until the dag is pinned
+        let mut held = self.store.hold_roots();
+        let mut buf = Vec::with_capacity(100);
+        while let Some(block) = reader.next_block().await.map_err(invalid_car)? {
+            held.add(block.cid);
+            buf.push((block.cid, block.data));
+            if buf.len() > 1000 {
+                self.store.put_many_keyed(buf.drain(..))?;
+            }
+        }
+        self.store.put_many_keyed(buf)?;
+        self.store.pin_dag(&root_cid)?;
+        drop(held);
         info!("The inserted cids are: {cids:?}");
-        self.provide_cid(root_cid, size).await.map(|_| cids)
+        self.provide_cid(root_cid, size).await?;
+        Ok(cids)
     }
 
     /// Used through CLI
-    async fn put_file(&self, path: String) -> Result<Vec<Cid>> {
+    async fn put_file(&self, path: String) -> Result<Vec<Cid>, NetworkError> {
         info!("Putting the file on network: {path}");
         self.put_car(Car::from_file(path).await?).await
     }
 
+    async fn put_stream<R: AsyncRead + Send + Unpin>(
+        &self,
+        mut reader: R,
+    ) -> Result<Vec<Cid>, NetworkError> {
+        info!("Putting a byte stream on the network");
+        // the blocks are written as the data comes in, without holding the whole stream
+        let mut importer = self.store.importer(&ChunkingStrategy::default())?.pinned();
+        let mut buffer = vec![0; STREAM_READ_SIZE];
+        loop {
+            let read = reader
+                .read(&mut buffer)
+                .await
+                .context("Failed to read the stream")?;
+            if read == 0 {
+                break;
+            }
+            importer.write(&buffer[..read])?;
+        }
+        let root_cid = importer.finish()?;
+        let size = self.store.car_size(&root_cid)?;
+        self.provide_cid(root_cid, size).await?;
+        Ok(vec![root_cid])
+    }
+
+    async fn import_file(
+        &self,
+        path: String,
+        chunking: ChunkingStrategy,
+    ) -> Result<Cid, NetworkError> {
+        info!("Importing the file {path} with {chunking:?}");
+        let data = read(&path)
+            .await
+            .with_context(|| format!("Failed to read {path}"))?;
+        Ok(self.import(&data, &chunking).await?)
+    }
+
+    async fn pin_file(&self, root_cid: Cid) -> Result<(), NetworkError> {
+        // the dag is kept from garbage collection until it is pinned
+        let mut held = self.store.hold_roots();
+        held.add(root_cid);
+        self.sync_content(root_cid).await?;
+        let store_error = |e: anyhow::Error| NetworkError::StoreError(e.to_string());
+        if let Some(cid) = self
+            .store
+            .first_missing_block(&root_cid)
+            .map_err(store_error)?
+        {
+            return Err(NetworkError::BlockNotFound(cid));
+        }
+        self.store.pin_dag(&root_cid).map_err(store_error)
+    }
+
+    async fn unpin_file(&self, root_cid: Cid) -> Result<(), NetworkError> {
+        self.store
+            .unpin_dag(&root_cid)
+            .map_err(|e| NetworkError::StoreError(e.to_string()))
+    }
+
     async fn get_peers(&self) -> Result<HashSet<PeerId>> {
         let (sender, receiver) = oneshot::channel();
         let request = NetworkCommand::GetPeers { sender };
 
-        self.network_send.send(request)?;
+        self.network_send.send(request).await?;
         match receiver.await {
             Ok(peer) => Ok(peer),
             Err(e) => Err(anyhow!(format!("GetPeers NetworkCommand failed {e:?}"))),
@@ -249,7 +454,7 @@ where
         let (sender, receiver) = oneshot::channel();
         let request = NetworkCommand::GetListenerAddresses { sender };
 
-        self.network_send.send(request)?;
+        self.network_send.send(request).await?;
         match receiver.await {
             Ok(addresses) => Ok(addresses),
             Err(e) => Err(anyhow!(format!(
@@ -287,7 +492,7 @@ where
 {
     pub fn new(
         store: Arc<UrsaStore<S>>,
-        network_send: Sender<NetworkCommand>,
+        network_send: BoundedSender<NetworkCommand>,
         provider_send: Sender<ProviderCommand>,
         origin_config: OriginConfig,
         mempool_address: String,
@@ -305,33 +510,41 @@ where
         }
     }
 
+    /// Store and pin `data` split into blocks with `chunking`, and start providing it
+    async fn import(&self, data: &[u8], chunking: &ChunkingStrategy) -> Result<Cid> {
+        let mut importer = self.store.importer(chunking)?.pinned();
+        importer.write(data)?;
+        let root_cid = importer.finish()?;
+        let size = self.store.car_size(&root_cid)?;
+        self.provide_cid(root_cid, size).await.map(|_| root_cid)
+    }
+
     /// Ensure a root cid is synced to the blockstore
-    async fn sync_content(&self, cid: Cid) -> Result<()> {
+    async fn sync_content(&self, cid: Cid) -> Result<(), NetworkError> {
         if !self.store.blockstore().has(&cid)? {
             info!("Requesting block with the cid {cid:?}");
 
             let size = match self.get_network(cid).await {
                 Ok(_) => self.store.car_size(&cid)?,
-                Err(e) => {
-                    info!("Failed to get content from network: {}", e);
-                    self.get_origin(cid).await?
+                Err(network_error) => {
+                    info!("Failed to get content from network: {}", network_error);
+                    match self.get_origin(cid).await {
+                        Ok(size) => size,
+                        Err(e) => {
+                            info!("Failed to get content from origin: {}", e);
+                            return Err(NetworkError::fetch_failed(cid, &network_error));
+                        }
+                    }
                 }
             };
-            self.provide_cid(cid, size).await
-        } else {
-            Ok(())
+            self.provide_cid(cid, size).await?;
         }
+        Ok(())
     }
 
     /// Fetch content from the network
     async fn get_network(&self, root_cid: Cid) -> Result<()> {
-        info!("Fetching cid {root_cid} from network");
-        let (send, recv) = oneshot::channel();
-        self.network_send.send(NetworkCommand::GetBitswap {
-            cid: root_cid,
-            sender: send,
-        })?;
-        recv.await?
+        get_network(&self.network_send, root_cid).await
     }
 
     /// Fetch content from the origin.
@@ -370,7 +583,7 @@ where
         .header("Accept", "application/vnd.ipld.car")
         .build();
 
-        let store = self.store.db.clone();
+        let store = Arc::clone(&self.store);
         task::spawn(async move {
             // send the request
             let result: Result<u64, String> = async {
@@ -450,7 +663,11 @@ where
     async fn provide_cid(&self, cid: Cid, size: u64) -> Result<()> {
         // network content replication
         let (sender, receiver) = oneshot::channel();
-        if let Err(e) = self.network_send.send(NetworkCommand::Put { cid, sender }) {
+        if let Err(e) = self
+            .network_send
+            .send(NetworkCommand::Put { cid, sender })
+            .await
+        {
             error!("Failed to send network command: {}", e);
         } else {
             match receiver.await {
@@ -478,6 +695,72 @@ where
     }
 }
 
+/// Write the blocks of `dag` as the car file `<root_cid>.car` in the directory `path`
+async fn write_car_file(path: String, root_cid: Cid, dag: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+    let header = CarHeader {
+        roots: vec![root_cid],
+        version: 1,
+    };
+
+    let buffer: Arc<RwLock<Vec<u8>>> = Default::default();
+    let (mut tx, mut rx) = unbounded();
+
+    let buffer_cloned = buffer.clone();
+    let write_task = tokio::task::spawn(async move {
+        header
+            .write_stream_async(&mut *buffer_cloned.write().await, &mut rx)
+            .await
+            .unwrap()
+    });
+
+    for (cid, data) in dag {
+        tx.send((cid, data)).await?;
+    }
+    drop(tx);
+    write_task.await?;
+
+    let buffer: Vec<_> = buffer.read().await.clone();
+    let file_path = PathBuf::from(path).join(format!("{root_cid}.car"));
+    create_dir_all(file_path.parent().unwrap()).await?;
+    let mut file = File::create(file_path).await?;
+    file.write_all(&buffer).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+/// Fetch the dag under `root_cid` from the network
+async fn get_network(network_send: &BoundedSender<NetworkCommand>, root_cid: Cid) -> Result<()> {
+    info!("Fetching cid {root_cid} from network");
+    let (send, recv) = oneshot::channel();
+    network_send
+        .send(NetworkCommand::GetBitswap {
+            cid: root_cid,
+            max_parallel_blocks: None,
+            trace: None,
+            max_bytes: None,
+            sender: send,
+        })
+        .await?;
+    let result = recv.await??;
+    if !result.is_complete() {
+        return Err(anyhow!(
+            "{} blocks of the dag under {root_cid} are not found with any peers",
+            result.missing.len()
+        ));
+    }
+    Ok(())
+}
+
+/// Fetch the single block `cid` from the network, without the blocks it links to.
+async fn get_block(network_send: &BoundedSender<NetworkCommand>, cid: Cid) -> Result<Vec<u8>> {
+    debug!("Fetching the block {cid} from network");
+    let (sender, receiver) = oneshot::channel();
+    network_send
+        .send(NetworkCommand::GetBlock { cid, sender })
+        .await?;
+    receiver.await?
+}
+
 pub struct Car<R> {
     pub size: u64,
     reader: R,
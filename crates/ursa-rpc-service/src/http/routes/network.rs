@@ -1,6 +1,6 @@
 pub const BASE_PATH: &str = "./car_files";
 
-use crate::api::{Car, NetworkInterface, NodeNetworkInterface};
+use crate::api::{self, Car, NetworkInterface, NodeNetworkInterface};
 use axum::{
     extract::{DefaultBodyLimit, Multipart, Path},
     http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
@@ -31,6 +31,9 @@ pub enum NetworkError {
     NotFoundError(String),
     InternalError(String),
     BadRequest(String),
+    GatewayTimeout(String),
+    TooManyRequests(String),
+    ServiceUnavailable(String),
 }
 impl IntoResponse for NetworkError {
     fn into_response(self) -> Response {
@@ -40,6 +43,27 @@ impl IntoResponse for NetworkError {
                 (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
             }
             NetworkError::BadRequest(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+            NetworkError::GatewayTimeout(e) => (StatusCode::GATEWAY_TIMEOUT, e).into_response(),
+            NetworkError::TooManyRequests(e) => (StatusCode::TOO_MANY_REQUESTS, e).into_response(),
+            NetworkError::ServiceUnavailable(e) => {
+                (StatusCode::SERVICE_UNAVAILABLE, e).into_response()
+            }
+        }
+    }
+}
+
+impl From<api::NetworkError> for NetworkError {
+    fn from(error: api::NetworkError) -> Self {
+        let message = error.to_string();
+        match error {
+            api::NetworkError::BlockNotFound(_) => NetworkError::NotFoundError(message),
+            api::NetworkError::BitswapTimeout(_) => NetworkError::GatewayTimeout(message),
+            api::NetworkError::Overloaded(_) => NetworkError::ServiceUnavailable(message),
+            api::NetworkError::BudgetExceeded(_) => NetworkError::TooManyRequests(message),
+            api::NetworkError::InvalidCar(_) => NetworkError::BadRequest(message),
+            api::NetworkError::Cancelled(_)
+            | api::NetworkError::StoreError(_)
+            | api::NetworkError::Other(_) => NetworkError::InternalError(message),
         }
     }
 }
@@ -73,7 +97,7 @@ where
                 {
                     Err(err) => {
                         error!("{:?}", err);
-                        Err(NetworkError::InternalError(err.to_string()))
+                        Err(err.into())
                     }
                     Ok(res) => Ok((StatusCode::OK, Json(format!("{res:?}")))),
                 }
@@ -119,7 +143,7 @@ where
             }
             Err(err) => {
                 error!("{:?}", err);
-                Err(NetworkError::InternalError(err.to_string()))
+                Err(err.into())
             }
         };
     } else {
@@ -1,53 +1,281 @@
 #[cfg(test)]
 mod tests {
-    use crate::api::{NetworkInterface, NodeNetworkInterface};
+    use crate::api::{NetworkError, NetworkInterface, NodeNetworkInterface};
     use crate::config::OriginConfig;
-    use crate::tests::{dummy_ipfs, init, setup_logger};
+    use crate::http;
+    use crate::tests::{dummy_ipfs, init, init_interface, setup_logger, test_dir};
     use anyhow::Result;
-    use async_fs::{remove_file, File};
-    use futures::io::BufReader;
-    use fvm_ipld_car::load_car;
-    use std::path::Path;
+    use async_fs::{read, File};
+    use axum::{http::StatusCode, response::IntoResponse};
+    use bytes::Bytes;
+    use db::Store;
+    use futures::io::{BufReader, Cursor};
+    use futures::TryStreamExt;
+    use fvm_ipld_car::{load_car, CarReader};
+    use libipld::{
+        multihash::{Code, MultihashDigest},
+        Cid,
+    };
     use std::sync::Arc;
     use tokio::task;
+    use ursa_store::ChunkingStrategy;
 
     use tracing::error;
 
     #[tokio::test]
     async fn test_put_and_get() -> Result<()> {
         setup_logger();
-        let (mut ursa_service, mut provider_engine, store, mempool_address, abci_send) = init()?;
-        let interface = Arc::new(NodeNetworkInterface::new(
-            Arc::clone(&store),
-            ursa_service.command_sender(),
-            provider_engine.command_sender(),
-            Default::default(),
-            mempool_address,
-            abci_send,
-        ));
-
-        // the test case does not start the provider engine, so the best way
-        // for put_file to not call provider engine is to close the channel
-        provider_engine.command_receiver().close();
-        ursa_service.close_command_receiver();
+        let (interface, store) = init_interface(Default::default())?;
 
         let put_file = interface
             .put_file("../../test_files/test.car".to_string())
             .await?;
         let root_cid = put_file[0];
 
+        let dir = test_dir()?;
         interface
-            .get_file("../../test_files".to_string(), root_cid)
+            .get_file(dir.path().display().to_string(), root_cid)
             .await?;
 
-        let path = format!("../../test_files/{root_cid}.car");
-        let path = Path::new(&path);
-        let file = File::open(path).await?;
+        let file = File::open(dir.path().join(format!("{root_cid}.car"))).await?;
         let reader = BufReader::new(file);
         let cids = load_car(store.blockstore(), reader).await?;
 
         assert_eq!(cids[0], root_cid);
-        remove_file(path).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_file() -> Result<()> {
+        setup_logger();
+        let (interface, store) = init_interface(Default::default())?;
+
+        let root_cid = interface
+            .put_file("../../test_files/test.car".to_string())
+            .await?[0];
+        let dir = test_dir()?;
+        interface
+            .get_file(dir.path().display().to_string(), root_cid)
+            .await?;
+
+        let path = dir.path().join(format!("{root_cid}.car"));
+        let mut car = CarReader::new(BufReader::new(File::open(&path).await?)).await?;
+        let mut expected = Vec::new();
+        while let Some(block) = car.next_block().await? {
+            expected.extend(block.data);
+        }
+
+        let streamed: Vec<Bytes> = interface.stream_file(root_cid).try_collect().await?;
+        assert_eq!(streamed.concat(), expected);
+
+        // the blocks of a file split in many chunks come in the same order as in its car file
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let root_cid = store.import(&data, &ChunkingStrategy::FixedSize(1024))?;
+        assert!(store.dag_traversal(&root_cid)?.len() > 2);
+        interface
+            .get_file(dir.path().display().to_string(), root_cid)
+            .await?;
+        let path = dir.path().join(format!("{root_cid}.car"));
+        let mut car = CarReader::new(BufReader::new(File::open(&path).await?)).await?;
+        let mut expected = Vec::new();
+        while let Some(block) = car.next_block().await? {
+            expected.push(Bytes::from(block.data));
+        }
+        let streamed: Vec<Bytes> = interface.stream_file(root_cid).try_collect().await?;
+        assert_eq!(streamed, expected);
+
+        // the network is not running, so a missing block can't be fetched
+        let root_cid = store.import(&[7; 4096], &ChunkingStrategy::FixedSize(1024))?;
+        let child = store
+            .dag_traversal(&root_cid)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .find(|cid| *cid != root_cid)
+            .expect("a child block");
+        store.db.delete(child.to_bytes())?;
+        let result: Result<Vec<Bytes>, NetworkError> =
+            interface.stream_file(root_cid).try_collect().await;
+        assert!(matches!(result, Err(NetworkError::BlockNotFound(cid)) if cid == child));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_file_range() -> Result<()> {
+        setup_logger();
+        let (interface, store) = init_interface(Default::default())?;
+
+        // test.car is a single raw block
+        let root_cid = interface
+            .put_file("../../test_files/test.car".to_string())
+            .await?[0];
+        let block = interface.get(root_cid).await?;
+        assert_eq!(
+            interface.get_file_range(root_cid, 100, 50).await?,
+            &block[100..150]
+        );
+
+        let data: Vec<u8> = (0..8192u32).map(|i| (i * 31 / 7) as u8).collect();
+        let root_cid = store.import(&data, &ChunkingStrategy::FixedSize(1024))?;
+        assert_eq!(
+            interface.get_file_range(root_cid, 1000, 2500).await?,
+            &data[1000..3500]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_stream() -> Result<()> {
+        setup_logger();
+        let (interface, store) = init_interface(Default::default())?;
+
+        let path = "../../test_files/test.car";
+        let data = read(path).await?;
+        let streamed = interface.put_stream(Cursor::new(data)).await?;
+        let imported = interface
+            .import_file(path.to_string(), ChunkingStrategy::default())
+            .await?;
+        assert_eq!(streamed, vec![imported]);
+
+        // a stream spanning many chunks and reads gets the blocks of an import
+        let data: Vec<u8> = (0..1024 * 1024u32).map(|i| (i * 31 / 7) as u8).collect();
+        let streamed = interface.put_stream(Cursor::new(data.clone())).await?;
+        assert_eq!(
+            streamed,
+            vec![store.import(&data, &ChunkingStrategy::default())?]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_missing_block_not_found() -> Result<()> {
+        setup_logger();
+        // nothing listens on the origin
+        let (interface, store) = init_interface(OriginConfig {
+            ipfs_gateway: "127.0.0.1:1".to_string(),
+            use_https: Some(false),
+        })?;
+
+        let cid = store.import(b"missing", &ChunkingStrategy::default())?;
+        store.db.delete(cid.to_bytes())?;
+        match interface.get(cid).await {
+            Err(NetworkError::BlockNotFound(missing)) => assert_eq!(missing, cid),
+            other => panic!("expected the block to not be found, got {other:?}"),
+        }
+
+        // the root is there but one of its children is not
+        let root_cid = store.import(&[7; 4096], &ChunkingStrategy::FixedSize(1024))?;
+        let child = store
+            .dag_traversal(&root_cid)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .find(|cid| *cid != root_cid)
+            .expect("a child block");
+        store.db.delete(child.to_bytes())?;
+        match interface.get_data(root_cid).await {
+            Err(NetworkError::BlockNotFound(missing)) => assert_eq!(missing, child),
+            other => panic!("expected the child to not be found, got {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_error_status() {
+        let cid = Cid::new_v1(0x55, Code::Blake3_256.digest(b"fetch error"));
+        let status = |error: ursa_network::NetworkError| {
+            let error = NetworkError::fetch_failed(cid, &error.into());
+            http::routes::network::NetworkError::from(error)
+                .into_response()
+                .status()
+        };
+
+        assert_eq!(
+            status(ursa_network::NetworkError::RecentlyNotFound(cid)),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(ursa_network::NetworkError::TooManyPendingQueries(1)),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            status(ursa_network::NetworkError::BudgetExceeded {
+                fetched: 2,
+                max_bytes: 1
+            }),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            status(ursa_network::NetworkError::Cancelled),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pin_file() -> Result<()> {
+        setup_logger();
+        let (interface, store) = init_interface(Default::default())?;
+
+        let data: Vec<u8> = (0..8192u32).map(|i| (i * 31 / 7) as u8).collect();
+        let root_cid = store.import(&data, &ChunkingStrategy::FixedSize(1024))?;
+        let cids: Vec<_> = store
+            .dag_traversal(&root_cid)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .collect();
+        assert!(cids.len() > 1);
+
+        interface.pin_file(root_cid).await?;
+        for cid in &cids {
+            assert!(store.is_pinned(cid)?);
+        }
+
+        interface.unpin_file(root_cid).await?;
+        for cid in &cids {
+            assert!(!store.is_pinned(cid)?);
+        }
+
+        // a dag missing a block is not pinned
+        let child = cids[1];
+        store.delete_block(&child)?;
+        let result = interface.pin_file(root_cid).await;
+        assert!(matches!(result, Err(NetworkError::BlockNotFound(cid)) if cid == child));
+        assert!(!store.is_pinned(&root_cid)?);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_keeps_uploads() -> Result<()> {
+        setup_logger();
+        let (interface, store) = init_interface(Default::default())?;
+
+        let car_root = interface
+            .put_file("../../test_files/test.car".to_string())
+            .await?[0];
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i * 31 / 7) as u8).collect();
+        let stream_root = interface.put_stream(Cursor::new(data.clone())).await?[0];
+        let orphan = store.import(b"orphan", &ChunkingStrategy::default())?;
+
+        // only the chunk and the root of the orphan are collected
+        assert_eq!(store.gc()?.blocks, 2);
+        assert!(!store.blockstore().has(&orphan)?);
+
+        let dir = test_dir()?;
+        interface
+            .get_file(dir.path().display().to_string(), car_root)
+            .await?;
+        let streamed: Vec<Bytes> = interface.stream_file(stream_root).try_collect().await?;
+        assert_eq!(streamed.len(), store.dag_traversal(&stream_root)?.len());
+        assert_eq!(
+            interface
+                .get_file_range(stream_root, 0, data.len() as u64)
+                .await?,
+            data
+        );
 
         Ok(())
     }
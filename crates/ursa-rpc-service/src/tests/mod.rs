@@ -7,6 +7,7 @@ use db::MemoryDB;
 use libp2p::identity::Keypair;
 use simple_logger::SimpleLogger;
 use std::sync::Arc;
+use tempfile::TempDir;
 use tendermint_proto::abci::ResponseQuery;
 use tokio::sync::{
     mpsc::{channel, Sender as BoundedSender},
@@ -18,6 +19,9 @@ use ursa_index_provider::{config::ProviderConfig, engine::ProviderEngine};
 use ursa_network::{NetworkConfig, UrsaService};
 use ursa_store::UrsaStore;
 
+use crate::api::NodeNetworkInterface;
+use crate::config::OriginConfig;
+
 pub fn setup_logger() {
     let level = LevelFilter::Debug;
     if let Err(err) = SimpleLogger::new()
@@ -29,6 +33,11 @@ pub fn setup_logger() {
     }
 }
 
+/// A directory of its own for the files written by a test, removed when dropped.
+pub fn test_dir() -> Result<TempDir> {
+    Ok(tempfile::Builder::new().prefix("ursa-test-").tempdir()?)
+}
+
 pub fn get_store() -> Arc<UrsaStore<MemoryDB>> {
     let db = Arc::new(MemoryDB::default());
     Arc::new(UrsaStore::new(Arc::clone(&db)))
@@ -67,6 +76,28 @@ pub fn init() -> InitResult {
     Ok((service, provider_engine, store, mempool_address, abci_send))
 }
 
+/// An interface over a fresh store, for the tests that don't run the network service nor
+/// the provider engine. Their command channels are closed, so puts don't wait on them.
+pub fn init_interface(
+    origin_config: OriginConfig,
+) -> Result<(
+    Arc<NodeNetworkInterface<MemoryDB>>,
+    Arc<UrsaStore<MemoryDB>>,
+)> {
+    let (mut ursa_service, mut provider_engine, store, mempool_address, abci_send) = init()?;
+    let interface = Arc::new(NodeNetworkInterface::new(
+        Arc::clone(&store),
+        ursa_service.command_sender(),
+        provider_engine.command_sender(),
+        origin_config,
+        mempool_address,
+        abci_send,
+    ));
+    provider_engine.command_receiver().close();
+    ursa_service.close_command_receiver();
+    Ok((interface, store))
+}
+
 pub async fn dummy_ipfs() -> Result<()> {
     let file: Vec<u8> = std::fs::read("../../test_files/test.car")?;
 
@@ -0,0 +1,114 @@
+use libipld::Cid;
+use lru::LruCache;
+use metrics::increment_counter;
+use serde::Serialize;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Lookups served by the block cache since the store was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Blocks recently read from the db, up to `capacity` bytes of block data.
+pub(crate) struct BlockCache {
+    capacity: u64,
+    /// The cached blocks and their total size.
+    blocks: Mutex<(LruCache<Cid, Vec<u8>>, u64)>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// A cache of `capacity` bytes, disabled if it is 0.
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            blocks: Mutex::new((LruCache::unbounded(), 0)),
+            hits: Default::default(),
+            misses: Default::default(),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    fn record(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            increment_counter!("store_cache_hits");
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            increment_counter!("store_cache_misses");
+        }
+    }
+
+    pub fn get(&self, cid: &Cid) -> Option<Vec<u8>> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let data = self.blocks.lock().unwrap().0.get(cid).cloned();
+        self.record(data.is_some());
+        data
+    }
+
+    pub fn contains(&self, cid: &Cid) -> bool {
+        if !self.is_enabled() {
+            return false;
+        }
+        let hit = self.blocks.lock().unwrap().0.contains(cid);
+        self.record(hit);
+        hit
+    }
+
+    /// Cache a block read from the db, evicting the least recently used ones over capacity.
+    pub fn insert(&self, cid: Cid, data: &[u8]) {
+        let len = data.len() as u64;
+        if !self.is_enabled() || len > self.capacity {
+            return;
+        }
+        let (blocks, size) = &mut *self.blocks.lock().unwrap();
+        if let Some(old) = blocks.put(cid, data.to_vec()) {
+            *size -= old.len() as u64;
+        }
+        *size += len;
+        while *size > self.capacity {
+            match blocks.pop_lru() {
+                Some((_, evicted)) => *size -= evicted.len() as u64,
+                None => break,
+            }
+        }
+    }
+
+    /// Drop a block deleted from the db.
+    pub fn remove(&self, cid: &Cid) {
+        let (blocks, size) = &mut *self.blocks.lock().unwrap();
+        if let Some(data) = blocks.pop(cid) {
+            *size -= data.len() as u64;
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
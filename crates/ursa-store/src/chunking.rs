@@ -0,0 +1,130 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::mem;
+
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Number of bytes the rolling hash looks at to find a chunk boundary.
+const RABIN_WINDOW: usize = 48;
+const RABIN_PRIME: u64 = 153_191;
+
+/// How a file is split into blocks when it is imported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChunkingStrategy {
+    /// Blocks of the same size, except for the last one.
+    FixedSize(usize),
+    /// Content defined blocks, cut where a rolling hash of the data matches a pattern.
+    /// Inserting or removing bytes only changes the blocks around the edit, so similar
+    /// files share most of their blocks.
+    Rabin { min: usize, avg: usize, max: usize },
+}
+
+impl Default for ChunkingStrategy {
+    fn default() -> Self {
+        Self::FixedSize(DEFAULT_CHUNK_SIZE)
+    }
+}
+
+impl ChunkingStrategy {
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Self::FixedSize(0) => Err(anyhow!("The chunk size must be greater than zero")),
+            Self::Rabin { min, avg, max } if min == 0 || min > avg || avg > max => Err(anyhow!(
+                "Rabin chunking requires 0 < min <= avg <= max, got {min}, {avg}, {max}"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Split `data` into chunks.
+    pub fn split<'a>(&self, data: &'a [u8]) -> Result<Vec<&'a [u8]>> {
+        self.validate()?;
+        let chunks = match *self {
+            Self::FixedSize(size) => data.chunks(size).collect(),
+            Self::Rabin { min, avg, max } => {
+                let mut chunks = Vec::new();
+                let mut rest = data;
+                while !rest.is_empty() {
+                    let (chunk, tail) = rest.split_at(rabin_boundary(rest, min, avg, max));
+                    chunks.push(chunk);
+                    rest = tail;
+                }
+                chunks
+            }
+        };
+        Ok(chunks)
+    }
+}
+
+/// Splits data coming in pieces into the same chunks [`ChunkingStrategy::split`] cuts the
+/// whole data into, holding on to the bytes of the chunk not completed yet.
+pub struct Chunker {
+    strategy: ChunkingStrategy,
+    buffer: Vec<u8>,
+}
+
+impl Chunker {
+    pub fn new(strategy: ChunkingStrategy) -> Result<Self> {
+        strategy.validate()?;
+        Ok(Self {
+            strategy,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Add the next piece of the data, returning the chunks it completed.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+        let mut chunks = Vec::new();
+        while let Some(len) = self.complete_chunk_len() {
+            let rest = self.buffer.split_off(len);
+            chunks.push(mem::replace(&mut self.buffer, rest));
+        }
+        chunks
+    }
+
+    /// The last chunk, once all the data was pushed.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let chunk = mem::take(&mut self.buffer);
+        (!chunk.is_empty()).then_some(chunk)
+    }
+
+    /// The length of the chunk at the start of the buffer, if more data can't change it.
+    fn complete_chunk_len(&self) -> Option<usize> {
+        let len = self.buffer.len();
+        match self.strategy {
+            ChunkingStrategy::FixedSize(size) => (len >= size).then_some(size),
+            ChunkingStrategy::Rabin { min, avg, max } => {
+                if len <= min {
+                    return None;
+                }
+                // the hash only looks back, a boundary found before the end is final
+                let boundary = rabin_boundary(&self.buffer, min, avg, max);
+                (boundary < len || len >= max).then_some(boundary)
+            }
+        }
+    }
+}
+
+/// Find the length of the next chunk of `data`.
+fn rabin_boundary(data: &[u8], min: usize, avg: usize, max: usize) -> usize {
+    if data.len() <= min {
+        return data.len();
+    }
+    // a boundary is found on average every `avg` bytes
+    let mask = avg.next_power_of_two() as u64 - 1;
+    let out_factor = RABIN_PRIME.wrapping_pow(RABIN_WINDOW as u32);
+
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(max) {
+        hash = hash.wrapping_mul(RABIN_PRIME).wrapping_add(*byte as u64);
+        if i >= RABIN_WINDOW {
+            hash = hash.wrapping_sub(out_factor.wrapping_mul(data[i - RABIN_WINDOW] as u64));
+        }
+        if i + 1 >= min && hash & mask == mask {
+            return i + 1;
+        }
+    }
+    data.len().min(max)
+}
@@ -0,0 +1,89 @@
+use metrics::histogram;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+/// Number of recent samples percentiles are computed from, per operation.
+const MAX_SAMPLES: usize = 1024;
+
+/// A store operation whose latency is recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreOp {
+    Get,
+    Contains,
+    Insert,
+}
+
+impl StoreOp {
+    fn metric(&self) -> &'static str {
+        match self {
+            StoreOp::Get => "store_get_latency_seconds",
+            StoreOp::Contains => "store_contains_latency_seconds",
+            StoreOp::Insert => "store_insert_latency_seconds",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+/// Latency percentiles of the recent store operations, `None` if an operation was never run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoreLatency {
+    pub get: Option<Percentiles>,
+    pub contains: Option<Percentiles>,
+    pub insert: Option<Percentiles>,
+}
+
+/// Latencies of the most recent store operations.
+#[derive(Debug, Default)]
+pub struct StoreLatencies {
+    get: Mutex<VecDeque<Duration>>,
+    contains: Mutex<VecDeque<Duration>>,
+    insert: Mutex<VecDeque<Duration>>,
+}
+
+impl StoreLatencies {
+    fn samples(&self, op: StoreOp) -> &Mutex<VecDeque<Duration>> {
+        match op {
+            StoreOp::Get => &self.get,
+            StoreOp::Contains => &self.contains,
+            StoreOp::Insert => &self.insert,
+        }
+    }
+
+    pub fn record(&self, op: StoreOp, elapsed: Duration) {
+        histogram!(op.metric(), elapsed.as_secs_f64());
+
+        let mut samples = self.samples(op).lock().unwrap();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(elapsed);
+    }
+
+    pub fn percentiles(&self, op: StoreOp) -> Option<Percentiles> {
+        let mut samples: Vec<_> = self.samples(op).lock().unwrap().iter().copied().collect();
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort_unstable();
+        let percentile = |p: usize| samples[(samples.len() * p / 100).min(samples.len() - 1)];
+        Some(Percentiles {
+            p50: percentile(50),
+            p95: percentile(95),
+            p99: percentile(99),
+        })
+    }
+
+    pub fn latency(&self) -> StoreLatency {
+        StoreLatency {
+            get: self.percentiles(StoreOp::Get),
+            contains: self.percentiles(StoreOp::Contains),
+            insert: self.percentiles(StoreOp::Insert),
+        }
+    }
+}
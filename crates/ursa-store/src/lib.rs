@@ -1,5 +1,12 @@
+mod cache;
+mod chunking;
+mod latency;
 mod store;
+mod unixfs;
 
+pub use self::cache::CacheStats;
+pub use self::chunking::*;
+pub use self::latency::*;
 pub use self::store::*;
 #[cfg(test)]
 mod tests;
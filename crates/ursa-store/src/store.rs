@@ -1,23 +1,124 @@
 use anyhow::anyhow;
 use db::Store;
-use fnv::FnvHashSet;
+use fnv::{FnvHashMap, FnvHashSet};
 use fvm_ipld_blockstore::Blockstore;
 use fvm_ipld_car::CarHeader;
 use fvm_ipld_encoding::{de::DeserializeOwned, from_slice, ser::Serialize, to_vec, DAG_CBOR};
 use integer_encoding::VarInt;
 use ipld_traversal::blockstore::Blockstore as GSBlockstore;
 use libipld::{
+    cbor::DagCborCodec,
     cid,
     multihash::{Code, MultihashDigest},
+    raw::RawCodec,
     store::DefaultParams,
-    Block, Cid, Result,
+    Block, Cid, Ipld, Result,
 };
 use libp2p_bitswap::BitswapStore;
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
+
+use crate::cache::{BlockCache, CacheStats};
+use crate::chunking::{Chunker, ChunkingStrategy};
+use crate::latency::{StoreLatencies, StoreLatency, StoreOp};
+use crate::unixfs::decode_unixfs_data;
+
+/// Prefix of the keys the pin counts of the blocks are stored under.
+const PIN_PREFIX: &[u8] = b"pin/";
+
+/// Prefix of the keys of the block index, listing the blocks written through the store
+/// so they can be found by the garbage collector. The entries are numbered in order.
+const BLOCK_INDEX_PREFIX: &[u8] = b"block_index/";
+/// Key the number of entries of the block index is stored under.
+const BLOCK_INDEX_LEN_KEY: &[u8] = b"block_index/len";
+/// Prefix of the keys the sizes of the blocks written through the store are stored under.
+const BLOCK_SIZE_PREFIX: &[u8] = b"block_size/";
+/// Key set once the blocks written before the block index existed were added to it.
+const BLOCK_INDEX_BACKFILLED_KEY: &[u8] = b"block_index/backfilled";
+
+/// Blocks removed by a garbage collection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct GcStats {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Number of blocks and bytes held by the store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize)]
+pub struct StoreSize {
+    pub blocks: u64,
+    pub bytes: u64,
+}
+
+/// Limits on the dags traversed to answer untrusted requests. `None` leaves a limit unbounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct DagLimits {
+    /// Maximum number of blocks in the dag.
+    #[serde(default)]
+    pub max_nodes: Option<usize>,
+    /// Maximum number of links from the root to a block, the root being at depth 0.
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    /// Maximum number of bytes of the blocks of the dag.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// Key the cids the node was asked to provide are stored under, so providing them
+/// resumes after a restart.
+pub const PROVIDE_INTENTS_KEY: &str = "provide_intents";
+/// Key the roots of the advertisements published by the index provider are stored under.
+pub const PUBLISHED_ROOTS_KEY: &str = "published_roots";
+
+/// Bytes `[start, end)` of a file being read, and where the read is in the file.
+struct FileRange {
+    start: u64,
+    end: u64,
+    pos: u64,
+    data: Vec<u8>,
+}
+
+impl FileRange {
+    fn is_done(&self) -> bool {
+        self.pos >= self.end
+    }
+
+    /// Whether the next `len` bytes of the file end before the range starts.
+    fn skips(&self, len: u64) -> bool {
+        self.pos + len <= self.start
+    }
+
+    /// Read the next bytes of the file, keeping the part within the range.
+    fn read(&mut self, bytes: &[u8]) {
+        let len = bytes.len() as u64;
+        let from = self.start.max(self.pos).min(self.pos + len);
+        let to = self.end.min(self.pos + len).max(from);
+        self.data
+            .extend_from_slice(&bytes[(from - self.pos) as usize..(to - self.pos) as usize]);
+        self.pos += len;
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct UrsaStore<S> {
     pub db: Arc<S>,
+    /// Latencies of the block operations served through the store.
+    latencies: Arc<StoreLatencies>,
+    /// Held to append to the block index. Guards the size of the indexed blocks, which
+    /// is counted from the index on first use and kept up to date from then on.
+    index_lock: Arc<Mutex<Option<StoreSize>>>,
+    /// Held for reading by the reads of dags and for writing by garbage collections, so a
+    /// collection doesn't delete blocks from under a read.
+    gc_lock: Arc<RwLock<()>>,
+    /// Held to update the pin counts.
+    pin_lock: Arc<Mutex<()>>,
+    /// Roots kept by garbage collections while in use, with the number of holds of each,
+    /// see [`UrsaStore::hold_roots`].
+    held_roots: Arc<Mutex<FnvHashMap<Cid, usize>>>,
+    /// Blocks recently read, served without reading the db again.
+    cache: Arc<BlockCache>,
 }
 
 impl<S> UrsaStore<S>
@@ -25,7 +126,46 @@ where
     S: Blockstore + Store + Send + Sync + 'static,
 {
     pub fn new(db: Arc<S>) -> Self {
-        Self { db }
+        Self {
+            db,
+            latencies: Default::default(),
+            index_lock: Default::default(),
+            gc_lock: Default::default(),
+            pin_lock: Default::default(),
+            held_roots: Default::default(),
+            cache: Arc::new(BlockCache::new(0)),
+        }
+    }
+
+    /// Keep up to `capacity` bytes of the blocks read in memory. The cache is disabled by
+    /// default.
+    pub fn with_block_cache(mut self, capacity: u64) -> Self {
+        self.cache = Arc::new(BlockCache::new(capacity));
+        self
+    }
+
+    /// Hits and misses of the block cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Read a block from the block cache, or from the db on a miss.
+    fn read_block(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.cache.get(cid) {
+            return Ok(Some(data));
+        }
+        let data = self.db.get(cid)?;
+        if let Some(data) = &data {
+            self.cache.insert(*cid, data);
+        }
+        Ok(data)
+    }
+
+    fn has_block(&self, cid: &Cid) -> Result<bool> {
+        if self.cache.contains(cid) {
+            return Ok(true);
+        }
+        self.db.has(cid)
     }
 
     /// return the inner blockstore
@@ -33,24 +173,326 @@ where
         &self.db
     }
 
+    /// Latency percentiles of the recent get, contains and insert operations.
+    pub fn latency(&self) -> StoreLatency {
+        self.latencies.latency()
+    }
+
+    /// Run a store operation, recording how long it took.
+    fn timed<T>(&self, op: StoreOp, f: impl FnOnce(&S) -> T) -> T {
+        let started = Instant::now();
+        let res = f(&self.db);
+        self.latencies.record(op, started.elapsed());
+        res
+    }
+
+    /// Write a block, adding it to the block index if it is new.
+    pub fn put_block(&self, cid: &Cid, data: &[u8]) -> Result<()> {
+        let mut index = self.index_lock.lock().unwrap();
+        let is_new = !self.db.has(cid)?;
+        self.db.put_keyed(cid, data)?;
+        if is_new {
+            let len = self.block_index_len()?;
+            self.db.write(Self::block_index_key(len), cid.to_bytes())?;
+            self.db
+                .write(BLOCK_INDEX_LEN_KEY, (len + 1).encode_var_vec())?;
+            self.db
+                .write(Self::block_size_key(cid), data.len().encode_var_vec())?;
+            if let Some(size) = index.as_mut() {
+                size.blocks += 1;
+                size.bytes += data.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write `blocks` in a single batch, adding the new ones to the block index.
+    pub fn insert_many(
+        &self,
+        blocks: impl IntoIterator<Item = Block<DefaultParams>>,
+    ) -> Result<()> {
+        self.put_blocks(blocks.into_iter().map(Block::into_inner))
+    }
+
+    /// Write the `(cid, data)` pairs of `blocks` in a single batch, adding the new ones to the
+    /// block index.
+    fn put_blocks<D: AsRef<[u8]>>(&self, blocks: impl IntoIterator<Item = (Cid, D)>) -> Result<()> {
+        let mut index = self.index_lock.lock().unwrap();
+        let mut len = self.block_index_len()?;
+        let mut added = StoreSize::default();
+        let mut new_cids = FnvHashSet::default();
+        let mut writes = Vec::new();
+        for (cid, data) in blocks {
+            let data = data.as_ref();
+            if !new_cids.contains(&cid) && !self.db.has(&cid)? {
+                new_cids.insert(cid);
+                writes.push((Self::block_index_key(len), cid.to_bytes()));
+                writes.push((Self::block_size_key(&cid), data.len().encode_var_vec()));
+                len += 1;
+                added.blocks += 1;
+                added.bytes += data.len() as u64;
+            }
+            writes.push((cid.to_bytes(), data.to_vec()));
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        writes.push((BLOCK_INDEX_LEN_KEY.to_vec(), len.encode_var_vec()));
+        self.timed(StoreOp::Insert, |db| db.bulk_write(&writes))?;
+        if let Some(size) = index.as_mut() {
+            size.blocks += added.blocks;
+            size.bytes += added.bytes;
+        }
+        Ok(())
+    }
+
+    /// Delete a block. It stays in the block index, which skips deleted blocks.
+    pub fn delete_block(&self, cid: &Cid) -> Result<()> {
+        let mut index = self.index_lock.lock().unwrap();
+        // only the blocks with a size entry are counted in the size of the store
+        let counted = match self.db.read(Self::block_size_key(cid))? {
+            Some(bytes) => Some(Self::decode_size(cid, &bytes)?),
+            None => None,
+        };
+        if self.db.has(cid)? {
+            self.db.delete(cid.to_bytes())?;
+            self.db.delete(Self::block_size_key(cid))?;
+            self.cache.remove(cid);
+            if let (Some(size), Some(len)) = (index.as_mut(), counted) {
+                size.blocks -= 1;
+                size.bytes -= len as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of blocks and bytes written through the store. The first call counts them
+    /// from the size index, after adding the blocks written before the block index existed
+    /// to it.
+    pub fn size(&self) -> Result<StoreSize> {
+        let mut index = self.index_lock.lock().unwrap();
+        if let Some(size) = *index {
+            return Ok(size);
+        }
+        self.backfill_block_index()?;
+        let mut size = StoreSize::default();
+        for cid in self.indexed_blocks()? {
+            if let Some(len) = self.indexed_size(&cid)? {
+                size.blocks += 1;
+                size.bytes += len as u64;
+            }
+        }
+        *index = Some(size);
+        Ok(size)
+    }
+
+    /// Add the blocks written before the block index existed to it, found from the content
+    /// the node provides or published. Done once per db, with the index lock held.
+    fn backfill_block_index(&self) -> Result<()> {
+        if self.db.exists(BLOCK_INDEX_BACKFILLED_KEY)? {
+            return Ok(());
+        }
+        let indexed = self.indexed_blocks()?;
+        let mut len = self.block_index_len()?;
+        let mut writes = Vec::new();
+        let mut seen = FnvHashSet::default();
+        let mut current = self.provide_intents()?;
+        current.extend(self.published_roots()?);
+        while let Some(cid) = current.pop() {
+            if !seen.insert(cid) {
+                continue;
+            }
+            if let Some(data) = self.db.get(&cid)? {
+                if !indexed.contains(&cid) {
+                    writes.push((Self::block_index_key(len), cid.to_bytes()));
+                    writes.push((Self::block_size_key(&cid), data.len().encode_var_vec()));
+                    len += 1;
+                }
+                Block::<DefaultParams>::new(cid, data)?.references(&mut current)?;
+            }
+        }
+        writes.push((BLOCK_INDEX_LEN_KEY.to_vec(), len.encode_var_vec()));
+        writes.push((BLOCK_INDEX_BACKFILLED_KEY.to_vec(), vec![1]));
+        self.db.bulk_write(&writes)?;
+        Ok(())
+    }
+
+    /// Size of an indexed block from the size index. The blocks indexed before the size
+    /// index existed are measured from their data once. `None` if the block was deleted.
+    fn indexed_size(&self, cid: &Cid) -> Result<Option<usize>> {
+        if let Some(bytes) = self.db.read(Self::block_size_key(cid))? {
+            return Self::decode_size(cid, &bytes).map(Some);
+        }
+        let len = self.db.get(cid)?.map(|data| data.len());
+        if let Some(len) = len {
+            self.db
+                .write(Self::block_size_key(cid), len.encode_var_vec())?;
+        }
+        Ok(len)
+    }
+
+    fn block_index_key(n: u64) -> Vec<u8> {
+        [BLOCK_INDEX_PREFIX, &n.to_be_bytes()].concat()
+    }
+
+    fn block_size_key(cid: &Cid) -> Vec<u8> {
+        [BLOCK_SIZE_PREFIX, &cid.to_bytes()].concat()
+    }
+
+    /// Size in bytes of a block, read from the size index without reading the block.
+    /// Blocks written before the size index existed are read once to be measured.
+    pub fn get_size(&self, cid: &Cid) -> Result<Option<usize>> {
+        if let Some(bytes) = self.db.read(Self::block_size_key(cid))? {
+            return Self::decode_size(cid, &bytes).map(Some);
+        }
+        Ok(self.read_block(cid)?.map(|data| data.len()))
+    }
+
+    fn decode_size(cid: &Cid, bytes: &[u8]) -> Result<usize> {
+        usize::decode_var(bytes)
+            .map(|(size, _)| size)
+            .ok_or_else(|| anyhow!("Invalid size of the block {cid}"))
+    }
+
+    fn block_index_len(&self) -> Result<u64> {
+        match self.db.read(BLOCK_INDEX_LEN_KEY)? {
+            Some(bytes) => u64::decode_var(&bytes)
+                .map(|(len, _)| len)
+                .ok_or_else(|| anyhow!("Invalid block index length")),
+            None => Ok(0),
+        }
+    }
+
+    /// Cids of the block index, which may include blocks that were deleted since.
+    fn indexed_blocks(&self) -> Result<FnvHashSet<Cid>> {
+        let mut cids = FnvHashSet::default();
+        for n in 0..self.block_index_len()? {
+            if let Some(bytes) = self.db.read(Self::block_index_key(n))? {
+                cids.insert(Cid::try_from(bytes)?);
+            }
+        }
+        Ok(cids)
+    }
+
+    /// Delete the indexed blocks that can't be reached from a pinned block, a cid the node
+    /// provides or published, or a held root. Blocks written directly to the db, bypassing
+    /// the store, are not indexed and never collected.
+    pub fn gc(&self) -> Result<GcStats> {
+        self.gc_keeping(&[])
+    }
+
+    /// Like [`UrsaStore::gc`], also keeping the blocks reachable from `roots`, e.g. the
+    /// roots of the dags being synced.
+    pub fn gc_keeping(&self, roots: &[Cid]) -> Result<GcStats> {
+        let _gc = self.gc_lock.write().unwrap();
+        let mut index = self.index_lock.lock().unwrap();
+        let indexed = self.indexed_blocks()?;
+
+        // mark
+        let mut reachable = FnvHashSet::default();
+        let mut current: Vec<Cid> = roots.to_vec();
+        current.extend(self.provide_intents()?);
+        current.extend(self.published_roots()?);
+        current.extend(self.held_roots.lock().unwrap().keys());
+        for cid in &indexed {
+            if self.is_pinned(cid)? {
+                current.push(*cid);
+            }
+        }
+        while let Some(cid) = current.pop() {
+            if !reachable.insert(cid) {
+                continue;
+            }
+            if let Some(data) = self.db.get(&cid)? {
+                Block::<DefaultParams>::new(cid, data)?.references(&mut current)?;
+            }
+        }
+
+        // sweep, then rewrite the index with the blocks left
+        let mut stats = GcStats::default();
+        let mut kept = Vec::new();
+        for cid in indexed {
+            if reachable.contains(&cid) {
+                kept.push(cid);
+                continue;
+            }
+            if let Some(data) = self.db.get(&cid)? {
+                self.db.delete(cid.to_bytes())?;
+                self.db.delete(Self::block_size_key(&cid))?;
+                self.cache.remove(&cid);
+                stats.blocks += 1;
+                stats.bytes += data.len() as u64;
+            }
+        }
+        let len = self.block_index_len()?;
+        for (n, cid) in kept.iter().enumerate() {
+            self.db
+                .write(Self::block_index_key(n as u64), cid.to_bytes())?;
+        }
+        for n in kept.len() as u64..len {
+            self.db.delete(Self::block_index_key(n))?;
+        }
+        self.db
+            .write(BLOCK_INDEX_LEN_KEY, (kept.len() as u64).encode_var_vec())?;
+        if let Some(size) = index.as_mut() {
+            size.blocks -= stats.blocks;
+            size.bytes -= stats.bytes;
+        }
+        Ok(stats)
+    }
+
     /// traverse a dag and get full dag given a root cid
     pub fn dag_traversal(&self, root_cid: &Cid) -> Result<Vec<(Cid, Vec<u8>)>> {
+        self.dag_traversal_with_limits(root_cid, &DagLimits::default())
+    }
+
+    /// Like [`UrsaStore::dag_traversal`], but fails as soon as the dag exceeds one of `limits`,
+    /// before loading the rest of it.
+    pub fn dag_traversal_with_limits(
+        &self,
+        root_cid: &Cid,
+        limits: &DagLimits,
+    ) -> Result<Vec<(Cid, Vec<u8>)>> {
+        let _gc = self.gc_lock.read().unwrap();
+        self.traverse_dag(root_cid, limits)
+    }
+
+    /// The traversal of [`UrsaStore::dag_traversal_with_limits`], for callers already
+    /// holding the gc lock.
+    fn traverse_dag(&self, root_cid: &Cid, limits: &DagLimits) -> Result<Vec<(Cid, Vec<u8>)>> {
         let mut res = Vec::new();
-        // get full dag starting with root id
-        let mut current = FnvHashSet::default();
+        let mut bytes = 0;
+        // get full dag starting with root id, along with the depth of each block
+        let mut current = vec![(*root_cid, 0)];
         let mut refs = FnvHashSet::default();
-        current.insert(*root_cid);
 
-        while let Some(cid) = current.iter().next().copied() {
-            current.remove(&cid);
+        while let Some((cid, depth)) = current.pop() {
             if refs.contains(&cid) {
                 continue;
             }
-            match self.db.get(&cid)? {
+            if let Some(max) = limits.max_depth.filter(|max| depth > *max) {
+                return Err(anyhow!(
+                    "The dag with the root {root_cid} is deeper than {max} links"
+                ));
+            }
+            if let Some(max) = limits.max_nodes.filter(|max| res.len() >= *max) {
+                return Err(anyhow!(
+                    "The dag with the root {root_cid} has more than {max} blocks"
+                ));
+            }
+            match self.read_block(&cid)? {
                 Some(data) => {
+                    bytes += data.len() as u64;
+                    if let Some(max) = limits.max_bytes.filter(|max| bytes > *max) {
+                        return Err(anyhow!(
+                            "The dag with the root {root_cid} has more than {max} bytes"
+                        ));
+                    }
                     res.push((cid, data.clone()));
                     let next_block = Block::<DefaultParams>::new(cid, data)?;
-                    next_block.references(&mut current)?;
+                    let mut links = FnvHashSet::default();
+                    next_block.references(&mut links)?;
+                    current.extend(links.into_iter().map(|link| (link, depth + 1)));
                     refs.insert(cid);
                 }
                 None => {
@@ -66,6 +508,310 @@ where
         Ok(res)
     }
 
+    /// Check whether every block of the dag under `root_cid` is in the store,
+    /// stopping at the first missing block.
+    pub fn has_full_dag(&self, root_cid: &Cid) -> Result<bool> {
+        Ok(self.first_missing_block(root_cid)?.is_none())
+    }
+
+    /// The first block of the dag under `root_cid` that is not in the store, if any. Only
+    /// the local store is read, and blocks linked more than once are checked once.
+    pub fn first_missing_block(&self, root_cid: &Cid) -> Result<Option<Cid>> {
+        let _gc = self.gc_lock.read().unwrap();
+        let mut current = FnvHashSet::default();
+        let mut refs = FnvHashSet::default();
+        current.insert(*root_cid);
+
+        while let Some(cid) = current.iter().next().copied() {
+            current.remove(&cid);
+            if !refs.insert(cid) {
+                continue;
+            }
+            match self.read_block(&cid)? {
+                Some(data) => {
+                    Block::<DefaultParams>::new(cid, data)?.references(&mut current)?;
+                }
+                None => return Ok(Some(cid)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every block of the dag under `root_cid` that is not in the store, reading only the
+    /// local store. The links of missing blocks are unknown, so they are not followed.
+    pub fn get_missing_blocks(&self, root_cid: &Cid) -> Result<Vec<Cid>> {
+        Ok(self.local_dag_blocks(root_cid)?.1)
+    }
+
+    /// The number of blocks of the dag under `root_cid` in the store, and the blocks
+    /// missing from it, see [`UrsaStore::get_missing_blocks`].
+    pub fn local_dag_blocks(&self, root_cid: &Cid) -> Result<(usize, Vec<Cid>)> {
+        let _gc = self.gc_lock.read().unwrap();
+        let mut stack = vec![*root_cid];
+        let mut visited = FnvHashSet::default();
+        let mut present = 0;
+        let mut missing = vec![];
+
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            match self.read_block(&cid)? {
+                Some(data) => {
+                    present += 1;
+                    Block::<DefaultParams>::new_unchecked(cid, data).references(&mut stack)?;
+                }
+                None => missing.push(cid),
+            }
+        }
+        Ok((present, missing))
+    }
+
+    /// Split `data` into raw blocks with `chunking` and store them, along with a root
+    /// block listing their links in order. Returns the cid of the root.
+    pub fn import(&self, data: &[u8], chunking: &ChunkingStrategy) -> Result<Cid> {
+        let mut importer = self.importer(chunking)?;
+        importer.write(data)?;
+        importer.finish()
+    }
+
+    /// Import a file whose data comes in pieces, into the same blocks as [`Self::import`].
+    pub fn importer(&self, chunking: &ChunkingStrategy) -> Result<Importer<'_, S>> {
+        Ok(Importer {
+            store: self,
+            chunker: Chunker::new(*chunking)?,
+            links: Vec::new(),
+            held: self.hold_roots(),
+            pin: false,
+        })
+    }
+
+    fn read_cids(&self, key: &str) -> Result<Vec<Cid>> {
+        match self.db.read(key)? {
+            Some(bytes) => Ok(from_slice(&bytes)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Append `cid` to the list stored under `key`, unless it is already in it.
+    fn add_cid(&self, key: &str, cid: &Cid) -> Result<()> {
+        let mut cids = self.read_cids(key)?;
+        if !cids.contains(cid) {
+            cids.push(*cid);
+            self.db.write(key, to_vec(&cids)?)?;
+        }
+        Ok(())
+    }
+
+    fn remove_cid(&self, key: &str, cid: &Cid) -> Result<()> {
+        let mut cids = self.read_cids(key)?;
+        let len = cids.len();
+        cids.retain(|c| c != cid);
+        if cids.len() != len {
+            self.db.write(key, to_vec(&cids)?)?;
+        }
+        Ok(())
+    }
+
+    /// The cids the node was asked to provide, in the order they were requested.
+    pub fn provide_intents(&self) -> Result<Vec<Cid>> {
+        self.read_cids(PROVIDE_INTENTS_KEY)
+    }
+
+    /// Durably record the intent to provide `cid`, before it is provided.
+    pub fn record_provide_intent(&self, cid: &Cid) -> Result<()> {
+        self.add_cid(PROVIDE_INTENTS_KEY, cid)
+    }
+
+    /// Forget the intent to provide `cid`.
+    pub fn remove_provide_intent(&self, cid: &Cid) -> Result<()> {
+        self.remove_cid(PROVIDE_INTENTS_KEY, cid)
+    }
+
+    /// The roots advertised by the index provider, in the order they were published.
+    pub fn published_roots(&self) -> Result<Vec<Cid>> {
+        self.read_cids(PUBLISHED_ROOTS_KEY)
+    }
+
+    pub fn record_published_root(&self, cid: &Cid) -> Result<()> {
+        self.add_cid(PUBLISHED_ROOTS_KEY, cid)
+    }
+
+    /// Forget `cid`, once an advertisement removing it is published.
+    pub fn remove_published_root(&self, cid: &Cid) -> Result<()> {
+        self.remove_cid(PUBLISHED_ROOTS_KEY, cid)
+    }
+
+    fn pin_key(cid: &Cid) -> Vec<u8> {
+        [PIN_PREFIX, &cid.to_bytes()].concat()
+    }
+
+    fn pin_count(&self, cid: &Cid) -> Result<u64> {
+        match self.db.read(Self::pin_key(cid))? {
+            Some(bytes) => u64::decode_var(&bytes)
+                .map(|(count, _)| count)
+                .ok_or_else(|| anyhow!("Invalid pin count for {cid}")),
+            None => Ok(0),
+        }
+    }
+
+    /// Pin `cid`, so garbage collection keeps it. A block pinned several times, e.g. as
+    /// part of several dags, stays pinned until it is unpinned as many times.
+    pub fn pin(&self, cid: &Cid) -> Result<()> {
+        let _gc = self.gc_lock.read().unwrap();
+        let _pins = self.pin_lock.lock().unwrap();
+        self.add_pin(cid)
+    }
+
+    /// Release a pin of `cid`.
+    pub fn unpin(&self, cid: &Cid) -> Result<()> {
+        let _gc = self.gc_lock.read().unwrap();
+        let _pins = self.pin_lock.lock().unwrap();
+        self.release_pin(cid)
+    }
+
+    /// Increment the pin count of `cid`, the pin lock being held.
+    fn add_pin(&self, cid: &Cid) -> Result<()> {
+        let count = self.pin_count(cid)? + 1;
+        self.db.write(Self::pin_key(cid), count.encode_var_vec())?;
+        Ok(())
+    }
+
+    /// Decrement the pin count of `cid`, the pin lock being held.
+    fn release_pin(&self, cid: &Cid) -> Result<()> {
+        match self.pin_count(cid)? {
+            0 => {}
+            1 => self.db.delete(Self::pin_key(cid))?,
+            count => self
+                .db
+                .write(Self::pin_key(cid), (count - 1).encode_var_vec())?,
+        }
+        Ok(())
+    }
+
+    pub fn is_pinned(&self, cid: &Cid) -> Result<bool> {
+        Ok(self.db.exists(Self::pin_key(cid))?)
+    }
+
+    /// Pin every block of the dag under `root_cid`.
+    pub fn pin_dag(&self, root_cid: &Cid) -> Result<()> {
+        let _gc = self.gc_lock.read().unwrap();
+        let _pins = self.pin_lock.lock().unwrap();
+        for cid in self.dag_cids(root_cid)? {
+            self.add_pin(&cid)?;
+        }
+        Ok(())
+    }
+
+    /// Release a pin of every block of the dag under `root_cid`.
+    pub fn unpin_dag(&self, root_cid: &Cid) -> Result<()> {
+        let _gc = self.gc_lock.read().unwrap();
+        let _pins = self.pin_lock.lock().unwrap();
+        for cid in self.dag_cids(root_cid)? {
+            self.release_pin(&cid)?;
+        }
+        Ok(())
+    }
+
+    /// Cids of the dag under `root_cid`, reading one block at a time without keeping the
+    /// data, for callers already holding the gc lock. Fails if a block is missing.
+    fn dag_cids(&self, root_cid: &Cid) -> Result<Vec<Cid>> {
+        let mut cids = Vec::new();
+        let mut current = vec![*root_cid];
+        let mut refs = FnvHashSet::default();
+        while let Some(cid) = current.pop() {
+            if !refs.insert(cid) {
+                continue;
+            }
+            let data = self.read_block(&cid)?.ok_or_else(|| {
+                anyhow!("The block with cid {cid} from the dag with the root {root_cid} is missing")
+            })?;
+            Block::<DefaultParams>::new(cid, data)?.references(&mut current)?;
+            cids.push(cid);
+        }
+        Ok(cids)
+    }
+
+    /// Keep the dags under the roots added to the returned guard from garbage collection
+    /// until it is dropped, for dags read or written over several calls.
+    pub fn hold_roots(&self) -> HeldRoots {
+        HeldRoots {
+            held: Arc::clone(&self.held_roots),
+            roots: Vec::new(),
+        }
+    }
+
+    /// Read `length` bytes of the file under `root_cid` starting at `offset`, only loading
+    /// the blocks overlapping the range. The range is cut short at the end of the file.
+    pub fn read_file_range(&self, root_cid: &Cid, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let mut range = FileRange {
+            start: offset,
+            end: offset.saturating_add(length),
+            pos: 0,
+            data: Vec::new(),
+        };
+        if length > 0 {
+            let _gc = self.gc_lock.read().unwrap();
+            self.read_range(root_cid, &mut range)?;
+        }
+        Ok(range.data)
+    }
+
+    /// Read the file under `root` into `range`, in depth first order. The blocks are walked
+    /// with an explicit stack, so a deep file can't overflow the stack of the thread.
+    fn read_range(&self, root: &Cid, range: &mut FileRange) -> Result<()> {
+        // the links left to follow, the next one last, with the size of their data if known
+        let mut current: Vec<Result<(Cid, Option<u64>)>> = vec![Ok((*root, None))];
+        while let Some(link) = current.pop() {
+            if range.is_done() {
+                break;
+            }
+            let (cid, size) = link?;
+            if let Some(size) = size.filter(|size| range.skips(*size)) {
+                range.pos += size;
+                continue;
+            }
+            let data = self.read_block(&cid)?.ok_or_else(|| {
+                anyhow!("The block with cid {cid} is missing, cannot read the file range")
+            })?;
+            let mut children = Vec::new();
+            match Block::<DefaultParams>::new(cid, data)?.ipld()? {
+                Ipld::Bytes(bytes) => range.read(&bytes),
+                Ipld::List(links) => {
+                    children.extend(links.into_iter().map(|link| match link {
+                        Ipld::Link(child) => Ok((child, None)),
+                        ipld => Err(anyhow!("Unexpected {ipld:?} in the file {cid}")),
+                    }));
+                }
+                // dag-pb node, with the sizes of its children in its unixfs data
+                Ipld::Map(mut node) => {
+                    let unixfs = match node.remove("Data") {
+                        Some(Ipld::Bytes(bytes)) => decode_unixfs_data(&bytes)?,
+                        _ => Default::default(),
+                    };
+                    let links = match node.remove("Links") {
+                        Some(Ipld::List(links)) => links,
+                        _ => Vec::new(),
+                    };
+                    range.read(&unixfs.data);
+                    children.extend(links.into_iter().enumerate().map(|(i, link)| {
+                        let size = unixfs.blocksizes.get(i).copied();
+                        match link {
+                            Ipld::Map(mut link) => match link.remove("Hash") {
+                                Some(Ipld::Link(child)) => Ok((child, size)),
+                                _ => Err(anyhow!("Invalid link in the dag-pb node {cid}")),
+                            },
+                            _ => Err(anyhow!("Invalid link in the dag-pb node {cid}")),
+                        }
+                    }));
+                }
+                _ => return Err(anyhow!("The block {cid} is not part of a file")),
+            }
+            current.extend(children.into_iter().rev());
+        }
+        Ok(())
+    }
+
     /// Calculate a car file size from a root cid
     pub fn car_size(&self, root_cid: &Cid) -> Result<u64> {
         let dag = self.dag_traversal(root_cid)?;
@@ -84,6 +830,44 @@ where
 
         Ok(len as u64)
     }
+
+    /// Encode the dag under `root_cid` as a car file, failing once it grows past `max_size` bytes.
+    pub fn car_bytes(&self, root_cid: &Cid, max_size: u64) -> Result<Vec<u8>> {
+        self.car_bytes_with_limits(root_cid, max_size, &DagLimits::default())
+    }
+
+    /// Like [`UrsaStore::car_bytes`], but fails as soon as the dag exceeds one of `limits`.
+    pub fn car_bytes_with_limits(
+        &self,
+        root_cid: &Cid,
+        max_size: u64,
+        limits: &DagLimits,
+    ) -> Result<Vec<u8>> {
+        let dag = self.dag_traversal_with_limits(root_cid, limits)?;
+
+        let header_bytes = to_vec(&CarHeader {
+            roots: vec![*root_cid],
+            version: 1,
+        })?;
+        let mut car = header_bytes.len().encode_var_vec();
+        car.extend_from_slice(&header_bytes);
+
+        for (cid, bytes) in dag {
+            let cid_bytes = cid.to_bytes();
+            let block_len = bytes.len() + cid_bytes.len();
+            car.extend_from_slice(&block_len.encode_var_vec());
+            car.extend_from_slice(&cid_bytes);
+            car.extend_from_slice(&bytes);
+
+            if car.len() as u64 > max_size {
+                return Err(anyhow!(
+                    "The car file for the root {root_cid} exceeds the maximum size of {max_size} bytes"
+                ));
+            }
+        }
+
+        Ok(car)
+    }
 }
 
 /// Extension methods for inserting and retrieving IPLD data with CIDs
@@ -147,20 +931,133 @@ where
     S: Blockstore + Store + Send + Sync + 'static,
 {
     fn get(&self, k: &cid::Cid) -> Result<Option<Vec<u8>>> {
-        self.db.get(k)
+        self.timed(StoreOp::Get, |_| self.read_block(k))
     }
 
     fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
-        self.db.put_keyed(k, block)
+        self.timed(StoreOp::Insert, |_| self.put_block(k, block))
     }
 
     fn delete_block(&self, k: &Cid) -> Result<()> {
-        self.db.delete(k.to_bytes()).map_err(|e| e.into())
+        UrsaStore::delete_block(self, k)
+    }
+}
+
+/// Blocks written through the store are indexed, so they can be garbage collected.
+impl<S> Blockstore for UrsaStore<S>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    fn get(&self, k: &Cid) -> Result<Option<Vec<u8>>> {
+        self.read_block(k)
+    }
+
+    fn put_keyed(&self, k: &Cid, block: &[u8]) -> Result<()> {
+        self.put_block(k, block)
+    }
+
+    fn put_many_keyed<D, I>(&self, blocks: I) -> Result<()>
+    where
+        Self: Sized,
+        D: AsRef<[u8]>,
+        I: IntoIterator<Item = (Cid, D)>,
+    {
+        self.put_blocks(blocks)
+    }
+
+    fn has(&self, k: &Cid) -> Result<bool> {
+        self.has_block(k)
     }
 }
 
 impl<T: Blockstore> BlockstoreExt for T {}
 
+/// Writes the blocks of a file as its data is written, see [`UrsaStore::importer`].
+pub struct Importer<'a, S>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    store: &'a UrsaStore<S>,
+    chunker: Chunker,
+    links: Vec<Ipld>,
+    /// The chunks written, kept from garbage collection until the root links to them.
+    held: HeldRoots,
+    pin: bool,
+}
+
+impl<'a, S> Importer<'a, S>
+where
+    S: Blockstore + Store + Send + Sync + 'static,
+{
+    /// Pin the dag once it is written, see [`UrsaStore::pin_dag`].
+    pub fn pinned(mut self) -> Self {
+        self.pin = true;
+        self
+    }
+
+    /// Store the chunks completed by the next piece of the file.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        for chunk in self.chunker.push(data) {
+            self.put_chunk(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Store the last chunk and the root linking to every chunk, returning the root cid.
+    pub fn finish(mut self) -> Result<Cid> {
+        if let Some(chunk) = self.chunker.finish() {
+            self.put_chunk(chunk)?;
+        }
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &Ipld::List(self.links),
+        )?;
+        self.store.put_block(root.cid(), root.data())?;
+        if self.pin {
+            self.store.pin_dag(root.cid())?;
+        }
+        Ok(*root.cid())
+    }
+
+    fn put_chunk(&mut self, chunk: Vec<u8>) -> Result<()> {
+        let block =
+            Block::<DefaultParams>::encode(RawCodec, Code::Blake3_256, &Ipld::Bytes(chunk))?;
+        self.store.put_block(block.cid(), block.data())?;
+        self.held.add(*block.cid());
+        self.links.push(Ipld::Link(*block.cid()));
+        Ok(())
+    }
+}
+
+/// Roots kept from garbage collection until dropped, see [`UrsaStore::hold_roots`].
+pub struct HeldRoots {
+    held: Arc<Mutex<FnvHashMap<Cid, usize>>>,
+    roots: Vec<Cid>,
+}
+
+impl HeldRoots {
+    /// Keep the dag under `cid` as well.
+    pub fn add(&mut self, cid: Cid) {
+        *self.held.lock().unwrap().entry(cid).or_default() += 1;
+        self.roots.push(cid);
+    }
+}
+
+impl Drop for HeldRoots {
+    fn drop(&mut self) {
+        let mut held = self.held.lock().unwrap();
+        for cid in &self.roots {
+            if let Some(count) = held.get_mut(cid) {
+                *count -= 1;
+                if *count == 0 {
+                    held.remove(cid);
+                }
+            }
+        }
+    }
+}
+
 pub struct BitswapStorage<P>(pub Arc<UrsaStore<P>>)
 where
     P: Blockstore + Store + Send + Sync + 'static;
@@ -172,33 +1069,28 @@ where
     type Params = DefaultParams;
 
     fn contains(&mut self, cid: &Cid) -> Result<bool> {
-        self.0.db.has(cid)
+        self.0.timed(StoreOp::Contains, |_| self.0.has_block(cid))
     }
 
     fn get(&mut self, cid: &Cid) -> Result<Option<Vec<u8>>> {
-        Ok(self.0.db.get(cid).unwrap())
+        Ok(self
+            .0
+            .timed(StoreOp::Get, |_| self.0.read_block(cid))
+            .unwrap())
     }
 
     fn insert(&mut self, block: &Block<Self::Params>) -> Result<()> {
-        self.0.db.put_keyed(block.cid(), block.data()).unwrap();
+        self.0
+            .timed(StoreOp::Insert, |_| {
+                self.0.put_block(block.cid(), block.data())
+            })
+            .unwrap();
 
         Ok(())
     }
 
     fn missing_blocks(&mut self, cid: &Cid) -> Result<Vec<Cid>> {
-        let mut stack = vec![*cid];
-        let mut missing = vec![];
-
-        while let Some(cid) = stack.pop() {
-            if let Some(data) = self.get(&cid)? {
-                let block = Block::<Self::Params>::new_unchecked(cid, data);
-                block.references(&mut stack)?;
-            } else {
-                missing.push(cid);
-            }
-        }
-
-        Ok(missing)
+        self.0.get_missing_blocks(cid)
     }
 }
 
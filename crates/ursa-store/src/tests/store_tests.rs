@@ -3,11 +3,32 @@ mod tests {
     use async_fs::File;
     use futures::io::BufReader;
     use fvm_ipld_car::{load_car, CarReader};
-    use libipld::Cid;
+    use ipld_traversal::blockstore::Blockstore as GSBlockstore;
+    use libipld::{cbor::DagCborCodec, ipld, multihash::Code, Block, Cid, DefaultParams, Ipld};
+    use libp2p_bitswap::BitswapStore;
+    use std::collections::HashSet;
     use std::path::Path;
     use std::sync::Arc;
+    use std::time::Duration;
 
     use crate::tests::{get_store, setup_logger};
+    use crate::{
+        BitswapStorage, CacheStats, Chunker, ChunkingStrategy, DagLimits, GcStats, StoreSize,
+        UrsaStore,
+    };
+
+    /// Deterministic pseudo random bytes, so the content defined boundaries don't repeat.
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
 
     #[tokio::test]
     async fn test_dag_traversal() -> anyhow::Result<()> {
@@ -34,4 +55,689 @@ mod tests {
         // todo: check if they both have sam cids
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_has_full_dag() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+
+        // test.car is a single raw block, so it has no child to delete
+        let child = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "data": "child" }),
+        )?;
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &ipld!({ "links": [*child.cid()] }),
+        )?;
+        for block in [&child, &root] {
+            fvm_ipld_blockstore::Blockstore::put_keyed(
+                store.blockstore(),
+                block.cid(),
+                block.data(),
+            )?;
+        }
+        let root = *root.cid();
+
+        assert!(store.has_full_dag(&root)?);
+        assert_eq!(store.first_missing_block(&root)?, None);
+
+        let child = store
+            .dag_traversal(&root)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .find(|cid| *cid != root)
+            .expect("the dag to have children");
+        store.delete_block(&child)?;
+
+        assert!(!store.has_full_dag(&root)?);
+        assert_eq!(store.first_missing_block(&root)?, Some(child));
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_full_dag_shared_subtrees() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let shared = store.import(&random_bytes(4096), &ChunkingStrategy::FixedSize(1024))?;
+        let branches: Vec<_> = (0..2)
+            .map(|n| {
+                Block::<DefaultParams>::encode(
+                    DagCborCodec,
+                    Code::Blake3_256,
+                    &Ipld::List(vec![Ipld::Integer(n), Ipld::Link(shared)]),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &Ipld::List(
+                branches
+                    .iter()
+                    .map(|branch| Ipld::Link(*branch.cid()))
+                    .chain([Ipld::Link(shared)])
+                    .collect(),
+            ),
+        )?;
+        for block in branches.iter().chain([&root]) {
+            store.put_block(block.cid(), block.data())?;
+        }
+        assert!(store.has_full_dag(root.cid())?);
+
+        let leaf = store
+            .dag_traversal(&shared)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .find(|cid| *cid != shared)
+            .expect("the shared dag to have children");
+        store.delete_block(&leaf)?;
+
+        assert!(!store.has_full_dag(root.cid())?);
+        assert_eq!(store.first_missing_block(root.cid())?, Some(leaf));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dag_traversal_limits() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        // a chain of 5 blocks, the leaf being at depth 4
+        let mut blocks = vec![Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &Ipld::Integer(0),
+        )?];
+        for n in 1..5 {
+            let link = *blocks[n - 1].cid();
+            blocks.push(Block::<DefaultParams>::encode(
+                DagCborCodec,
+                Code::Blake3_256,
+                &Ipld::List(vec![Ipld::Integer(n as i128), Ipld::Link(link)]),
+            )?);
+        }
+        for block in &blocks {
+            store.put_block(block.cid(), block.data())?;
+        }
+        let root = *blocks[4].cid();
+        let bytes: u64 = blocks.iter().map(|block| block.data().len() as u64).sum();
+
+        let within = DagLimits {
+            max_nodes: Some(5),
+            max_depth: Some(4),
+            max_bytes: Some(bytes),
+        };
+        assert_eq!(store.dag_traversal_with_limits(&root, &within)?.len(), 5);
+
+        let exceeded = [
+            (
+                DagLimits {
+                    max_nodes: Some(4),
+                    ..within
+                },
+                "blocks",
+            ),
+            (
+                DagLimits {
+                    max_depth: Some(3),
+                    ..within
+                },
+                "deeper",
+            ),
+            (
+                DagLimits {
+                    max_bytes: Some(bytes - 1),
+                    ..within
+                },
+                "bytes",
+            ),
+        ];
+        for (limits, reason) in exceeded {
+            let err = store.dag_traversal_with_limits(&root, &limits).unwrap_err();
+            assert!(err.to_string().contains(reason), "{err}");
+        }
+        // the unbounded traversal stays available
+        assert_eq!(store.dag_traversal(&root)?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_missing_blocks() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let root = store.import(&random_bytes(8192), &ChunkingStrategy::FixedSize(1024))?;
+        assert!(store.get_missing_blocks(&root)?.is_empty());
+
+        let removed: HashSet<Cid> = store
+            .dag_traversal(&root)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .filter(|cid| *cid != root)
+            .step_by(3)
+            .collect();
+        assert!(removed.len() > 1);
+        for cid in &removed {
+            store.delete_block(cid)?;
+        }
+        let missing = store.get_missing_blocks(&root)?;
+        assert_eq!(missing.len(), removed.len());
+        assert_eq!(missing.into_iter().collect::<HashSet<_>>(), removed);
+
+        store.delete_block(&root)?;
+        assert_eq!(store.get_missing_blocks(&root)?, vec![root]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_chunking_strategies() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let chunks = |root: &Cid| -> anyhow::Result<Vec<Cid>> {
+            let data = store.blockstore().get(root)?.expect("the root block");
+            match Block::<DefaultParams>::new(*root, data)?.decode::<DagCborCodec, Ipld>()? {
+                Ipld::List(links) => Ok(links
+                    .into_iter()
+                    .filter_map(|link| match link {
+                        Ipld::Link(cid) => Some(cid),
+                        _ => None,
+                    })
+                    .collect()),
+                ipld => panic!("unexpected root {ipld:?}"),
+            }
+        };
+        let shared = |a: &[Cid], b: &[Cid]| {
+            let a: HashSet<_> = a.iter().collect();
+            b.iter().filter(|cid| a.contains(cid)).count()
+        };
+
+        let data = random_bytes(1024 * 1024);
+        // the same content with a few bytes inserted at the start
+        let shifted = [b"shifted".as_slice(), &data].concat();
+
+        let fixed = ChunkingStrategy::FixedSize(64 * 1024);
+        let fixed_chunks = chunks(&store.import(&data, &fixed)?)?;
+        assert_eq!(fixed_chunks.len(), 16);
+        for cid in &fixed_chunks {
+            assert_eq!(store.blockstore().get(cid)?.unwrap().len(), 64 * 1024);
+        }
+        // every fixed size boundary moves, so nothing is shared
+        let fixed_shifted = chunks(&store.import(&shifted, &fixed)?)?;
+        assert_eq!(shared(&fixed_chunks, &fixed_shifted), 0);
+
+        let rabin = ChunkingStrategy::Rabin {
+            min: 16 * 1024,
+            avg: 64 * 1024,
+            max: 256 * 1024,
+        };
+        let rabin_chunks = chunks(&store.import(&data, &rabin)?)?;
+        let mut len = 0;
+        for cid in &rabin_chunks {
+            let size = store.blockstore().get(cid)?.unwrap().len();
+            assert!(size <= 256 * 1024);
+            len += size;
+        }
+        assert_eq!(len, data.len());
+        // the boundaries follow the content, so only the first block changes
+        let rabin_shifted = chunks(&store.import(&shifted, &rabin)?)?;
+        assert!(rabin_chunks.len() > 1);
+        assert_eq!(
+            shared(&rabin_chunks, &rabin_shifted),
+            rabin_chunks.len() - 1
+        );
+
+        assert!(store
+            .import(&data, &ChunkingStrategy::FixedSize(0))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunker_matches_split() -> anyhow::Result<()> {
+        let data = random_bytes(1024 * 1024);
+        for strategy in [
+            ChunkingStrategy::FixedSize(64 * 1024),
+            ChunkingStrategy::Rabin {
+                min: 16 * 1024,
+                avg: 64 * 1024,
+                max: 256 * 1024,
+            },
+        ] {
+            // pieces smaller and larger than the chunks
+            for piece in [1000, 300 * 1024] {
+                let mut chunker = Chunker::new(strategy)?;
+                let mut chunks = Vec::new();
+                for data in data.chunks(piece) {
+                    chunks.extend(chunker.push(data));
+                }
+                chunks.extend(chunker.finish());
+                assert_eq!(chunks, strategy.split(&data)?);
+            }
+
+            let store = get_store();
+            let mut importer = store.importer(&strategy)?;
+            for data in data.chunks(1000) {
+                importer.write(data)?;
+            }
+            assert_eq!(importer.finish()?, store.import(&data, &strategy)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_file_range() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let data = random_bytes(10 * 1024);
+        let root = store.import(&data, &ChunkingStrategy::FixedSize(1024))?;
+
+        // within a single block
+        assert_eq!(store.read_file_range(&root, 1100, 200)?, &data[1100..1300]);
+        // spanning several blocks
+        assert_eq!(store.read_file_range(&root, 900, 3000)?, &data[900..3900]);
+        // cut short at the end of the file
+        assert_eq!(store.read_file_range(&root, 9000, 5000)?, &data[9000..]);
+        assert!(store.read_file_range(&root, 20 * 1024, 10)?.is_empty());
+        assert!(store.read_file_range(&root, 100, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_file_range_deep_file() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        // a file nested far deeper than the stack of a thread could recurse
+        let leaf = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &Ipld::Bytes(b"deep".to_vec()),
+        )?;
+        store.put_block(leaf.cid(), leaf.data())?;
+        let mut root = *leaf.cid();
+        for _ in 0..50_000 {
+            let node = Block::<DefaultParams>::encode(
+                DagCborCodec,
+                Code::Blake3_256,
+                &Ipld::List(vec![Ipld::Link(root)]),
+            )?;
+            store.put_block(node.cid(), node.data())?;
+            root = *node.cid();
+        }
+
+        assert_eq!(store.read_file_range(&root, 1, 2)?, b"ee");
+        Ok(())
+    }
+
+    #[test]
+    fn test_provide_intents() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let first = store.import(b"first", &ChunkingStrategy::default())?;
+        let second = store.import(b"second", &ChunkingStrategy::default())?;
+        assert!(store.provide_intents()?.is_empty());
+
+        store.record_provide_intent(&first)?;
+        store.record_provide_intent(&second)?;
+        store.record_provide_intent(&first)?;
+        // a store reopened on the same db sees the intents
+        let reopened = UrsaStore::new(Arc::clone(&store.db));
+        assert_eq!(reopened.provide_intents()?, vec![first, second]);
+
+        reopened.remove_provide_intent(&first)?;
+        assert_eq!(store.provide_intents()?, vec![second]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_published_roots() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let first = store.import(b"first root", &ChunkingStrategy::default())?;
+        let second = store.import(b"second root", &ChunkingStrategy::default())?;
+        assert!(store.published_roots()?.is_empty());
+
+        store.record_published_root(&first)?;
+        store.record_published_root(&second)?;
+        store.record_published_root(&second)?;
+        assert_eq!(store.published_roots()?, vec![first, second]);
+
+        store.remove_published_root(&first)?;
+        assert_eq!(store.published_roots()?, vec![second]);
+        // the provide intents are kept apart
+        assert!(store.provide_intents()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_shared_blocks() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let data = random_bytes(4096);
+        let first = store.import(&data, &ChunkingStrategy::FixedSize(1024))?;
+        // shares its first three blocks with the first dag
+        let second = store.import(&data[..3072], &ChunkingStrategy::FixedSize(1024))?;
+        let shared = store
+            .dag_traversal(&second)?
+            .into_iter()
+            .map(|(cid, _)| cid)
+            .find(|cid| *cid != second)
+            .expect("a child block");
+
+        store.pin_dag(&first)?;
+        store.pin_dag(&second)?;
+        store.unpin_dag(&first)?;
+        assert!(!store.is_pinned(&first)?);
+        assert!(store.is_pinned(&second)?);
+        assert!(store.is_pinned(&shared)?);
+
+        // pins are kept in the db
+        let reopened = UrsaStore::new(Arc::clone(&store.db));
+        assert!(reopened.is_pinned(&shared)?);
+        reopened.unpin_dag(&second)?;
+        assert!(!store.is_pinned(&shared)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_removes_unpinned_blocks() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let data = random_bytes(8192);
+        let pinned = store.import(&data[..4096], &ChunkingStrategy::FixedSize(1024))?;
+        let orphan = store.import(&data[4096..], &ChunkingStrategy::FixedSize(1024))?;
+        store.pin_dag(&pinned)?;
+
+        let pinned_dag = store.dag_traversal(&pinned)?;
+        let orphan_dag = store.dag_traversal(&orphan)?;
+        let stats = store.gc()?;
+        assert_eq!(stats.blocks, orphan_dag.len() as u64);
+        assert_eq!(
+            stats.bytes,
+            orphan_dag
+                .iter()
+                .map(|(_, data)| data.len() as u64)
+                .sum::<u64>()
+        );
+        for (cid, _) in &pinned_dag {
+            assert!(store.blockstore().get(cid)?.is_some());
+        }
+        for (cid, _) in &orphan_dag {
+            assert!(store.blockstore().get(cid)?.is_none());
+        }
+
+        // the collected blocks left the index
+        assert_eq!(store.gc()?, GcStats::default());
+        store.unpin_dag(&pinned)?;
+        assert_eq!(store.gc()?.blocks, pinned_dag.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_keeps_served_roots() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let data = random_bytes(5120);
+        let chunking = ChunkingStrategy::FixedSize(1024);
+        let provided = store.import(&data[..1024], &chunking)?;
+        let published = store.import(&data[1024..2048], &chunking)?;
+        let held = store.import(&data[2048..3072], &chunking)?;
+        let syncing = store.import(&data[3072..4096], &chunking)?;
+        let orphan = store.import(&data[4096..], &chunking)?;
+        store.record_provide_intent(&provided)?;
+        store.record_published_root(&published)?;
+        let mut guard = store.hold_roots();
+        guard.add(held);
+
+        let orphan_dag = store.dag_traversal(&orphan)?;
+        assert_eq!(
+            store.gc_keeping(&[syncing])?.blocks,
+            orphan_dag.len() as u64
+        );
+        for root in [provided, published, held, syncing] {
+            assert!(store.has_full_dag(&root)?);
+        }
+
+        // the held root is collected once released
+        drop(guard);
+        let held_dag = store.dag_traversal(&held)?;
+        assert_eq!(store.gc_keeping(&[syncing])?.blocks, held_dag.len() as u64);
+        assert!(!store.has_full_dag(&held)?);
+
+        // a pinned import keeps its dag on its own
+        let mut importer = store.importer(&chunking)?.pinned();
+        importer.write(&data)?;
+        let pinned = importer.finish()?;
+        store.gc()?;
+        assert!(store.has_full_dag(&pinned)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_size() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        assert_eq!(store.size()?, StoreSize::default());
+
+        let blocks: Vec<_> = (0..10)
+            .map(|n| {
+                Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::Integer(n))
+            })
+            .collect::<Result<_, _>>()?;
+        for block in &blocks {
+            store.put_block(block.cid(), block.data())?;
+        }
+        // writing a block again doesn't count it twice
+        store.put_block(blocks[0].cid(), blocks[0].data())?;
+        let bytes: u64 = blocks.iter().map(|block| block.data().len() as u64).sum();
+        assert_eq!(store.size()?, StoreSize { blocks: 10, bytes });
+
+        store.delete_block(blocks[0].cid())?;
+        let size = StoreSize {
+            blocks: 9,
+            bytes: bytes - blocks[0].data().len() as u64,
+        };
+        assert_eq!(store.size()?, size);
+
+        // counted again from the block index on a new store over the same db
+        let store = UrsaStore::new(Arc::clone(&store.db));
+        assert_eq!(store.size()?, size);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_size_backfills_the_block_index() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+
+        // a dag the node still provides, written before the block index existed
+        let leaf =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::Integer(1))?;
+        let root = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &Ipld::List(vec![Ipld::Link(*leaf.cid())]),
+        )?;
+        for block in [&leaf, &root] {
+            store.blockstore().put_keyed(block.cid(), block.data())?;
+        }
+        store.record_provide_intent(root.cid())?;
+        // and a block written bypassing the store that nothing links to
+        let stray =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::Integer(2))?;
+        store.blockstore().put_keyed(stray.cid(), stray.data())?;
+
+        let bytes = (leaf.data().len() + root.data().len()) as u64;
+        assert_eq!(store.size()?, StoreSize { blocks: 2, bytes });
+
+        // deleting a block that isn't counted leaves the count alone
+        store.delete_block(stray.cid())?;
+        assert_eq!(store.size()?, StoreSize { blocks: 2, bytes });
+        store.delete_block(leaf.cid())?;
+        let size = StoreSize {
+            blocks: 1,
+            bytes: root.data().len() as u64,
+        };
+        assert_eq!(store.size()?, size);
+
+        // the backfilled blocks are counted from the size index on a new store
+        let store = UrsaStore::new(Arc::clone(&store.db));
+        assert_eq!(store.size()?, size);
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_many() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let blocks: Vec<_> = (0..5000)
+            .map(|n| {
+                Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::Integer(n))
+            })
+            .collect::<Result<_, _>>()?;
+        // a block already in the store and one repeated in the batch are indexed once
+        store.put_block(blocks[0].cid(), blocks[0].data())?;
+        let mut batch = blocks.clone();
+        batch.push(blocks[1].clone());
+
+        let started = std::time::Instant::now();
+        store.insert_many(batch)?;
+        tracing::info!(
+            "inserted {} blocks in {:?}",
+            blocks.len(),
+            started.elapsed()
+        );
+
+        let mut bitswap_store = BitswapStorage(Arc::clone(&store));
+        for block in &blocks {
+            assert_eq!(bitswap_store.get(block.cid())?, Some(block.data().to_vec()));
+        }
+        let bytes: u64 = blocks.iter().map(|block| block.data().len() as u64).sum();
+        let size = StoreSize {
+            blocks: blocks.len() as u64,
+            bytes,
+        };
+        assert_eq!(store.size()?, size);
+        assert_eq!(UrsaStore::new(Arc::clone(&store.db)).size()?, size);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_load_car_indexes_blocks() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        let path = Path::new("../../test_files/test.car");
+        let reader = BufReader::new(File::open(path).await?);
+        load_car(store.as_ref(), reader).await?;
+
+        let mut car_reader = CarReader::new(BufReader::new(File::open(path).await?)).await?;
+        let mut bitswap_store = BitswapStorage(Arc::clone(&store));
+        let mut cids = HashSet::new();
+        while let Some(block) = car_reader.next_block().await? {
+            assert!(bitswap_store.contains(&block.cid)?);
+            cids.insert(block.cid);
+        }
+        assert_eq!(store.size()?.blocks, cids.len() as u64);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_cache() -> anyhow::Result<()> {
+        setup_logger();
+        let block =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::Integer(1))?;
+        let store = Arc::new(UrsaStore::new(Arc::clone(&get_store().db)).with_block_cache(1024));
+        store.put_block(block.cid(), block.data())?;
+        let mut bitswap_store = BitswapStorage(Arc::clone(&store));
+
+        // the first read misses and caches the block, the second is served from the cache
+        assert_eq!(bitswap_store.get(block.cid())?, Some(block.data().to_vec()));
+        assert_eq!(store.cache_stats(), CacheStats { hits: 0, misses: 1 });
+        assert_eq!(bitswap_store.get(block.cid())?, Some(block.data().to_vec()));
+        assert!(bitswap_store.contains(block.cid())?);
+        assert_eq!(store.cache_stats(), CacheStats { hits: 2, misses: 1 });
+
+        // deleted blocks are not served from the cache
+        store.delete_block(block.cid())?;
+        assert_eq!(bitswap_store.get(block.cid())?, None);
+        assert!(!bitswap_store.contains(block.cid())?);
+
+        // blocks are evicted over the capacity
+        let blocks: Vec<_> = (0..100)
+            .map(|n| {
+                Block::<DefaultParams>::encode(
+                    DagCborCodec,
+                    Code::Blake3_256,
+                    &Ipld::Bytes(vec![n; 100]),
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        store.insert_many(blocks.clone())?;
+        for block in &blocks {
+            bitswap_store.get(block.cid())?;
+        }
+        let misses = store.cache_stats().misses;
+        bitswap_store.get(blocks[0].cid())?;
+        assert_eq!(store.cache_stats().misses, misses + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_size() -> anyhow::Result<()> {
+        setup_logger();
+        let store = UrsaStore::new(Arc::clone(&get_store().db)).with_block_cache(1024);
+        let block = Block::<DefaultParams>::encode(
+            DagCborCodec,
+            Code::Blake3_256,
+            &Ipld::Bytes(vec![7; 300]),
+        )?;
+        store.put_block(block.cid(), block.data())?;
+
+        // the size is read from the size index, the block itself is never read
+        assert_eq!(store.get_size(block.cid())?, Some(block.data().len()));
+        assert_eq!(store.cache_stats(), CacheStats::default());
+
+        store.delete_block(block.cid())?;
+        assert_eq!(store.get_size(block.cid())?, None);
+
+        // blocks written bypassing the store are measured from their data
+        let unindexed =
+            Block::<DefaultParams>::encode(DagCborCodec, Code::Blake3_256, &Ipld::Integer(3))?;
+        store
+            .blockstore()
+            .put_keyed(unindexed.cid(), unindexed.data())?;
+        assert_eq!(
+            store.get_size(unindexed.cid())?,
+            Some(unindexed.data().len())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_latency() -> anyhow::Result<()> {
+        setup_logger();
+        let store = get_store();
+        assert_eq!(store.latency().get, None);
+
+        let mut bitswap_store = BitswapStorage(Arc::clone(&store));
+        for i in 0..100u32 {
+            let block = Block::<DefaultParams>::encode(
+                DagCborCodec,
+                Code::Blake3_256,
+                &Ipld::Integer(i.into()),
+            )?;
+            bitswap_store.insert(&block)?;
+            assert!(bitswap_store.contains(block.cid())?);
+            assert!(bitswap_store.get(block.cid())?.is_some());
+        }
+
+        let latency = store.latency();
+        for percentiles in [latency.get, latency.contains, latency.insert] {
+            let percentiles = percentiles.expect("percentiles to be populated");
+            assert!(percentiles.p50 <= percentiles.p95);
+            assert!(percentiles.p95 <= percentiles.p99);
+            assert!(percentiles.p99 < Duration::from_secs(1));
+        }
+        Ok(())
+    }
 }
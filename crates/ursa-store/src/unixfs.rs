@@ -0,0 +1,95 @@
+use anyhow::{anyhow, Result};
+use integer_encoding::VarInt;
+
+const DATA_FIELD: u64 = 2;
+const BLOCKSIZES_FIELD: u64 = 4;
+
+/// The parts of a UnixFS `Data` message needed to read the content of a file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct UnixFsData {
+    /// File content stored in the node itself.
+    pub data: Vec<u8>,
+    /// Size of the file content under each link of the node, in link order.
+    pub blocksizes: Vec<u64>,
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64> {
+    let (value, len) =
+        u64::decode_var(&bytes[*pos..]).ok_or_else(|| anyhow!("Invalid varint in UnixFS data"))?;
+    *pos += len;
+    Ok(value)
+}
+
+fn read_slice<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let slice = bytes
+        .get(*pos..*pos + len)
+        .ok_or_else(|| anyhow!("Truncated UnixFS data"))?;
+    *pos += len;
+    Ok(slice)
+}
+
+/// Decode the protobuf encoded UnixFS `Data` of a dag-pb node, skipping the fields
+/// that don't matter to read the file.
+pub(crate) fn decode_unixfs_data(bytes: &[u8]) -> Result<UnixFsData> {
+    let mut unixfs = UnixFsData::default();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let key = read_varint(bytes, &mut pos)?;
+        match (key >> 3, key & 0x7) {
+            (DATA_FIELD, 2) => {
+                let len = read_varint(bytes, &mut pos)? as usize;
+                unixfs.data = read_slice(bytes, &mut pos, len)?.to_vec();
+            }
+            (BLOCKSIZES_FIELD, 0) => unixfs.blocksizes.push(read_varint(bytes, &mut pos)?),
+            // packed encoding
+            (BLOCKSIZES_FIELD, 2) => {
+                let len = read_varint(bytes, &mut pos)? as usize;
+                let packed = read_slice(bytes, &mut pos, len)?;
+                let mut packed_pos = 0;
+                while packed_pos < packed.len() {
+                    unixfs
+                        .blocksizes
+                        .push(read_varint(packed, &mut packed_pos)?);
+                }
+            }
+            (_, 0) => {
+                read_varint(bytes, &mut pos)?;
+            }
+            (_, 1) => {
+                read_slice(bytes, &mut pos, 8)?;
+            }
+            (_, 2) => {
+                let len = read_varint(bytes, &mut pos)? as usize;
+                read_slice(bytes, &mut pos, len)?;
+            }
+            (_, 5) => {
+                read_slice(bytes, &mut pos, 4)?;
+            }
+            (field, wire_type) => {
+                return Err(anyhow!(
+                    "Unsupported wire type {wire_type} for UnixFS field {field}"
+                ))
+            }
+        }
+    }
+    Ok(unixfs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_unixfs_data() -> Result<()> {
+        // Type = File, Data = "hi", filesize = 6, blocksizes = [2, 2]
+        let bytes = [
+            0x08, 0x02, 0x12, 0x02, b'h', b'i', 0x18, 0x06, 0x20, 0x02, 0x20, 0x02,
+        ];
+        let unixfs = decode_unixfs_data(&bytes)?;
+        assert_eq!(unixfs.data, b"hi");
+        assert_eq!(unixfs.blocksizes, vec![2, 2]);
+
+        assert!(decode_unixfs_data(&[0x12, 0x05, b'h']).is_err());
+        Ok(())
+    }
+}
@@ -11,7 +11,7 @@ use tracing::{info, warn};
 use ursa_application::ApplicationConfig;
 use ursa_consensus::config::ConsensusConfig;
 use ursa_index_provider::config::ProviderConfig;
-use ursa_network::NetworkConfig;
+use ursa_network::{ConfigError, NetworkConfig};
 use ursa_rpc_service::config::ServerConfig;
 
 pub const DEFAULT_CONFIG_PATH_STR: &str = ".ursa/config.toml";
@@ -68,6 +68,19 @@ impl UrsaConfig {
             Ok(config)
         }
     }
+
+    /// Check the config before starting anything, returning every problem found.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = self.network_config.validate().err().unwrap_or_default();
+        if let Err(e) = self.provider_config.validate() {
+            errors.push(ConfigError::Invalid(e.to_string()));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
 }
 
 pub fn write<S: Display, P: Into<PathBuf>>(str: S, path: P) -> Result<()> {
@@ -65,6 +65,17 @@ async fn run() -> Result<()> {
         return Ok(());
     }
 
+    if let Err(errors) = config.validate() {
+        for error in &errors {
+            error!("Config error: {error}");
+        }
+        bail!("The config has {} error(s)", errors.len());
+    }
+    if opts.validate {
+        info!("The config is valid");
+        return Ok(());
+    }
+
     let UrsaConfig {
         network_config,
         provider_config,
@@ -91,8 +102,10 @@ async fn run() -> Result<()> {
 
     let db = RocksDb::open(db_path, &RocksDbConfig::default())
         .expect("Opening blockstore RocksDB must succeed");
-    let store = Arc::new(UrsaStore::new(Arc::clone(&Arc::new(db))));
-    let (event_sender, event_receiver) = channel(4096);
+    let store = Arc::new(
+        UrsaStore::new(Arc::clone(&Arc::new(db))).with_block_cache(network_config.block_cache_size),
+    );
+    let (event_sender, event_receiver) = channel(network_config.event_buffer);
     let service = UrsaService::new(
         keypair.clone(),
         &network_config,
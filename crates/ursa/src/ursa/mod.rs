@@ -45,6 +45,11 @@ pub struct CliOpts {
         help = "Set logging level: info (default), error, warn, debug, trace"
     )]
     pub log: Option<String>,
+    #[structopt(
+        long,
+        help = "Validate the configuration and exit without starting the node"
+    )]
+    pub validate: bool,
 }
 
 impl CliOpts {